@@ -0,0 +1,478 @@
+/* AArch64 backend, generating GAS syntax assembly for Linux from the IR. Calls follow AAPCS64:
+ * integer arguments are passed in x0-x7 and then on the stack, results come back in x0, and sp is
+ * always kept 16 byte aligned. Like the x86_64 backend every slot and virtual register has a home
+ * in the stack frame, with x0 and x1 taking the place of rax and rcx as scratch registers.
+ */
+
+
+use crate::lexer::*;
+use crate::ir::*;
+use crate::Flags;
+use crate::backend::{self, *};
+use crate::target::Target;
+use crate::error::*;
+use crate::intrinsics::{self, Intrinsic};
+
+// Registers in order of arguments for passing into a function with AAPCS64
+const REGS: [&str; 8] = ["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7"];
+// Registers "r" operands of inline assembly can be given. x16 and x17 are left out, since they're
+// needed to reach slots in big frames.
+const ASM_SCRATCH_REGS: [&str; 16] = ["x9", "x10", "x11", "x12", "x13", "x14", "x15", "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8"];
+
+pub struct Aarch64;
+
+impl Target for Aarch64 {
+    fn arg_regs(&self) -> &'static [&'static str] {
+        &REGS
+    }
+
+    // Values of 32 bits or less are accessed through the w view of the register.
+    fn register_of_size(&self, original: &str, typ: Type) -> String {
+        match type_to_size(typ) {
+            8 => String::from(original),
+            _ => original.replacen('x', "w", 1),
+        }
+    }
+
+    // Suffix of the load/store instructions for an access of the type's size.
+    fn ptr_ident_of_size(&self, typ: Type) -> String {
+        let s = match type_to_size(typ) {
+            1 => "b",
+            2 => "h",
+            4 | 8 => "",
+            _ => unreachable!()
+        };
+        String::from(s)
+    }
+
+    fn compile(&self, module: &Module, flags: &Flags) -> String {
+        compile(module, flags.clone())
+    }
+
+    fn assembler_cmd(&self) -> &'static str {
+        "aarch64-linux-gnu-as"
+    }
+
+    fn linker_cmd(&self) -> &'static str {
+        "aarch64-linux-gnu-gcc"
+    }
+
+    fn ld_cmd(&self) -> &'static str {
+        "aarch64-linux-gnu-ld"
+    }
+
+    fn triple(&self) -> &'static str {
+        "aarch64-linux-gnu"
+    }
+
+    fn start_stub(&self) -> &'static str {
+        "_start: mov x29, #0
+        mov x30, #0
+        ldr x0, [sp]
+        add x1, sp, #8
+        bl main
+        mov x8, #93
+        svc #0"
+    }
+}
+
+// Loads any 64 bit value into a register, with as few instructions as possible.
+fn load_imm(reg: &str, val: u64) -> String {
+    if val <= 0xFFFF || !val <= 0xFFFF {
+        return format!("mov {}, #{}", reg, val as i64)
+    }
+    let mut insts = Vec::new();
+    for i in 0..4 {
+        let chunk = (val >> (16 * i)) & 0xFFFF;
+        if chunk == 0 { continue }
+        let op = if insts.is_empty() { "movz" } else { "movk" };
+        insts.push(format!("{} {}, #{}, lsl #{}", op, reg, chunk, 16 * i));
+    }
+    insts.join("\n")
+}
+
+/* Accesses memory at an offset from sp. Offsets are always multiples of 8 so they can be scaled,
+ * but big frames can still go past what fits in the instruction, in which case the address is
+ * worked out in x16 first. */
+fn frame_access(op: &str, reg: &str, off: usize) -> String {
+    if off <= 4095 {
+        return format!("{} {}, [sp, #{}]", op, reg, off)
+    }
+    format!("{}\nadd x16, sp, x16\n{} {}, [x16]", load_imm("x16", off as u64), op, reg)
+}
+
+// The load instruction and register view which give a value of the type, zero or sign extended to 64 bits.
+fn load_op(reg: &str, typ: Type) -> (String, String) {
+    let op = match (type_to_size(typ.clone()), check_type_signed(typ.clone())) {
+        (8, _) | (4, false) => "ldr",
+        (4, true) => "ldrsw",
+        (2, false) => "ldrh",
+        (2, true) => "ldrsh",
+        (_, false) => "ldrb",
+        (_, true) => "ldrsb",
+    };
+    // Sign extending loads write the whole x register, zero extending ones are done through w.
+    let reg = if check_type_signed(typ.clone()) { String::from(reg) } else { Aarch64.register_of_size(reg, typ) };
+    (String::from(op), reg)
+}
+
+fn store_op(reg: &str, typ: Type) -> (String, String) {
+    (format!("str{}", Aarch64.ptr_ident_of_size(typ.clone())), Aarch64.register_of_size(reg, typ))
+}
+
+fn load_vreg(frame: &Frame, reg: &str, v: VReg) -> String {
+    frame_access("ldr", reg, frame.vreg_off(v))
+}
+
+fn store_vreg(frame: &Frame, reg: &str, v: VReg) -> String {
+    frame_access("str", reg, frame.vreg_off(v))
+}
+
+// The result is fixed up in x0.
+fn normalise(out: &mut CompiledAsm, typ: Type) {
+    backend::normalise(out, typ, |size, signed| match (size, signed) {
+        (4, false) => "mov w0, w0",
+        (4, true) => "sxtw x0, w0",
+        (2, false) => "uxth w0, w0",
+        (2, true) => "sxth x0, w0",
+        (_, false) => "uxtb w0, w0",
+        (_, true) => "sxtb x0, w0",
+    });
+}
+
+/* Operands are in x0 and x1, and returns in x0. `typ` is the type of the operands. */
+fn compile_operation(out: &mut CompiledAsm, op: Operation, typ: Type) {
+    let is_signed = check_type_signed(typ.clone());
+    match op {
+        Operation::Div => {
+            let div = if is_signed { "sdiv" } else { "udiv" };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{} x0, x0, x1", div).as_str());
+        },
+        Operation::Mod => {
+            let div = if is_signed { "sdiv" } else { "udiv" };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{} x2, x0, x1\nmsub x0, x2, x1, x0", div).as_str());
+        },
+        Operation::GreaterEqu | Operation::LessEqu | Operation::Less | Operation::Greater | Operation::NotEqu | Operation::Equ => {
+            let cond = match (op, is_signed) {
+                (Operation::GreaterEqu, true) => "ge",
+                (Operation::GreaterEqu, false) => "hs",
+                (Operation::LessEqu, true) => "le",
+                (Operation::LessEqu, false) => "ls",
+                (Operation::Less, true) => "lt",
+                (Operation::Less, false) => "lo",
+                (Operation::Greater, true) => "gt",
+                (Operation::Greater, false) => "hi",
+                (Operation::NotEqu, _) => "ne",
+                _ => "eq",
+            };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("cmp x0, x1\ncset x0, {}", cond).as_str());
+            return
+        },
+        Operation::And | Operation::Or => {
+            let combine = if op == Operation::And { "and" } else { "orr" };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("cmp x0, #0\ncset x0, ne\ncmp x1, #0\ncset x1, ne\n{} x0, x0, x1", combine).as_str());
+            return
+        },
+        Operation::BitXor => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "eor x0, x0, x1");
+        },
+        Operation::Ampersand => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "and x0, x0, x1");
+        },
+        Operation::BitOr => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "orr x0, x0, x1");
+        },
+        Operation::LeftShift => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "lsl x0, x0, x1");
+        },
+        Operation::RightShift => {
+            let shift = if is_signed { "asr" } else { "lsr" };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{} x0, x0, x1", shift).as_str());
+        },
+        Operation::Star => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mul x0, x0, x1");
+        },
+        Operation::Add => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "add x0, x0, x1");
+        },
+        Operation::Sub => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "sub x0, x0, x1");
+        },
+        Operation::Pow => {
+            let loop_label = format!("sect{}", out.num_subroutines);
+            let done_label = format!("sect{}", out.num_subroutines + 1);
+            out.num_subroutines += 2;
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mov x2, x0\nmov x0, #1");
+            write_text(&mut out.text, String::new(), out.flags.clone(), format!("{}:", loop_label).as_str());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("cbz x1, {}\nmul x0, x0, x2\nsub x1, x1, #1\nb {}", done_label, loop_label).as_str());
+            write_text(&mut out.text, String::new(), out.flags.clone(), format!("{}:", done_label).as_str());
+        },
+        _ => {
+            panic!("Unsupported operation.")
+        }
+    }
+    normalise(out, typ);
+}
+
+fn compile_unary_operation(out: &mut CompiledAsm, op: Operation, typ: Type) {
+    match op {
+        Operation::BitNot => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mvn x0, x0");
+            normalise(out, typ);
+        },
+        Operation::Not => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "cmp x0, #0\ncset x0, eq");
+        },
+        Operation::Sub => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "neg x0, x0");
+            normalise(out, typ);
+        },
+        _ => {
+            panic!("Unary operator not implemented yet.");
+        }
+    }
+}
+
+// Clobbered registers are saved below sp, which moves the memory operands (see `Frame`).
+fn compile_inline_asm(out: &mut CompiledAsm, frame: &Frame, asm: &str, inputs: &[AsmInput], outputs: &[AsmOutput], clobbers: &[String], tok: &Token) {
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "// Inline assembly");
+    let regs = pick_asm_regs(inputs, outputs, clobbers, &ASM_SCRATCH_REGS, tok);
+    for clobber in clobbers {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("str {}, [sp, #-16]!", clobber).as_str());
+    }
+    let saved = clobbers.len() * 16;
+    let mem_operand = |off: usize| {
+        assert_report(off <= 4095, Component::CODEGEN, tok.clone(), "Memory operand of inline assembly is too far from sp to be addressed directly.");
+        format!("[sp, #{}]", off)
+    };
+    let mut texts = Vec::new();
+    for (output, reg) in outputs.iter().zip(&regs) {
+        let typ = frame.func.slots[output.slot].typ.clone();
+        match reg {
+            Some(reg) => {
+                if output.read_write {
+                    let (op, reg) = load_op(reg, typ.clone());
+                    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), frame_access(&op, &reg, frame.slot_off(output.slot) + saved).as_str());
+                }
+                texts.push(Aarch64.register_of_size(reg, typ));
+            },
+            None => texts.push(mem_operand(frame.slot_off(output.slot) + saved)),
+        }
+    }
+    for (input, reg) in inputs.iter().zip(&regs[outputs.len()..]) {
+        match (&input.val, reg) {
+            (AsmValue::Imm(v), _) => texts.push(v.to_string()),
+            (AsmValue::VReg(v), Some(reg)) => {
+                write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), frame_access("ldr", reg, frame.vreg_off(*v) + saved).as_str());
+                texts.push(Aarch64.register_of_size(reg, frame.func.vreg_type(*v)));
+            },
+            (AsmValue::VReg(v), None) => texts.push(mem_operand(frame.vreg_off(*v) + saved)),
+        }
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), substitute_asm_operands(asm, &asm_operand_names(inputs, outputs), &texts).as_str());
+    for (output, reg) in outputs.iter().zip(&regs) {
+        if let Some(reg) = reg {
+            let (op, reg) = store_op(reg, frame.func.slots[output.slot].typ.clone());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), frame_access(&op, &reg, frame.slot_off(output.slot) + saved).as_str());
+        }
+    }
+    for clobber in clobbers.iter().rev() {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("ldr {}, [sp], #16", clobber).as_str());
+    }
+}
+
+/* The system call number goes in x8 and its arguments in x0-x5. cntvct_el0 stands in for the
+ * timestamp counter, since it's the counter every EL0 program is allowed to read. */
+fn compile_intrinsic(out: &mut CompiledAsm, frame: &Frame, dst: Option<VReg>, intrinsic: Intrinsic, args: &[VReg]) {
+    let asm = match intrinsic {
+        Intrinsic::Syscall => {
+            let mut asm = load_vreg(frame, "x8", args[0]);
+            for (reg, arg) in Aarch64.arg_regs().iter().zip(&args[1..]) {
+                asm.push_str(format!("\n{}", load_vreg(frame, reg, *arg)).as_str());
+            }
+            asm + "\nsvc #0"
+        },
+        Intrinsic::Rdtsc => String::from("mrs x0, cntvct_el0"),
+        Intrinsic::Pause => String::from("yield"),
+        Intrinsic::Hlt => String::from("wfi"),
+        Intrinsic::Unreachable | Intrinsic::Trap => String::from("brk #1"),
+        Intrinsic::Outb | Intrinsic::Inb | Intrinsic::Cpuid => unreachable!(),
+    };
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), asm.as_str());
+    if let Some(dst) = dst {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), store_vreg(frame, "x0", dst).as_str());
+    }
+}
+
+fn compile_func_call(out: &mut CompiledAsm, frame: &Frame, dst: Option<VReg>, callee: &str, args: &[VReg]) {
+    if let Some(intrinsic) = intrinsics::lookup(callee) {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("// Intrinsic {}", callee).as_str());
+        return compile_intrinsic(out, frame, dst, intrinsic, args)
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("// Call {}", callee).as_str());
+    // Arguments past the eighth go at the bottom of the frame, which is where the callee expects them.
+    for (i, arg) in args.iter().enumerate().skip(Aarch64.arg_regs().len()) {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", load_vreg(frame, "x16", *arg), frame_access("str", "x16", (i - Aarch64.arg_regs().len()) * 8)).as_str());
+    }
+    for (reg, arg) in Aarch64.arg_regs().iter().zip(args.iter()) {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_vreg(frame, reg, *arg).as_str());
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("bl {}", callee).as_str());
+    if let Some(dst) = dst {
+        normalise(out, frame.func.vreg_type(dst));
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), store_vreg(frame, "x0", dst).as_str());
+    }
+}
+
+fn compile_inst(out: &mut CompiledAsm, frame: &Frame, inst: &Inst) {
+    let func = frame.func;
+    match inst {
+        Inst::Imm { dst, val } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", load_imm("x0", *val), store_vreg(frame, "x0", *dst)).as_str());
+        },
+        Inst::Load { dst, slot } => {
+            let (op, reg) = load_op("x0", func.slots[*slot].typ.clone());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", frame_access(&op, &reg, frame.slot_off(*slot)), store_vreg(frame, "x0", *dst)).as_str());
+        },
+        Inst::Store { slot, src } => {
+            let (op, reg) = store_op("x0", func.slots[*slot].typ.clone());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", load_vreg(frame, "x0", *src), frame_access(&op, &reg, frame.slot_off(*slot))).as_str());
+        },
+        Inst::AddrOf { dst, slot } => {
+            let off = frame.slot_off(*slot);
+            let addr = if off <= 4095 { format!("add x0, sp, #{}", off) } else { format!("{}\nadd x0, sp, x16", load_imm("x16", off as u64)) };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", addr, store_vreg(frame, "x0", *dst)).as_str());
+        },
+        Inst::StrAddr { dst, idx } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("adrp x0, strlit{}\nadd x0, x0, :lo12:strlit{}\n{}", idx, idx, store_vreg(frame, "x0", *dst)).as_str());
+        },
+        Inst::LoadPtr { dst, addr } => {
+            let (op, reg) = load_op("x0", func.vreg_type(*dst));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{} {}, [x1]\n{}", load_vreg(frame, "x1", *addr), op, reg, store_vreg(frame, "x0", *dst)).as_str());
+        },
+        Inst::StorePtr { addr, src } => {
+            let (op, reg) = store_op("x0", func.vreg_type(*src));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}\n{} {}, [x1]", load_vreg(frame, "x1", *addr), load_vreg(frame, "x0", *src), op, reg).as_str());
+        },
+        Inst::Binary { dst, op, lhs, rhs } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", load_vreg(frame, "x0", *lhs), load_vreg(frame, "x1", *rhs)).as_str());
+            compile_operation(out, *op, func.vreg_type(*lhs));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), store_vreg(frame, "x0", *dst).as_str());
+        },
+        Inst::Unary { dst, op, src } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_vreg(frame, "x0", *src).as_str());
+            compile_unary_operation(out, *op, func.vreg_type(*dst));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), store_vreg(frame, "x0", *dst).as_str());
+        },
+        Inst::Cast { dst, src } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_vreg(frame, "x0", *src).as_str());
+            normalise(out, func.vreg_type(*dst));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), store_vreg(frame, "x0", *dst).as_str());
+        },
+        Inst::Call { dst, func: callee, args, .. } => {
+            compile_func_call(out, frame, *dst, callee, args);
+        },
+        Inst::InlineAsm { asm, inputs, outputs, clobbers, tok } => {
+            compile_inline_asm(out, frame, asm, inputs, outputs, clobbers, tok);
+        },
+    }
+}
+
+fn compile_epilogue(out: &mut CompiledAsm) {
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mov sp, x29");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "ldp x29, x30, [sp], #16");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "ret");
+}
+
+fn compile_terminator(out: &mut CompiledAsm, frame: &Frame, term: &Terminator, base: u64) {
+    match term {
+        Terminator::Jump(target) => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("b {}", block_label(base, *target)).as_str());
+        },
+        Terminator::Branch { cond, then_blk, else_blk } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_vreg(frame, "x0", *cond).as_str());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("cbz x0, {}\nb {}", block_label(base, *else_blk), block_label(base, *then_blk)).as_str());
+        },
+        Terminator::Return(v) => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "// Return from function");
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_vreg(frame, "x0", *v).as_str());
+            compile_epilogue(out);
+        },
+    }
+}
+
+fn compile_function(out: &mut CompiledAsm, func: &Function) {
+    let frame = Frame::new(func, REGS.len());
+    if !func.is_static { out.globals.push(func.name.clone()) }
+    out.spaces.clear();
+    gas_func_section(out, func, '%');
+    if func.naked {
+        compile_naked_function(out, func);
+        if func.section.is_some() {
+            write_text(&mut out.text, String::new(), out.flags.clone(), ".text");
+        }
+        return
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("\n{}: stp x29, x30, [sp, #-16]!", func.name).as_str());
+    for _ in func.name.chars() {
+        out.spaces.push(' ');
+    }
+    out.spaces.push_str("  ");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mov x29, sp");
+    let size = frame.size();
+    let alloc = if size <= 4095 { format!("sub sp, sp, #{}", size) } else { format!("{}\nsub sp, sp, x16", load_imm("x16", size as u64)) };
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), alloc.as_str());
+    for (i, slot) in func.params.iter().enumerate() {
+        let typ = func.slots[*slot].typ.clone();
+        if i < Aarch64.arg_regs().len() {
+            let (op, reg) = store_op(Aarch64.arg_regs()[i], typ);
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), frame_access(&op, &reg, frame.slot_off(*slot)).as_str());
+        } else {
+            // The caller left these at the bottom of its frame, just above the saved x29 and x30.
+            let stack_off = 16 + (i - Aarch64.arg_regs().len()) * 8;
+            let (op, reg) = store_op("x16", typ);
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("ldr x16, [x29, #{}]\n{}", stack_off, frame_access(&op, &reg, frame.slot_off(*slot))).as_str());
+        }
+    }
+    let base = out.num_subroutines;
+    out.num_subroutines += func.blocks.len() as u64;
+    for block in &func.blocks {
+        write_text(&mut out.text, String::new(), out.flags.clone(), format!("{}:", block_label(base, block.id)).as_str());
+        for inst in &block.insts {
+            compile_inst(out, &frame, inst);
+        }
+        compile_terminator(out, &frame, &block.term, base);
+    }
+    if func.section.is_some() {
+        write_text(&mut out.text, String::new(), out.flags.clone(), ".text");
+    }
+}
+
+fn compile(module: &Module, flags: Flags) -> String {
+    let mut out = CompiledAsm::new(flags);
+    write_start_stub(&mut out, module, &Aarch64);
+    for func in &module.functions {
+        compile_function(&mut out, func);
+    }
+    let sections = gas_const_sections(&mut out, module, '%');
+
+    out.spaces.clear();
+
+    for (i, strlit) in module.strings.iter().enumerate() {
+        let mut stringchars: Vec<String> = strlit.bytes().map(|c| c.to_string()).collect();
+        stringchars.push(String::from("0")); // make sure it has a null terminator
+        write_text(&mut out.rodata, out.spaces.clone(), out.flags.clone(), format!("strlit{}: .byte {}", i, stringchars.join(", ")).as_str());
+    }
+
+    let mut asm = String::from(".text\n");
+    for global in &out.globals {
+        asm.push_str(format!(".globl {}\n.type {}, %function\n", global, global).as_str());
+    }
+    asm.push_str(format!("{}\n", out.text).as_str());
+    asm.push_str(format!(".data\n{}", out.data).as_str());
+    asm.push_str(format!(".section .rodata\n{}", out.rodata).as_str());
+    asm.push_str(sections.as_str());
+    // Without this, linkers assume the stack needs to be executable.
+    asm.push_str(".section .note.GNU-stack,\"\",%progbits\n");
+    asm
+}
@@ -0,0 +1,71 @@
+/* Writes `ar` archives (static libraries) in the System V/GNU format that `ld` reads:
+ *
+ * !<arch>\n
+ * /               the symbol index: how many symbols, the offset of the member defining each one
+ *                 (all big endian u32s), then their names, each ending with a null byte
+ * //              member names too long for a header, each ending with "/\n"
+ * name.o/         then every member, each with its own 60 byte header
+ *
+ * Timestamps, owners and modes are always the same, so building the same archive twice gives
+ * exactly the same file.
+ */
+
+use crate::elf;
+
+const MAGIC: &[u8] = b"!<arch>\n";
+const HEADER_SIZE: usize = 60;
+
+fn header(name: &str, size: usize) -> Vec<u8> {
+    let header = format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 644, size);
+    header.into_bytes()
+}
+
+fn push_member(buf: &mut Vec<u8>, name: &str, data: &[u8]) {
+    buf.extend(header(name, data.len()));
+    buf.extend_from_slice(data);
+    // Members start at even offsets.
+    if data.len() % 2 == 1 { buf.push(b'\n') }
+}
+
+fn padded(size: usize) -> usize {
+    HEADER_SIZE + size + size % 2
+}
+
+// Takes the name and contents of each object file, and returns the archive holding them.
+pub fn write(members: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut long_names = Vec::new();
+    let names: Vec<String> = members.iter().map(|(name, _)| {
+        if name.len() < 16 { return format!("{}/", name) }
+        let offset = long_names.len();
+        long_names.extend_from_slice(format!("{}/\n", name).as_bytes());
+        format!("/{}", offset)
+    }).collect();
+    let symbols: Vec<Vec<String>> = members.iter().map(|(_, data)| elf::defined_symbols(data)).collect();
+    let count = symbols.iter().map(|s| s.len()).sum::<usize>();
+    let names_size: usize = symbols.iter().flatten().map(|s| s.len() + 1).sum();
+    let index_size = 4 + 4 * count + names_size;
+
+    // Offsets in the index are to member headers, so they're worked out before anything is written.
+    let mut offset = MAGIC.len() + padded(index_size);
+    if !long_names.is_empty() { offset += padded(long_names.len()) }
+    let mut index = Vec::new();
+    index.extend((count as u32).to_be_bytes());
+    for ((_, data), syms) in members.iter().zip(&symbols) {
+        for _ in syms {
+            index.extend((offset as u32).to_be_bytes());
+        }
+        offset += padded(data.len());
+    }
+    for sym in symbols.iter().flatten() {
+        index.extend_from_slice(sym.as_bytes());
+        index.push(0);
+    }
+
+    let mut buf = Vec::from(MAGIC);
+    push_member(&mut buf, "/", &index);
+    if !long_names.is_empty() { push_member(&mut buf, "//", &long_names) }
+    for ((_, data), name) in members.iter().zip(&names) {
+        push_member(&mut buf, name, data);
+    }
+    buf
+}
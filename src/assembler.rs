@@ -0,0 +1,1125 @@
+/* Built in x86_64 assembler. Takes the assembly the backend generates (including any inline
+ * assembly blocks) and encodes it straight into an ELF object, so NASM isn't needed to build
+ * anything. Only the subset of NASM that's actually useful here is supported: `global`, `extern`,
+ * `section`, labels, `db`/`dw`/`dd`/`dq`, `align`, and the common integer instructions. After a
+ * `.intel_syntax noprefix` directive it reads the GAS flavour of Intel syntax instead, which is what
+ * `--asm-dialect=gas` outputs.
+ */
+
+use std::collections::HashMap;
+use crate::elf::*;
+use crate::error::*;
+
+const REGS_64: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+const REGS_32: [&str; 16] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"];
+const REGS_16: [&str; 16] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w"];
+const REGS_8: [&str; 16] = ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b"];
+const REGS_8_HIGH: [&str; 4] = ["ah", "ch", "dh", "bh"];
+
+const ALU_OPS: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFT_OPS: [(&str, u8); 7] = [("rol", 0), ("ror", 1), ("rcl", 2), ("rcr", 3), ("shl", 4), ("sal", 4), ("shr", 5)];
+const GROUP3_OPS: [(&str, u8); 5] = [("not", 2), ("neg", 3), ("mul", 4), ("div", 6), ("idiv", 7)];
+
+// Instructions without any operands, and their encodings.
+const PLAIN_INSTRUCTIONS: [(&str, &[u8]); 30] = [
+    ("ret", &[0xC3]), ("nop", &[0x90]), ("cqo", &[0x48, 0x99]), ("cdq", &[0x99]), ("cdqe", &[0x48, 0x98]),
+    ("cwde", &[0x98]), ("syscall", &[0x0F, 0x05]), ("hlt", &[0xF4]), ("leave", &[0xC9]), ("int3", &[0xCC]),
+    ("cli", &[0xFA]), ("sti", &[0xFB]), ("ud2", &[0x0F, 0x0B]), ("pause", &[0xF3, 0x90]), ("rdtsc", &[0x0F, 0x31]),
+    ("rdtscp", &[0x0F, 0x01, 0xF9]), ("cpuid", &[0x0F, 0xA2]), ("iretq", &[0x48, 0xCF]), ("clc", &[0xF8]), ("stc", &[0xF9]),
+    ("cld", &[0xFC]), ("std", &[0xFD]), ("lfence", &[0x0F, 0xAE, 0xE8]), ("mfence", &[0x0F, 0xAE, 0xF0]), ("sfence", &[0x0F, 0xAE, 0xF8]),
+    ("swapgs", &[0x0F, 0x01, 0xF8]), ("rdmsr", &[0x0F, 0x32]), ("wrmsr", &[0x0F, 0x30]), ("pushfq", &[0x9C]), ("popfq", &[0x9D]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reg {
+    pub num: u8,
+    pub size: u8,
+    pub high_byte: bool, // ah, ch, dh and bh, which can't be used with a REX prefix
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mem {
+    pub size: Option<u8>,
+    pub base: Option<Reg>,
+    pub index: Option<(Reg, u8)>,
+    pub disp: i64,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    Mem(Mem),
+    Imm(i64),
+    Label(String, i64),
+}
+
+#[derive(Debug, Default)]
+struct Encoding {
+    prefix66: bool,
+    rex_w: bool,
+    rex_r: bool,
+    rex_x: bool,
+    rex_b: bool,
+    force_rex: bool,
+    no_rex: bool,
+    opcode: Vec<u8>,
+    modrm: Option<u8>,
+    sib: Option<u8>,
+    disp: Vec<u8>,
+    disp_ref: Option<(String, i64, RelocKind)>,
+    imm: Vec<u8>,
+    imm_ref: Option<(String, i64, RelocKind)>,
+}
+
+// A reference to a label from within an instruction: (offset into the instruction, label, addend, kind)
+type InstFixup = (usize, String, i64, RelocKind);
+
+struct Fixup {
+    section: usize,
+    offset: u64,
+    symbol: String,
+    kind: RelocKind,
+    addend: i64,
+    line: usize,
+}
+
+pub fn parse_reg(name: &str) -> Option<Reg> {
+    let name = name.to_ascii_lowercase();
+    let sized = [(&REGS_64, 8), (&REGS_32, 4), (&REGS_16, 2), (&REGS_8, 1)];
+    for (table, size) in sized {
+        if let Some(num) = table.iter().position(|r| *r == name) {
+            return Some(Reg { num: num as u8, size, high_byte: false })
+        }
+    }
+    REGS_8_HIGH.iter().position(|r| *r == name).map(|num| Reg { num: num as u8 + 4, size: 1, high_byte: true })
+}
+
+pub fn reg_name(reg: &Reg) -> &'static str {
+    match (reg.size, reg.high_byte) {
+        (1, true) => REGS_8_HIGH[reg.num as usize - 4],
+        (1, false) => REGS_8[reg.num as usize],
+        (2, _) => REGS_16[reg.num as usize],
+        (4, _) => REGS_32[reg.num as usize],
+        _ => REGS_64[reg.num as usize],
+    }
+}
+
+pub fn parse_number(s: &str) -> Option<i64> {
+    let (neg, body) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest.trim()),
+        None => (false, s),
+    };
+    let body = body.replace('_', "");
+    let val = if let Some(hex) = body.strip_prefix("0x").or_else(|| body.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = body.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2).ok()?
+    } else if body.len() > 1 && body.ends_with(['h', 'H']) && body.starts_with(|c: char| c.is_ascii_digit()) {
+        u64::from_str_radix(&body[..body.len() - 1], 16).ok()?
+    } else if body.len() == 3 && (body.starts_with('\'') && body.ends_with('\'') || body.starts_with('`') && body.ends_with('`')) {
+        body.as_bytes()[1] as u64
+    } else {
+        body.parse::<u64>().ok()?
+    };
+    Some(if neg { (val as i64).wrapping_neg() } else { val as i64 })
+}
+
+pub fn is_label_name(s: &str) -> bool {
+    !s.is_empty() && s.starts_with(|c: char| c.is_ascii_alphabetic() || "._?@$".contains(c))
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || "._?@$#~".contains(c))
+}
+
+// Splits on commas, except for ones inside of quotes or brackets.
+pub fn split_operands(s: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut depth = 0;
+    for c in s.chars() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {},
+            (None, '"' | '\'' | '`') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(current.trim().to_string());
+                current.clear();
+                continue
+            },
+            _ => {},
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() { parts.push(current.trim().to_string()) }
+    parts
+}
+
+// Splits a line into the code and the comment after it. Comments start with `;` in NASM and `#` in GAS.
+pub fn split_comment(line: &str, gas: bool) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), '\\') if gas && !escaped => { escaped = true; continue },
+            (Some(q), _) if c == q && !escaped => quote = None,
+            (Some(_), _) => {},
+            (None, '"' | '\'' | '`') => quote = Some(c),
+            (None, ';') if !gas => return (&line[..i], Some(&line[i + 1..])),
+            (None, '#') if gas => return (&line[..i], Some(&line[i + 1..])),
+            _ => {},
+        }
+        escaped = false;
+    }
+    (line, None)
+}
+
+fn strip_size(s: &str) -> (Option<u8>, &str) {
+    let lower = s.to_ascii_lowercase();
+    for (word, size) in [("byte", 1), ("word", 2), ("dword", 4), ("qword", 8)] {
+        if lower.starts_with(word) && lower[word.len()..].starts_with([' ', '[']) {
+            let rest = s[word.len()..].trim_start();
+            let rest = if rest.to_ascii_lowercase().starts_with("ptr") { rest[3..].trim_start() } else { rest };
+            return (Some(size), rest)
+        }
+    }
+    (None, s)
+}
+
+fn parse_mem(inner: &str, size: Option<u8>) -> Result<Mem, String> {
+    let mut inner = inner.trim();
+    for prefix in ["rel ", "abs "] {
+        if inner.to_ascii_lowercase().starts_with(prefix) { inner = inner[prefix.len()..].trim_start() }
+    }
+    let mut mem = Mem { size, base: None, index: None, disp: 0, label: None };
+    let mut rip = false;
+    let mut terms = Vec::new();
+    let mut current = String::new();
+    let mut negative = false;
+    for c in inner.chars() {
+        if c == '+' || c == '-' {
+            if !current.trim().is_empty() { terms.push((negative, current.trim().to_string())) }
+            current.clear();
+            negative = c == '-';
+            continue
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() { terms.push((negative, current.trim().to_string())) }
+    for (negative, term) in terms {
+        if term.eq_ignore_ascii_case("rip") && !negative && !rip {
+            // Labels are always addressed relative to rip anyway, so it's only noted to check it's used with one.
+            rip = true;
+        } else if let Some((a, b)) = term.split_once('*') {
+            let (reg, scale) = match (parse_reg(a.trim()), parse_reg(b.trim())) {
+                (Some(r), None) => (r, parse_number(b.trim())),
+                (None, Some(r)) => (r, parse_number(a.trim())),
+                _ => return Err(format!("Invalid scaled index in memory operand: {}", term)),
+            };
+            match scale {
+                Some(s @ (1 | 2 | 4 | 8)) if !negative && mem.index.is_none() => mem.index = Some((reg, s as u8)),
+                _ => return Err(format!("Invalid scaled index in memory operand: {}", term)),
+            }
+        } else if let Some(reg) = parse_reg(&term) {
+            if negative { return Err(String::from("Registers can't be subtracted in memory operands.")) }
+            if mem.base.is_none() { mem.base = Some(reg) }
+            else if mem.index.is_none() { mem.index = Some((reg, 1)) }
+            else { return Err(String::from("Too many registers in memory operand.")) }
+        } else if let Some(n) = parse_number(&term) {
+            mem.disp = mem.disp.wrapping_add(if negative { n.wrapping_neg() } else { n });
+        } else if is_label_name(&term) && !negative && mem.label.is_none() {
+            mem.label = Some(term);
+        } else {
+            return Err(format!("Couldn't understand memory operand: [{}]", inner))
+        }
+    }
+    for reg in mem.base.iter().chain(mem.index.iter().map(|(r, _)| r)) {
+        if reg.size != 8 { return Err(String::from("Only 64 bit registers can be used for addressing memory.")) }
+    }
+    if mem.index.is_some_and(|(r, _)| r.num == 4) { return Err(String::from("rsp can't be used as an index register.")) }
+    if rip && (mem.base.is_some() || mem.index.is_some() || mem.label.is_none()) {
+        return Err(String::from("rip relative addressing can only be used with a label."))
+    }
+    Ok(mem)
+}
+
+pub fn parse_operand(s: &str) -> Result<Operand, String> {
+    let (size, rest) = strip_size(s.trim());
+    // GAS needs `OFFSET` to tell the address of a label apart from loading from it, NASM doesn't.
+    let rest = match rest.get(..7) {
+        Some(word) if word.eq_ignore_ascii_case("offset ") => rest[7..].trim_start(),
+        _ => rest,
+    };
+    if rest.starts_with('[') && rest.ends_with(']') {
+        return Ok(Operand::Mem(parse_mem(&rest[1..rest.len() - 1], size)?))
+    }
+    // GCC puts the displacement outside of the brackets, like `.LC0[rip]`.
+    if let (Some(open), true) = (rest.find('['), rest.ends_with(']')) {
+        return Ok(Operand::Mem(parse_mem(format!("{} + {}", &rest[open + 1..rest.len() - 1], &rest[..open]).as_str(), size)?))
+    }
+    if let Some(reg) = parse_reg(rest) { return Ok(Operand::Reg(reg)) }
+    if let Some(n) = parse_number(rest) { return Ok(Operand::Imm(n)) }
+    if is_label_name(rest) { return Ok(Operand::Label(String::from(rest), 0)) }
+    if let Some((label, off)) = rest.split_once('+') {
+        if let (true, Some(n)) = (is_label_name(label.trim()), parse_number(off.trim())) {
+            return Ok(Operand::Label(String::from(label.trim()), n))
+        }
+    }
+    Err(format!("Couldn't understand operand: {}", s.trim()))
+}
+
+fn condition_code(s: &str) -> Option<u8> {
+    let cc = match s {
+        "o" => 0, "no" => 1, "b" | "c" | "nae" => 2, "ae" | "nb" | "nc" => 3,
+        "e" | "z" => 4, "ne" | "nz" => 5, "be" | "na" => 6, "a" | "nbe" => 7,
+        "s" => 8, "ns" => 9, "p" | "pe" => 10, "np" | "po" => 11,
+        "l" | "nge" => 12, "ge" | "nl" => 13, "le" | "ng" => 14, "g" | "nle" => 15,
+        _ => return None,
+    };
+    Some(cc)
+}
+
+fn fits_i8(v: i64) -> bool {
+    v >= i8::MIN as i64 && v <= i8::MAX as i64
+}
+
+fn fits_i32(v: i64) -> bool {
+    v >= i32::MIN as i64 && v <= i32::MAX as i64
+}
+
+// Encodes an immediate for an operation of the given size. 64 bit operations take a sign extended
+// 32 bit immediate.
+fn imm_bytes(v: i64, size: u8) -> Result<Vec<u8>, String> {
+    let ok = match size {
+        1 => v >= i8::MIN as i64 && v <= u8::MAX as i64,
+        2 => v >= i16::MIN as i64 && v <= u16::MAX as i64,
+        4 => v >= i32::MIN as i64 && v <= u32::MAX as i64,
+        _ => fits_i32(v),
+    };
+    if !ok { return Err(format!("Immediate value {} doesn't fit in the operation.", v)) }
+    let bytes = v.to_le_bytes();
+    Ok(bytes[..size.min(4) as usize].to_vec())
+}
+
+fn operand_size(operands: &[&Operand]) -> Result<u8, String> {
+    let mut size = None;
+    for op in operands {
+        let this = match op {
+            Operand::Reg(r) => Some(r.size),
+            Operand::Mem(m) => m.size,
+            _ => None,
+        };
+        match (size, this) {
+            (Some(a), Some(b)) if a != b => return Err(String::from("Operand sizes don't match.")),
+            (None, Some(b)) => size = Some(b),
+            _ => {},
+        }
+    }
+    size.ok_or_else(|| String::from("Operation size wasn't specified."))
+}
+
+impl Encoding {
+    fn new(opcode: &[u8]) -> Self {
+        Self { opcode: opcode.to_vec(), ..Default::default() }
+    }
+
+    fn size(&mut self, size: u8) {
+        if size == 2 { self.prefix66 = true }
+        if size == 8 { self.rex_w = true }
+    }
+
+    fn note_reg(&mut self, reg: &Reg) {
+        if reg.size == 1 && reg.high_byte { self.no_rex = true }
+        if reg.size == 1 && !reg.high_byte && (4..8).contains(&reg.num) { self.force_rex = true }
+    }
+
+    // Fills in the ModRM byte, with `reg_field` being either a register number or an opcode extension.
+    fn rm(&mut self, reg_field: u8, rm: &Operand) -> Result<(), String> {
+        if reg_field >= 8 { self.rex_r = true }
+        let reg_bits = (reg_field & 7) << 3;
+        match rm {
+            Operand::Reg(r) => {
+                self.note_reg(r);
+                if r.num >= 8 { self.rex_b = true }
+                self.modrm = Some(0xC0 | reg_bits | (r.num & 7));
+            },
+            Operand::Mem(m) => {
+                if let (None, None, Some(label)) = (m.base, m.index, &m.label) {
+                    // Labels on their own are always addressed relative to rip.
+                    self.modrm = Some(reg_bits | 0b101);
+                    self.disp = vec![0; 4];
+                    self.disp_ref = Some((label.clone(), m.disp, RelocKind::Pc32));
+                    return Ok(())
+                }
+                let base = match m.base {
+                    Some(b) => b,
+                    None => {
+                        // No base register, so there's always a 32 bit displacement.
+                        let index = m.index.map(|(r, s)| (r.num, s)).unwrap_or((4, 1));
+                        if index.0 >= 8 { self.rex_x = true }
+                        self.modrm = Some(reg_bits | 0b100);
+                        self.sib = Some((index.1.trailing_zeros() as u8) << 6 | (index.0 & 7) << 3 | 0b101);
+                        self.disp = (m.disp as i32).to_le_bytes().to_vec();
+                        if let Some(label) = &m.label { self.disp_ref = Some((label.clone(), m.disp, RelocKind::Abs32S)) }
+                        return Ok(())
+                    },
+                };
+                if base.num >= 8 { self.rex_b = true }
+                let mode = if m.label.is_some() || !fits_i8(m.disp) {
+                    self.disp = (m.disp as i32).to_le_bytes().to_vec();
+                    0b10
+                } else if m.disp == 0 && base.num & 7 != 5 {
+                    0b00
+                } else {
+                    self.disp = vec![m.disp as u8];
+                    0b01
+                };
+                if let Some(label) = &m.label { self.disp_ref = Some((label.clone(), m.disp, RelocKind::Abs32S)) }
+                if m.index.is_some() || base.num & 7 == 4 {
+                    let (index, scale) = m.index.map(|(r, s)| (r.num, s)).unwrap_or((4, 1));
+                    if index >= 8 { self.rex_x = true }
+                    self.modrm = Some(mode << 6 | reg_bits | 0b100);
+                    self.sib = Some((scale.trailing_zeros() as u8) << 6 | (index & 7) << 3 | (base.num & 7));
+                } else {
+                    self.modrm = Some(mode << 6 | reg_bits | (base.num & 7));
+                }
+            },
+            _ => return Err(String::from("Expected a register or memory operand.")),
+        }
+        Ok(())
+    }
+
+    // Puts a register in the ModRM reg field.
+    fn reg(&mut self, reg: &Reg, rm: &Operand) -> Result<(), String> {
+        self.note_reg(reg);
+        self.rm(reg.num, rm)
+    }
+
+    // For opcodes with the register number added to them.
+    fn plus_reg(&mut self, reg: &Reg) {
+        self.note_reg(reg);
+        if reg.num >= 8 { self.rex_b = true }
+        let last = self.opcode.len() - 1;
+        self.opcode[last] += reg.num & 7;
+    }
+
+    fn rel32(&mut self, label: &str, extra: i64, kind: RelocKind) {
+        self.imm = vec![0; 4];
+        self.imm_ref = Some((String::from(label), extra, kind));
+    }
+}
+
+fn encode(mnemonic: &str, ops: &[Operand]) -> Result<Encoding, String> {
+    let bad = || Err(format!("Invalid combination of operands for {}.", mnemonic));
+    if let Some((_, bytes)) = PLAIN_INSTRUCTIONS.iter().find(|(m, _)| *m == mnemonic) {
+        if !(ops.is_empty() || mnemonic == "ret" && ops.len() == 1) { return bad() }
+        if let [Operand::Imm(v)] = ops {
+            let mut enc = Encoding::new(&[0xC2]);
+            enc.imm = imm_bytes(*v, 2)?;
+            return Ok(enc)
+        }
+        return Ok(Encoding::new(bytes))
+    }
+    if let Some(n) = ALU_OPS.iter().position(|m| *m == mnemonic) {
+        let n = n as u8;
+        return match ops {
+            [dst @ (Operand::Reg(_) | Operand::Mem(_)), Operand::Reg(src)] => {
+                let size = operand_size(&[dst, &ops[1]])?;
+                let mut enc = Encoding::new(&[8 * n + if size == 1 { 0 } else { 1 }]);
+                enc.size(size);
+                enc.reg(src, dst)?;
+                Ok(enc)
+            },
+            [Operand::Reg(dst), src @ Operand::Mem(_)] => {
+                let size = operand_size(&[&ops[0], src])?;
+                let mut enc = Encoding::new(&[8 * n + if size == 1 { 2 } else { 3 }]);
+                enc.size(size);
+                enc.reg(dst, src)?;
+                Ok(enc)
+            },
+            [dst @ (Operand::Reg(_) | Operand::Mem(_)), Operand::Imm(v)] => {
+                let size = operand_size(&[dst])?;
+                let mut enc = if size == 1 {
+                    Encoding::new(&[0x80])
+                } else if fits_i8(*v) {
+                    Encoding::new(&[0x83])
+                } else {
+                    Encoding::new(&[0x81])
+                };
+                enc.size(size);
+                enc.rm(n, dst)?;
+                enc.imm = imm_bytes(*v, if enc.opcode[0] == 0x83 { 1 } else { size })?;
+                Ok(enc)
+            },
+            _ => bad(),
+        }
+    }
+    if let Some((_, n)) = SHIFT_OPS.iter().chain([("sar", 7)].iter()).find(|(m, _)| *m == mnemonic) {
+        return match ops {
+            [dst, Operand::Reg(Reg { num: 1, size: 1, high_byte: false })] => {
+                let size = operand_size(&[dst])?;
+                let mut enc = Encoding::new(&[if size == 1 { 0xD2 } else { 0xD3 }]);
+                enc.size(size);
+                enc.rm(*n, dst)?;
+                Ok(enc)
+            },
+            [dst] | [dst, Operand::Imm(1)] => {
+                let size = operand_size(&[dst])?;
+                let mut enc = Encoding::new(&[if size == 1 { 0xD0 } else { 0xD1 }]);
+                enc.size(size);
+                enc.rm(*n, dst)?;
+                Ok(enc)
+            },
+            [dst, Operand::Imm(v)] => {
+                let size = operand_size(&[dst])?;
+                let mut enc = Encoding::new(&[if size == 1 { 0xC0 } else { 0xC1 }]);
+                enc.size(size);
+                enc.rm(*n, dst)?;
+                enc.imm = imm_bytes(*v, 1)?;
+                Ok(enc)
+            },
+            _ => bad(),
+        }
+    }
+    if let Some((_, n)) = GROUP3_OPS.iter().find(|(m, _)| *m == mnemonic) {
+        return match ops {
+            [dst @ (Operand::Reg(_) | Operand::Mem(_))] => {
+                let size = operand_size(&[dst])?;
+                let mut enc = Encoding::new(&[if size == 1 { 0xF6 } else { 0xF7 }]);
+                enc.size(size);
+                enc.rm(*n, dst)?;
+                Ok(enc)
+            },
+            _ => bad(),
+        }
+    }
+    if let Some(cc) = mnemonic.strip_prefix("set").and_then(condition_code) {
+        return match ops {
+            [dst @ (Operand::Reg(_) | Operand::Mem(_))] => {
+                if operand_size(&[dst]).unwrap_or(1) != 1 { return bad() }
+                let mut enc = Encoding::new(&[0x0F, 0x90 + cc]);
+                enc.rm(0, dst)?;
+                Ok(enc)
+            },
+            _ => bad(),
+        }
+    }
+    if let Some(cc) = mnemonic.strip_prefix("cmov").and_then(condition_code) {
+        return match ops {
+            [Operand::Reg(dst), src @ (Operand::Reg(_) | Operand::Mem(_))] => {
+                let size = operand_size(&[&ops[0], src])?;
+                if size == 1 { return bad() }
+                let mut enc = Encoding::new(&[0x0F, 0x40 + cc]);
+                enc.size(size);
+                enc.reg(dst, src)?;
+                Ok(enc)
+            },
+            _ => bad(),
+        }
+    }
+    if let Some(cc) = mnemonic.strip_prefix('j').and_then(condition_code) {
+        return match ops {
+            [Operand::Label(label, extra)] => {
+                let mut enc = Encoding::new(&[0x0F, 0x80 + cc]);
+                enc.rel32(label, *extra, RelocKind::Pc32);
+                Ok(enc)
+            },
+            _ => bad(),
+        }
+    }
+    match (mnemonic, ops) {
+        ("mov", [dst @ (Operand::Reg(_) | Operand::Mem(_)), Operand::Reg(src)]) => {
+            let size = operand_size(&[dst, &ops[1]])?;
+            let mut enc = Encoding::new(&[if size == 1 { 0x88 } else { 0x89 }]);
+            enc.size(size);
+            enc.reg(src, dst)?;
+            Ok(enc)
+        },
+        ("mov", [Operand::Reg(dst), src @ Operand::Mem(_)]) => {
+            let size = operand_size(&[&ops[0], src])?;
+            let mut enc = Encoding::new(&[if size == 1 { 0x8A } else { 0x8B }]);
+            enc.size(size);
+            enc.reg(dst, src)?;
+            Ok(enc)
+        },
+        ("mov", [Operand::Reg(dst), Operand::Imm(v)]) => {
+            if dst.size == 8 && fits_i32(*v) {
+                // The sign extended 32 bit form is shorter than a full 64 bit immediate.
+                let mut enc = Encoding::new(&[0xC7]);
+                enc.size(8);
+                enc.rm(0, &ops[0])?;
+                enc.imm = imm_bytes(*v, 4)?;
+                return Ok(enc)
+            }
+            let mut enc = Encoding::new(&[if dst.size == 1 { 0xB0 } else { 0xB8 }]);
+            enc.size(dst.size);
+            enc.plus_reg(dst);
+            enc.imm = if dst.size == 8 { v.to_le_bytes().to_vec() } else { imm_bytes(*v, dst.size)? };
+            Ok(enc)
+        },
+        ("mov", [Operand::Reg(dst), Operand::Label(label, extra)]) if dst.size == 8 => {
+            let mut enc = Encoding::new(&[0xB8]);
+            enc.size(8);
+            enc.plus_reg(dst);
+            enc.imm = vec![0; 8];
+            enc.imm_ref = Some((label.clone(), *extra, RelocKind::Abs64));
+            Ok(enc)
+        },
+        ("mov", [dst @ Operand::Mem(_), Operand::Imm(v)]) => {
+            let size = operand_size(&[dst])?;
+            let mut enc = Encoding::new(&[if size == 1 { 0xC6 } else { 0xC7 }]);
+            enc.size(size);
+            enc.rm(0, dst)?;
+            enc.imm = imm_bytes(*v, size)?;
+            Ok(enc)
+        },
+        ("movzx" | "movsx", [Operand::Reg(dst), src @ (Operand::Reg(_) | Operand::Mem(_))]) => {
+            let src_size = operand_size(&[src])?;
+            if src_size >= dst.size || src_size > 2 {
+                if mnemonic == "movsx" && src_size == 4 { return encode("movsxd", ops) }
+                return bad()
+            }
+            let op = match (mnemonic, src_size) {
+                ("movzx", 1) => 0xB6,
+                ("movzx", _) => 0xB7,
+                (_, 1) => 0xBE,
+                _ => 0xBF,
+            };
+            let mut enc = Encoding::new(&[0x0F, op]);
+            enc.size(dst.size);
+            enc.reg(dst, src)?;
+            Ok(enc)
+        },
+        ("movsxd", [Operand::Reg(dst), src @ (Operand::Reg(_) | Operand::Mem(_))]) => {
+            if dst.size != 8 || operand_size(&[src])? != 4 { return bad() }
+            let mut enc = Encoding::new(&[0x63]);
+            enc.size(8);
+            enc.reg(dst, src)?;
+            Ok(enc)
+        },
+        ("lea", [Operand::Reg(dst), src @ Operand::Mem(_)]) => {
+            if dst.size == 1 { return bad() }
+            let mut enc = Encoding::new(&[0x8D]);
+            enc.size(dst.size);
+            enc.reg(dst, src)?;
+            Ok(enc)
+        },
+        ("test", [dst @ (Operand::Reg(_) | Operand::Mem(_)), Operand::Reg(src)]) | ("xchg", [dst @ (Operand::Reg(_) | Operand::Mem(_)), Operand::Reg(src)]) => {
+            let size = operand_size(&[dst, &ops[1]])?;
+            let base = if mnemonic == "test" { 0x84 } else { 0x86 };
+            let mut enc = Encoding::new(&[if size == 1 { base } else { base + 1 }]);
+            enc.size(size);
+            enc.reg(src, dst)?;
+            Ok(enc)
+        },
+        ("xchg", [Operand::Reg(_), Operand::Mem(_)]) => encode("xchg", &[ops[1].clone(), ops[0].clone()]),
+        ("test", [dst @ (Operand::Reg(_) | Operand::Mem(_)), Operand::Imm(v)]) => {
+            let size = operand_size(&[dst])?;
+            let mut enc = Encoding::new(&[if size == 1 { 0xF6 } else { 0xF7 }]);
+            enc.size(size);
+            enc.rm(0, dst)?;
+            enc.imm = imm_bytes(*v, size)?;
+            Ok(enc)
+        },
+        ("imul", [src @ (Operand::Reg(_) | Operand::Mem(_))]) => {
+            let size = operand_size(&[src])?;
+            let mut enc = Encoding::new(&[if size == 1 { 0xF6 } else { 0xF7 }]);
+            enc.size(size);
+            enc.rm(5, src)?;
+            Ok(enc)
+        },
+        ("imul", [Operand::Reg(dst), src @ (Operand::Reg(_) | Operand::Mem(_))]) => {
+            let size = operand_size(&[&ops[0], src])?;
+            if size == 1 { return bad() }
+            let mut enc = Encoding::new(&[0x0F, 0xAF]);
+            enc.size(size);
+            enc.reg(dst, src)?;
+            Ok(enc)
+        },
+        ("imul", [Operand::Reg(_), Operand::Imm(_)]) => encode("imul", &[ops[0].clone(), ops[0].clone(), ops[1].clone()]),
+        ("imul", [Operand::Reg(dst), src @ (Operand::Reg(_) | Operand::Mem(_)), Operand::Imm(v)]) => {
+            let size = operand_size(&[&ops[0], src])?;
+            if size == 1 { return bad() }
+            let mut enc = Encoding::new(&[if fits_i8(*v) { 0x6B } else { 0x69 }]);
+            enc.size(size);
+            enc.reg(dst, src)?;
+            enc.imm = imm_bytes(*v, if fits_i8(*v) { 1 } else { size })?;
+            Ok(enc)
+        },
+        ("inc" | "dec", [dst @ (Operand::Reg(_) | Operand::Mem(_))]) => {
+            let size = operand_size(&[dst])?;
+            let mut enc = Encoding::new(&[if size == 1 { 0xFE } else { 0xFF }]);
+            enc.size(size);
+            enc.rm(if mnemonic == "inc" { 0 } else { 1 }, dst)?;
+            Ok(enc)
+        },
+        ("push" | "pop", [Operand::Reg(r)]) => {
+            if r.size != 8 && r.size != 2 { return bad() }
+            let mut enc = Encoding::new(&[if mnemonic == "push" { 0x50 } else { 0x58 }]);
+            if r.size == 2 { enc.prefix66 = true }
+            enc.plus_reg(r);
+            Ok(enc)
+        },
+        ("push" | "pop", [m @ Operand::Mem(mem)]) => {
+            if mem.size.is_some_and(|s| s != 8) { return bad() }
+            let mut enc = if mnemonic == "push" { Encoding::new(&[0xFF]) } else { Encoding::new(&[0x8F]) };
+            enc.rm(if mnemonic == "push" { 6 } else { 0 }, m)?;
+            Ok(enc)
+        },
+        ("push", [Operand::Imm(v)]) => {
+            let mut enc = Encoding::new(&[if fits_i8(*v) { 0x6A } else { 0x68 }]);
+            enc.imm = imm_bytes(*v, if fits_i8(*v) { 1 } else { 4 })?;
+            Ok(enc)
+        },
+        ("call" | "jmp", [Operand::Label(label, extra)]) => {
+            let mut enc = Encoding::new(&[if mnemonic == "call" { 0xE8 } else { 0xE9 }]);
+            enc.rel32(label, *extra, RelocKind::Plt32);
+            Ok(enc)
+        },
+        ("call" | "jmp", [target @ (Operand::Reg(_) | Operand::Mem(_))]) => {
+            if operand_size(&[target]).unwrap_or(8) != 8 { return bad() }
+            let mut enc = Encoding::new(&[0xFF]);
+            enc.rm(if mnemonic == "call" { 2 } else { 4 }, target)?;
+            Ok(enc)
+        },
+        ("int", [Operand::Imm(v)]) => {
+            let mut enc = Encoding::new(&[0xCD]);
+            enc.imm = imm_bytes(*v, 1)?;
+            Ok(enc)
+        },
+        ("out", [port, Operand::Reg(Reg { num: 0, size, high_byte: false })]) | ("in", [Operand::Reg(Reg { num: 0, size, high_byte: false }), port]) => {
+            let is_out = mnemonic == "out";
+            let (op_dx, op_imm) = match (is_out, *size == 1) {
+                (true, true) => (0xEE, 0xE6),
+                (true, false) => (0xEF, 0xE7),
+                (false, true) => (0xEC, 0xE4),
+                (false, false) => (0xED, 0xE5),
+            };
+            if *size == 8 { return bad() }
+            let mut enc = match port {
+                Operand::Reg(Reg { num: 2, size: 2, high_byte: false }) => Encoding::new(&[op_dx]),
+                Operand::Imm(v) => {
+                    let mut enc = Encoding::new(&[op_imm]);
+                    enc.imm = imm_bytes(*v, 1)?;
+                    enc
+                },
+                _ => return bad(),
+            };
+            if *size == 2 { enc.prefix66 = true }
+            Ok(enc)
+        },
+        _ => Err(format!("Unsupported instruction: {}", mnemonic)),
+    }
+}
+
+impl Encoding {
+    /* Lays out the final bytes, returning them along with any fixups relative to the start of the
+     * instruction. */
+    fn finish(&self) -> Result<(Vec<u8>, Vec<InstFixup>), String> {
+        let mut bytes = Vec::new();
+        if self.prefix66 { bytes.push(0x66) }
+        // pause has a mandatory prefix which has to come before any REX prefix.
+        if self.opcode.len() == 2 && self.opcode[0] == 0xF3 { return Ok((self.opcode.clone(), Vec::new())) }
+        let rex = 0x40 | (self.rex_w as u8) << 3 | (self.rex_r as u8) << 2 | (self.rex_x as u8) << 1 | self.rex_b as u8;
+        if rex != 0x40 || self.force_rex {
+            if self.no_rex { return Err(String::from("ah, bh, ch and dh can't be used in an instruction which needs a REX prefix.")) }
+            bytes.push(rex);
+        }
+        bytes.extend_from_slice(&self.opcode);
+        if let Some(modrm) = self.modrm { bytes.push(modrm) }
+        if let Some(sib) = self.sib { bytes.push(sib) }
+        let disp_pos = bytes.len();
+        bytes.extend_from_slice(&self.disp);
+        let imm_pos = bytes.len();
+        bytes.extend_from_slice(&self.imm);
+        let end = bytes.len() as i64;
+        let mut fixups = Vec::new();
+        for (pos, reference) in [(disp_pos, &self.disp_ref), (imm_pos, &self.imm_ref)] {
+            if let Some((label, extra, kind)) = reference {
+                // PC relative values are relative to the end of the instruction, not the field itself.
+                let addend = if kind.is_pc_relative() { extra - (end - pos as i64) } else { *extra };
+                fixups.push((pos, label.clone(), addend, *kind));
+            }
+        }
+        Ok((bytes, fixups))
+    }
+}
+
+struct Assembler {
+    sections: Vec<Section>,
+    current: usize,
+    labels: HashMap<String, (usize, u64)>,
+    label_order: Vec<String>,
+    globals: Vec<String>,
+    externs: Vec<String>,
+    fixups: Vec<Fixup>,
+    last_label: String,
+    gas: bool, // set by `.intel_syntax noprefix`
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.sections[self.current].data.extend_from_slice(bytes);
+    }
+
+    fn offset(&self) -> u64 {
+        self.sections[self.current].data.len() as u64
+    }
+
+    // NASM style local labels (starting with a dot) belong to the last non-local label. GAS doesn't have them.
+    fn full_label(&self, name: &str) -> Result<String, String> {
+        if self.gas {
+            // Calls through the PLT are what's done for every extern anyway.
+            let name = name.strip_suffix("@PLT").unwrap_or(name);
+            if name.contains('@') { return Err(format!("Unsupported relocation type: {}", name)) }
+            return Ok(String::from(name))
+        }
+        Ok(if name.starts_with('.') && !self.last_label.is_empty() { format!("{}{}", self.last_label, name) } else { String::from(name) })
+    }
+
+    fn switch_section(&mut self, args: &str) {
+        let mut words = args.split([' ', '\t', ',']).filter(|w| !w.is_empty());
+        let name = words.next().unwrap_or(".text");
+        // The object writer always adds this section itself.
+        if name == ".note.GNU-stack" { return }
+        let idx = match self.sections.iter().position(|s| s.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.sections.push(Section::new(name));
+                self.sections.len() - 1
+            },
+        };
+        for word in words {
+            let section = &mut self.sections[idx];
+            match word {
+                "exec" => section.exec = true,
+                "noexec" => section.exec = false,
+                "write" => section.write = true,
+                "nowrite" => section.write = false,
+                "nobits" | "@nobits" => section.nobits = true,
+                _ if word.starts_with('"') => {
+                    // GAS section flags, such as "awx"
+                    section.write = word.contains('w');
+                    section.exec = word.contains('x');
+                },
+                _ => if let Some(align) = word.strip_prefix("align=").and_then(parse_number) { section.align = align as u64 },
+            }
+        }
+        self.current = idx;
+    }
+
+    fn data(&mut self, size: usize, args: &str, line: usize) -> Result<(), String> {
+        for item in split_operands(args) {
+            if let Some(s) = item.strip_prefix(['"', '\'', '`']).and_then(|s| s.strip_suffix(['"', '\'', '`'])) {
+                if item.len() > 3 || size == 1 {
+                    let mut bytes = s.as_bytes().to_vec();
+                    while !bytes.len().is_multiple_of(size) { bytes.push(0) }
+                    self.emit(&bytes);
+                    continue
+                }
+            }
+            match parse_operand(&item)? {
+                Operand::Imm(v) => {
+                    if size < 8 { imm_bytes(v, size as u8)?; }
+                    self.emit(&v.to_le_bytes()[..size]);
+                },
+                Operand::Label(label, extra) if size == 8 || size == 4 => {
+                    let kind = if size == 8 { RelocKind::Abs64 } else { RelocKind::Abs32 };
+                    self.fixups.push(Fixup { section: self.current, offset: self.offset(), symbol: self.full_label(&label)?, kind, addend: extra, line });
+                    self.emit(&vec![0; size]);
+                },
+                _ => return Err(format!("Invalid data item: {}", item)),
+            }
+        }
+        Ok(())
+    }
+
+    fn add_symbol_names(&mut self, args: &str, global: bool) {
+        for name in args.split(',').map(|n| String::from(n.trim().split(':').next().unwrap().trim())) {
+            let list = if global { &mut self.globals } else { &mut self.externs };
+            if !list.contains(&name) { list.push(name) }
+        }
+    }
+
+    fn align(&mut self, align: i64) -> Result<(), String> {
+        if align <= 0 { return Err(String::from("Invalid alignment.")) }
+        let fill = if self.sections[self.current].exec { 0x90 } else { 0 };
+        while !self.offset().is_multiple_of(align as u64) { self.emit(&[fill]) }
+        Ok(())
+    }
+
+    // Strings for `.ascii`, `.asciz` and `.string`, which can use C style escapes.
+    fn gas_strings(&mut self, args: &str, terminate: bool) -> Result<(), String> {
+        for item in split_operands(args) {
+            let s = item.strip_prefix('"').and_then(|s| s.strip_suffix('"')).ok_or(format!("Expected a string, got {}", item))?;
+            let mut bytes = Vec::new();
+            let mut chars = s.chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    continue
+                }
+                bytes.push(match chars.next() {
+                    Some('n') => b'\n',
+                    Some('t') => b'\t',
+                    Some('r') => b'\r',
+                    Some('0') => 0,
+                    Some(c @ ('\\' | '"' | '\'')) => c as u8,
+                    _ => return Err(format!("Unsupported escape sequence in string: {}", item)),
+                });
+            }
+            if terminate { bytes.push(0) }
+            self.emit(&bytes);
+        }
+        Ok(())
+    }
+
+    fn gas_directive(&mut self, directive: &str, args: &str, line: usize) -> Result<(), String> {
+        match directive {
+            ".intel_syntax" => {
+                if args == "prefix" { return Err(String::from("Only `.intel_syntax noprefix` is supported.")) }
+                self.gas = true;
+            },
+            ".att_syntax" => return Err(String::from("AT&T syntax isn't supported by the built in assembler, use --assembler=as instead.")),
+            ".globl" | ".global" => self.add_symbol_names(args, true),
+            ".extern" => self.add_symbol_names(args, false),
+            ".text" | ".data" | ".bss" => self.switch_section(directive),
+            ".section" => self.switch_section(args),
+            ".byte" => return self.data(1, args, line),
+            ".word" | ".short" | ".value" => return self.data(2, args, line),
+            ".long" | ".int" => return self.data(4, args, line),
+            ".quad" => return self.data(8, args, line),
+            ".ascii" => return self.gas_strings(args, false),
+            ".asciz" | ".string" => return self.gas_strings(args, true),
+            ".zero" | ".skip" | ".space" => {
+                let count = parse_number(args).ok_or("Invalid number of bytes to skip.")? as usize;
+                self.emit(&vec![0; count]);
+            },
+            ".balign" | ".align" => return self.align(parse_number(args.split(',').next().unwrap().trim()).unwrap_or(0)),
+            ".p2align" => return self.align(1 << parse_number(args.split(',').next().unwrap().trim()).unwrap_or(0).clamp(0, 16)),
+            // Only matter for debug info and symbol sizes, which aren't emitted anyway.
+            ".type" | ".size" | ".file" | ".ident" | ".loc" => {},
+            _ if directive.starts_with(".cfi_") => {},
+            _ => return Err(format!("Unsupported directive: {}", directive)),
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, text: &str, line: usize) -> Result<(), String> {
+        let mut text = split_comment(text, self.gas).0.trim();
+        if text.is_empty() { return Ok(()) }
+        if text.starts_with('[') && text.ends_with(']') {
+            let directive = text[1..text.len() - 1].trim().to_ascii_lowercase();
+            return match directive.as_str() {
+                "bits 64" => Ok(()),
+                _ if directive.starts_with("section ") || directive.starts_with("segment ") => {
+                    self.switch_section(&directive[8..]);
+                    Ok(())
+                },
+                _ => Err(format!("Unsupported directive: {}", text)),
+            }
+        }
+        // A label, possibly with something else after it on the same line.
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if is_label_name(label) && !label.contains(' ') {
+                let name = self.full_label(label)?;
+                if !label.starts_with('.') { self.last_label = String::from(label) }
+                if self.labels.insert(name.clone(), (self.current, self.offset())).is_some() {
+                    return Err(format!("Label defined more than once: {}", name))
+                }
+                self.label_order.push(name);
+                text = rest.trim();
+                if text.is_empty() { return Ok(()) }
+            }
+        }
+        let (mnemonic, args) = match text.split_once(char::is_whitespace) {
+            Some((m, a)) => (m.to_ascii_lowercase(), a.trim()),
+            None => (text.to_ascii_lowercase(), ""),
+        };
+        if mnemonic.starts_with('.') { return self.gas_directive(&mnemonic, args, line) }
+        match mnemonic.as_str() {
+            "bits" => return if args == "64" { Ok(()) } else { Err(String::from("Only 64 bit code is supported.")) },
+            "default" => return Ok(()),
+            "global" | "extern" => {
+                self.add_symbol_names(args, mnemonic == "global");
+                return Ok(())
+            },
+            "section" | "segment" => {
+                self.switch_section(args);
+                return Ok(())
+            },
+            "db" => return self.data(1, args, line),
+            "dw" => return self.data(2, args, line),
+            "dd" => return self.data(4, args, line),
+            "dq" => return self.data(8, args, line),
+            "align" => return self.align(parse_number(args).unwrap_or(0)),
+            "resb" | "resw" | "resd" | "resq" => {
+                let unit = match mnemonic.as_str() { "resb" => 1, "resw" => 2, "resd" => 4, _ => 8 };
+                let count = parse_number(args).ok_or("Invalid reserve count.")? as usize;
+                self.emit(&vec![0; unit * count]);
+                return Ok(())
+            },
+            _ => {},
+        }
+        let mut ops = Vec::new();
+        for op in split_operands(args) {
+            // Calls to other objects always go through the PLT anyway.
+            let op = op.strip_suffix("wrt ..plt").map(|l| String::from(l.trim())).unwrap_or(op);
+            let mut op = parse_operand(&op)?;
+            // Local labels get expanded here, so the encoder never has to care about them.
+            match &mut op {
+                Operand::Label(label, _) => *label = self.full_label(label)?,
+                Operand::Mem(Mem { label: Some(label), .. }) => *label = self.full_label(label)?,
+                _ => {},
+            }
+            ops.push(op);
+        }
+        let (bytes, fixups) = encode(&mnemonic, &ops)?.finish()?;
+        let start = self.offset();
+        for (pos, symbol, addend, kind) in fixups {
+            self.fixups.push(Fixup { section: self.current, offset: start + pos as u64, symbol, kind, addend, line });
+        }
+        self.emit(&bytes);
+        Ok(())
+    }
+
+    /* Fills in references to labels in the same section, and turns everything else into
+     * relocations. */
+    fn resolve(&mut self, file: &str, lines: &[&str]) -> Object {
+        for fixup in std::mem::take(&mut self.fixups) {
+            let size = fixup.kind.size();
+            match self.labels.get(&fixup.symbol) {
+                Some(&(section, offset)) if section == fixup.section && fixup.kind.is_pc_relative() => {
+                    let val = offset as i64 + fixup.addend - fixup.offset as i64;
+                    let at = fixup.offset as usize;
+                    self.sections[section].data[at..at + size].copy_from_slice(&(val as i32).to_le_bytes());
+                },
+                Some(&(section, offset)) => {
+                    // Labels in other sections (or absolute references) are relative to the start of
+                    // the section they're in.
+                    let kind = if fixup.kind == RelocKind::Plt32 { RelocKind::Pc32 } else { fixup.kind };
+                    self.sections[fixup.section].relocs.push(Reloc { offset: fixup.offset, target: RelocTarget::Section(section), kind, addend: fixup.addend + offset as i64 });
+                },
+                None => {
+                    // Like GAS itself, undefined symbols are implicitly external in GAS syntax.
+                    if self.gas && !self.externs.contains(&fixup.symbol) { self.externs.push(fixup.symbol.clone()) }
+                    if !self.externs.contains(&fixup.symbol) {
+                        report_asm_err(file, fixup.line, lines[fixup.line - 1], format!("Symbol isn't defined or declared as extern: {}", fixup.symbol).as_str());
+                    }
+                    self.sections[fixup.section].relocs.push(Reloc { offset: fixup.offset, target: RelocTarget::Symbol(fixup.symbol.clone()), kind: fixup.kind, addend: fixup.addend });
+                },
+            }
+        }
+        let mut symbols = Vec::new();
+        for name in &self.label_order {
+            // GAS doesn't put `.L` labels in the symbol table.
+            if self.gas && name.starts_with(".L") { continue }
+            let (section, value) = self.labels[name];
+            symbols.push(Symbol { name: name.clone(), section: Some(section), value, global: self.globals.contains(name) });
+        }
+        for global in &self.globals {
+            if !self.labels.contains_key(global) {
+                report_asm_err(file, 1, lines.first().unwrap_or(&""), format!("Symbol declared global but never defined: {}", global).as_str());
+            }
+        }
+        for ext in &self.externs {
+            if !self.labels.contains_key(ext) { symbols.push(Symbol { name: ext.clone(), section: None, value: 0, global: true }) }
+        }
+        for section in &mut self.sections {
+            section.size = section.data.len() as u64;
+        }
+        Object { sections: std::mem::take(&mut self.sections), symbols }
+    }
+}
+
+/* Assembles NASM (or GAS Intel) syntax source into an ELF object. Errors are reported against lines
+ * of the assembly file, as they normally come from inline assembly blocks. */
+pub fn assemble(source: &str, file: &str) -> Object {
+    let mut asm = Assembler {
+        sections: vec![Section::new(".text")],
+        current: 0,
+        labels: HashMap::new(),
+        label_order: Vec::new(),
+        globals: Vec::new(),
+        externs: Vec::new(),
+        fixups: Vec::new(),
+        last_label: String::new(),
+        gas: false,
+    };
+    let lines: Vec<&str> = source.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        if let Err(msg) = asm.line(line, i + 1) {
+            report_asm_err(file, i + 1, line, msg.as_str());
+        }
+    }
+    asm.resolve(file, &lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(source: &str) -> Object {
+        let obj = assemble(source, "test.asm");
+        assert_eq!(obj.sections[0].name, ".text");
+        obj
+    }
+
+    fn bytes(line: &str) -> Vec<u8> {
+        text(line).sections[0].data.clone()
+    }
+
+    // The expected bytes are what GNU as gives for the same instructions.
+    #[test]
+    fn encodes_instructions() {
+        let cases: [(&str, &[u8]); 22] = [
+            ("mov rax, rbx", &[0x48, 0x89, 0xd8]),
+            ("mov eax, 1", &[0xb8, 0x01, 0x00, 0x00, 0x00]),
+            ("mov rax, -1", &[0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]),
+            ("mov rax, 0x123456789", &[0x48, 0xb8, 0x89, 0x67, 0x45, 0x23, 0x01, 0x00, 0x00, 0x00]),
+            ("add rsp, 8", &[0x48, 0x83, 0xc4, 0x08]),
+            ("sub rsp, 1024", &[0x48, 0x81, 0xec, 0x00, 0x04, 0x00, 0x00]),
+            ("mov QWORD [rbp - 8], rax", &[0x48, 0x89, 0x45, 0xf8]),
+            ("mov r8b, BYTE [rsp + 16]", &[0x44, 0x8a, 0x44, 0x24, 0x10]),
+            ("mov cx, WORD [rax + rcx * 2 + 4]", &[0x66, 0x8b, 0x4c, 0x48, 0x04]),
+            ("push r12", &[0x41, 0x54]),
+            ("pop rbp", &[0x5d]),
+            ("movzx eax, BYTE [rdi]", &[0x0f, 0xb6, 0x07]),
+            ("movsxd rax, DWORD [r13]", &[0x49, 0x63, 0x45, 0x00]),
+            ("imul rax, rcx", &[0x48, 0x0f, 0xaf, 0xc1]),
+            ("shl rax, 3", &[0x48, 0xc1, 0xe0, 0x03]),
+            ("sar r9, cl", &[0x49, 0xd3, 0xf9]),
+            ("cqo", &[0x48, 0x99]),
+            ("idiv rcx", &[0x48, 0xf7, 0xf9]),
+            ("sete al", &[0x0f, 0x94, 0xc0]),
+            ("test rax, rax", &[0x48, 0x85, 0xc0]),
+            ("syscall", &[0x0f, 0x05]),
+            ("iretq", &[0x48, 0xcf]),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(bytes(line), expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn resolves_labels_and_relocations() {
+        // Jumps to labels in the same section are resolved by the assembler.
+        assert_eq!(bytes("f:\njmp .end\nnop\n.end:\nret"), [0xe9, 0x01, 0x00, 0x00, 0x00, 0x90, 0xc3]);
+        let obj = text("extern printf\nglobal main\nmain:\ncall printf\nlea rdi, [msg]\nret\nsection .data\nmsg: db \"hi\", 0");
+        let relocs = &obj.sections[0].relocs;
+        assert_eq!(relocs.len(), 2);
+        assert!(matches!(&relocs[0], Reloc {offset: 1, target: RelocTarget::Symbol(s), kind: RelocKind::Plt32, addend: -4} if s == "printf"));
+        assert_eq!(relocs[1].offset, 8);
+        assert!(relocs[1].kind.is_pc_relative());
+        assert_eq!(obj.sections[1].data, b"hi\0");
+        assert!(obj.symbols.iter().any(|s| s.name == "main" && s.global && s.section == Some(0)));
+    }
+
+    #[test]
+    fn reads_gas_intel_syntax() {
+        assert_eq!(bytes(".intel_syntax noprefix\nmov QWORD PTR [rbp - 8], rax"), [0x48, 0x89, 0x45, 0xf8]);
+    }
+}
@@ -147,7 +147,7 @@ fn find_highest_priority_token(tokens: &mut &[Token], priorities: &HashMap<Opera
         *tokens = &tokens[1..tokens.len() - 1];
         return find_highest_priority_token(tokens, priorities)
     }
-    return highest_priority_idx
+    highest_priority_idx
 }
 
 fn parse_branch(mut tokens: &[Token], priorities_map: &HashMap<Operation, u8>) -> Box<BranchChild> {
@@ -166,6 +166,12 @@ fn parse_branch(mut tokens: &[Token], priorities_map: &HashMap<Operation, u8>) -
         }
     }
     
+    // Brackets around the whole expression (like around a lone function call) don't change anything.
+    if tokens_len > 2 && tokens[0].val == TokenVal::Lparen && tokens[tokens_len - 1].val == TokenVal::Rparen
+            && (1..tokens_len - 1).all(|idx| token_in_brackets(idx as u64, tokens)) {
+        return parse_branch(&tokens[1..tokens_len - 1], priorities_map)
+    }
+
     let lit_o = if let TokenVal::Literal(v) = &tokens[0].val {Some(v)} else {None};
 
     if tokens_len == 1 {
@@ -180,30 +186,28 @@ fn parse_branch(mut tokens: &[Token], priorities_map: &HashMap<Operation, u8>) -
         }
     }
 
-    match &tokens[0].val {
-        TokenVal::Literal(Literal {val: LitVal::Ident(v), typ: _}) => {
-            if (tokens[1].val == TokenVal::Lparen) && (tokens[tokens_len - 1].val == TokenVal::Rparen) {
-                // All that's left is a function call statement. Parse it.
-                let mut tokens_vec = Vec::from(tokens);
-                tokens_vec.push(Token { val: TokenVal::Endln, row: 0, col: 0, } );
-                let statement = parse_func_call_statement(tokens_vec);
-                let fn_statement = if let Statement::FuncCall(val) = statement {
-                    val
-                } else {
-                    unreachable!()
-                };
-                return Box::new(
-                    BranchChild {
-                        val: BranchChildVal::Fn(
-                            fn_statement
-                        ),
-                        row: tokens[0].row,
-                        col: tokens[0].col,
-                    }
-                )
-            }
-        },
-        _ => {}
+    if let TokenVal::Literal(Literal {val: LitVal::Ident(v), typ: _}) = &tokens[0].val {
+        if (tokens[1].val == TokenVal::Lparen) && (tokens[tokens_len - 1].val == TokenVal::Rparen)
+                && (2..tokens_len - 1).all(|idx| token_in_brackets(idx as u64, tokens)) {
+            // All that's left is a function call statement. Parse it.
+            let mut tokens_vec = Vec::from(tokens);
+            tokens_vec.push(Token { val: TokenVal::Endln, row: 0, col: 0, } );
+            let statement = parse_func_call_statement(tokens_vec);
+            let fn_statement = if let Statement::FuncCall(val) = statement {
+                val
+            } else {
+                unreachable!()
+            };
+            return Box::new(
+                BranchChild {
+                    val: BranchChildVal::Fn(
+                        fn_statement
+                    ),
+                    row: tokens[0].row,
+                    col: tokens[0].col,
+                }
+            )
+        }
     }
     let max_priority_idx = find_highest_priority_token(&mut tokens, priorities_map);
     let max_priority_token = if let TokenVal::Ops(max_priority_token) = tokens[max_priority_idx].val {
@@ -0,0 +1,686 @@
+/* C backend for `--emit=c`. Rather than going through the IR, the typed function table is translated
+ * straight into a single C11 file, so the structure of the program (variable names, `if` and `while`
+ * blocks) survives. Every expression is given the same type the IR would give it, with explicit
+ * casts wherever C's integer promotions could otherwise change the result.
+ */
+
+
+use std::collections::HashMap;
+use crate::parser::*;
+use crate::statements::*;
+use crate::lexer::*;
+use crate::ast::*;
+use crate::error::*;
+use crate::typecheck::typecheck_expr;
+use crate::backend::{check_type_signed, type_to_size, substitute_asm_operands};
+use crate::Arch;
+use crate::dialect;
+use crate::ir::is_comparison;
+use crate::optimisation::immediate_value;
+use crate::intrinsics::{self, Intrinsic, MAX_SYSCALL_ARGS};
+
+const PRELUDE: &str = "#include <stdint.h>
+#include <stdbool.h>
+
+static inline uint64_t ctfaw_pow(uint64_t base, uint64_t exp) {
+    uint64_t result = 1;
+    while (exp--) result *= base;
+    return result;
+}
+";
+
+// Helpers for intrinsics, which are only emitted for the target they're written for.
+const X86_64_INTRINSICS: &str = "
+static inline int64_t ctfaw_syscall(int64_t n, int64_t a1, int64_t a2, int64_t a3, int64_t a4, int64_t a5, int64_t a6) {
+    register int64_t r10 __asm__(\"r10\") = a4;
+    register int64_t r8 __asm__(\"r8\") = a5;
+    register int64_t r9 __asm__(\"r9\") = a6;
+    __asm__ volatile (\"syscall\" : \"+a\"(n) : \"D\"(a1), \"S\"(a2), \"d\"(a3), \"r\"(r10), \"r\"(r8), \"r\"(r9) : \"rcx\", \"r11\", \"memory\");
+    return n;
+}
+
+static inline void ctfaw_outb(uint16_t port, uint8_t val) {
+    __asm__ volatile (\"outb %0, %1\" : : \"a\"(val), \"Nd\"(port) : \"memory\");
+}
+
+static inline uint8_t ctfaw_inb(uint16_t port) {
+    uint8_t val;
+    __asm__ volatile (\"inb %1, %0\" : \"=a\"(val) : \"Nd\"(port) : \"memory\");
+    return val;
+}
+
+static inline uint64_t ctfaw_rdtsc(void) {
+    uint32_t lo, hi;
+    __asm__ volatile (\"rdtsc\" : \"=a\"(lo), \"=d\"(hi));
+    return ((uint64_t)hi << 32) | lo;
+}
+
+static inline void ctfaw_cpuid(uint32_t leaf, uint32_t subleaf, uint32_t *eax, uint32_t *ebx, uint32_t *ecx, uint32_t *edx) {
+    __asm__ volatile (\"cpuid\" : \"=a\"(*eax), \"=b\"(*ebx), \"=c\"(*ecx), \"=d\"(*edx) : \"a\"(leaf), \"c\"(subleaf));
+}
+
+static inline void ctfaw_pause(void) { __asm__ volatile (\"pause\"); }
+static inline void ctfaw_hlt(void) { __asm__ volatile (\"hlt\"); }
+";
+
+const AARCH64_INTRINSICS: &str = "
+static inline int64_t ctfaw_syscall(int64_t n, int64_t a1, int64_t a2, int64_t a3, int64_t a4, int64_t a5, int64_t a6) {
+    register int64_t x8 __asm__(\"x8\") = n;
+    register int64_t x0 __asm__(\"x0\") = a1;
+    register int64_t x1 __asm__(\"x1\") = a2;
+    register int64_t x2 __asm__(\"x2\") = a3;
+    register int64_t x3 __asm__(\"x3\") = a4;
+    register int64_t x4 __asm__(\"x4\") = a5;
+    register int64_t x5 __asm__(\"x5\") = a6;
+    __asm__ volatile (\"svc #0\" : \"+r\"(x0) : \"r\"(x8), \"r\"(x1), \"r\"(x2), \"r\"(x3), \"r\"(x4), \"r\"(x5) : \"memory\");
+    return x0;
+}
+
+static inline uint64_t ctfaw_rdtsc(void) {
+    uint64_t val;
+    __asm__ volatile (\"mrs %0, cntvct_el0\" : \"=r\"(val));
+    return val;
+}
+
+static inline void ctfaw_pause(void) { __asm__ volatile (\"yield\"); }
+static inline void ctfaw_hlt(void) { __asm__ volatile (\"wfi\"); }
+";
+
+const RISCV64_INTRINSICS: &str = "
+static inline int64_t ctfaw_syscall(int64_t n, int64_t a1, int64_t a2, int64_t a3, int64_t a4, int64_t a5, int64_t a6) {
+    register int64_t a7_ __asm__(\"a7\") = n;
+    register int64_t a0_ __asm__(\"a0\") = a1;
+    register int64_t a1_ __asm__(\"a1\") = a2;
+    register int64_t a2_ __asm__(\"a2\") = a3;
+    register int64_t a3_ __asm__(\"a3\") = a4;
+    register int64_t a4_ __asm__(\"a4\") = a5;
+    register int64_t a5_ __asm__(\"a5\") = a6;
+    __asm__ volatile (\"ecall\" : \"+r\"(a0_) : \"r\"(a7_), \"r\"(a1_), \"r\"(a2_), \"r\"(a3_), \"r\"(a4_), \"r\"(a5_) : \"memory\");
+    return a0_;
+}
+
+static inline uint64_t ctfaw_rdtsc(void) {
+    uint64_t val;
+    __asm__ volatile (\"rdtime %0\" : \"=r\"(val));
+    return val;
+}
+
+static inline void ctfaw_pause(void) { __asm__ volatile (\".4byte 0x0100000f\"); }
+static inline void ctfaw_hlt(void) { __asm__ volatile (\"wfi\"); }
+";
+
+pub fn c_type(typ: &Type) -> String {
+    let base = match typ.val {
+        TypeVal::U8 => "uint8_t",
+        TypeVal::U16 => "uint16_t",
+        TypeVal::U32 => "uint32_t",
+        TypeVal::U64 | TypeVal::Any => "uint64_t",
+        TypeVal::I8 => "int8_t",
+        TypeVal::I16 => "int16_t",
+        TypeVal::I32 => "int32_t",
+        TypeVal::I64 => "int64_t",
+        TypeVal::Char => "char",
+        TypeVal::Boolean => "bool",
+        TypeVal::F64 => "double",
+    };
+    let mut s = String::from(base);
+    for _ in 0..typ.ptr_depth {
+        s.push('*');
+    }
+    s
+}
+
+fn c_int(val: u64) -> String {
+    if val <= i32::MAX as u64 { val.to_string() } else { format!("{}ULL", val) }
+}
+
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7E => out.push(b as char),
+            // Always three digits, so a digit after it can't be taken as part of the escape.
+            _ => out.push_str(format!("\\{:03o}", b).as_str()),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn cast(expr: String, from: &Type, to: &Type) -> String {
+    if from == to || to.val == TypeVal::Any { return expr }
+    format!("(({}){})", c_type(to), expr)
+}
+
+fn resolve_any(typ: Type, want: &Type) -> Type {
+    if typ.val != TypeVal::Any { return typ }
+    if want.val != TypeVal::Any { return want.clone() }
+    Type {val: TypeVal::U64, ptr_depth: typ.ptr_depth}
+}
+
+fn prototype(name: &str, sig: &FuncSig) -> String {
+    let mut args: Vec<String> = sig.args.iter().map(|a| format!("{} {}", c_type(&a.arg_type), a.val)).collect();
+    if sig.varargs_idx.is_some() {
+        args.push(String::from("..."));
+    }
+    if args.is_empty() {
+        args.push(String::from("void"));
+    }
+    // C requires main to return int, which every CTFAW return type converts to anyway.
+    let ret = if name == "main" { String::from("int") } else { c_type(&sig.ret_type) };
+    format!("{} {}({})", ret, name, args.join(", "))
+}
+
+/* A header declaring the functions which other object files can call, for `--emit=header`, so C code
+ * can use them. main is left out, since the program including it has its own. */
+pub fn header(program: &HashMap<String, FuncTableVal>, guard: &str) -> String {
+    let mut names: Vec<&String> = program.iter()
+        .filter(|(name, f)| f.statements.is_some() && !f.is_static && *name != "main")
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    let mut out = format!("/* Generated by CTFAW. CTFAW's char is unsigned, so build with -funsigned-char for the same results. */\n#ifndef {0}\n#define {0}\n\n#include <stdint.h>\n#include <stdbool.h>\n\n", guard);
+    for name in names {
+        out.push_str(format!("{};\n", prototype(name, &program[name].signature)).as_str());
+    }
+    out.push_str(format!("\n#endif /* {} */\n", guard).as_str());
+    out
+}
+
+struct FuncWriter<'a> {
+    program: &'a HashMap<String, FuncTableVal>,
+    vars: HashMap<String, Type>,
+    // Values of the globals that aren't shadowed by a local, for "i" asm operands.
+    consts: HashMap<String, u64>,
+    ret_type: Type,
+    target: Arch,
+    out: String,
+    depth: usize,
+}
+
+impl FuncWriter<'_> {
+    fn line(&mut self, s: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+
+    fn expr_type(&self, expr: &BranchChild, want: &Type) -> Type {
+        resolve_any(typecheck_expr(expr.clone(), &self.vars, self.program), want)
+    }
+
+    // An expression converted to the given type.
+    fn expr_as(&self, expr: &BranchChild, to: &Type) -> String {
+        let (s, typ) = self.expr(expr, to);
+        cast(s, &typ, to)
+    }
+
+    /* Translates an expression, returning it along with the type it has. Types are worked out the
+     * same way as when lowering to the IR. */
+    fn expr(&self, expr: &BranchChild, want: &Type) -> (String, Type) {
+        match &expr.val {
+            BranchChildVal::Int(val) => {
+                let typ = resolve_any(Type {val: TypeVal::Any, ptr_depth: 0}, want);
+                (format!("(({}){})", c_type(&typ), c_int(*val)), typ)
+            },
+            BranchChildVal::Char(val) => (format!("((char){})", val), Type {val: TypeVal::Char, ptr_depth: 0}),
+            BranchChildVal::Ident(ident) | BranchChildVal::Ref(ident) => {
+                let mut typ = match self.vars.get(ident) {
+                    Some(t) => t.clone(),
+                    None => {
+                        report_err(Component::CODEGEN, Token {val: TokenVal::Endln, row: expr.row, col: expr.col}, "Variable is not defined.");
+                        unreachable!();
+                    }
+                };
+                if let BranchChildVal::Ref(_) = expr.val {
+                    typ.ptr_depth += 1;
+                    return (format!("(&{})", ident), typ)
+                }
+                (ident.clone(), typ)
+            },
+            BranchChildVal::StrLit(val) => (c_string(val), Type {val: TypeVal::Char, ptr_depth: 1}),
+            BranchChildVal::Deref(inner) => {
+                let ptr_type = self.expr_type(inner, &Type {val: TypeVal::U64, ptr_depth: 0});
+                if ptr_type.ptr_depth == 0 {
+                    report_err(Component::CODEGEN, Token {val: TokenVal::Endln, row: expr.row, col: expr.col}, "Cannot dereference a value which isn't a pointer.");
+                }
+                let addr = self.expr_as(inner, &ptr_type);
+                let mut typ = ptr_type;
+                typ.ptr_depth -= 1;
+                (format!("(*{})", addr), typ)
+            },
+            BranchChildVal::Cast(c) => {
+                let from = self.expr_type(&c.val, &c.typ);
+                let v = self.expr_as(&c.val, &from);
+                (cast(v, &from, &c.typ), c.typ.clone())
+            },
+            BranchChildVal::Branch(branch) => {
+                let left_type = typecheck_expr(*branch.left_val.clone(), &self.vars, self.program);
+                let right_type = typecheck_expr(*branch.right_val.clone(), &self.vars, self.program);
+                let is_logical = is_comparison(branch.op) || branch.op == Operation::And || branch.op == Operation::Or;
+                let operand_type = if left_type.val != TypeVal::Any {
+                    left_type
+                } else if right_type.val != TypeVal::Any {
+                    right_type
+                } else if is_logical {
+                    Type {val: TypeVal::U64, ptr_depth: 0}
+                } else {
+                    resolve_any(left_type, want)
+                };
+                let lhs = self.expr_as(&branch.left_val, &operand_type);
+                let rhs = self.expr_as(&branch.right_val, &operand_type);
+                let t = c_type(&operand_type);
+                if is_comparison(branch.op) {
+                    return (format!("({} {} {})", lhs, c_operator(branch.op), rhs), Type {val: TypeVal::Boolean, ptr_depth: 0})
+                }
+                let s = match branch.op {
+                    Operation::Pow => format!("(({})ctfaw_pow({}, {}))", t, lhs, rhs),
+                    /* Signed overflow is undefined in C and pointer arithmetic is scaled, while CTFAW
+                     * wraps and works in bytes, so these are done on unsigned 64 bit values. */
+                    Operation::Add | Operation::Sub | Operation::Star | Operation::LeftShift
+                        if check_type_signed(operand_type.clone()) || operand_type.ptr_depth > 0 => {
+                        format!("(({})((uint64_t){} {} (uint64_t){}))", t, lhs, c_operator(branch.op), rhs)
+                    },
+                    op => format!("(({})({} {} {}))", t, lhs, c_operator(op), rhs),
+                };
+                (s, operand_type)
+            },
+            BranchChildVal::Unary(unary) => {
+                let typ = self.expr_type(&unary.val, want);
+                let src = self.expr_as(&unary.val, &typ);
+                let s = match unary.op {
+                    Operation::Sub => format!("(({})-(uint64_t){})", c_type(&typ), src),
+                    op => format!("(({}){}{})", c_type(&typ), c_operator(op), src),
+                };
+                (s, typ)
+            },
+            BranchChildVal::Fn(call) => {
+                let ret_type = self.program[&call.fn_ident].signature.ret_type.clone();
+                (self.call(call), ret_type)
+            },
+            BranchChildVal::Float(_) => {
+                report_err(Component::CODEGEN, Token {val: TokenVal::Endln, row: expr.row, col: expr.col}, "Floating point values aren't supported yet.");
+                unreachable!();
+            },
+        }
+    }
+
+    /* Intrinsics call the helpers for them at the top of the file, or a GCC builtin. The arguments
+     * of `__syscall` which weren't given are passed as 0. */
+    fn intrinsic_call(&self, intrinsic: Intrinsic, call: &FuncCallStatement) -> String {
+        let signature = intrinsic.signature();
+        let mut args = Vec::new();
+        for (i, arg) in call.args.iter().enumerate() {
+            let want = if i < signature.args.len() { signature.args[i].arg_type.clone() } else { self.expr_type(arg, &Type {val: TypeVal::U64, ptr_depth: 0}) };
+            let v = self.expr_as(arg, &want);
+            args.push(if intrinsic == Intrinsic::Syscall { format!("(int64_t)({})", v) } else { v });
+        }
+        let name = match intrinsic {
+            Intrinsic::Unreachable => String::from("__builtin_unreachable"),
+            Intrinsic::Trap => String::from("__builtin_trap"),
+            Intrinsic::Syscall => {
+                args.resize(MAX_SYSCALL_ARGS + 1, String::from("0"));
+                String::from("ctfaw_syscall")
+            },
+            _ => format!("ctfaw{}", &intrinsic.name()[1..]),
+        };
+        format!("{}({})", name, args.join(", "))
+    }
+
+    fn call(&self, call: &FuncCallStatement) -> String {
+        if let Some(intrinsic) = intrinsics::lookup(&call.fn_ident) {
+            return self.intrinsic_call(intrinsic, call)
+        }
+        let signature = match self.program.get(&call.fn_ident) {
+            Some(f) => f.signature.clone(),
+            None => {
+                report_err(Component::CODEGEN, call.ident_tok.clone(), format!("Undefined function: {}", call.fn_ident).as_str());
+                unreachable!();
+            }
+        };
+        let mut args = Vec::new();
+        for (i, arg) in call.args.iter().enumerate() {
+            let is_vararg = match signature.varargs_idx {
+                Some(v) => i >= v as usize,
+                None => false,
+            };
+            let want = if !is_vararg && i < signature.args.len() {
+                signature.args[i].arg_type.clone()
+            } else {
+                self.expr_type(arg, &Type {val: TypeVal::U64, ptr_depth: 0})
+            };
+            args.push(self.expr_as(arg, &want));
+        }
+        format!("{}({})", call.fn_ident, args.join(", "))
+    }
+
+    fn asm_text(&self, s: &InlineAsmStatement) -> String {
+        if self.target == Arch::X86_64 {
+            match dialect::block_to_gas(&s.asm) {
+                Ok(v) => format!(".intel_syntax noprefix\n{}\n.att_syntax prefix", v),
+                Err(msg) => {
+                    report_err(Component::ASSEMBLER, s.asm_tok.clone(), format!("Couldn't translate inline assembly to GAS syntax: {}", msg).as_str());
+                    unreachable!();
+                }
+            }
+        } else {
+            s.asm.clone()
+        }
+    }
+
+    // How GCC is told to print a register operand with the size of its type.
+    fn reg_operand(&self, n: usize, typ: &Type) -> String {
+        let size = type_to_size(typ.clone());
+        match self.target {
+            Arch::X86_64 => format!("%{}{}", match size { 1 => "b", 2 => "w", 4 => "k", _ => "q" }, n),
+            Arch::Aarch64 => format!("%{}{}", if size <= 4 { "w" } else { "x" }, n),
+            _ => format!("%{}", n),
+        }
+    }
+
+    /* Inline assembly becomes GNU extended asm, with operands in the same order so {N} is just %N.
+     * Operands bound to a specific register go through a register variable. GCC prints operands in
+     * AT&T syntax on x86_64, so memory operands are given as a pointer in a register there instead. */
+    fn inline_asm(&mut self, s: &InlineAsmStatement) {
+        let asm = self.asm_text(s);
+        self.line("{");
+        self.depth += 1;
+        let x86 = self.target == Arch::X86_64;
+        let mut texts = Vec::new();
+        let mut outputs = Vec::new();
+        let mut inputs = Vec::new();
+        let mut copy_back = Vec::new();
+        for (i, entry) in s.outputs.iter().enumerate() {
+            let ident = entry.identifier();
+            let typ = self.vars[ident].clone();
+            let prefix = if entry.read_write { "+" } else { "=" };
+            let name = entry.name.as_ref().map(|n| format!("[{}] ", n)).unwrap_or_default();
+            match &entry.constraint {
+                AsmConstraint::Fixed(reg) => {
+                    let init = if entry.read_write { format!(" = (uint64_t){}", ident) } else { String::new() };
+                    self.line(format!("register uint64_t ctfaw_out{} __asm__(\"{}\"){};", i, reg, init).as_str());
+                    outputs.push(format!("{}\"{}r\"(ctfaw_out{})", name, prefix, i));
+                    copy_back.push(format!("{} = ({})ctfaw_out{};", ident, c_type(&typ), i));
+                    texts.push(reg.clone());
+                },
+                AsmConstraint::Mem if x86 => {
+                    self.line(format!("{} *ctfaw_addr{} = &{};", c_type(&typ), i, ident).as_str());
+                    outputs.push(format!("{}\"+r\"(ctfaw_addr{})", name, i));
+                    texts.push(format!("{}[%q{}]", dialect::size_ptr(type_to_size(typ.clone()) as u8), i));
+                },
+                AsmConstraint::Mem => {
+                    outputs.push(format!("{}\"{}m\"({})", name, prefix, ident));
+                    texts.push(format!("%{}", i));
+                },
+                _ => {
+                    outputs.push(format!("{}\"{}r\"({})", name, prefix, ident));
+                    texts.push(self.reg_operand(i, &typ));
+                },
+            }
+        }
+        for (i, entry) in s.inputs.iter().enumerate() {
+            let n = s.outputs.len() + i;
+            let typ = self.expr_type(&entry.expr, &Type {val: TypeVal::U64, ptr_depth: 0});
+            let val = self.expr_as(&entry.expr, &typ);
+            let name = entry.name.as_ref().map(|n| format!("[{}] ", n)).unwrap_or_default();
+            match &entry.constraint {
+                AsmConstraint::Fixed(reg) => {
+                    self.line(format!("register uint64_t ctfaw_in{} __asm__(\"{}\") = (uint64_t){};", i, reg, val).as_str());
+                    inputs.push(format!("{}\"r\"(ctfaw_in{})", name, i));
+                    texts.push(reg.clone());
+                },
+                AsmConstraint::Mem => {
+                    // Memory inputs have to be something with an address.
+                    self.line(format!("{} ctfaw_mem{} = {};", c_type(&typ), i, val).as_str());
+                    if x86 {
+                        inputs.push(format!("{}\"r\"(&ctfaw_mem{})", name, i));
+                        texts.push(format!("{}[%q{}]", dialect::size_ptr(type_to_size(typ.clone()) as u8), n));
+                    } else {
+                        inputs.push(format!("{}\"m\"(ctfaw_mem{})", name, i));
+                        texts.push(format!("%{}", n));
+                    }
+                },
+                AsmConstraint::Imm => {
+                    // GCC only takes non literals for "i" when it's optimising, so it gets the folded value.
+                    let Some(imm) = immediate_value(&entry.expr, &typ, &|ident| self.consts.get(ident).copied()) else {
+                        report_err(Component::CODEGEN, entry.tok.clone(), "Inline assembly operands with the \"i\" constraint must be constant.");
+                        unreachable!();
+                    };
+                    inputs.push(format!("{}\"i\"(({}){})", name, c_type(&typ), c_int(imm)));
+                    texts.push(if self.target == Arch::Riscv64 { format!("%{}", n) } else { format!("%c{}", n) });
+                },
+                AsmConstraint::Reg => {
+                    inputs.push(format!("{}\"r\"({})", name, val));
+                    texts.push(self.reg_operand(n, &typ));
+                },
+            }
+        }
+        // A register can't be both bound to an operand and clobbered.
+        let fixed: Vec<&AsmConstraint> = s.inputs.iter().chain(s.outputs.iter()).map(|e| &e.constraint).collect();
+        let mut clobbers: Vec<String> = s.clobbers.iter()
+            .filter(|c| !fixed.contains(&&AsmConstraint::Fixed((*c).clone())))
+            .map(|c| format!("\"{}\"", c))
+            .collect();
+        clobbers.push(String::from("\"memory\""));
+        clobbers.push(String::from("\"cc\""));
+        let names: Vec<Option<String>> = s.outputs.iter().chain(s.inputs.iter()).map(|e| e.name.clone()).collect();
+        let asm = substitute_asm_operands(&asm.replace('%', "%%"), &names, &texts);
+        self.line(format!("__asm__ volatile ({} : {} : {} : {});", c_string(&asm), outputs.join(", "), inputs.join(", "), clobbers.join(", ")).as_str());
+        for line in copy_back {
+            self.line(line.as_str());
+        }
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn scope(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            match statement {
+                Statement::Define(s) => {
+                    let v = self.expr_as(&s.expr, &s.def_type);
+                    self.line(format!("{} = {};", s.identifier, v).as_str());
+                },
+                Statement::Assign(s) => {
+                    let var_type = match self.vars.get(&s.identifier) {
+                        Some(t) => t.clone(),
+                        None => {
+                            report_err(Component::CODEGEN, s.ident_tok.clone(), format!("Undefined variable: {}", s.identifier).as_str());
+                            unreachable!();
+                        }
+                    };
+                    if s.deref {
+                        let mut target = var_type;
+                        target.ptr_depth -= 1;
+                        let v = self.expr_as(&s.expr, &target);
+                        self.line(format!("*{} = {};", s.identifier, v).as_str());
+                    } else {
+                        let v = self.expr_as(&s.expr, &var_type);
+                        self.line(format!("{} = {};", s.identifier, v).as_str());
+                    }
+                },
+                Statement::FuncCall(s) => {
+                    let call = self.call(s);
+                    self.line(format!("{};", call).as_str());
+                },
+                Statement::InlineAsm(s) => self.inline_asm(s),
+                Statement::Return(expr) => {
+                    let ret_type = self.ret_type.clone();
+                    let v = self.expr_as(expr, &ret_type);
+                    self.line(format!("return {};", v).as_str());
+                },
+                Statement::If(s) => {
+                    let cond = self.expr_as(&s.condition, &Type {val: TypeVal::Boolean, ptr_depth: 0});
+                    self.line(format!("if ({}) {{", cond).as_str());
+                    self.depth += 1;
+                    self.scope(&s.body);
+                    self.depth -= 1;
+                    self.line("}");
+                },
+                Statement::While(s) => {
+                    let cond = self.expr_as(&s.condition, &Type {val: TypeVal::Boolean, ptr_depth: 0});
+                    self.line(format!("while ({}) {{", cond).as_str());
+                    self.depth += 1;
+                    self.scope(&s.body);
+                    self.depth -= 1;
+                    self.line("}");
+                },
+                Statement::Extern(_) | Statement::NullStatement => {},
+            }
+        }
+    }
+}
+
+/* Local variables are all declared at the top of the function, the same as they each get a slot in
+ * the IR. A variable defined more than once keeps the type it was first defined with. */
+fn collect_locals(statements: &[Statement], locals: &mut Vec<(String, Type)>) {
+    for statement in statements {
+        match statement {
+            Statement::Define(s) if !locals.iter().any(|(name, _)| *name == s.identifier) => {
+                locals.push((s.identifier.clone(), s.def_type.clone()));
+            },
+            Statement::If(s) => collect_locals(&s.body, locals),
+            Statement::While(s) => collect_locals(&s.body, locals),
+            _ => {},
+        }
+    }
+}
+
+// Externs declared inside functions still need a prototype at the top of the file.
+fn collect_externs(statements: &[Statement], externs: &mut Vec<(String, FuncTableVal)>) {
+    for statement in statements {
+        match statement {
+            Statement::Extern(s) => externs.push((s.identifier.clone(), s.val.clone())),
+            Statement::If(s) => collect_externs(&s.body, externs),
+            Statement::While(s) => collect_externs(&s.body, externs),
+            _ => {},
+        }
+    }
+}
+
+fn c_operator(op: Operation) -> &'static str {
+    match op {
+        Operation::Add => "+",
+        Operation::Sub => "-",
+        Operation::Star => "*",
+        Operation::Div => "/",
+        Operation::Mod => "%",
+        Operation::LeftShift => "<<",
+        Operation::RightShift => ">>",
+        Operation::Ampersand => "&",
+        Operation::BitOr => "|",
+        Operation::BitXor => "^",
+        Operation::BitNot => "~",
+        Operation::Not => "!",
+        Operation::And => "&&",
+        Operation::Or => "||",
+        Operation::Less => "<",
+        Operation::LessEqu => "<=",
+        Operation::Greater => ">",
+        Operation::GreaterEqu => ">=",
+        Operation::Equ => "==",
+        Operation::NotEqu => "!=",
+        _ => panic!("Unsupported operation."),
+    }
+}
+
+/* Translates the whole (already type checked) function table into C. Inline assembly is translated
+ * to GAS syntax when targeting x86_64, otherwise it's copied as it is. */
+pub fn generate(program: &HashMap<String, FuncTableVal>, globals: &[GlobalVar], extern_idents: &[String], target: Arch) -> String {
+    let mut program = program.clone();
+    let mut externs: Vec<(String, FuncTableVal)> = extern_idents.iter().map(|e| (e.clone(), program[e].clone())).collect();
+    for func in program.values() {
+        if let Some(statements) = &func.statements { collect_externs(statements, &mut externs) }
+    }
+    let mut out = String::from("/* Generated by CTFAW. CTFAW's char is unsigned, so build with -funsigned-char for the same results. */\n");
+    out.push_str(PRELUDE);
+    out.push_str(match target {
+        Arch::X86_64 => X86_64_INTRINSICS,
+        Arch::Aarch64 => AARCH64_INTRINSICS,
+        Arch::Riscv64 => RISCV64_INTRINSICS,
+        Arch::Wasm32 => "",
+    });
+    out.push('\n');
+    let mut declared = Vec::new();
+    for (ident, ext) in externs {
+        if declared.contains(&ident) { continue }
+        out.push_str(format!("{};\n", prototype(&ident, &ext.signature)).as_str());
+        declared.push(ident.clone());
+        program.insert(ident, ext);
+    }
+    for global in globals {
+        // Ones placed in a section have to be kept and visible, since nothing in the program refers to them.
+        let storage = match global.section() {
+            Some(section) => format!("__attribute__((section(\"{}\"), used)) ", section),
+            None => String::from("static "),
+        };
+        out.push_str(format!("{}const {} {} = (({}){});\n", storage, c_type(&global.typ), global.identifier, c_type(&global.typ), c_int(global.val)).as_str());
+    }
+    let mut names: Vec<String> = program.iter()
+        .filter(|(_, f)| f.statements.is_some())
+        .map(|(k, _)| k.clone())
+        .collect();
+    names.sort();
+    out.push('\n');
+    for name in &names {
+        let section = program[name].section().map(|s| format!("__attribute__((section(\"{}\"))) ", s)).unwrap_or_default();
+        let naked = if program[name].has_attribute("naked") { "__attribute__((naked)) " } else { "" };
+        let storage = if program[name].is_static { "static " } else { "" };
+        out.push_str(format!("{}{}{}{};\n", storage, naked, section, prototype(name, &program[name].signature)).as_str());
+    }
+    for name in &names {
+        let func = &program[name];
+        let statements = func.statements.as_ref().unwrap();
+        let mut locals = Vec::new();
+        collect_locals(statements, &mut locals);
+        let mut vars = HashMap::new();
+        let mut consts = HashMap::new();
+        for global in globals {
+            vars.insert(global.identifier.clone(), global.typ.clone());
+            consts.insert(global.identifier.clone(), global.val);
+        }
+        for arg in &func.signature.args {
+            vars.insert(arg.val.clone(), arg.arg_type.clone());
+        }
+        let mut writer = FuncWriter {
+            program: &program,
+            vars: HashMap::new(),
+            consts: HashMap::new(),
+            ret_type: func.signature.ret_type.clone(),
+            target: target.clone(),
+            out: String::new(),
+            depth: 1,
+        };
+        for (ident, typ) in &locals {
+            if func.signature.args.iter().any(|a| a.val == *ident) { continue }
+            writer.line(format!("{} {} = 0;", c_type(typ), ident).as_str());
+            vars.insert(ident.clone(), typ.clone());
+        }
+        for ident in locals.iter().map(|(ident, _)| ident).chain(func.signature.args.iter().map(|a| &a.val)) {
+            consts.remove(ident);
+        }
+        writer.vars = vars;
+        writer.consts = consts;
+        if func.has_attribute("naked") {
+            // Naked functions can only hold basic asm, and have to return by themselves.
+            for statement in statements {
+                if let Statement::InlineAsm(s) = statement {
+                    let asm = writer.asm_text(s);
+                    writer.line(format!("__asm__ ({});", c_string(&asm)).as_str());
+                }
+            }
+            out.push_str(format!("\n{} {{\n{}}}\n", prototype(name, &func.signature), writer.out).as_str());
+            continue
+        }
+        writer.scope(statements);
+        // Falling off the end of a function returns 0.
+        writer.line("return 0;");
+        out.push_str(format!("\n{} {{\n{}}}\n", prototype(name, &func.signature), writer.out).as_str());
+    }
+    out
+}
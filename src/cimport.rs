@@ -0,0 +1,525 @@
+/* Reads the parts of C headers which CTFAW can use, for `import_c "stdio.h"`: function prototypes,
+ * simple typedefs and `#define`s of integer constants. Prototypes become externs and the defines
+ * become constants, in the file which imported the header.
+ *
+ * There's no real preprocessor. Macros without arguments are expanded, but conditionals are ignored
+ * and both sides of an `#if` are read. The first declaration of a name wins, but the last definition
+ * of a macro does, since headers tend to define them for other configurations (32 bit systems and
+ * C++) first. Anything else (structs, macros with arguments, inline
+ * functions, declarations with function pointers or types CTFAW doesn't have) is skipped. Headers
+ * it `#include`s are read the same way, when they can be found.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::parser::*;
+use crate::lexer::*;
+use crate::error::*;
+
+// What a header declares, by name.
+#[derive(Default)]
+pub struct Header {
+    pub functions: HashMap<String, FuncTableVal>,
+    pub consts: HashMap<String, GlobalVar>,
+    pub files: Vec<PathBuf>, // every header that was read, including ones it included
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum CTok {
+    Ident(String),
+    Num(String),
+    Str,
+    Punct(char),
+    Ellipsis,
+}
+
+// What a C type is underneath any pointers to it.
+#[derive(Debug, Clone, PartialEq)]
+enum Base {
+    Val(TypeVal),
+    Void,
+    Opaque, // structs, unions and anything else which can only be used through a pointer
+}
+
+const QUALIFIERS: [&str; 13] = ["const", "volatile", "restrict", "__restrict", "__restrict__", "__const", "register", "extern",
+    "inline", "__inline", "__inline__", "__extension__", "_Noreturn"];
+
+fn system_include_dirs() -> Vec<PathBuf> {
+    let multiarch = format!("/usr/include/{}-linux-gnu", std::env::consts::ARCH);
+    ["/usr/local/include", multiarch.as_str(), "/usr/include"].iter().map(PathBuf::from).collect()
+}
+
+// Typedefs which normally come from the C compiler's own headers, rather than libc's.
+fn builtin_typedefs() -> HashMap<String, (Base, u64)> {
+    [
+        ("size_t", TypeVal::U64), ("ssize_t", TypeVal::I64), ("ptrdiff_t", TypeVal::I64), ("wchar_t", TypeVal::I32),
+        ("int8_t", TypeVal::I8), ("int16_t", TypeVal::I16), ("int32_t", TypeVal::I32), ("int64_t", TypeVal::I64),
+        ("uint8_t", TypeVal::U8), ("uint16_t", TypeVal::U16), ("uint32_t", TypeVal::U32), ("uint64_t", TypeVal::U64),
+        ("intptr_t", TypeVal::I64), ("uintptr_t", TypeVal::U64), ("bool", TypeVal::Boolean),
+    ].into_iter().map(|(name, typ)| (String::from(name), (Base::Val(typ), 0))).chain([(String::from("va_list"), (Base::Opaque, 1))]).collect()
+}
+
+// Splits a header into its preprocessor lines and the tokens of everything else, leaving out comments.
+fn tokenize(src: &str) -> (Vec<String>, Vec<CTok>) {
+    let chars: Vec<char> = src.chars().collect();
+    let mut directives = Vec::new();
+    let mut tokens = Vec::new();
+    let mut line_start = true;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            line_start = true;
+            i += 1;
+            continue;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) { i += 1 }
+            i += 2;
+            continue;
+        }
+        if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' { i += 1 }
+            continue;
+        }
+        if c == '#' && line_start {
+            let mut line = String::new();
+            i += 1;
+            while i < chars.len() && chars[i] != '\n' {
+                if chars[i] == '\\' && chars.get(i + 1) == Some(&'\n') {
+                    i += 2;
+                    continue;
+                }
+                if chars[i] == '/' && chars.get(i + 1) == Some(&'*') {
+                    i += 2;
+                    while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) { i += 1 }
+                    i += 2;
+                    line.push(' ');
+                    continue;
+                }
+                if chars[i] == '/' && chars.get(i + 1) == Some(&'/') {
+                    while i < chars.len() && chars[i] != '\n' { i += 1 }
+                    break;
+                }
+                line.push(chars[i]);
+                i += 1;
+            }
+            directives.push(line);
+            continue;
+        }
+        line_start = false;
+        if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') { i += 1 }
+            tokens.push(CTok::Ident(chars[start..i].iter().collect()));
+            continue;
+        }
+        if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') { i += 1 }
+            tokens.push(CTok::Num(chars[start..i].iter().collect()));
+            continue;
+        }
+        if c == '"' || c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' { i += 1 }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(CTok::Str);
+            continue;
+        }
+        if c == '.' && chars.get(i + 1) == Some(&'.') && chars.get(i + 2) == Some(&'.') {
+            i += 3;
+            tokens.push(CTok::Ellipsis);
+            continue;
+        }
+        tokens.push(CTok::Punct(c));
+        i += 1;
+    }
+    (directives, tokens)
+}
+
+/* Splits the tokens into declarations, taking the bodies of structs, unions and enums out. Function
+ * definitions (like `static inline` ones), and `extern "C" {` from headers which can be used from
+ * C++, are left out entirely. */
+fn split_declarations(tokens: &[CTok]) -> Vec<Vec<CTok>> {
+    let mut decls = Vec::new();
+    let mut current = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        if tokens[i] == CTok::Ident(String::from("extern")) && tokens.get(i + 1) == Some(&CTok::Str) && tokens.get(i + 2) == Some(&CTok::Punct('{')) {
+            i += 3;
+            continue;
+        }
+        match &tokens[i] {
+            CTok::Punct(';') => decls.push(std::mem::take(&mut current)),
+            CTok::Punct('{') => {
+                let is_function = current.last() == Some(&CTok::Punct(')'));
+                let mut depth = 0;
+                while i < tokens.len() {
+                    if tokens[i] == CTok::Punct('{') { depth += 1 }
+                    if tokens[i] == CTok::Punct('}') {
+                        depth -= 1;
+                        if depth == 0 { break }
+                    }
+                    i += 1;
+                }
+                if is_function { current.clear() }
+            },
+            // Left over from `extern "C" {`.
+            CTok::Punct('}') => {},
+            tok => current.push(tok.clone()),
+        }
+        i += 1;
+    }
+    decls
+}
+
+// Takes out `__attribute__((...))`, `__asm__("...")` and the like, returning whether a symbol was renamed with asm.
+fn strip_attributes(decl: &mut Vec<CTok>) -> bool {
+    let mut renamed = false;
+    let mut i = 0;
+    while i < decl.len() {
+        let name = match &decl[i] {
+            CTok::Ident(name) => name.clone(),
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        let is_attribute = ["__attribute__", "__attribute", "__asm__", "__asm", "asm", "__declspec"].contains(&name.as_str());
+        if !is_attribute || decl.get(i + 1) != Some(&CTok::Punct('(')) {
+            i += 1;
+            continue;
+        }
+        renamed |= name.contains("asm");
+        let mut end = i + 1;
+        let mut depth = 0;
+        while end < decl.len() {
+            if decl[end] == CTok::Punct('(') { depth += 1 }
+            if decl[end] == CTok::Punct(')') {
+                depth -= 1;
+                if depth == 0 { break }
+            }
+            end += 1;
+        }
+        decl.drain(i..(end + 1).min(decl.len()));
+    }
+    renamed
+}
+
+struct Reader {
+    typedefs: HashMap<String, (Base, u64)>,
+    macros: HashMap<String, Vec<CTok>>, // only ones without arguments
+    header: Header,
+    read: HashSet<PathBuf>,
+    include_dirs: Vec<PathBuf>,
+}
+
+impl Reader {
+    fn is_ident(tok: Option<&CTok>) -> bool {
+        matches!(tok, Some(CTok::Ident(_)))
+    }
+
+    /* Reads the type specifiers at the start of a declaration, like `const unsigned long int` or
+     * `struct tm`, up to the declarator. Returns the type, how many pointers deep a typedef already
+     * made it and the number of tokens it took up. */
+    fn base_type(&self, toks: &[CTok]) -> Option<(Base, u64, usize)> {
+        let mut n = 0;
+        let (mut signed, mut unsigned, mut longs, mut short) = (false, false, 0, false);
+        let mut base = None;
+        let mut ptr_depth = 0;
+        while let Some(CTok::Ident(word)) = toks.get(n) {
+            match word.as_str() {
+                w if QUALIFIERS.contains(&w) => {},
+                "signed" | "__signed__" => signed = true,
+                "unsigned" => unsigned = true,
+                "long" => longs += 1,
+                "short" => short = true,
+                "int" => base = base.or(Some(Base::Val(TypeVal::I32))),
+                "char" => base = Some(Base::Val(TypeVal::Char)),
+                "_Bool" => base = Some(Base::Val(TypeVal::Boolean)),
+                "double" => base = Some(Base::Val(TypeVal::F64)),
+                "void" => base = Some(Base::Void),
+                "float" => return None,
+                "struct" | "union" | "enum" => {
+                    // Untagged ones have had their body taken out, so the next name is the declarator.
+                    let tagged = Self::is_ident(toks.get(n + 1)) && !matches!(toks.get(n + 2), None | Some(CTok::Punct(',')));
+                    base = Some(if word == "enum" { Base::Val(TypeVal::I32) } else { Base::Opaque });
+                    if tagged { n += 1 }
+                },
+                name if base.is_none() && !signed && !unsigned && longs == 0 && !short => {
+                    match self.typedefs.get(name) {
+                        Some((typedef, depth)) => {
+                            base = Some(typedef.clone());
+                            ptr_depth = *depth;
+                        },
+                        // Macros defined somewhere that wasn't read, which are usually attributes.
+                        None if name.starts_with("__") && Self::is_ident(toks.get(n + 1)) => {},
+                        None => return None,
+                    }
+                },
+                _ => break,
+            }
+            n += 1;
+        }
+        let base = match base {
+            Some(Base::Val(TypeVal::Char)) if signed => Base::Val(TypeVal::I8),
+            Some(Base::Val(TypeVal::Char)) if unsigned => Base::Val(TypeVal::U8),
+            Some(Base::Val(TypeVal::F64)) if longs > 0 => return None,
+            Some(base) if !(signed || unsigned || longs > 0 || short) => base,
+            Some(Base::Val(TypeVal::I32)) | None => match (unsigned, short, longs > 0) {
+                (false, true, _) => Base::Val(TypeVal::I16),
+                (true, true, _) => Base::Val(TypeVal::U16),
+                (false, false, true) => Base::Val(TypeVal::I64),
+                (true, false, true) => Base::Val(TypeVal::U64),
+                (false, false, false) if signed => Base::Val(TypeVal::I32),
+                (true, false, false) => Base::Val(TypeVal::U32),
+                _ => return None,
+            },
+            Some(_) => return None,
+        };
+        Some((base, ptr_depth, n))
+    }
+
+    // Reads the `*`s of a declarator, along with any qualifiers between them.
+    fn pointers(toks: &[CTok], mut n: usize) -> (u64, usize) {
+        let mut depth = 0;
+        loop {
+            match toks.get(n) {
+                Some(CTok::Punct('*')) => depth += 1,
+                Some(CTok::Ident(q)) if QUALIFIERS.contains(&q.as_str()) => {},
+                _ => return (depth, n),
+            }
+            n += 1;
+        }
+    }
+
+    fn to_type(base: Base, ptr_depth: u64) -> Option<Type> {
+        match (base, ptr_depth) {
+            (Base::Val(val), _) => Some(Type { val, ptr_depth }),
+            // CTFAW has no void pointer, so pointers to anything it doesn't know about are u8*.
+            (_, 1..) => Some(Type { val: TypeVal::U8, ptr_depth }),
+            _ => None,
+        }
+    }
+
+    fn param(&self, toks: &[CTok], n: usize) -> Option<FuncArg> {
+        let (base, depth, used) = self.base_type(toks)?;
+        let (ptrs, mut used) = Self::pointers(toks, used);
+        let mut ptr_depth = depth + ptrs;
+        let val = match toks.get(used) {
+            Some(CTok::Ident(name)) => {
+                used += 1;
+                name.clone()
+            },
+            _ => format!("arg{}", n),
+        };
+        // Arrays are passed as pointers.
+        if toks.get(used) == Some(&CTok::Punct('[')) && toks.last() == Some(&CTok::Punct(']')) {
+            ptr_depth += 1;
+            used = toks.len();
+        }
+        if used != toks.len() { return None }
+        Some(FuncArg { arg_type: Self::to_type(base, ptr_depth)?, val })
+    }
+
+    fn function(&self, decl: &[CTok], ret: Type, name_idx: usize) -> Option<FuncTableVal> {
+        let mut args = Vec::new();
+        let mut varargs_idx = None;
+        let mut param = Vec::new();
+        let mut depth = 0;
+        let mut end = None;
+        for (i, tok) in decl.iter().enumerate().skip(name_idx + 2) {
+            match tok {
+                CTok::Punct('(') => depth += 1,
+                CTok::Punct(')') if depth == 0 => {
+                    end = Some(i);
+                    break;
+                },
+                CTok::Punct(')') => depth -= 1,
+                _ => {},
+            }
+            if *tok == CTok::Punct(',') && depth == 0 {
+                args.push(std::mem::take(&mut param));
+            } else {
+                param.push(tok.clone());
+            }
+        }
+        args.push(param);
+        let end = end?;
+        // Anything after the arguments should be attribute macros like __THROW and __wur.
+        if decl.get(end + 1).is_some_and(|t| !matches!(t, CTok::Ident(n) if n.starts_with("__"))) { return None }
+        let mut sig_args = Vec::new();
+        for (n, param) in args.iter().enumerate() {
+            match param.as_slice() {
+                [] if args.len() == 1 => {},
+                [CTok::Ident(v)] if v == "void" && args.len() == 1 => {},
+                [CTok::Ellipsis] if n == args.len() - 1 => varargs_idx = Some(sig_args.len() as u64),
+                _ => sig_args.push(self.param(param, n)?),
+            }
+        }
+        Some(FuncTableVal {
+            signature: FuncSig { ret_type: ret, args: sig_args, varargs_idx },
+            statements: None,
+            is_extern: true,
+            is_pub: false,
+            is_static: false,
+            attributes: Vec::new(),
+        })
+    }
+
+    fn declaration(&mut self, mut decl: Vec<CTok>) {
+        if strip_attributes(&mut decl) { return }
+        let is_typedef = decl.first() == Some(&CTok::Ident(String::from("typedef")));
+        if is_typedef { decl.remove(0); }
+        if decl.first() == Some(&CTok::Ident(String::from("static"))) { return }
+        let Some((base, depth, used)) = self.base_type(&decl) else { return };
+        let (ptrs, used) = Self::pointers(&decl, used);
+        let Some(CTok::Ident(name)) = decl.get(used) else { return };
+        let name = name.clone();
+        if is_typedef {
+            if used + 1 == decl.len() && !self.typedefs.contains_key(&name) { self.typedefs.insert(name, (base, depth + ptrs)); }
+            return;
+        }
+        if decl.get(used + 1) != Some(&CTok::Punct('(')) || self.header.functions.contains_key(&name) { return }
+        // CTFAW has no void, functions which don't return anything just return 0 like ones with no return type.
+        let ret = match (&base, depth + ptrs) {
+            (Base::Void, 0) => Type { val: TypeVal::U32, ptr_depth: 0 },
+            _ => match Self::to_type(base, depth + ptrs) {
+                Some(t) => t,
+                None => return,
+            },
+        };
+        if let Some(func) = self.function(&decl, ret, used) {
+            self.header.functions.insert(name, func);
+        }
+    }
+
+    fn define(&mut self, line: &str) {
+        let mut words = line.splitn(2, |c: char| c.is_whitespace() || c == '(');
+        let name = words.next().unwrap_or("");
+        // Macros with arguments have their `(` straight after the name.
+        if name.is_empty() || line[name.len()..].starts_with('(') { return }
+        let value = words.next().unwrap_or("").trim();
+        self.macros.insert(String::from(name), tokenize(value).1);
+        if self.header.consts.contains_key(name) { return }
+        let Some((typ, val)) = self.const_value(value) else { return };
+        self.header.consts.insert(String::from(name), GlobalVar { identifier: String::from(name), typ: Type { val: typ, ptr_depth: 0 }, val, is_pub: false, attributes: Vec::new() });
+    }
+
+    /* The type and value of an integer constant like `8192`, `(-1)`, `0x10u` or the name of another
+     * one, with the type C would give it. */
+    fn const_value(&self, value: &str) -> Option<(TypeVal, u64)> {
+        let mut value = value.trim();
+        while value.starts_with('(') && value.ends_with(')') {
+            value = value[1..value.len() - 1].trim();
+        }
+        if let Some(other) = self.header.consts.get(value) { return Some((other.typ.val.clone(), other.val)) }
+        let (negative, value) = match value.strip_prefix('-') {
+            Some(v) => (true, v.trim()),
+            None => (false, value),
+        };
+        let digits = value.trim_end_matches(['u', 'U', 'l', 'L']);
+        let suffix = value[digits.len()..].to_lowercase();
+        let (digits, radix) = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+            (hex, 16)
+        } else if digits.len() > 1 && digits.starts_with('0') {
+            (&digits[1..], 8)
+        } else {
+            (digits, 10)
+        };
+        let magnitude = u64::from_str_radix(digits, radix).ok()?;
+        let unsigned = suffix.contains('u');
+        let long = suffix.contains('l');
+        // Hex and octal constants can be unsigned without a suffix when they don't fit in a signed type.
+        let typ = match (unsigned, long) {
+            (false, false) if magnitude <= i32::MAX as u64 => TypeVal::I32,
+            (false, false) if radix != 10 && magnitude <= u32::MAX as u64 => TypeVal::U32,
+            (true, false) if magnitude <= u32::MAX as u64 => TypeVal::U32,
+            (false, _) if magnitude <= i64::MAX as u64 => TypeVal::I64,
+            _ => TypeVal::U64,
+        };
+        let val = if negative { (magnitude as i64).wrapping_neg() as u64 } else { magnitude };
+        Some((typ, val))
+    }
+
+    /* Expands the macros in some tokens, apart from ones already being expanded, which would never
+     * end. Names followed by `(` are left alone, since they're functions, which some configurations
+     * rename to others with macros (like `#define fopen fopen64`). */
+    fn expand(&self, tokens: Vec<CTok>, active: &mut Vec<String>) -> Vec<CTok> {
+        let mut out = Vec::new();
+        for (i, tok) in tokens.iter().enumerate() {
+            if let CTok::Ident(name) = tok {
+                let is_call = tokens.get(i + 1) == Some(&CTok::Punct('('));
+                if let Some(body) = self.macros.get(name).filter(|_| !active.contains(name) && !is_call) {
+                    active.push(name.clone());
+                    out.extend(self.expand(body.clone(), active));
+                    active.pop();
+                    continue;
+                }
+            }
+            out.push(tok.clone());
+        }
+        out
+    }
+
+    fn find(&self, header: &str, dir: &Path, quoted: bool) -> Option<PathBuf> {
+        let local = if quoted { Some(dir.to_path_buf()) } else { None };
+        local.into_iter().chain(self.include_dirs.iter().cloned()).map(|d| d.join(header)).find(|p| p.is_file())
+    }
+
+    fn read(&mut self, path: &Path) {
+        if !self.read.insert(fs::canonicalize(path).unwrap_or(path.to_path_buf())) { return }
+        let Ok(src) = fs::read(path) else { return };
+        let (directives, tokens) = tokenize(&String::from_utf8_lossy(&src));
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        for line in directives {
+            let line = line.trim();
+            if let Some(define) = line.strip_prefix("define") {
+                self.define(define.trim());
+            } else if let Some(include) = line.strip_prefix("include") {
+                let include = include.trim();
+                let quoted = include.starts_with('"');
+                let name = include.trim_matches(|c| c == '"' || c == '<' || c == '>');
+                if let Some(found) = self.find(name, &dir, quoted) { self.read(&found) }
+            }
+        }
+        let tokens = self.expand(tokens, &mut Vec::new());
+        for decl in split_declarations(&tokens) {
+            self.declaration(decl);
+        }
+    }
+}
+
+/* Reads a header for `import_c`, looking for it next to the file importing it, then in each
+ * directory given with `-I`, then where the system's headers are. */
+pub fn import(header: &str, from: &str, include_dirs: &[String], tok: Token) -> Header {
+    let mut reader = Reader {
+        typedefs: builtin_typedefs(),
+        macros: HashMap::new(),
+        header: Header::default(),
+        read: HashSet::new(),
+        include_dirs: include_dirs.iter().map(PathBuf::from).chain(system_include_dirs()).collect(),
+    };
+    let dir = Path::new(from).parent().unwrap_or(Path::new("")).to_path_buf();
+    let path = match reader.find(header, &dir, true) {
+        Some(path) => path,
+        None => {
+            report_err(Component::PARSER, tok, format!("Couldn't find the C header {}, it isn't next to this file, in any directory given with -I or in the system's include directories.", header).as_str());
+            unreachable!();
+        }
+    };
+    reader.read(&path);
+    reader.header.files = reader.read.into_iter().collect();
+    reader.header.files.sort();
+    reader.header
+}
@@ -0,0 +1,345 @@
+/* The backend always generates NASM syntax. For `--asm-dialect=gas` it's translated afterwards, line
+ * by line, into the Intel syntax flavour of GAS (`.intel_syntax noprefix`), using the same operand
+ * parser as the built in assembler. Inline assembly is checked here too, since it's copied into the
+ * output as is.
+ */
+
+use std::collections::HashMap;
+use crate::assembler::*;
+use crate::parser::*;
+use crate::statements::*;
+use crate::error::*;
+use crate::AsmDialect;
+
+const NASM_ONLY_WORDS: [&str; 12] = ["db", "dw", "dd", "dq", "resb", "resw", "resd", "resq", "section", "segment", "global", "extern"];
+
+struct Translator {
+    last_label: String,
+}
+
+// Escapes a string for `.ascii`.
+fn gas_string(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for b in bytes {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7E => out.push(*b as char),
+            _ => out.push_str(format!("\\{:03o}", b).as_str()),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn size_ptr(size: u8) -> &'static str {
+    match size {
+        1 => "BYTE PTR ",
+        2 => "WORD PTR ",
+        4 => "DWORD PTR ",
+        _ => "QWORD PTR ",
+    }
+}
+
+// Adds the PTR GAS needs after the size of a memory operand containing an inline assembly operand.
+fn placeholder_operand(op: &str) -> String {
+    for size in ["BYTE", "WORD", "DWORD", "QWORD"] {
+        if let Some(rest) = op.strip_prefix(size).or_else(|| op.strip_prefix(size.to_ascii_lowercase().as_str())) {
+            let rest = rest.trim_start();
+            if rest.starts_with('[') { return format!("{} PTR {}", size, rest) }
+        }
+    }
+    String::from(op)
+}
+
+fn is_branch(mnemonic: &str) -> bool {
+    mnemonic == "call" || mnemonic.starts_with('j') || mnemonic.starts_with("loop")
+}
+
+impl Translator {
+    // Same as the built in assembler, NASM style local labels get the last non-local label prepended.
+    fn full_label(&self, name: &str) -> String {
+        if name.starts_with('.') && !self.last_label.is_empty() { format!("{}{}", self.last_label, name) } else { String::from(name) }
+    }
+
+    fn operand(&self, op: &Operand, mnemonic: &str) -> String {
+        match op {
+            Operand::Reg(r) => String::from(reg_name(r)),
+            Operand::Imm(v) => v.to_string(),
+            Operand::Label(label, extra) => {
+                let mut s = self.full_label(label);
+                if *extra != 0 { s.push_str(format!("{:+}", extra).as_str()) }
+                // Without OFFSET, GAS would load from the label rather than use its address.
+                if is_branch(mnemonic) { s } else { format!("OFFSET {}", s) }
+            },
+            Operand::Mem(m) => {
+                let mut parts = Vec::new();
+                if let Some(base) = &m.base { parts.push(String::from(reg_name(base))) }
+                if let Some((index, scale)) = &m.index { parts.push(format!("{}*{}", reg_name(index), scale)) }
+                if let Some(label) = &m.label {
+                    if parts.is_empty() { parts.push(String::from("rip")) }
+                    parts.push(self.full_label(label));
+                }
+                let mut inner = parts.join(" + ");
+                if m.disp != 0 || inner.is_empty() {
+                    if inner.is_empty() { inner = m.disp.to_string() }
+                    else if m.disp < 0 { inner.push_str(format!(" - {}", m.disp.unsigned_abs()).as_str()) }
+                    else { inner.push_str(format!(" + {}", m.disp).as_str()) }
+                }
+                format!("{}[{}]", m.size.map(size_ptr).unwrap_or(""), inner)
+            },
+        }
+    }
+
+    fn section(&self, args: &str) -> String {
+        let mut words = args.split_whitespace();
+        let name = words.next().unwrap_or(".text");
+        let (mut write, mut exec, mut nobits) = match name {
+            ".text" | ".data" | ".bss" => return String::from(name),
+            _ => (false, false, false),
+        };
+        for word in words {
+            match word {
+                "write" => write = true,
+                "exec" => exec = true,
+                "nobits" => nobits = true,
+                _ => {},
+            }
+        }
+        format!(".section {},\"a{}{}\",@{}", name, if write { "w" } else { "" }, if exec { "x" } else { "" }, if nobits { "nobits" } else { "progbits" })
+    }
+
+    fn data(&self, mnemonic: &str, args: &str) -> Result<Vec<String>, String> {
+        let directive = match mnemonic {
+            "db" => ".byte",
+            "dw" => ".word",
+            "dd" => ".long",
+            _ => ".quad",
+        };
+        let mut lines = Vec::new();
+        let mut values = Vec::new();
+        for item in split_operands(args) {
+            if let Some(s) = item.strip_prefix(['"', '\'', '`']).and_then(|s| s.strip_suffix(['"', '\'', '`'])) {
+                if mnemonic != "db" { return Err(format!("Strings can only be used with db, got {}", item)) }
+                if !values.is_empty() { lines.push(format!("{} {}", directive, std::mem::take(&mut values).join(", "))) }
+                lines.push(format!(".ascii {}", gas_string(s.as_bytes())));
+                continue
+            }
+            values.push(match parse_operand(&item)? {
+                Operand::Imm(v) => v.to_string(),
+                Operand::Label(label, 0) => self.full_label(&label),
+                Operand::Label(label, extra) => format!("{}{:+}", self.full_label(&label), extra),
+                _ => return Err(format!("Invalid data item: {}", item)),
+            });
+        }
+        if !values.is_empty() { lines.push(format!("{} {}", directive, values.join(", "))) }
+        Ok(lines)
+    }
+
+    fn instruction(&self, mnemonic: &str, args: &str) -> Result<String, String> {
+        if ["rep", "repe", "repz", "repne", "repnz", "lock"].contains(&mnemonic) {
+            let (inner, inner_args) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            return Ok(format!("{} {}", mnemonic, self.instruction(&inner.to_ascii_lowercase(), inner_args.trim())?))
+        }
+        let mut ops = Vec::new();
+        for op in split_operands(args) {
+            // Inline assembly operands ({name}) are only known later, so they're kept as they are.
+            if op.contains('{') {
+                ops.push(placeholder_operand(&op));
+                continue
+            }
+            if let Some(label) = op.strip_suffix("wrt ..plt") {
+                ops.push(format!("{}@PLT", self.full_label(label.trim())));
+                continue
+            }
+            ops.push(self.operand(&parse_operand(&op)?, mnemonic));
+        }
+        Ok(if ops.is_empty() { String::from(mnemonic) } else { format!("{} {}", mnemonic, ops.join(", ")) })
+    }
+
+    // Takes a line of NASM (or already GAS) syntax and returns the GAS lines for it.
+    fn line(&mut self, line: &str) -> Result<Vec<String>, String> {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let (code, nasm_comment) = split_comment(line, false);
+        let (code, gas_comment) = split_comment(code, true);
+        let comment = nasm_comment.or(gas_comment).map(|c| c.trim_start_matches(';').trim());
+        let mut code = code.trim();
+        let mut label = String::new();
+        let mut lines = Vec::new();
+        if code.starts_with('[') && code.ends_with(']') {
+            let directive = code[1..code.len() - 1].trim().to_ascii_lowercase();
+            if directive == "bits 64" {
+                lines.push(String::from(".intel_syntax noprefix"));
+            } else if directive.starts_with("section ") || directive.starts_with("segment ") {
+                lines.push(self.section(&directive[8..]));
+            } else {
+                return Err(format!("Unsupported directive: {}", code))
+            }
+            code = "";
+        }
+        if let Some((name, rest)) = code.split_once(':') {
+            let name = name.trim();
+            if is_label_name(name) && !name.contains(' ') {
+                label = format!("{}:", self.full_label(name));
+                if !name.starts_with('.') { self.last_label = String::from(name) }
+                code = rest.trim();
+            }
+        }
+        let (mnemonic, args) = match code.split_once(char::is_whitespace) {
+            Some((m, a)) => (m.to_ascii_lowercase(), a.trim()),
+            None => (code.to_ascii_lowercase(), ""),
+        };
+        match mnemonic.as_str() {
+            "" | "default" | "bits" => {},
+            // GAS directives are already fine as they are.
+            _ if mnemonic.starts_with('.') => lines.push(String::from(code)),
+            "global" | "extern" => {
+                for name in args.split(',') {
+                    lines.push(format!(".{} {}", if mnemonic == "global" { "globl" } else { "extern" }, name.split(':').next().unwrap().trim()));
+                }
+            },
+            "section" | "segment" => lines.push(self.section(args)),
+            "db" | "dw" | "dd" | "dq" => lines.extend(self.data(&mnemonic, args)?),
+            "align" => lines.push(format!(".balign {}", args)),
+            "resb" | "resw" | "resd" | "resq" => {
+                let unit = match mnemonic.as_str() { "resb" => 1, "resw" => 2, "resd" => 4, _ => 8 };
+                lines.push(format!(".zero {}", unit * parse_number(args).ok_or("Invalid reserve count.")?));
+            },
+            _ => lines.push(self.instruction(&mnemonic, args)?),
+        }
+        if !label.is_empty() {
+            if lines.is_empty() { lines.push(label) } else { lines[0] = format!("{} {}", label, lines[0]) }
+        }
+        if let Some(comment) = comment {
+            if lines.is_empty() { lines.push(format!("# {}", comment)) } else { lines.last_mut().unwrap().push_str(format!(" # {}", comment).as_str()) }
+        }
+        Ok(lines.into_iter().map(|l| format!("{}{}", indent, l)).collect())
+    }
+}
+
+/* Translates the NASM syntax output of the backend into GAS syntax. On error, returns the line
+ * number (starting from 1) and what went wrong. */
+pub fn to_gas(nasm: &str) -> Result<String, (usize, String)> {
+    let mut translator = Translator { last_label: String::new() };
+    let mut out = Vec::new();
+    for (i, line) in nasm.lines().enumerate() {
+        out.extend(translator.line(line).map_err(|e| (i + 1, e))?);
+    }
+    // Without this, linkers assume the stack needs to be executable.
+    out.push(String::from(".section .note.GNU-stack,\"\",@progbits"));
+    Ok(out.join("\n") + "\n")
+}
+
+// Translates a single block of inline assembly into GAS syntax, without anything around it.
+pub fn block_to_gas(asm: &str) -> Result<String, String> {
+    let mut translator = Translator { last_label: String::new() };
+    let mut out = Vec::new();
+    for line in asm.lines() {
+        out.extend(translator.line(line)?);
+    }
+    Ok(out.join("\n"))
+}
+
+#[derive(Debug, PartialEq)]
+enum InlineAsmStyle {
+    Either, // Intel syntax that NASM and GAS would both take
+    Nasm,
+    Gas,
+    Att,
+}
+
+// Works out which dialect a block of inline assembly is written in from anything specific to one.
+fn detect_style(asm: &str) -> InlineAsmStyle {
+    let mut style = InlineAsmStyle::Either;
+    for line in asm.lines() {
+        let code = split_comment(split_comment(line, false).0, true).0.trim();
+        let lower = code.to_ascii_lowercase();
+        let words: Vec<&str> = lower.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '%' || c == '$')).filter(|w| !w.is_empty()).collect();
+        if words.iter().any(|w| w.strip_prefix('%').and_then(parse_reg).is_some()) {
+            return InlineAsmStyle::Att
+        }
+        let first = words.first().copied().unwrap_or("");
+        if words.contains(&"ptr") || words.contains(&"offset") || (first.starts_with('.') && !code.contains(':')) {
+            style = InlineAsmStyle::Gas;
+        } else if style == InlineAsmStyle::Either && (NASM_ONLY_WORDS.contains(&first) || lower.starts_with('%') || lower.starts_with('[')
+            || ["byte [", "word [", "dword [", "qword ["].iter().any(|w| lower.contains(w))) {
+            style = InlineAsmStyle::Nasm;
+        }
+    }
+    style
+}
+
+fn check_statements(statements: &[Statement], dialect: &AsmDialect) {
+    for statement in statements {
+        match statement {
+            Statement::InlineAsm(s) => {
+                let style = detect_style(&s.asm);
+                assert_report(style != InlineAsmStyle::Att, Component::ASSEMBLER, s.asm_tok.clone(), "Inline assembly is written in AT&T syntax, but only Intel syntax is supported.");
+                match dialect {
+                    AsmDialect::Nasm => assert_report(style != InlineAsmStyle::Gas, Component::ASSEMBLER, s.asm_tok.clone(),
+                        "Inline assembly is written in GAS syntax, but the output is NASM syntax. Use --asm-dialect=gas to output GAS syntax."),
+                    AsmDialect::Gas => {
+                        // NASM style blocks get translated along with everything else, so they have to be understood.
+                        if let Err(msg) = block_to_gas(&s.asm) {
+                            report_err(Component::ASSEMBLER, s.asm_tok.clone(), format!("Couldn't translate inline assembly to GAS syntax: {}", msg).as_str());
+                        }
+                    },
+                }
+            },
+            Statement::If(s) => check_statements(&s.body, dialect),
+            Statement::While(s) => check_statements(&s.body, dialect),
+            _ => {},
+        }
+    }
+}
+
+// Makes sure every inline assembly block is written in a dialect that can be used for the output.
+pub fn check_inline_asm(program: &HashMap<String, FuncTableVal>, dialect: &AsmDialect) {
+    for func in program.values() {
+        if let Some(statements) = &func.statements { check_statements(statements, dialect) }
+    }
+}
+
+fn forbid_statements(statements: &[Statement], target: &str) {
+    for statement in statements {
+        match statement {
+            Statement::InlineAsm(s) => report_err(Component::ASSEMBLER, s.asm_tok.clone(), format!("Inline assembly isn't supported when compiling for {}.", target).as_str()),
+            Statement::If(s) => forbid_statements(&s.body, target),
+            Statement::While(s) => forbid_statements(&s.body, target),
+            _ => {},
+        }
+    }
+}
+
+// For targets which have no assembly, like wasm32.
+pub fn forbid_inline_asm(program: &HashMap<String, FuncTableVal>, target: &str) {
+    for func in program.values() {
+        if let Some(statements) = &func.statements { forbid_statements(statements, target) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_to_gas() {
+        let nasm = "section .text\nglobal main\nextern printf\nmain:\nmov QWORD [rbp - 8], rax\nmovzx eax, BYTE [rdi + rcx * 2]\n\
+            call printf wrt ..plt\njmp .l\n.l:\nlea rdi, [str0]\nsection .data\nstr0: db \"a\", 10, 0\nalign 8";
+        let gas = to_gas(nasm).unwrap();
+        assert_eq!(gas.lines().collect::<Vec<_>>(), [
+            ".text", ".globl main", ".extern printf", "main:", "mov QWORD PTR [rbp - 8], rax", "movzx eax, BYTE PTR [rdi + rcx*2]",
+            "call printf@PLT", "jmp main.l", "main.l:", "lea rdi, [rip + str0]", ".data", "str0: .ascii \"a\"", ".byte 10, 0", ".balign 8",
+            ".section .note.GNU-stack,\"\",@progbits",
+        ]);
+        assert_eq!(block_to_gas("mov rax, QWORD [rbx]\nint3").unwrap(), "mov rax, QWORD PTR [rbx]\nint3");
+    }
+
+    #[test]
+    fn detects_inline_asm_style() {
+        assert_eq!(detect_style("mov rax, rbx"), InlineAsmStyle::Either);
+        assert_eq!(detect_style("mov rax, QWORD [rbx]"), InlineAsmStyle::Nasm);
+        assert_eq!(detect_style("mov rax, QWORD PTR [rbx]"), InlineAsmStyle::Gas);
+        assert_eq!(detect_style("movq %rax, %rbx"), InlineAsmStyle::Att);
+    }
+}
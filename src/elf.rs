@@ -0,0 +1,325 @@
+/* Writes ELF64 relocatable object files (the same kind of `.o` file NASM produces) for x86_64, from
+ * sections of already assembled machine code along with their symbols and relocations.
+ */
+
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const EM_X86_64: u16 = 62;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocKind {
+    Abs64,  // R_X86_64_64
+    Pc32,   // R_X86_64_PC32
+    Plt32,  // R_X86_64_PLT32
+    Abs32,  // R_X86_64_32
+    Abs32S, // R_X86_64_32S
+}
+
+impl RelocKind {
+    fn elf_type(&self) -> u64 {
+        match self {
+            RelocKind::Abs64 => 1,
+            RelocKind::Pc32 => 2,
+            RelocKind::Plt32 => 4,
+            RelocKind::Abs32 => 10,
+            RelocKind::Abs32S => 11,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        if *self == RelocKind::Abs64 { 8 } else { 4 }
+    }
+
+    pub fn is_pc_relative(&self) -> bool {
+        *self == RelocKind::Pc32 || *self == RelocKind::Plt32
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RelocTarget {
+    Section(usize), // index into `Object::sections`
+    Symbol(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Reloc {
+    pub offset: u64,
+    pub target: RelocTarget,
+    pub kind: RelocKind,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub write: bool,
+    pub exec: bool,
+    pub nobits: bool,
+    pub align: u64,
+    pub data: Vec<u8>,
+    pub size: u64, // only differs from data.len() for nobits sections
+    pub relocs: Vec<Reloc>,
+}
+
+impl Section {
+    pub fn new(name: &str) -> Self {
+        let (write, exec, nobits) = match name {
+            ".text" => (false, true, false),
+            ".data" => (true, false, false),
+            ".bss" => (true, false, true),
+            _ => (false, false, false),
+        };
+        Self {
+            name: String::from(name),
+            write,
+            exec,
+            nobits,
+            align: if exec { 16 } else { 8 },
+            data: Vec::new(),
+            size: 0,
+            relocs: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Option<usize>, // None if it's undefined (an extern)
+    pub value: u64,
+    pub global: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+}
+
+struct StrTab {
+    data: Vec<u8>,
+}
+
+impl StrTab {
+    fn new() -> Self {
+        Self { data: vec![0] }
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let idx = self.data.len() as u32;
+        self.data.extend_from_slice(s.as_bytes());
+        self.data.push(0);
+        idx
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    typ: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+fn pad_to(buf: &mut Vec<u8>, align: u64) {
+    while !(buf.len() as u64).is_multiple_of(align) {
+        buf.push(0);
+    }
+}
+
+fn push_symbol(buf: &mut Vec<u8>, name: u32, info: u8, shndx: u16, value: u64) {
+    buf.extend_from_slice(&name.to_le_bytes());
+    buf.push(info);
+    buf.push(0); // st_other
+    buf.extend_from_slice(&shndx.to_le_bytes());
+    buf.extend_from_slice(&value.to_le_bytes());
+    buf.extend_from_slice(&0u64.to_le_bytes()); // st_size
+}
+
+/* Section header indices are laid out as: the null section, then every section in the object,
+ * then an empty `.note.GNU-stack` (so the linker doesn't assume the stack needs to be executable),
+ * then a `.rela` section for every section with relocations, then the symbol table and the string
+ * tables. */
+pub fn write_relocatable(obj: &Object) -> Vec<u8> {
+    let mut out = vec![0u8; 64]; // header gets filled in at the end
+    let mut shstrtab = StrTab::new();
+    let mut strtab = StrTab::new();
+    let mut headers = vec![SectionHeader { name: 0, typ: 0, flags: 0, offset: 0, size: 0, link: 0, info: 0, align: 0, entsize: 0 }];
+
+    for section in &obj.sections {
+        pad_to(&mut out, section.align);
+        let offset = out.len() as u64;
+        if !section.nobits { out.extend_from_slice(&section.data) }
+        let mut flags = SHF_ALLOC;
+        if section.write { flags |= SHF_WRITE }
+        if section.exec { flags |= SHF_EXECINSTR }
+        headers.push(SectionHeader {
+            name: shstrtab.add(&section.name),
+            typ: if section.nobits { SHT_NOBITS } else { SHT_PROGBITS },
+            flags,
+            offset,
+            size: if section.nobits { section.size } else { section.data.len() as u64 },
+            link: 0,
+            info: 0,
+            align: section.align,
+            entsize: 0,
+        });
+    }
+    headers.push(SectionHeader { name: shstrtab.add(".note.GNU-stack"), typ: SHT_PROGBITS, flags: 0, offset: out.len() as u64, size: 0, link: 0, info: 0, align: 1, entsize: 0 });
+
+    // Symbols: the null symbol, a symbol for each section, local labels, then globals and externs.
+    let mut symtab = Vec::new();
+    push_symbol(&mut symtab, 0, 0, 0, 0);
+    let mut sym_indices = std::collections::HashMap::new();
+    let mut num_syms = 1;
+    for i in 0..obj.sections.len() {
+        push_symbol(&mut symtab, 0, (STB_LOCAL << 4) | STT_SECTION, (i + 1) as u16, 0);
+        num_syms += 1;
+    }
+    let (locals, globals): (Vec<&Symbol>, Vec<&Symbol>) = obj.symbols.iter().partition(|s| !s.global && s.section.is_some());
+    for sym in locals.iter().chain(globals.iter()) {
+        let (bind, shndx) = match sym.section {
+            Some(idx) => (if sym.global { STB_GLOBAL } else { STB_LOCAL }, (idx + 1) as u16),
+            None => (STB_GLOBAL, 0),
+        };
+        let typ = match sym.section {
+            Some(idx) if sym.global && obj.sections[idx].exec => STT_FUNC,
+            Some(_) if sym.global => STT_OBJECT,
+            _ => STT_NOTYPE,
+        };
+        push_symbol(&mut symtab, strtab.add(&sym.name), (bind << 4) | typ, shndx, sym.value);
+        sym_indices.insert(sym.name.clone(), num_syms);
+        num_syms += 1;
+    }
+    let first_global = 1 + obj.sections.len() + locals.len();
+
+    let symtab_idx = headers.len() + obj.sections.iter().filter(|s| !s.relocs.is_empty()).count();
+    for (i, section) in obj.sections.iter().enumerate() {
+        if section.relocs.is_empty() { continue }
+        pad_to(&mut out, 8);
+        let offset = out.len() as u64;
+        for reloc in &section.relocs {
+            let sym = match &reloc.target {
+                RelocTarget::Section(idx) => (idx + 1) as u64,
+                RelocTarget::Symbol(name) => sym_indices[name] as u64,
+            };
+            out.extend_from_slice(&reloc.offset.to_le_bytes());
+            out.extend_from_slice(&((sym << 32) | reloc.kind.elf_type()).to_le_bytes());
+            out.extend_from_slice(&reloc.addend.to_le_bytes());
+        }
+        headers.push(SectionHeader {
+            name: shstrtab.add(format!(".rela{}", section.name).as_str()),
+            typ: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            offset,
+            size: out.len() as u64 - offset,
+            link: symtab_idx as u32,
+            info: (i + 1) as u32,
+            align: 8,
+            entsize: 24,
+        });
+    }
+
+    pad_to(&mut out, 8);
+    headers.push(SectionHeader { name: shstrtab.add(".symtab"), typ: SHT_SYMTAB, flags: 0, offset: out.len() as u64, size: symtab.len() as u64, link: (symtab_idx + 1) as u32, info: first_global as u32, align: 8, entsize: 24 });
+    out.extend_from_slice(&symtab);
+    headers.push(SectionHeader { name: shstrtab.add(".strtab"), typ: SHT_STRTAB, flags: 0, offset: out.len() as u64, size: strtab.data.len() as u64, link: 0, info: 0, align: 1, entsize: 0 });
+    out.extend_from_slice(&strtab.data);
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    headers.push(SectionHeader { name: shstrtab_name, typ: SHT_STRTAB, flags: 0, offset: out.len() as u64, size: shstrtab.data.len() as u64, link: 0, info: 0, align: 1, entsize: 0 });
+    out.extend_from_slice(&shstrtab.data);
+
+    pad_to(&mut out, 8);
+    let shoff = out.len() as u64;
+    for h in &headers {
+        out.extend_from_slice(&h.name.to_le_bytes());
+        out.extend_from_slice(&h.typ.to_le_bytes());
+        out.extend_from_slice(&h.flags.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes()); // sh_addr
+        out.extend_from_slice(&h.offset.to_le_bytes());
+        out.extend_from_slice(&h.size.to_le_bytes());
+        out.extend_from_slice(&h.link.to_le_bytes());
+        out.extend_from_slice(&h.info.to_le_bytes());
+        out.extend_from_slice(&h.align.to_le_bytes());
+        out.extend_from_slice(&h.entsize.to_le_bytes());
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    header.extend_from_slice(&1u16.to_le_bytes()); // e_type = ET_REL
+    header.extend_from_slice(&EM_X86_64.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    header.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    header.extend_from_slice(&0u64.to_le_bytes()); // e_phoff
+    header.extend_from_slice(&shoff.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    header.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+    header.extend_from_slice(&0u16.to_le_bytes()); // e_phentsize
+    header.extend_from_slice(&0u16.to_le_bytes()); // e_phnum
+    header.extend_from_slice(&64u16.to_le_bytes()); // e_shentsize
+    header.extend_from_slice(&(headers.len() as u16).to_le_bytes());
+    header.extend_from_slice(&((headers.len() - 1) as u16).to_le_bytes()); // e_shstrndx
+    out[..64].copy_from_slice(&header);
+    out
+}
+
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/* Reads the names of the global symbols an ELF64 object file (from any assembler, for any
+ * architecture) defines, which is what an archive's symbol index lists for it. */
+pub fn defined_symbols(obj: &[u8]) -> Vec<String> {
+    let mut symbols = Vec::new();
+    if obj.len() < 64 || &obj[..4] != b"\x7fELF" { return symbols }
+    let shoff = read_u64(obj, 0x28) as usize;
+    let shentsize = read_u16(obj, 0x3a) as usize;
+    let shnum = read_u16(obj, 0x3c) as usize;
+    let header = |i: usize| shoff + i * shentsize;
+    for i in 0..shnum {
+        if read_u32(obj, header(i) + 4) != SHT_SYMTAB { continue }
+        let offset = read_u64(obj, header(i) + 24) as usize;
+        let size = read_u64(obj, header(i) + 32) as usize;
+        let strtab = read_u64(obj, header(read_u32(obj, header(i) + 40) as usize) + 24) as usize;
+        for sym in (offset..offset + size).step_by(24) {
+            let bind = obj[sym + 4] >> 4;
+            let shndx = read_u16(obj, sym + 6);
+            // Weak symbols are defined too, and undefined ones (shndx 0) are what it needs from elsewhere.
+            if (bind != STB_GLOBAL && bind != STB_WEAK) || shndx == 0 { continue }
+            let name = &obj[strtab + read_u32(obj, sym) as usize..];
+            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            symbols.push(String::from_utf8_lossy(&name[..end]).to_string());
+        }
+    }
+    symbols
+}
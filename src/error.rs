@@ -1,22 +1,27 @@
 #![allow(dead_code)]
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, BufRead};
 use std::env;
+use std::path::Path;
 use crate::lexer::*;
 use std::fmt;
-use std::process;
+use std::process::{self, Command};
 
 const GRN: &str = "\x1B[0;32m";
 const CYN: &str = "\x1B[0;36m";
 const BRED: &str = "\x1B[1;31m";
 const NCL: &str = "\x1B[0m";
 
+// Every error is reported with these names, so they keep their original spelling.
+#[allow(clippy::upper_case_acronyms)]
 pub enum Component {
     LEXER,
     PARSER,
     ANALYSIS,
-    CODEGEN
+    CODEGEN,
+    ASSEMBLER,
+    LINKER,
 }
 
 fn read_specific_line(file_path: &str, line_number: usize) -> io::Result<String> {
@@ -40,6 +45,8 @@ impl fmt::Display for Component {
             Component::PARSER => write!(f, "parser"),
             Component::CODEGEN => write!(f, "codegen"),
             Component::ANALYSIS=> write!(f, "analysis"),
+            Component::ASSEMBLER => write!(f, "assembler"),
+            Component::LINKER => write!(f, "linker"),
         }
     }
 }
@@ -50,15 +57,15 @@ pub fn report_err(component: Component, token: Token, msg: &str) {
     let num_row_digits = token.row.to_string().chars().count();
     let mut row_spaces= String::new();
     let mut line_spaces = String::new();
-    for i in 0..num_row_digits { row_spaces.push_str(" "); }
-    for i in 0..token.col { line_spaces.push_str(" "); }
+    for i in 0..num_row_digits { row_spaces.push(' '); }
+    for i in 0..token.col { line_spaces.push(' '); }
     println!("{BRED}Error{NCL}: {}", msg);
     println!("{CYN} -->{NCL} {}:{}:{}", fname, token.row, token.col);
     println!("{CYN}{} |{NCL}", row_spaces);
     println!("{CYN}{} |{NCL} {}", token.row, line);
     println!("{CYN}{} |{NCL}{}{GRN}^ error here{NCL}", row_spaces, line_spaces);
     println!("Exiting due to {} error, could not build.", component);
-    process::exit(0xDEAD);
+    exit();
 }
 
 pub fn assert_report(condition: bool, component: Component, token: Token, msg: &str) {
@@ -66,3 +73,65 @@ pub fn assert_report(condition: bool, component: Component, token: Token, msg: &
         report_err(component, token, msg);
     }
 }
+
+/* Errors in the generated assembly don't have a source token to point at (they'll almost always be
+ * from inline assembly), so point at the line of the assembly file instead. */
+pub fn report_asm_err(file: &str, line_num: usize, line: &str, msg: &str) {
+    let file = keep_asm(file);
+    let num_row_digits = line_num.to_string().chars().count();
+    let mut row_spaces = String::new();
+    for i in 0..num_row_digits { row_spaces.push(' '); }
+    println!("{BRED}Error{NCL}: {}", msg);
+    println!("{CYN} -->{NCL} {}:{}", file, line_num);
+    println!("{CYN}{} |{NCL}", row_spaces);
+    println!("{CYN}{} |{NCL} {}", line_num, line);
+    println!("The generated assembly was kept in {}.", file);
+    println!("Exiting due to {} error, could not build.", Component::ASSEMBLER);
+    exit();
+}
+
+/* Runs an external assembler or linker, which prints its own errors, and returns whether it worked.
+ * The caller should stop the build with `fail` if it didn't, rather than carry on with an output file
+ * that doesn't exist. */
+pub fn run_tool(command: &mut Command) -> bool {
+    let program = command.get_program().to_string_lossy().to_string();
+    match command.status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            println!("{BRED}Error{NCL}: `{}` failed ({}).", program, status);
+            false
+        },
+        Err(err) => {
+            println!("{BRED}Error{NCL}: Couldn't run `{}`: {}", program, err);
+            false
+        },
+    }
+}
+
+// An external assembler failed, after printing errors which point at the generated assembly.
+pub fn fail_assembling(file: &str) -> ! {
+    println!("The generated assembly was kept in {}.", keep_asm(file));
+    fail(Component::ASSEMBLER);
+}
+
+/* A temporary build directory is removed when the build fails, so assembly that didn't assemble is
+ * copied out to the current directory first. Files in the build directory are named `<index>-<stem>`,
+ * so it's named after its input file. Returns where it can be found. */
+fn keep_asm(file: &str) -> String {
+    if env::var("CTFAW_BUILD_DIR").is_err() { return String::from(file) }
+    let name = Path::new(file).file_name().unwrap().to_string_lossy().to_string();
+    let kept = name.split_once('-').map_or(name.clone(), |(_, stem)| String::from(stem));
+    let _ = fs::copy(file, &kept);
+    kept
+}
+
+pub fn fail(component: Component) -> ! {
+    println!("Exiting due to {} error, could not build.", component);
+    exit();
+}
+
+// Exiting skips the cleanup at the end of a build, so the build's temporary directory is removed here.
+fn exit() -> ! {
+    if let Ok(dir) = env::var("CTFAW_BUILD_DIR") { let _ = fs::remove_dir_all(dir); }
+    process::exit(0xDEAD);
+}
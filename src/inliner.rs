@@ -0,0 +1,467 @@
+/* Function inliner. Replaces calls to small functions (or ones marked with `#[inline]`) with the
+ * body of the function being called, which saves setting up a whole stack frame for every call to
+ * things like accessor functions. This works on the AST after typechecking, and runs before the
+ * rest of the optimisations so they can clean up whatever gets left behind.
+ */
+
+use std::collections::{HashMap, HashSet};
+use crate::ast::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::statements::*;
+use crate::optimisation::{visit_expr, rewrite_expr, has_side_effects};
+
+// Functions with at most this many statements get inlined without being asked to at -O2.
+const MAX_INLINE_STATEMENTS: usize = 8;
+
+fn calls_in_statements(statements: &[Statement], out: &mut Vec<String>) {
+    let visit = |expr: &BranchChild, out: &mut Vec<String>| {
+        visit_expr(expr, &mut |node| {
+            if let BranchChildVal::Fn(call) = &node.val { out.push(call.fn_ident.clone()) }
+        });
+    };
+    for statement in statements {
+        match statement {
+            Statement::Define(s) => visit(&s.expr, out),
+            Statement::Assign(s) => visit(&s.expr, out),
+            Statement::Return(e) => visit(e, out),
+            Statement::InlineAsm(s) => s.inputs.iter().for_each(|i| visit(&i.expr, out)),
+            Statement::FuncCall(c) => {
+                out.push(c.fn_ident.clone());
+                for arg in &c.args {
+                    visit(arg, out);
+                }
+            },
+            Statement::If(s) => {
+                visit(&s.condition, out);
+                calls_in_statements(&s.body, out);
+            },
+            Statement::While(s) => {
+                visit(&s.condition, out);
+                calls_in_statements(&s.body, out);
+            },
+            _ => {},
+        }
+    }
+}
+
+fn count_statements(statements: &[Statement]) -> usize {
+    statements.iter().map(|s| match s {
+        Statement::If(s) => 1 + count_statements(&s.body),
+        Statement::While(s) => 1 + count_statements(&s.body),
+        _ => 1,
+    }).sum()
+}
+
+/* Inline assembly could define labels which would clash once the body is copied, and extern
+ * statements change the function table, so functions using either are never inlined. */
+fn has_uninlinable_statements(statements: &[Statement]) -> bool {
+    statements.iter().any(|s| match s {
+        Statement::InlineAsm(_) | Statement::Extern(_) | Statement::NullStatement => true,
+        Statement::If(s) => has_uninlinable_statements(&s.body),
+        Statement::While(s) => has_uninlinable_statements(&s.body),
+        _ => false,
+    })
+}
+
+// Whether there's a return anywhere other than as the very last statement of the function.
+fn has_early_return(statements: &[Statement]) -> bool {
+    fn any_return(statements: &[Statement]) -> bool {
+        statements.iter().any(|s| match s {
+            Statement::Return(_) => true,
+            Statement::If(s) => any_return(&s.body),
+            Statement::While(s) => any_return(&s.body),
+            _ => false,
+        })
+    }
+    match statements.split_last() {
+        Some((Statement::Return(_), rest)) => any_return(rest),
+        _ => any_return(statements),
+    }
+}
+
+fn defined_names(statements: &[Statement], out: &mut HashSet<String>) {
+    for statement in statements {
+        match statement {
+            Statement::Define(s) => { out.insert(s.identifier.clone()); },
+            Statement::If(s) => defined_names(&s.body, out),
+            Statement::While(s) => defined_names(&s.body, out),
+            _ => {},
+        }
+    }
+}
+
+fn names_in_expr(expr: &BranchChild, out: &mut HashSet<String>) {
+    visit_expr(expr, &mut |node| {
+        match &node.val {
+            BranchChildVal::Ident(s) | BranchChildVal::Ref(s) => { out.insert(s.clone()); },
+            _ => {},
+        }
+    });
+}
+
+fn names_in_statements(statements: &[Statement], out: &mut HashSet<String>) {
+    for statement in statements {
+        match statement {
+            Statement::Define(s) => names_in_expr(&s.expr, out),
+            Statement::Assign(s) => {
+                out.insert(s.identifier.clone());
+                names_in_expr(&s.expr, out);
+            },
+            Statement::Return(e) => names_in_expr(e, out),
+            Statement::FuncCall(c) => c.args.iter().for_each(|a| names_in_expr(a, out)),
+            Statement::If(s) => {
+                names_in_expr(&s.condition, out);
+                names_in_statements(&s.body, out);
+            },
+            Statement::While(s) => {
+                names_in_expr(&s.condition, out);
+                names_in_statements(&s.body, out);
+            },
+            _ => {},
+        }
+    }
+}
+
+fn rename_expr(expr: &mut BranchChild, prefix: &str, names: &HashSet<String>) {
+    rewrite_expr(expr, &mut |node| {
+        match &node.val {
+            BranchChildVal::Ident(s) if names.contains(s) => Some(BranchChild { val: BranchChildVal::Ident(format!("{}{}", prefix, s)), ..node.clone() }),
+            BranchChildVal::Ref(s) if names.contains(s) => Some(BranchChild { val: BranchChildVal::Ref(format!("{}{}", prefix, s)), ..node.clone() }),
+            _ => None,
+        }
+    });
+}
+
+fn rename_statements(statements: &mut [Statement], prefix: &str, names: &HashSet<String>) {
+    for statement in statements {
+        match statement {
+            Statement::Define(s) => {
+                rename_expr(&mut s.expr, prefix, names);
+                s.identifier = format!("{}{}", prefix, s.identifier);
+            },
+            Statement::Assign(s) => {
+                rename_expr(&mut s.expr, prefix, names);
+                if names.contains(&s.identifier) { s.identifier = format!("{}{}", prefix, s.identifier) }
+            },
+            Statement::Return(e) => rename_expr(e, prefix, names),
+            Statement::FuncCall(c) => c.args.iter_mut().for_each(|a| rename_expr(a, prefix, names)),
+            Statement::If(s) => {
+                rename_expr(&mut s.condition, prefix, names);
+                rename_statements(&mut s.body, prefix, names);
+            },
+            Statement::While(s) => {
+                rename_expr(&mut s.condition, prefix, names);
+                rename_statements(&mut s.body, prefix, names);
+            },
+            _ => {},
+        }
+    }
+}
+
+fn cast_to(expr: BranchChild, typ: &Type) -> BranchChild {
+    let (row, col) = (expr.row, expr.col);
+    BranchChild {
+        val: BranchChildVal::Cast(Box::new(Cast {
+            val: expr,
+            typ: typ.clone(),
+            original_type: Type {val: TypeVal::Any, ptr_depth: 0},
+        })),
+        row,
+        col,
+    }
+}
+
+fn is_atomic(expr: &BranchChild) -> bool {
+    match &expr.val {
+        BranchChildVal::Int(_) | BranchChildVal::Char(_) | BranchChildVal::Ident(_) | BranchChildVal::Ref(_) => true,
+        BranchChildVal::Cast(c) => is_atomic(&c.val),
+        _ => false,
+    }
+}
+
+/* Works out which functions can end up calling themselves, whether directly or through other
+ * functions. */
+fn find_recursive(graph: &HashMap<String, Vec<String>>) -> HashSet<String> {
+    let mut recursive = HashSet::new();
+    for start in graph.keys() {
+        let mut stack = graph[start].clone();
+        let mut seen = HashSet::new();
+        while let Some(name) = stack.pop() {
+            if name == *start {
+                recursive.insert(start.clone());
+                break
+            }
+            if !seen.insert(name.clone()) { continue }
+            if let Some(callees) = graph.get(&name) { stack.extend(callees.iter().cloned()) }
+        }
+    }
+    recursive
+}
+
+// Orders functions so that everything a function calls comes before it (as far as possible).
+fn callees_first(graph: &HashMap<String, Vec<String>>) -> Vec<String> {
+    fn visit(name: &str, graph: &HashMap<String, Vec<String>>, seen: &mut HashSet<String>, order: &mut Vec<String>) {
+        if !seen.insert(String::from(name)) { return }
+        let Some(callees) = graph.get(name) else { return };
+        for callee in callees {
+            visit(callee, graph, seen, order);
+        }
+        order.push(String::from(name));
+    }
+    let mut names: Vec<&String> = graph.keys().collect();
+    names.sort();
+    let mut seen = HashSet::new();
+    let mut order = Vec::new();
+    for name in names {
+        visit(name, graph, &mut seen, &mut order);
+    }
+    order
+}
+
+struct Inliner<'a> {
+    program: &'a HashMap<String, FuncTableVal>,
+    recursive: &'a HashSet<String>,
+    level: u8,
+    // Names of the caller's parameters and locals, which callees mustn't see in place of globals.
+    caller_names: HashSet<String>,
+    num_inlined: usize,
+}
+
+impl Inliner<'_> {
+    fn candidate(&self, call: &FuncCallStatement) -> Option<&FuncTableVal> {
+        let func = self.program.get(&call.fn_ident)?;
+        let body = func.statements.as_ref()?;
+        if func.is_extern || func.has_attribute("noinline") || func.has_attribute("naked") || self.recursive.contains(&call.fn_ident)
+            || func.signature.varargs_idx.is_some() || func.signature.args.len() != call.args.len()
+            || has_uninlinable_statements(body) || has_early_return(body) {
+            return None
+        }
+        if !(func.has_attribute("inline") || (self.level >= 2 && count_statements(body) <= MAX_INLINE_STATEMENTS)) { return None }
+        // Globals used by the callee would end up referring to the caller's locals instead.
+        let mut locals: HashSet<String> = func.signature.args.iter().map(|a| a.val.clone()).collect();
+        defined_names(body, &mut locals);
+        let mut used = HashSet::new();
+        names_in_statements(body, &mut used);
+        if used.iter().any(|n| !locals.contains(n) && self.caller_names.contains(n)) { return None }
+        Some(func)
+    }
+
+    /* Inlines a call to a function whose whole body is `return <expr>;` straight into the
+     * expression, substituting the arguments for the parameters. This only happens when doing so
+     * can't change how many times (or whether) something with side effects runs. */
+    fn inline_call_expr(&self, call: &FuncCallStatement) -> Option<BranchChild> {
+        let func = self.candidate(call)?;
+        let ret_expr = match func.statements.as_ref().unwrap().as_slice() {
+            [Statement::Return(e)] => e,
+            _ => return None,
+        };
+        let mut substitutions = HashMap::new();
+        for (param, arg) in func.signature.args.iter().zip(&call.args) {
+            let mut uses = 0;
+            let mut address_taken = false;
+            visit_expr(ret_expr, &mut |node| {
+                match &node.val {
+                    BranchChildVal::Ident(s) if *s == param.val => uses += 1,
+                    BranchChildVal::Ref(s) if *s == param.val => address_taken = true,
+                    _ => {},
+                }
+            });
+            if address_taken || has_side_effects(arg) || (uses > 1 && !is_atomic(arg)) { return None }
+            substitutions.insert(param.val.clone(), cast_to(arg.clone(), &param.arg_type));
+        }
+        let mut result = ret_expr.clone();
+        rewrite_expr(&mut result, &mut |node| {
+            match &node.val {
+                BranchChildVal::Ident(s) => substitutions.get(s).cloned(),
+                _ => None,
+            }
+        });
+        result.row = call.row;
+        result.col = call.col;
+        Some(cast_to(result, &func.signature.ret_type))
+    }
+
+    fn inline_expr(&self, expr: &mut BranchChild) {
+        rewrite_expr(expr, &mut |node| {
+            match &node.val {
+                BranchChildVal::Fn(call) => self.inline_call_expr(call),
+                _ => None,
+            }
+        });
+    }
+
+    /* Copies the whole body of the function being called in front of the statement the call is in.
+     * Returns the statements to insert along with the expression to use in place of the call. */
+    fn splice_call(&mut self, call: &FuncCallStatement) -> Option<(Vec<Statement>, BranchChild)> {
+        let func = self.candidate(call)?;
+        let prefix = format!("__inl{}_", self.num_inlined);
+        let mut names: HashSet<String> = func.signature.args.iter().map(|a| a.val.clone()).collect();
+        let mut body = func.statements.clone().unwrap();
+        defined_names(&body, &mut names);
+        rename_statements(&mut body, &prefix, &names);
+        let ret_expr = match body.last() {
+            Some(Statement::Return(_)) => match body.pop() {
+                Some(Statement::Return(e)) => e,
+                _ => unreachable!(),
+            },
+            // Falling off the end of a function returns 0.
+            _ => BranchChild { val: BranchChildVal::Int(0), row: call.row, col: call.col },
+        };
+        let mut params: Vec<Statement> = func.signature.args.iter().zip(&call.args).map(|(param, arg)| {
+            Statement::Define(DefineStatement {
+                is_const: false,
+                identifier: format!("{}{}", prefix, param.val),
+                def_type: param.arg_type.clone(),
+                type_tok: call.ident_tok.clone(),
+                expr: arg.clone(),
+            })
+        }).collect();
+        let ret_expr = cast_to(ret_expr, &func.signature.ret_type);
+        self.num_inlined += 1;
+        // The arguments haven't been looked at yet, and could have calls of their own to inline.
+        params = self.inline_statements(params);
+        params.append(&mut body);
+        Some((params, ret_expr))
+    }
+
+    fn inline_statements(&mut self, statements: Vec<Statement>) -> Vec<Statement> {
+        let mut new_statements = Vec::new();
+        for mut statement in statements {
+            // Calls making up the entire right hand side of a statement can have any body spliced in.
+            let direct_call = match &statement {
+                Statement::Define(DefineStatement {expr, ..}) | Statement::Assign(AssignStatement {expr, ..}) | Statement::Return(expr) => match &expr.val {
+                    BranchChildVal::Fn(call) if self.inline_call_expr(call).is_none() => Some(call.clone()),
+                    _ => None,
+                },
+                Statement::FuncCall(call) => Some(call.clone()),
+                _ => None,
+            };
+            if let Some((mut prelude, ret_expr)) = direct_call.and_then(|call| self.splice_call(&call)) {
+                new_statements.append(&mut prelude);
+                match &mut statement {
+                    Statement::Define(s) => s.expr = ret_expr,
+                    Statement::Assign(s) => s.expr = ret_expr,
+                    Statement::Return(e) => *e = ret_expr,
+                    _ => {
+                        // The result of a call statement is thrown away, but anything with side
+                        // effects in it still has to run.
+                        if has_side_effects(&ret_expr) {
+                            new_statements.push(Statement::Define(DefineStatement {
+                                is_const: false,
+                                identifier: format!("__inl{}_ret", self.num_inlined - 1),
+                                def_type: Type {val: TypeVal::U64, ptr_depth: 0},
+                                type_tok: Token {val: TokenVal::Endln, row: ret_expr.row, col: ret_expr.col},
+                                expr: cast_to(ret_expr, &Type {val: TypeVal::U64, ptr_depth: 0}),
+                            }));
+                        }
+                        continue
+                    },
+                }
+                new_statements.push(statement);
+                continue
+            }
+            match &mut statement {
+                Statement::Define(s) => self.inline_expr(&mut s.expr),
+                Statement::Assign(s) => self.inline_expr(&mut s.expr),
+                Statement::Return(e) => self.inline_expr(e),
+                Statement::FuncCall(c) => c.args.iter_mut().for_each(|a| self.inline_expr(a)),
+                Statement::If(s) => {
+                    self.inline_expr(&mut s.condition);
+                    s.body = self.inline_statements(std::mem::take(&mut s.body));
+                },
+                Statement::While(s) => {
+                    self.inline_expr(&mut s.condition);
+                    s.body = self.inline_statements(std::mem::take(&mut s.body));
+                },
+                _ => {},
+            }
+            new_statements.push(statement);
+        }
+        new_statements
+    }
+}
+
+/* At -O1 only functions marked `#[inline]` are inlined, and at -O2 any small enough function is
+ * too. Functions marked `#[noinline]`, externs, variadic functions, and anything recursive are never
+ * inlined. */
+pub fn inline_functions(program: &mut HashMap<String, FuncTableVal>, level: u8) {
+    if level == 0 { return }
+    let mut graph = HashMap::new();
+    for (name, func) in program.iter() {
+        let mut callees = Vec::new();
+        if let Some(statements) = &func.statements { calls_in_statements(statements, &mut callees) }
+        graph.insert(name.clone(), callees);
+    }
+    let recursive = find_recursive(&graph);
+    let mut num_inlined = 0;
+    for name in callees_first(&graph) {
+        let func = program.get(&name).unwrap();
+        let Some(statements) = func.statements.clone() else { continue };
+        let mut caller_names: HashSet<String> = func.signature.args.iter().map(|a| a.val.clone()).collect();
+        defined_names(&statements, &mut caller_names);
+        let mut inliner = Inliner { program, recursive: &recursive, level, caller_names, num_inlined };
+        let statements = inliner.inline_statements(statements);
+        num_inlined = inliner.num_inlined;
+        program.get_mut(&name).unwrap().statements = Some(statements);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inline(src: &str, level: u8) -> HashMap<String, FuncTableVal> {
+        let mut program = parse(lex(src), &mut Vec::new(), &mut Vec::new());
+        inline_functions(&mut program, level);
+        program
+    }
+
+    fn calls(program: &HashMap<String, FuncTableVal>, name: &str) -> Vec<String> {
+        let mut out = Vec::new();
+        calls_in_statements(program[name].statements.as_ref().unwrap(), &mut out);
+        out
+    }
+
+    fn defines(program: &HashMap<String, FuncTableVal>, name: &str) -> Vec<String> {
+        program[name].statements.as_ref().unwrap().iter().filter_map(|s| match s {
+            Statement::Define(d) => Some(d.identifier.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn finds_recursive_functions() {
+        let graph = HashMap::from([
+            (String::from("main"), vec![String::from("a"), String::from("fact")]),
+            (String::from("a"), vec![String::from("b")]),
+            (String::from("b"), vec![String::from("a")]),
+            (String::from("fact"), vec![String::from("fact")]),
+            (String::from("leaf"), vec![]),
+        ]);
+        let mut recursive: Vec<String> = find_recursive(&graph).into_iter().collect();
+        recursive.sort();
+        assert_eq!(recursive, ["a", "b", "fact"]);
+        let order = callees_first(&graph);
+        assert!(order.iter().position(|n| n == "fact") < order.iter().position(|n| n == "main"));
+    }
+
+    #[test]
+    fn renames_inlined_locals() {
+        let program = inline("fn add(x: u64) -> u64 {\n    let y: u64 = x + 1;\n    return y;\n}\n\
+            fn main() -> i32 {\n    let y: u64 = 5;\n    let x: u64 = add(y);\n    return 0;\n}\n", 2);
+        assert!(calls(&program, "main").is_empty());
+        assert_eq!(defines(&program, "main"), ["y", "__inl0_x", "__inl0_y", "x"]);
+    }
+
+    #[test]
+    fn leaves_recursive_and_unmarked_calls() {
+        let src = "fn even(n: u64) -> u64 {\n    return odd(n);\n}\nfn odd(n: u64) -> u64 {\n    return even(n);\n}\n\
+            fn one() -> u64 {\n    return 1;\n}\n#[inline]\nfn two() -> u64 {\n    return 2;\n}\n\
+            fn main() -> i32 {\n    let a: u64 = even(one());\n    let b: u64 = two();\n    return 0;\n}\n";
+        // At -O1 only functions marked #[inline] are.
+        assert_eq!(calls(&inline(src, 1), "main"), ["even", "one"]);
+        assert_eq!(calls(&inline(src, 2), "main"), ["even"]);
+        assert_eq!(calls(&inline(src, 0), "main"), ["even", "one", "two"]);
+    }
+}
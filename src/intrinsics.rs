@@ -0,0 +1,133 @@
+/* Built in functions which compile straight to the instructions they stand for, rather than a call,
+ * so they need no `extern` and no hand written inline assembly. They're added to the function table
+ * without a body before type checking, so they're type checked and lowered to the IR like any
+ * other call, and each backend checks for them when compiling a call.
+ */
+
+use std::collections::HashMap;
+use crate::parser::*;
+use crate::lexer::*;
+use crate::error::*;
+use crate::statements::*;
+use crate::typecheck::visit_calls;
+use crate::Arch;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Intrinsic {
+    Syscall,
+    Outb,
+    Inb,
+    Rdtsc,
+    Cpuid,
+    Pause,
+    Hlt,
+    Unreachable,
+    Trap,
+}
+
+const INTRINSICS: [(&str, Intrinsic); 9] = [
+    ("__syscall", Intrinsic::Syscall), ("__outb", Intrinsic::Outb), ("__inb", Intrinsic::Inb),
+    ("__rdtsc", Intrinsic::Rdtsc), ("__cpuid", Intrinsic::Cpuid), ("__pause", Intrinsic::Pause),
+    ("__hlt", Intrinsic::Hlt), ("__unreachable", Intrinsic::Unreachable), ("__trap", Intrinsic::Trap),
+];
+
+// The system call number, then up to six arguments.
+pub const MAX_SYSCALL_ARGS: usize = 6;
+
+pub fn lookup(name: &str) -> Option<Intrinsic> {
+    INTRINSICS.iter().find(|(n, _)| *n == name).map(|(_, i)| *i)
+}
+
+fn arg(name: &str, val: TypeVal, ptr_depth: u64) -> FuncArg {
+    FuncArg { arg_type: Type {val, ptr_depth}, val: String::from(name) }
+}
+
+impl Intrinsic {
+    pub fn name(self) -> &'static str {
+        INTRINSICS.iter().find(|(_, i)| *i == self).unwrap().0
+    }
+
+    /* The arguments of `__syscall` after the number are variadic, so that pointers and integers of
+     * any size can be passed without casting. They're all passed as 64 bit values. */
+    pub fn signature(self) -> FuncSig {
+        let (args, ret, varargs_idx) = match self {
+            Intrinsic::Syscall => (vec![arg("n", TypeVal::I64, 0)], TypeVal::I64, Some(1)),
+            Intrinsic::Outb => (vec![arg("port", TypeVal::U16, 0), arg("val", TypeVal::U8, 0)], TypeVal::U32, None),
+            Intrinsic::Inb => (vec![arg("port", TypeVal::U16, 0)], TypeVal::U8, None),
+            Intrinsic::Rdtsc => (vec![], TypeVal::U64, None),
+            // eax, ebx, ecx and edx are written to wherever the last four arguments point.
+            Intrinsic::Cpuid => {
+                let mut args = vec![arg("leaf", TypeVal::U32, 0), arg("subleaf", TypeVal::U32, 0)];
+                args.extend(["eax", "ebx", "ecx", "edx"].map(|r| arg(r, TypeVal::U32, 1)));
+                (args, TypeVal::U32, None)
+            },
+            _ => (vec![], TypeVal::U32, None),
+        };
+        FuncSig { ret_type: Type {val: ret, ptr_depth: 0}, args, varargs_idx }
+    }
+
+    pub fn returns_value(self) -> bool {
+        matches!(self, Intrinsic::Syscall | Intrinsic::Inb | Intrinsic::Rdtsc)
+    }
+
+    /* Port I/O and cpuid only exist on x86_64. Wasm can't do anything besides trapping, which is
+     * what reaching an `__unreachable` does too. */
+    pub fn supported_on(self, target: &Arch) -> bool {
+        match self {
+            Intrinsic::Outb | Intrinsic::Inb | Intrinsic::Cpuid => *target == Arch::X86_64,
+            Intrinsic::Unreachable | Intrinsic::Trap => true,
+            _ => *target != Arch::Wasm32,
+        }
+    }
+}
+
+// Adds every intrinsic to the function table, where they can be called like any other function.
+pub fn add_intrinsics(program: &mut HashMap<String, FuncTableVal>) {
+    for (name, intrinsic) in INTRINSICS {
+        program.insert(String::from(name), FuncTableVal {
+            signature: intrinsic.signature(),
+            statements: None,
+            is_extern: false,
+            is_pub: false,
+            is_static: false,
+            attributes: Vec::new(),
+        });
+    }
+}
+
+/* Type checking only counts the arguments of calls which are statements of their own, but the
+ * backends rely on intrinsics getting exactly the arguments they take wherever they're called. */
+fn check_call(call: &FuncCallStatement, target: &Arch) {
+    let intrinsic = match lookup(&call.fn_ident) {
+        Some(i) => i,
+        None => return,
+    };
+    let target_name = match target {
+        Arch::X86_64 => "x86_64",
+        Arch::Aarch64 => "aarch64",
+        Arch::Riscv64 => "riscv64",
+        Arch::Wasm32 => "wasm32",
+    };
+    assert_report(intrinsic.supported_on(target), Component::ANALYSIS, call.ident_tok.clone(),
+        format!("{} isn't supported when compiling for {}.", call.fn_ident, target_name).as_str());
+    let sig = intrinsic.signature();
+    if intrinsic == Intrinsic::Syscall {
+        assert_report(!call.args.is_empty() && call.args.len() <= MAX_SYSCALL_ARGS + 1, Component::ANALYSIS, call.ident_tok.clone(),
+            format!("__syscall takes the system call number and up to {} arguments.", MAX_SYSCALL_ARGS).as_str());
+    } else {
+        assert_report(call.args.len() == sig.args.len(), Component::ANALYSIS, call.ident_tok.clone(),
+            format!("{} takes {} argument(s), but was given {}.", call.fn_ident, sig.args.len(), call.args.len()).as_str());
+    }
+}
+
+pub fn check_intrinsics(program: &HashMap<String, FuncTableVal>, target: &Arch) {
+    for func in program.values() {
+        if let Some(statements) = &func.statements { visit_calls(statements, &mut |call| check_call(call, target)) }
+    }
+}
+
+// Functions and externs can't be given the name of an intrinsic, since they'd never be called.
+pub fn check_not_intrinsic(name: &str, ident_tok: &Token) {
+    assert_report(lookup(name).is_none(), Component::PARSER, ident_tok.clone(),
+        format!("{} is the name of a built in function, so it can't be defined or declared.", name).as_str());
+}
@@ -0,0 +1,649 @@
+/* Three-address intermediate representation which sits between the typed statement lists and the
+ * backends. Every function is split into basic blocks of straight-line instructions operating on
+ * virtual registers, each block ending in a single terminator, so the blocks of a function form
+ * its control flow graph. Local variables live in stack slots which are only ever accessed through
+ * explicit loads and stores, and every virtual register is assigned exactly once.
+ */
+
+
+use std::collections::HashMap;
+use std::fmt;
+use crate::parser::*;
+use crate::statements::*;
+use crate::lexer::*;
+use crate::ast::*;
+use crate::error::*;
+use crate::typecheck::typecheck_expr;
+use crate::optimisation::immediate_value;
+use crate::backend::{type_to_size, check_type_signed};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VReg(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, Clone)]
+pub struct Slot {
+    pub ident: String,
+    pub typ: Type,
+}
+
+#[derive(Debug, Clone)]
+pub enum AsmValue {
+    VReg(VReg),
+    Imm(i64), // only for "i" operands
+}
+
+#[derive(Debug, Clone)]
+pub struct AsmInput {
+    pub name: Option<String>,
+    pub constraint: AsmConstraint,
+    pub val: AsmValue,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsmOutput {
+    pub name: Option<String>,
+    pub constraint: AsmConstraint,
+    pub slot: usize,
+    pub read_write: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Inst {
+    Imm { dst: VReg, val: u64 },
+    Load { dst: VReg, slot: usize },
+    Store { slot: usize, src: VReg },
+    AddrOf { dst: VReg, slot: usize },
+    StrAddr { dst: VReg, idx: usize },
+    LoadPtr { dst: VReg, addr: VReg },
+    StorePtr { addr: VReg, src: VReg },
+    Binary { dst: VReg, op: Operation, lhs: VReg, rhs: VReg },
+    Unary { dst: VReg, op: Operation, src: VReg },
+    Cast { dst: VReg, src: VReg },
+    Call { dst: Option<VReg>, func: String, args: Vec<VReg>, varargs_idx: Option<u64> },
+    InlineAsm { asm: String, inputs: Vec<AsmInput>, outputs: Vec<AsmOutput>, clobbers: Vec<String>, tok: Token },
+}
+
+#[derive(Debug, Clone)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch { cond: VReg, then_blk: BlockId, else_blk: BlockId },
+    Return(VReg),
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub id: BlockId,
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<usize>,
+    pub slots: Vec<Slot>,
+    pub vregs: Vec<Type>,
+    pub blocks: Vec<Block>,
+    pub ret_type: Type,
+    pub section: Option<String>,
+    pub naked: bool,
+    pub interrupt: bool,
+    pub is_static: bool, // only visible inside of the object file
+}
+
+#[derive(Debug, Clone)]
+pub struct Extern {
+    pub identifier: String,
+    pub signature: FuncSig,
+}
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub functions: Vec<Function>,
+    pub externs: Vec<Extern>,
+    pub globals: Vec<GlobalVar>,
+    pub strings: Vec<String>,
+}
+
+impl Function {
+    pub fn vreg_type(&self, v: VReg) -> Type {
+        self.vregs[v.0].clone()
+    }
+}
+
+/* Truncates a value to the width of a type, then zero or sign extends it back out to 64 bits
+ * depending on the type's signedness. This is the form every integer value takes in the IR. */
+pub fn canonical(val: u64, typ: &Type) -> u64 {
+    let bits = type_to_size(typ.clone()) * 8;
+    if bits >= 64 { return val }
+    let mask = (1u64 << bits) - 1;
+    let truncated = val & mask;
+    if check_type_signed(typ.clone()) && truncated >> (bits - 1) == 1 {
+        truncated | !mask
+    } else {
+        truncated
+    }
+}
+
+pub fn is_comparison(op: Operation) -> bool {
+    matches!(op, Operation::Less | Operation::LessEqu | Operation::Greater | Operation::GreaterEqu | Operation::Equ | Operation::NotEqu)
+}
+
+struct FuncBuilder<'a> {
+    program: &'a mut HashMap<String, FuncTableVal>,
+    globals: &'a [GlobalVar],
+    strings: &'a mut Vec<String>,
+    externs: &'a mut Vec<Extern>,
+    slots: Vec<Slot>,
+    vregs: Vec<Type>,
+    blocks: Vec<(Vec<Inst>, Option<Terminator>)>,
+    current: BlockId,
+    ret_type: Type,
+}
+
+impl<'a> FuncBuilder<'a> {
+    fn new_vreg(&mut self, typ: Type) -> VReg {
+        self.vregs.push(typ);
+        VReg(self.vregs.len() - 1)
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push((Vec::new(), None));
+        BlockId(self.blocks.len() - 1)
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.blocks[self.current.0].0.push(inst);
+    }
+
+    // Only the first terminator of a block counts, anything after it is unreachable anyway.
+    fn terminate(&mut self, term: Terminator) {
+        let block = &mut self.blocks[self.current.0];
+        if block.1.is_none() {
+            block.1 = Some(term);
+        }
+    }
+
+    fn slot_of(&self, ident: &str) -> Option<usize> {
+        self.slots.iter().position(|s| s.ident == ident)
+    }
+
+    fn define_slot(&mut self, ident: &str, typ: Type) -> usize {
+        match self.slot_of(ident) {
+            Some(v) => v,
+            None => {
+                self.slots.push(Slot { ident: String::from(ident), typ });
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    fn var_types(&self) -> HashMap<String, Type> {
+        let mut vars = HashMap::new();
+        for global in self.globals {
+            vars.insert(global.identifier.clone(), global.typ.clone());
+        }
+        for slot in self.slots.iter().rev() {
+            vars.insert(slot.ident.clone(), slot.typ.clone());
+        }
+        vars
+    }
+
+    // Works out the type of an expression, using `want` for untyped literals.
+    fn expr_type(&self, expr: &BranchChild, want: &Type) -> Type {
+        let typ = typecheck_expr(expr.clone(), &self.var_types(), self.program);
+        resolve_any(typ, want)
+    }
+
+    fn coerce(&mut self, v: VReg, to: &Type) -> VReg {
+        if self.vregs[v.0] == *to || to.val == TypeVal::Any { return v }
+        let dst = self.new_vreg(to.clone());
+        self.emit(Inst::Cast { dst, src: v });
+        dst
+    }
+
+    fn lower_expr(&mut self, expr: &BranchChild, want: &Type) -> VReg {
+        match &expr.val {
+            BranchChildVal::Int(val) => {
+                let typ = resolve_any(Type {val: TypeVal::Any, ptr_depth: 0}, want);
+                let dst = self.new_vreg(typ.clone());
+                self.emit(Inst::Imm { dst, val: canonical(*val, &typ) });
+                dst
+            },
+            BranchChildVal::Char(val) => {
+                let dst = self.new_vreg(Type {val: TypeVal::Char, ptr_depth: 0});
+                self.emit(Inst::Imm { dst, val: *val as u64 });
+                dst
+            },
+            BranchChildVal::Ident(ident) => {
+                if let Some(slot) = self.slot_of(ident) {
+                    let dst = self.new_vreg(self.slots[slot].typ.clone());
+                    self.emit(Inst::Load { dst, slot });
+                    return dst
+                }
+                match self.globals.iter().find(|g| g.identifier == *ident) {
+                    Some(global) => {
+                        let typ = global.typ.clone();
+                        let val = canonical(global.val, &typ);
+                        let dst = self.new_vreg(typ);
+                        self.emit(Inst::Imm { dst, val });
+                        dst
+                    },
+                    None => {
+                        report_err(Component::CODEGEN, Token {val: TokenVal::Endln, row: expr.row, col: expr.col}, "Variable is not defined.");
+                        unreachable!();
+                    }
+                }
+            },
+            BranchChildVal::Ref(ident) => {
+                let slot = match self.slot_of(ident) {
+                    Some(v) => v,
+                    None => {
+                        report_err(Component::CODEGEN, Token {val: TokenVal::Endln, row: expr.row, col: expr.col}, "Cannot take the address of a global constant.");
+                        unreachable!();
+                    }
+                };
+                let mut typ = self.slots[slot].typ.clone();
+                typ.ptr_depth += 1;
+                let dst = self.new_vreg(typ);
+                self.emit(Inst::AddrOf { dst, slot });
+                dst
+            },
+            BranchChildVal::StrLit(val) => {
+                self.strings.push(val.clone());
+                let dst = self.new_vreg(Type {val: TypeVal::Char, ptr_depth: 1});
+                self.emit(Inst::StrAddr { dst, idx: self.strings.len() - 1 });
+                dst
+            },
+            BranchChildVal::Deref(inner) => {
+                let ptr_type = self.expr_type(inner, &Type {val: TypeVal::U64, ptr_depth: 0});
+                if ptr_type.ptr_depth == 0 {
+                    report_err(Component::CODEGEN, Token {val: TokenVal::Endln, row: expr.row, col: expr.col}, "Cannot dereference a value which isn't a pointer.");
+                }
+                let addr = self.lower_expr(inner, &ptr_type);
+                let mut typ = ptr_type;
+                typ.ptr_depth -= 1;
+                let dst = self.new_vreg(typ);
+                self.emit(Inst::LoadPtr { dst, addr });
+                dst
+            },
+            BranchChildVal::Cast(cast) => {
+                let from = self.expr_type(&cast.val, &cast.typ);
+                let v = self.lower_expr(&cast.val, &from);
+                let v = self.coerce(v, &from);
+                self.coerce(v, &cast.typ)
+            },
+            BranchChildVal::Branch(branch) => {
+                let vars = self.var_types();
+                let left_type = typecheck_expr(*branch.left_val.clone(), &vars, self.program);
+                let right_type = typecheck_expr(*branch.right_val.clone(), &vars, self.program);
+                let is_logical = is_comparison(branch.op) || branch.op == Operation::And || branch.op == Operation::Or;
+                let operand_type = if left_type.val != TypeVal::Any {
+                    left_type
+                } else if right_type.val != TypeVal::Any {
+                    right_type
+                } else if is_logical {
+                    Type {val: TypeVal::U64, ptr_depth: 0}
+                } else {
+                    resolve_any(left_type, want)
+                };
+                let lhs = self.lower_expr(&branch.left_val, &operand_type);
+                let lhs = self.coerce(lhs, &operand_type);
+                let rhs = self.lower_expr(&branch.right_val, &operand_type);
+                let rhs = self.coerce(rhs, &operand_type);
+                let dst_type = if is_comparison(branch.op) { Type {val: TypeVal::Boolean, ptr_depth: 0} } else { operand_type };
+                let dst = self.new_vreg(dst_type);
+                self.emit(Inst::Binary { dst, op: branch.op, lhs, rhs });
+                dst
+            },
+            BranchChildVal::Unary(unary) => {
+                let typ = self.expr_type(&unary.val, want);
+                let src = self.lower_expr(&unary.val, &typ);
+                let src = self.coerce(src, &typ);
+                let dst = self.new_vreg(typ);
+                self.emit(Inst::Unary { dst, op: unary.op, src });
+                dst
+            },
+            BranchChildVal::Fn(call) => self.lower_call(call, true).unwrap(),
+            BranchChildVal::Float(_) => {
+                report_err(Component::CODEGEN, Token {val: TokenVal::Endln, row: expr.row, col: expr.col}, "Floating point values aren't supported yet.");
+                unreachable!();
+            },
+        }
+    }
+
+    fn lower_call(&mut self, call: &FuncCallStatement, wants_result: bool) -> Option<VReg> {
+        let signature = match self.program.get(&call.fn_ident) {
+            Some(f) => f.signature.clone(),
+            None => {
+                report_err(Component::CODEGEN, call.ident_tok.clone(), format!("Undefined function: {}", call.fn_ident).as_str());
+                unreachable!();
+            }
+        };
+        let mut args = Vec::new();
+        for (i, arg) in call.args.iter().enumerate() {
+            let is_vararg = match signature.varargs_idx {
+                Some(v) => i >= v as usize,
+                None => false,
+            };
+            let want = if !is_vararg && i < signature.args.len() {
+                signature.args[i].arg_type.clone()
+            } else {
+                self.expr_type(arg, &Type {val: TypeVal::U64, ptr_depth: 0})
+            };
+            let v = self.lower_expr(arg, &want);
+            args.push(self.coerce(v, &want));
+        }
+        let dst = if wants_result { Some(self.new_vreg(signature.ret_type.clone())) } else { None };
+        self.emit(Inst::Call { dst, func: call.fn_ident.clone(), args, varargs_idx: signature.varargs_idx });
+        dst
+    }
+
+    fn lower_asm_input(&mut self, operand: &AsmOperand) -> AsmInput {
+        let val = if operand.constraint == AsmConstraint::Imm {
+            let typ = self.expr_type(&operand.expr, &Type {val: TypeVal::U64, ptr_depth: 0});
+            let global = |ident: &str| if self.slot_of(ident).is_some() { None } else {
+                self.globals.iter().find(|g| g.identifier == ident).map(|g| g.val)
+            };
+            match immediate_value(&operand.expr, &typ, &global) {
+                Some(v) => AsmValue::Imm(v as i64),
+                None => {
+                    report_err(Component::CODEGEN, operand.tok.clone(), "Inline assembly operands with the \"i\" constraint must be constant.");
+                    unreachable!();
+                }
+            }
+        } else {
+            let typ = self.expr_type(&operand.expr, &Type {val: TypeVal::U64, ptr_depth: 0});
+            let v = self.lower_expr(&operand.expr, &typ);
+            AsmValue::VReg(self.coerce(v, &typ))
+        };
+        AsmInput { name: operand.name.clone(), constraint: operand.constraint.clone(), val }
+    }
+
+    fn lower_asm_output(&self, operand: &AsmOperand) -> AsmOutput {
+        match self.slot_of(operand.identifier()) {
+            Some(slot) => AsmOutput { name: operand.name.clone(), constraint: operand.constraint.clone(), slot, read_write: operand.read_write },
+            None => {
+                report_err(Component::CODEGEN, operand.tok.clone(), format!("Inline assembly output {} isn't a variable.", operand.identifier()).as_str());
+                unreachable!();
+            }
+        }
+    }
+
+    fn lower_scope(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            match statement {
+                Statement::Define(s) => {
+                    let v = self.lower_expr(&s.expr, &s.def_type);
+                    let v = self.coerce(v, &s.def_type);
+                    let slot = self.define_slot(&s.identifier, s.def_type.clone());
+                    self.emit(Inst::Store { slot, src: v });
+                },
+                Statement::Assign(s) => {
+                    let slot = match self.slot_of(&s.identifier) {
+                        Some(v) => v,
+                        None => {
+                            report_err(Component::CODEGEN, s.ident_tok.clone(), format!("Undefined variable: {}", s.identifier).as_str());
+                            unreachable!();
+                        }
+                    };
+                    let slot_type = self.slots[slot].typ.clone();
+                    if s.deref {
+                        let mut target = slot_type.clone();
+                        target.ptr_depth -= 1;
+                        let addr = self.new_vreg(slot_type);
+                        self.emit(Inst::Load { dst: addr, slot });
+                        let v = self.lower_expr(&s.expr, &target);
+                        let v = self.coerce(v, &target);
+                        self.emit(Inst::StorePtr { addr, src: v });
+                    } else {
+                        let v = self.lower_expr(&s.expr, &slot_type);
+                        let v = self.coerce(v, &slot_type);
+                        self.emit(Inst::Store { slot, src: v });
+                    }
+                },
+                Statement::FuncCall(s) => {
+                    self.lower_call(s, false);
+                },
+                Statement::InlineAsm(s) => {
+                    let inputs = s.inputs.iter().map(|i| self.lower_asm_input(i)).collect();
+                    let outputs = s.outputs.iter().map(|o| self.lower_asm_output(o)).collect();
+                    self.emit(Inst::InlineAsm { asm: s.asm.clone(), inputs, outputs, clobbers: s.clobbers.clone(), tok: s.asm_tok.clone() });
+                },
+                Statement::Return(expr) => {
+                    let ret_type = self.ret_type.clone();
+                    let v = self.lower_expr(expr, &ret_type);
+                    let v = self.coerce(v, &ret_type);
+                    self.terminate(Terminator::Return(v));
+                    // Anything after a return is unreachable, but it still needs a block to live in.
+                    self.current = self.new_block();
+                },
+                Statement::If(s) => {
+                    let cond = self.lower_expr(&s.condition, &Type {val: TypeVal::Boolean, ptr_depth: 0});
+                    let then_blk = self.new_block();
+                    let join_blk = self.new_block();
+                    self.terminate(Terminator::Branch { cond, then_blk, else_blk: join_blk });
+                    self.current = then_blk;
+                    self.lower_scope(&s.body);
+                    self.terminate(Terminator::Jump(join_blk));
+                    self.current = join_blk;
+                },
+                Statement::While(s) => {
+                    let cond_blk = self.new_block();
+                    let body_blk = self.new_block();
+                    let exit_blk = self.new_block();
+                    self.terminate(Terminator::Jump(cond_blk));
+                    self.current = cond_blk;
+                    let cond = self.lower_expr(&s.condition, &Type {val: TypeVal::Boolean, ptr_depth: 0});
+                    self.terminate(Terminator::Branch { cond, then_blk: body_blk, else_blk: exit_blk });
+                    self.current = body_blk;
+                    self.lower_scope(&s.body);
+                    self.terminate(Terminator::Jump(cond_blk));
+                    self.current = exit_blk;
+                },
+                Statement::Extern(s) => {
+                    if !self.externs.iter().any(|e| e.identifier == s.identifier) {
+                        self.externs.push(Extern { identifier: s.identifier.clone(), signature: s.val.signature.clone() });
+                    }
+                    self.program.insert(s.identifier.clone(), s.val.clone());
+                },
+                Statement::NullStatement => {},
+            }
+        }
+    }
+}
+
+fn resolve_any(typ: Type, want: &Type) -> Type {
+    if typ.val != TypeVal::Any { return typ }
+    if want.val != TypeVal::Any { return want.clone() }
+    Type {val: TypeVal::U64, ptr_depth: typ.ptr_depth}
+}
+
+fn build_function(name: &str, func: &FuncTableVal, program: &mut HashMap<String, FuncTableVal>, globals: &[GlobalVar], strings: &mut Vec<String>, externs: &mut Vec<Extern>) -> Function {
+    let mut builder = FuncBuilder {
+        program,
+        globals,
+        strings,
+        externs,
+        slots: Vec::new(),
+        vregs: Vec::new(),
+        blocks: Vec::new(),
+        current: BlockId(0),
+        ret_type: func.signature.ret_type.clone(),
+    };
+    builder.current = builder.new_block();
+    let params = func.signature.args.iter()
+        .map(|arg| builder.define_slot(&arg.val, arg.arg_type.clone()))
+        .collect();
+    builder.lower_scope(func.statements.as_ref().unwrap());
+    // Falling off the end of a function returns 0.
+    let ret_type = builder.ret_type.clone();
+    let zero = builder.new_vreg(ret_type);
+    builder.emit(Inst::Imm { dst: zero, val: 0 });
+    builder.terminate(Terminator::Return(zero));
+    let blocks = builder.blocks.into_iter()
+        .enumerate()
+        .map(|(i, (insts, term))| Block { id: BlockId(i), insts, term: term.unwrap() })
+        .collect();
+    Function {
+        name: String::from(name),
+        params,
+        slots: builder.slots,
+        vregs: builder.vregs,
+        blocks,
+        ret_type: builder.ret_type,
+        section: func.section(),
+        naked: func.has_attribute("naked"),
+        interrupt: func.has_attribute("interrupt"),
+        is_static: func.is_static,
+    }
+}
+
+/* Lowers the whole (already type checked) function table into an IR module. */
+pub fn build(program: &mut HashMap<String, FuncTableVal>, globals: &[GlobalVar], extern_idents: &[String]) -> Module {
+    let mut externs = Vec::new();
+    for ident in extern_idents {
+        externs.push(Extern { identifier: ident.clone(), signature: program[ident].signature.clone() });
+    }
+    let mut strings = Vec::new();
+    let mut functions = Vec::new();
+    let mut names: Vec<String> = program.iter()
+        .filter(|(_, f)| f.statements.is_some())
+        .map(|(k, _)| k.clone())
+        .collect();
+    names.sort();
+    for name in names {
+        let func = program[&name].clone();
+        functions.push(build_function(&name, &func, program, globals, &mut strings, &mut externs));
+    }
+    Module { functions, externs, globals: globals.to_vec(), strings }
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+fn fmt_constraint(constraint: &AsmConstraint) -> String {
+    match constraint {
+        AsmConstraint::Reg => String::from("r"),
+        AsmConstraint::Mem => String::from("m"),
+        AsmConstraint::Imm => String::from("i"),
+        AsmConstraint::Fixed(reg) => reg.clone(),
+    }
+}
+
+fn fmt_asm_name(name: &Option<String>) -> String {
+    name.as_ref().map(|n| format!("[{}] ", n)).unwrap_or_default()
+}
+
+fn fmt_asm_inputs(inputs: &[AsmInput]) -> String {
+    inputs.iter()
+        .map(|i| match &i.val {
+            AsmValue::VReg(v) => format!("{}\"{}\" {}", fmt_asm_name(&i.name), fmt_constraint(&i.constraint), v),
+            AsmValue::Imm(v) => format!("{}\"{}\" {}", fmt_asm_name(&i.name), fmt_constraint(&i.constraint), v),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn fmt_asm_outputs(outputs: &[AsmOutput]) -> String {
+    outputs.iter()
+        .map(|o| format!("{}\"{}{}\" ${}", fmt_asm_name(&o.name), if o.read_write { "+" } else { "=" }, fmt_constraint(&o.constraint), o.slot))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn fmt_signature(sig: &FuncSig) -> String {
+    let mut args: Vec<String> = sig.args.iter().map(|a| format!("{}", a.arg_type)).collect();
+    if let Some(idx) = sig.varargs_idx {
+        args.insert(idx as usize, String::from("..."));
+    }
+    format!("({}) -> {}", args.join(", "), sig.ret_type)
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ext in &self.externs {
+            writeln!(f, "extern {}{}", ext.identifier, fmt_signature(&ext.signature))?;
+        }
+        for global in &self.globals {
+            if let Some(section) = global.section() {
+                write!(f, "#[section(\"{}\")] ", section)?;
+            }
+            writeln!(f, "const {}: {} = {}", global.identifier, global.typ, global.val)?;
+        }
+        for (i, s) in self.strings.iter().enumerate() {
+            writeln!(f, "str{} = \"{}\"", i, s.escape_debug())?;
+        }
+        for func in &self.functions {
+            writeln!(f)?;
+            write!(f, "{}", func)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<String> = self.params.iter().map(|p| format!("${}", p)).collect();
+        if let Some(section) = &self.section {
+            writeln!(f, "#[section(\"{}\")]", section)?;
+        }
+        if self.naked {
+            writeln!(f, "#[naked]")?;
+        }
+        if self.interrupt {
+            writeln!(f, "#[interrupt]")?;
+        }
+        let static_kw = if self.is_static { "static " } else { "" };
+        writeln!(f, "{}fn {}({}) -> {} {{", static_kw, self.name, params.join(", "), self.ret_type)?;
+        for (i, slot) in self.slots.iter().enumerate() {
+            writeln!(f, "    slot ${}: {} ({})", i, slot.typ, slot.ident)?;
+        }
+        for block in &self.blocks {
+            writeln!(f, "  {}:", block.id)?;
+            for inst in &block.insts {
+                write!(f, "    ")?;
+                match inst {
+                    Inst::Imm { dst, val } => writeln!(f, "{}: {} = imm {}", dst, self.vreg_type(*dst), val)?,
+                    Inst::Load { dst, slot } => writeln!(f, "{}: {} = load ${}", dst, self.vreg_type(*dst), slot)?,
+                    Inst::Store { slot, src } => writeln!(f, "store ${}, {}", slot, src)?,
+                    Inst::AddrOf { dst, slot } => writeln!(f, "{}: {} = addr ${}", dst, self.vreg_type(*dst), slot)?,
+                    Inst::StrAddr { dst, idx } => writeln!(f, "{}: {} = addr str{}", dst, self.vreg_type(*dst), idx)?,
+                    Inst::LoadPtr { dst, addr } => writeln!(f, "{}: {} = load [{}]", dst, self.vreg_type(*dst), addr)?,
+                    Inst::StorePtr { addr, src } => writeln!(f, "store [{}], {}", addr, src)?,
+                    Inst::Binary { dst, op, lhs, rhs } => writeln!(f, "{}: {} = {:?} {}, {}", dst, self.vreg_type(*dst), op, lhs, rhs)?,
+                    Inst::Unary { dst, op, src } => writeln!(f, "{}: {} = {:?} {}", dst, self.vreg_type(*dst), op, src)?,
+                    Inst::Cast { dst, src } => writeln!(f, "{}: {} = cast {}: {}", dst, self.vreg_type(*dst), src, self.vreg_type(*src))?,
+                    Inst::Call { dst, func, args, .. } => {
+                        let args: Vec<String> = args.iter().map(|a| format!("{}", a)).collect();
+                        match dst {
+                            Some(d) => writeln!(f, "{}: {} = call {}({})", d, self.vreg_type(*d), func, args.join(", "))?,
+                            None => writeln!(f, "call {}({})", func, args.join(", "))?,
+                        }
+                    },
+                    Inst::InlineAsm { asm, inputs, outputs, clobbers, .. } => {
+                        writeln!(f, "asm \"{}\" : {} : {} : {}", asm.escape_debug(), fmt_asm_inputs(inputs), fmt_asm_outputs(outputs), clobbers.join(", "))?;
+                    },
+                }
+            }
+            match &block.term {
+                Terminator::Jump(target) => writeln!(f, "    jmp {}", target)?,
+                Terminator::Branch { cond, then_blk, else_blk } => writeln!(f, "    br {}, {}, {}", cond, then_blk, else_blk)?,
+                Terminator::Return(v) => writeln!(f, "    ret {}", v)?,
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
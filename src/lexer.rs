@@ -3,6 +3,7 @@
 #![allow(dead_code, unused_assignments)]
 
 use crate::error::*;
+use std::fmt;
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
 pub enum Operation {
@@ -46,6 +47,30 @@ pub struct Type {
     pub ptr_depth: u64,
 }
 
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.val {
+            TypeVal::Any => "any",
+            TypeVal::Char => "char",
+            TypeVal::U8 => "u8",
+            TypeVal::U16 => "u16",
+            TypeVal::U32 => "u32",
+            TypeVal::U64 => "u64",
+            TypeVal::I8 => "i8",
+            TypeVal::I16 => "i16",
+            TypeVal::I32 => "i32",
+            TypeVal::I64 => "i64",
+            TypeVal::F64 => "f64",
+            TypeVal::Boolean => "bool",
+        };
+        write!(f, "{}", name)?;
+        for _ in 0..self.ptr_depth {
+            write!(f, "*")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Literal {
     pub val: LitVal,
@@ -62,10 +87,11 @@ pub enum TokenVal {
     Type(Type),
 
     // Some other keywords
-    Let, Const, If, Else, ElseIf, Func, While, Return, Extern,
+    Let, Const, If, Else, ElseIf, Func, While, Return, Extern, Import, ImportC, Pub, Static,
 
     // Other
     Comma, Colon, Lbrace, Rbrace, Endln, Assign, TripleDot,
+    Hash, Lbracket, Rbracket,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -76,21 +102,15 @@ pub struct Token {
 }
 
 pub fn is_val(tok: TokenVal) -> bool {
-    match tok {
-        TokenVal::Literal(_) => true,
-        _ => false,
-    }
+    matches!(tok, TokenVal::Literal(_))
 }
 
 fn is_num_digit(ch: char) -> bool {
-    (ch >= '0' && ch <= '9') || ch == '.'
+    ch.is_ascii_digit() || ch == '.'
 }
 
 fn is_ident_char(ch: char) -> bool {
-    (ch >= '0' && ch <= '9')
-     || (ch >= 'a' && ch <= 'z')
-     || (ch >= 'A' && ch <= 'Z')
-     || ch == '_' 
+    ch.is_ascii_alphanumeric() || ch == '_' 
 }
 
 fn parse_escape_characters(s: &mut String) {
@@ -103,7 +123,7 @@ fn parse_escape_characters(s: &mut String) {
 }
 
 fn escape_char(c: char, row: u64, col: u64) -> char {
-    return match c {
+    match c {
         '\\' => '\\',
         '\"' => '\"',
         'n' => '\n',
@@ -154,10 +174,13 @@ pub fn lex(txt: &str) -> Vec<Token> {
             ';' => tokens.push(Token::new(TokenVal::Endln, row, col)),
             '{' => tokens.push(Token::new(TokenVal::Lbrace, row, col)),
             '}' => tokens.push(Token::new(TokenVal::Rbrace, row, col)),
+            '#' => tokens.push(Token::new(TokenVal::Hash, row, col)),
+            '[' => tokens.push(Token::new(TokenVal::Lbracket, row, col)),
+            ']' => tokens.push(Token::new(TokenVal::Rbracket, row, col)),
             // some less easy ones
             '!' => {
                 match next {
-                    '=' => {tokens.push(Token::new(TokenVal::Arrow, row, col)); iter.next(); c += 1; col += 1; },
+                    '=' => {tokens.push(Token::new(TokenVal::Ops(Operation::NotEqu), row, col)); iter.next(); c += 1; col += 1; },
                     _ => tokens.push(Token::new(TokenVal::Ops(Operation::Not), row, col)),
                 }
             },
@@ -180,7 +203,10 @@ pub fn lex(txt: &str) -> Vec<Token> {
                 }
             },
             '*' => {
+                // After a type (or a pointer type), `**` is two levels of pointer rather than a power.
+                let after_type = tokens.iter().rev().find(|t| t.val != TokenVal::Ops(Operation::Star)).is_some_and(|t| matches!(t.val, TokenVal::Type(_)));
                 match next {
+                    '*' if after_type => tokens.push(Token::new(TokenVal::Ops(Operation::Star), row, col)),
                     '*' => {tokens.push(Token::new(TokenVal::Ops(Operation::Pow), row, col)); iter.next(); c += 1; col += 1; }
                     _ => tokens.push(Token::new(TokenVal::Ops(Operation::Star), row, col)),
                 }
@@ -324,6 +350,15 @@ pub fn lex(txt: &str) -> Vec<Token> {
                 let mut i = 0;
                 let mut whole = &txt[c..];
                 while is_ident_char(this_char) && whole.len() > 1 {
+                    // Qualified names like `net::socket::connect` are a single identifier.
+                    if whole[1..].starts_with("::") && whole[3..].starts_with(|ch: char| ch.is_ascii_alphabetic() || ch == '_') {
+                        iter.next();
+                        iter.next();
+                        this_char = iter.next().unwrap();
+                        whole = &whole[3..];
+                        i += 3;
+                        continue;
+                    }
                     if !is_ident_char(*iter.peek().unwrap()) { break }
                     this_char = iter.next().unwrap();
                     whole = &whole[1..];
@@ -356,6 +391,10 @@ pub fn lex(txt: &str) -> Vec<Token> {
                     "while" => tokens.push(Token::new(TokenVal::While, row, col)),
                     "return" => tokens.push(Token::new(TokenVal::Return, row, col)),
                     "extern" => tokens.push(Token::new(TokenVal::Extern, row, col)),
+                    "import" => tokens.push(Token::new(TokenVal::Import, row, col)),
+                    "import_c" => tokens.push(Token::new(TokenVal::ImportC, row, col)),
+                    "pub" => tokens.push(Token::new(TokenVal::Pub, row, col)),
+                    "static" => tokens.push(Token::new(TokenVal::Static, row, col)),
                     _ => tokens.push(Token::new(TokenVal::Literal(Literal { val: LitVal::Ident(String::from(s)), typ: Type {val: TypeVal::Any, ptr_depth: 0}}), row, col)),
                 }
             },
@@ -0,0 +1,586 @@
+/* LLVM backend for `--emit=llvm`, turning the IR into textual LLVM IR. The two line up closely:
+ * blocks become basic blocks, virtual registers become SSA values (each is only assigned once
+ * already), and slots become `alloca`s, which LLVM's mem2reg will turn back into registers.
+ * Pointers are opaque (`ptr`), so this needs LLVM 15 or later, or `-opaque-pointers` before that.
+ */
+
+
+use std::fmt::Write;
+use crate::lexer::*;
+use crate::ir::*;
+use crate::parser::FuncSig;
+use crate::backend::{type_to_size, check_type_signed, asm_operand_names, substitute_asm_operands};
+use crate::statements::AsmConstraint;
+use crate::dialect;
+use crate::error::*;
+use crate::intrinsics::{self, Intrinsic};
+use crate::Arch;
+
+const PRELUDE: &str = "declare void @llvm.trap() cold noreturn nounwind
+
+define internal i64 @ctfaw.pow(i64 %base, i64 %exp) {
+entry:
+  br label %loop
+loop:
+  %result = phi i64 [ 1, %entry ], [ %next, %body ]
+  %left = phi i64 [ %exp, %entry ], [ %dec, %body ]
+  %done = icmp eq i64 %left, 0
+  br i1 %done, label %exit, label %body
+body:
+  %next = mul i64 %result, %base
+  %dec = sub i64 %left, 1
+  br label %loop
+exit:
+  ret i64 %result
+}
+";
+
+pub fn ll_type(typ: &Type) -> String {
+    if typ.ptr_depth > 0 { return String::from("ptr") }
+    format!("i{}", type_to_size(typ.clone()) * 8)
+}
+
+// Integer constants are written signed, which LLVM accepts for any width.
+fn ll_int(val: u64, typ: &Type) -> i64 {
+    let bits = type_to_size(typ.clone()) * 8;
+    if bits >= 64 { return val as i64 }
+    let shift = 64 - bits;
+    ((val << shift) as i64) >> shift
+}
+
+fn ll_string(bytes: &[u8]) -> String {
+    let mut out = String::from("c\"");
+    for b in bytes {
+        match b {
+            b'"' | b'\\' => out.push_str(format!("\\{:02X}", b).as_str()),
+            0x20..=0x7E => out.push(*b as char),
+            _ => out.push_str(format!("\\{:02X}", b).as_str()),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn fn_type(sig: &FuncSig) -> String {
+    let mut args: Vec<String> = sig.args.iter().map(|a| ll_type(&a.arg_type)).collect();
+    if sig.varargs_idx.is_some() {
+        args.push(String::from("..."));
+    }
+    format!("{} ({})", ll_type(&sig.ret_type), args.join(", "))
+}
+
+struct FuncWriter<'a> {
+    func: &'a Function,
+    module: &'a Module,
+    target: Arch,
+    out: String,
+    tmp: usize,
+}
+
+impl FuncWriter<'_> {
+    fn line(&mut self, s: &str) {
+        let _ = writeln!(self.out, "  {}", s);
+    }
+
+    // A new unnamed temporary, for the extra steps some instructions need.
+    fn tmp(&mut self) -> String {
+        self.tmp += 1;
+        format!("%t{}", self.tmp)
+    }
+
+    fn vreg(&self, v: VReg) -> String {
+        format!("%v{}", v.0)
+    }
+
+    fn vtype(&self, v: VReg) -> Type {
+        self.func.vreg_type(v)
+    }
+
+    /* Converts a value between any two types, the same way a cast does in the IR: integers are
+     * truncated, or zero/sign extended depending on the type they came from. */
+    fn convert(&mut self, val: String, from: &Type, to: &Type) -> String {
+        let (from_ll, to_ll) = (ll_type(from), ll_type(to));
+        if from_ll == "ptr" && to_ll == "ptr" { return val }
+        if from_ll == "ptr" {
+            let t = self.tmp();
+            self.line(format!("{} = ptrtoint ptr {} to i64", t, val).as_str());
+            return self.convert(t, &Type {val: TypeVal::U64, ptr_depth: 0}, to)
+        }
+        if to_ll == "ptr" {
+            let wide = self.convert(val, from, &Type {val: TypeVal::U64, ptr_depth: 0});
+            let t = self.tmp();
+            self.line(format!("{} = inttoptr i64 {} to ptr", t, wide).as_str());
+            return t
+        }
+        let (from_bits, to_bits) = (type_to_size(from.clone()), type_to_size(to.clone()));
+        if from_bits == to_bits { return val }
+        let op = if from_bits > to_bits { "trunc" } else if check_type_signed(from.clone()) { "sext" } else { "zext" };
+        let t = self.tmp();
+        self.line(format!("{} = {} {} {} to {}", t, op, from_ll, val, to_ll).as_str());
+        t
+    }
+
+    // Gives a value a name of its own, for when a conversion didn't need any instructions.
+    fn define(&mut self, dst: VReg, val: String) {
+        let typ = ll_type(&self.vtype(dst));
+        if typ == "ptr" {
+            self.line(format!("{} = getelementptr i8, ptr {}, i64 0", self.vreg(dst), val).as_str());
+        } else {
+            self.line(format!("{} = add {} {}, 0", self.vreg(dst), typ, val).as_str());
+        }
+    }
+
+    fn is_true(&mut self, v: VReg) -> String {
+        let typ = ll_type(&self.vtype(v));
+        let zero = if typ == "ptr" { "null" } else { "0" };
+        let t = self.tmp();
+        self.line(format!("{} = icmp ne {} {}, {}", t, typ, self.vreg(v), zero).as_str());
+        t
+    }
+
+    fn binary(&mut self, dst: VReg, op: Operation, lhs: VReg, rhs: VReg) {
+        let typ = self.vtype(lhs);
+        let is_signed = check_type_signed(typ.clone());
+        // Arithmetic on pointers is done on their address, in bytes.
+        let int_type = if typ.ptr_depth > 0 { Type {val: TypeVal::U64, ptr_depth: 0} } else { typ.clone() };
+        let ll = ll_type(&int_type);
+        let l = self.convert(self.vreg(lhs), &typ, &int_type);
+        let r = self.convert(self.vreg(rhs), &typ, &int_type);
+        let dst_type = self.vtype(dst);
+        if is_comparison(op) {
+            let cond = match op {
+                Operation::Less => if is_signed { "slt" } else { "ult" },
+                Operation::LessEqu => if is_signed { "sle" } else { "ule" },
+                Operation::Greater => if is_signed { "sgt" } else { "ugt" },
+                Operation::GreaterEqu => if is_signed { "sge" } else { "uge" },
+                Operation::Equ => "eq",
+                _ => "ne",
+            };
+            let t = self.tmp();
+            self.line(format!("{} = icmp {} {} {}, {}", t, cond, ll, l, r).as_str());
+            self.line(format!("{} = zext i1 {} to {}", self.vreg(dst), t, ll_type(&dst_type)).as_str());
+            return
+        }
+        let result = match op {
+            Operation::And | Operation::Or => {
+                let (a, b) = (self.tmp(), self.tmp());
+                self.line(format!("{} = icmp ne {} {}, 0", a, ll, l).as_str());
+                self.line(format!("{} = icmp ne {} {}, 0", b, ll, r).as_str());
+                let (c, t) = (self.tmp(), self.tmp());
+                self.line(format!("{} = {} i1 {}, {}", c, if op == Operation::And { "and" } else { "or" }, a, b).as_str());
+                self.line(format!("{} = zext i1 {} to {}", t, c, ll).as_str());
+                t
+            },
+            Operation::Pow => {
+                let u64_type = Type {val: TypeVal::U64, ptr_depth: 0};
+                let base = self.convert(l, &int_type, &u64_type);
+                let exp = self.convert(r, &int_type, &u64_type);
+                let t = self.tmp();
+                self.line(format!("{} = call i64 @ctfaw.pow(i64 {}, i64 {})", t, base, exp).as_str());
+                self.convert(t, &u64_type, &int_type)
+            },
+            _ => {
+                let inst = match op {
+                    Operation::Add => "add",
+                    Operation::Sub => "sub",
+                    Operation::Star => "mul",
+                    Operation::Div => if is_signed { "sdiv" } else { "udiv" },
+                    Operation::Mod => if is_signed { "srem" } else { "urem" },
+                    Operation::LeftShift => "shl",
+                    Operation::RightShift => if is_signed { "ashr" } else { "lshr" },
+                    Operation::Ampersand => "and",
+                    Operation::BitOr => "or",
+                    Operation::BitXor => "xor",
+                    _ => panic!("Unsupported operation."),
+                };
+                let t = self.tmp();
+                self.line(format!("{} = {} {} {}, {}", t, inst, ll, l, r).as_str());
+                t
+            },
+        };
+        let converted = self.convert(result, &int_type, &dst_type);
+        self.define(dst, converted);
+    }
+
+    fn unary(&mut self, dst: VReg, op: Operation, src: VReg) {
+        let typ = ll_type(&self.vtype(dst));
+        let v = self.vreg(src);
+        match op {
+            Operation::BitNot => self.line(format!("{} = xor {} {}, -1", self.vreg(dst), typ, v).as_str()),
+            Operation::Sub => self.line(format!("{} = sub {} 0, {}", self.vreg(dst), typ, v).as_str()),
+            Operation::Not => {
+                let t = self.tmp();
+                self.line(format!("{} = icmp eq {} {}, 0", t, typ, v).as_str());
+                self.line(format!("{} = zext i1 {} to {}", self.vreg(dst), t, typ).as_str());
+            },
+            _ => panic!("Unary operator not implemented yet."),
+        }
+    }
+
+    fn call(&mut self, dst: Option<VReg>, callee: &str, args: &[VReg], varargs_idx: Option<u64>) {
+        // Only externs are variadic, so only they need their signature.
+        let (ret_type, sig) = if let Some(f) = self.module.functions.iter().find(|f| f.name == callee) {
+            (f.ret_type.clone(), None)
+        } else if let Some(e) = self.module.externs.iter().find(|e| e.identifier == callee) {
+            (e.signature.ret_type.clone(), Some(e.signature.clone()))
+        } else {
+            report_err(Component::CODEGEN, Token {val: TokenVal::Endln, row: 0, col: 0}, format!("Undefined function: {}", callee).as_str());
+            unreachable!();
+        };
+        let mut ll_args = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            let typ = self.vtype(*arg);
+            let is_vararg = varargs_idx.is_some_and(|v| i >= v as usize);
+            // Like in C, variadic arguments smaller than an int get promoted to one.
+            if is_vararg && typ.ptr_depth == 0 && type_to_size(typ.clone()) < 4 {
+                let promoted = Type {val: if check_type_signed(typ.clone()) { TypeVal::I32 } else { TypeVal::U32 }, ptr_depth: 0};
+                let v = self.convert(self.vreg(*arg), &typ, &promoted);
+                ll_args.push(format!("i32 {}", v));
+            } else {
+                ll_args.push(format!("{} {}", ll_type(&typ), self.vreg(*arg)));
+            }
+        }
+        // Variadic functions have to be called with their full type.
+        let callee_type = match &sig {
+            Some(s) if s.varargs_idx.is_some() => fn_type(s),
+            _ => ll_type(&ret_type),
+        };
+        let call = format!("call {} @{}({})", callee_type, callee, ll_args.join(", "));
+        match dst {
+            Some(d) => self.line(format!("{} = {}", self.vreg(d), call).as_str()),
+            None => self.line(call.as_str()),
+        }
+    }
+
+    /* Inline assembly becomes a call to an `asm` value, with constraints binding each operand in the
+     * same order they're numbered in, so {N} is just $N. Outputs in registers come back as the
+     * result (a struct if there's more than one), while memory operands are passed as a pointer.
+     * Operands bound to a specific register are always 64 bits, the rest have their own type. */
+    fn inline_asm(&mut self, asm: &str, inputs: &[AsmInput], outputs: &[AsmOutput], clobbers: &[String], tok: &Token) {
+        let text = if self.target == Arch::X86_64 {
+            match dialect::block_to_gas(asm) {
+                Ok(v) => v,
+                Err(msg) => {
+                    report_err(Component::ASSEMBLER, tok.clone(), format!("Couldn't translate inline assembly to GAS syntax: {}", msg).as_str());
+                    unreachable!();
+                }
+            }
+        } else {
+            String::from(asm)
+        };
+        let u64_type = Type {val: TypeVal::U64, ptr_depth: 0};
+        let mut constraints = Vec::new();
+        let mut args = Vec::new();
+        let mut ret_types = Vec::new();
+        let mut texts = Vec::new();
+        // Read-write outputs in registers take their value in through an input tied to them.
+        let mut tied = Vec::new();
+        // LLVM doesn't give x86_64 memory operands a size, which Intel syntax can need.
+        let x86 = self.target == Arch::X86_64;
+        let mem_text = |n: usize, typ: &Type| if x86 { format!("{}${{{}}}", dialect::size_ptr(type_to_size(typ.clone()) as u8), n) } else { format!("${{{}}}", n) };
+        for (i, output) in outputs.iter().enumerate() {
+            let typ = self.func.slots[output.slot].typ.clone();
+            texts.push(if output.constraint == AsmConstraint::Mem { mem_text(i, &typ) } else { format!("${{{}}}", i) });
+            let op_type = match &output.constraint {
+                AsmConstraint::Mem => {
+                    constraints.push(String::from("=*m"));
+                    args.push(format!("ptr elementtype({}) %s{}", ll_type(&typ), output.slot));
+                    continue
+                },
+                AsmConstraint::Fixed(reg) => {
+                    constraints.push(format!("={{{}}}", reg));
+                    u64_type.clone()
+                },
+                _ => {
+                    constraints.push(String::from("=r"));
+                    typ.clone()
+                },
+            };
+            ret_types.push(ll_type(&op_type));
+            if output.read_write {
+                let t = self.tmp();
+                self.line(format!("{} = load {}, ptr %s{}", t, ll_type(&typ), output.slot).as_str());
+                let v = self.convert(t, &typ, &op_type);
+                tied.push((i.to_string(), format!("{} {}", ll_type(&op_type), v)));
+            }
+        }
+        for (i, input) in inputs.iter().enumerate() {
+            let n = outputs.len() + i;
+            match (&input.val, &input.constraint) {
+                (AsmValue::Imm(v), _) => {
+                    constraints.push(String::from("i"));
+                    args.push(format!("i64 {}", v));
+                    texts.push(format!("${{{}:c}}", n));
+                },
+                (AsmValue::VReg(v), AsmConstraint::Mem) => {
+                    let typ = ll_type(&self.vtype(*v));
+                    self.line(format!("store {} {}, ptr %m{}", typ, self.vreg(*v), v.0).as_str());
+                    constraints.push(String::from("*m"));
+                    args.push(format!("ptr elementtype({}) %m{}", typ, v.0));
+                    texts.push(mem_text(n, &self.vtype(*v)));
+                },
+                (AsmValue::VReg(v), AsmConstraint::Fixed(reg)) => {
+                    let typ = self.vtype(*v);
+                    let wide = self.convert(self.vreg(*v), &typ, &u64_type);
+                    constraints.push(format!("{{{}}}", reg));
+                    args.push(format!("i64 {}", wide));
+                    texts.push(format!("${{{}}}", n));
+                },
+                (AsmValue::VReg(v), _) => {
+                    constraints.push(String::from("r"));
+                    args.push(format!("{} {}", ll_type(&self.vtype(*v)), self.vreg(*v)));
+                    texts.push(format!("${{{}}}", n));
+                },
+            }
+        }
+        for (idx, arg) in tied {
+            constraints.push(idx);
+            args.push(arg);
+        }
+        constraints.extend(clobbers.iter().map(|c| format!("~{{{}}}", c)));
+        constraints.push(String::from("~{memory}"));
+        let ret = match ret_types.len() {
+            0 => String::from("void"),
+            1 => ret_types[0].clone(),
+            _ => format!("{{ {} }}", ret_types.join(", ")),
+        };
+        let dialect = if self.target == Arch::X86_64 { " inteldialect" } else { "" };
+        let text = substitute_asm_operands(&text.replace('$', "$$"), &asm_operand_names(inputs, outputs), &texts);
+        let escaped = ll_string(text.as_bytes());
+        let call = format!("call {} asm sideeffect{} {}, \"{}\"({})", ret, dialect, &escaped[1..], constraints.join(","), args.join(", "));
+        if ret_types.is_empty() {
+            self.line(call.as_str());
+            return
+        }
+        let result = self.tmp();
+        self.line(format!("{} = {}", result, call).as_str());
+        let mut idx = 0;
+        for output in outputs {
+            if output.constraint == AsmConstraint::Mem { continue }
+            let typ = self.func.slots[output.slot].typ.clone();
+            let op_type = if matches!(output.constraint, AsmConstraint::Fixed(_)) { u64_type.clone() } else { typ.clone() };
+            let v = if ret_types.len() == 1 {
+                result.clone()
+            } else {
+                let t = self.tmp();
+                self.line(format!("{} = extractvalue {} {}, {}", t, ret, result, idx).as_str());
+                t
+            };
+            idx += 1;
+            let v = self.convert(v, &op_type, &typ);
+            self.line(format!("store {} {}, ptr %s{}", ll_type(&typ), v, output.slot).as_str());
+        }
+    }
+
+    /* Intrinsics become inline assembly, with every register they use bound by a constraint so LLVM
+     * knows what they clobber. The exceptions are trapping and `__unreachable`, which LLVM has its
+     * own instructions for. */
+    fn intrinsic(&mut self, dst: Option<VReg>, intrinsic: Intrinsic, args: &[VReg]) {
+        let u64_type = Type {val: TypeVal::U64, ptr_depth: 0};
+        let x86 = self.target == Arch::X86_64;
+        let mut ll_args = Vec::new();
+        let (asm, ret, constraints) = match intrinsic {
+            Intrinsic::Unreachable => {
+                // Anything after it still needs a block to go in, which is never branched to.
+                self.line("unreachable");
+                let label = self.tmp();
+                let _ = writeln!(self.out, "{}:", &label[1..]);
+                return
+            },
+            Intrinsic::Trap => {
+                self.line("call void @llvm.trap()");
+                return
+            },
+            Intrinsic::Syscall => {
+                let (insn, regs, clobbers) = match self.target {
+                    Arch::X86_64 => ("syscall", ["rax", "rdi", "rsi", "rdx", "r10", "r8", "r9"], ",~{rcx},~{r11}"),
+                    Arch::Aarch64 => ("svc #0", ["x8", "x0", "x1", "x2", "x3", "x4", "x5"], ""),
+                    _ => ("ecall", ["a7", "a0", "a1", "a2", "a3", "a4", "a5"], ""),
+                };
+                let mut constraints = format!("={{{}}}", if x86 { "rax" } else { regs[1] });
+                for (reg, arg) in regs.iter().zip(args) {
+                    let v = self.convert(self.vreg(*arg), &self.vtype(*arg), &u64_type);
+                    ll_args.push(format!("i64 {}", v));
+                    constraints.push_str(format!(",{{{}}}", reg).as_str());
+                }
+                (insn, "i64", constraints + clobbers)
+            },
+            Intrinsic::Outb => {
+                ll_args.push(format!("i16 {}", self.vreg(args[0])));
+                ll_args.push(format!("i8 {}", self.vreg(args[1])));
+                ("out dx, al", "void", String::from("{dx},{ax}"))
+            },
+            Intrinsic::Inb => {
+                ll_args.push(format!("i16 {}", self.vreg(args[0])));
+                ("in al, dx", "i8", String::from("={ax},{dx}"))
+            },
+            Intrinsic::Rdtsc => match self.target {
+                Arch::X86_64 => ("rdtsc\nshl rdx, 32\nor rax, rdx", "i64", String::from("={rax},~{rdx}")),
+                Arch::Aarch64 => ("mrs $0, cntvct_el0", "i64", String::from("=r")),
+                _ => ("rdtime $0", "i64", String::from("=r")),
+            },
+            Intrinsic::Cpuid => {
+                ll_args.push(format!("i32 {}", self.vreg(args[0])));
+                ll_args.push(format!("i32 {}", self.vreg(args[1])));
+                ("cpuid", "{ i32, i32, i32, i32 }", String::from("={ax},={bx},={cx},={dx},{ax},{cx}"))
+            },
+            Intrinsic::Pause => match self.target {
+                Arch::X86_64 => ("pause", "void", String::new()),
+                Arch::Aarch64 => ("yield", "void", String::new()),
+                _ => (".4byte 0x0100000f", "void", String::new()),
+            },
+            Intrinsic::Hlt => (if x86 { "hlt" } else { "wfi" }, "void", String::new()),
+        };
+        let dialect = if x86 { " inteldialect" } else { "" };
+        let constraints = if constraints.is_empty() { String::from("~{memory}") } else { constraints + ",~{memory}" };
+        let escaped = ll_string(asm.as_bytes());
+        let call = format!("call {} asm sideeffect{} {}, \"{}\"({})", ret, dialect, &escaped[1..], constraints, ll_args.join(", "));
+        if ret == "void" {
+            self.line(call.as_str());
+            return
+        }
+        let result = self.tmp();
+        self.line(format!("{} = {}", result, call).as_str());
+        if intrinsic == Intrinsic::Cpuid {
+            for (i, arg) in args[2..].iter().enumerate() {
+                let t = self.tmp();
+                self.line(format!("{} = extractvalue {} {}, {}", t, ret, result, i).as_str());
+                self.line(format!("store i32 {}, ptr {}", t, self.vreg(*arg)).as_str());
+            }
+            return
+        }
+        if let Some(dst) = dst {
+            self.define(dst, result);
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Imm { dst, val } => {
+                let typ = self.vtype(*dst);
+                if typ.ptr_depth > 0 {
+                    self.line(format!("{} = inttoptr i64 {} to ptr", self.vreg(*dst), *val as i64).as_str());
+                } else {
+                    self.line(format!("{} = add {} 0, {}", self.vreg(*dst), ll_type(&typ), ll_int(*val, &typ)).as_str());
+                }
+            },
+            Inst::Load { dst, slot } => {
+                self.line(format!("{} = load {}, ptr %s{}", self.vreg(*dst), ll_type(&self.vtype(*dst)), slot).as_str());
+            },
+            Inst::Store { slot, src } => {
+                self.line(format!("store {} {}, ptr %s{}", ll_type(&self.vtype(*src)), self.vreg(*src), slot).as_str());
+            },
+            Inst::AddrOf { dst, slot } => {
+                self.line(format!("{} = getelementptr i8, ptr %s{}, i64 0", self.vreg(*dst), slot).as_str());
+            },
+            Inst::StrAddr { dst, idx } => {
+                let len = self.module.strings[*idx].len() + 1;
+                self.line(format!("{} = getelementptr [{} x i8], ptr @strlit{}, i64 0, i64 0", self.vreg(*dst), len, idx).as_str());
+            },
+            Inst::LoadPtr { dst, addr } => {
+                self.line(format!("{} = load {}, ptr {}", self.vreg(*dst), ll_type(&self.vtype(*dst)), self.vreg(*addr)).as_str());
+            },
+            Inst::StorePtr { addr, src } => {
+                self.line(format!("store {} {}, ptr {}", ll_type(&self.vtype(*src)), self.vreg(*src), self.vreg(*addr)).as_str());
+            },
+            Inst::Binary { dst, op, lhs, rhs } => self.binary(*dst, *op, *lhs, *rhs),
+            Inst::Unary { dst, op, src } => self.unary(*dst, *op, *src),
+            Inst::Cast { dst, src } => {
+                let v = self.convert(self.vreg(*src), &self.vtype(*src), &self.vtype(*dst));
+                self.define(*dst, v);
+            },
+            Inst::Call { dst, func, args, .. } if intrinsics::lookup(func).is_some() => self.intrinsic(*dst, intrinsics::lookup(func).unwrap(), args),
+            Inst::Call { dst, func, args, varargs_idx } => self.call(*dst, func, args, *varargs_idx),
+            Inst::InlineAsm { asm, inputs, outputs, clobbers, tok } => self.inline_asm(asm, inputs, outputs, clobbers, tok),
+        }
+    }
+
+    fn terminator(&mut self, term: &Terminator) {
+        match term {
+            Terminator::Jump(target) => self.line(format!("br label %{}", target).as_str()),
+            Terminator::Branch { cond, then_blk, else_blk } => {
+                let t = self.is_true(*cond);
+                self.line(format!("br i1 {}, label %{}, label %{}", t, then_blk, else_blk).as_str());
+            },
+            Terminator::Return(v) => self.line(format!("ret {} {}", ll_type(&self.func.ret_type), self.vreg(*v)).as_str()),
+        }
+    }
+}
+
+fn generate_function(module: &Module, func: &Function, target: &Arch) -> String {
+    let mut writer = FuncWriter { func, module, target: target.clone(), out: String::new(), tmp: 0 };
+    let params: Vec<String> = func.params.iter().map(|p| format!("{} %p{}", ll_type(&func.slots[*p].typ), p)).collect();
+    let section = func.section.as_ref().map(|s| format!(" section \"{}\"", s)).unwrap_or_default();
+    let naked = if func.naked { " naked noinline" } else { "" };
+    let linkage = if func.is_static { "internal " } else { "" };
+    let _ = writeln!(writer.out, "define {}{} @{}({}){}{} {{", linkage, ll_type(&func.ret_type), func.name, params.join(", "), naked, section);
+    if func.naked {
+        // Only the assembly is kept, and it has to return by itself.
+        let _ = writeln!(writer.out, "entry:");
+        for inst in func.blocks.iter().flat_map(|b| &b.insts) {
+            if let Inst::InlineAsm { asm, tok, .. } = inst {
+                writer.inline_asm(asm, &[], &[], &[], tok);
+            }
+        }
+        writer.line("unreachable");
+        writer.out.push_str("}\n");
+        return writer.out
+    }
+    // The first block can't be branched to, so slots are set up in a block of their own.
+    let _ = writeln!(writer.out, "entry:");
+    for (i, slot) in func.slots.iter().enumerate() {
+        writer.line(format!("%s{} = alloca {}", i, ll_type(&slot.typ)).as_str());
+    }
+    // Inputs used from memory by inline assembly need somewhere to be stored first.
+    for inst in func.blocks.iter().flat_map(|b| &b.insts) {
+        if let Inst::InlineAsm { inputs, .. } = inst {
+            for input in inputs {
+                if let (AsmValue::VReg(v), AsmConstraint::Mem) = (&input.val, &input.constraint) {
+                    writer.line(format!("%m{} = alloca {}", v.0, ll_type(&func.vreg_type(*v))).as_str());
+                }
+            }
+        }
+    }
+    for p in &func.params {
+        writer.line(format!("store {} %p{}, ptr %s{}", ll_type(&func.slots[*p].typ), p, p).as_str());
+    }
+    writer.line(format!("br label %{}", func.blocks[0].id).as_str());
+    for block in &func.blocks {
+        let _ = writeln!(writer.out, "{}:", block.id);
+        for inst in &block.insts {
+            writer.inst(inst);
+        }
+        writer.terminator(&block.term);
+    }
+    writer.out.push_str("}\n");
+    writer.out
+}
+
+/* Generates an LLVM IR module. Inline assembly is translated to GAS Intel syntax when targeting
+ * x86_64, otherwise it's copied as it is. */
+pub fn generate(module: &Module, target: Arch) -> String {
+    let mut out = String::new();
+    for (i, s) in module.strings.iter().enumerate() {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        let _ = writeln!(out, "@strlit{} = private unnamed_addr constant [{} x i8] {}", i, bytes.len(), ll_string(&bytes));
+    }
+    // Constants are folded into where they're used, unless they were placed in a section.
+    for global in &module.globals {
+        if let Some(section) = global.section() {
+            let _ = writeln!(out, "@{} = constant {} {}, section \"{}\", align {}", global.identifier, ll_type(&global.typ), global.val as i64, section, type_to_size(global.typ.clone()));
+        }
+    }
+    out.push('\n');
+    for ext in &module.externs {
+        if module.functions.iter().any(|f| f.name == ext.identifier) { continue }
+        let _ = writeln!(out, "declare {}", fn_type(&ext.signature).replacen(" (", format!(" @{}(", ext.identifier).as_str(), 1));
+    }
+    out.push('\n');
+    out.push_str(PRELUDE);
+    for func in &module.functions {
+        out.push('\n');
+        out.push_str(generate_function(module, func, &target).as_str());
+    }
+    out
+}
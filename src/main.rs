@@ -1,9 +1,10 @@
 #![allow(unused_variables)]
 
 use std::process::Command;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::fs;
 use std::env;
-use std::collections::HashMap;
 
 mod utils;
 mod typecheck;
@@ -14,6 +15,85 @@ mod ast;
 mod optimisation;
 mod backend;
 mod error;
+mod ir;
+mod peephole;
+mod inliner;
+mod elf;
+mod assembler;
+mod dialect;
+mod target;
+mod x86_64;
+mod aarch64;
+mod riscv64;
+mod cgen;
+mod llvm;
+mod wasm32;
+mod intrinsics;
+mod stdlib;
+mod modules;
+mod cimport;
+mod project;
+mod archive;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum Emit {
+    #[default]
+    Default,
+    Ir, // --emit=ir
+    C, // --emit=c
+    Llvm, // --emit=llvm
+    Header, // --emit=header
+    StaticLib, // --emit=staticlib
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum Assembler {
+    #[default]
+    Builtin, // --assembler=builtin
+    Nasm, // --assembler=nasm
+    Gas, // --assembler=as
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum Arch {
+    #[default]
+    X86_64, // --target=x86_64-linux
+    Aarch64, // --target=aarch64-linux
+    Riscv64, // --target=riscv64-linux
+    Wasm32, // --target=wasm32
+}
+
+// What the program runs on top of, which decides how it's linked.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum Os {
+    #[default]
+    Linux, // --target=<arch>-linux
+    None, // --target=x86_64-none, for kernels and other bare metal programs
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum Linker {
+    #[default]
+    Default, // The target's C compiler, or its ld when there's no libc
+    Ld, // --linker=ld
+    Gcc, // --linker=gcc
+    Clang, // --linker=clang
+    Mold, // --linker=mold
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum LinkMode {
+    #[default]
+    Dynamic, // --dynamic
+    Static, // --static
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum AsmDialect {
+    #[default]
+    Nasm, // --asm-dialect=nasm
+    Gas, // --asm-dialect=gas
+}
 
 #[derive(Debug, Default, Clone)]
 pub struct Flags {
@@ -23,6 +103,39 @@ pub struct Flags {
     pub include_comments: bool, // --asm-comments
     pub outfile_set: bool,
     pub out_file: String, // -o <filename>
+    pub emit: Emit,
+    pub opt_level: u8, // -O0, -O1, -O2
+    pub opt_level_set: bool,
+    pub stats: bool, // --stats
+    pub assembler: Assembler,
+    pub asm_dialect: AsmDialect,
+    pub target: Arch,
+    pub target_set: bool, // --target=<target>
+    pub freestanding: bool, // --freestanding
+    pub os: Os,
+    pub linker_script: Option<String>, // --linker-script <path>
+    pub multiboot2: bool, // --multiboot2
+    pub no_std: bool, // --no-std
+    pub include_dirs: Vec<String>, // -I <dir>
+    pub libs: Vec<String>, // -l <lib>, libs in ctfaw.toml
+    pub lib_dirs: Vec<String>, // -L <dir>
+    pub objects: Vec<String>, // .o, .a and .so input files, objects in ctfaw.toml
+    pub linker: Linker,
+    pub link_mode: LinkMode,
+    pub pie: bool, // --pie
+    pub pic: bool, // --pic
+    pub shared: bool, // --shared
+    pub linker_args: Vec<String>, // -Wl,<args>
+    pub check: bool, // ctfaw check
+    pub asm_file: String, // Where the assembly for the input file being compiled is written.
+}
+
+impl Flags {
+    // The assembly file is named after the dialect it's in, or is a .wat file for wasm32.
+    pub fn asm_ext(&self) -> &'static str {
+        if self.target == Arch::Wasm32 { return "wat" }
+        if self.asm_dialect == AsmDialect::Gas { "s" } else { "asm" }
+    }
 }
 
 fn check_flags_allowed(flags: &Flags) -> bool {
@@ -30,6 +143,64 @@ fn check_flags_allowed(flags: &Flags) -> bool {
         println!("Cannot run program automatically if only generating object file or assembly file.");
         return false
     }
+    if flags.emit != Emit::Default && (flags.run || flags.just_asm || flags.just_obj) {
+        println!("Cannot use --emit together with -r, -S or -c.");
+        return false
+    }
+    if flags.assembler == Assembler::Nasm && flags.asm_dialect != AsmDialect::Nasm {
+        println!("NASM can only assemble --asm-dialect=nasm.");
+        return false
+    }
+    if flags.assembler == Assembler::Gas && flags.asm_dialect != AsmDialect::Gas {
+        println!("GNU as can only assemble --asm-dialect=gas.");
+        return false
+    }
+    if flags.target == Arch::Wasm32 && (flags.run || flags.just_obj || flags.emit == Emit::StaticLib) {
+        println!("Can only generate a .wat file for --target=wasm32, it isn't assembled, linked or run.");
+        return false
+    }
+    if flags.target == Arch::Wasm32 && flags.freestanding {
+        println!("--freestanding can't be used with --target=wasm32, which has no entry point.");
+        return false
+    }
+    if flags.freestanding && flags.emit != Emit::Default && flags.emit != Emit::StaticLib {
+        println!("--freestanding can't be used with --emit, since it only changes the entry point and linking.");
+        return false
+    }
+    if flags.os == Os::None && flags.run {
+        println!("Cannot run a program built for --target=x86_64-none automatically.");
+        return false
+    }
+    if flags.os == Os::None && flags.freestanding {
+        println!("--freestanding is only for Linux targets, --target=x86_64-none programs already have no libc or _start.");
+        return false
+    }
+    if flags.multiboot2 && flags.os != Os::None {
+        println!("--multiboot2 can only be used with --target=x86_64-none.");
+        return false
+    }
+    let link_options = flags.linker_script.is_some() || !flags.libs.is_empty() || !flags.lib_dirs.is_empty() || !flags.objects.is_empty()
+        || flags.linker != Linker::Default || flags.link_mode == LinkMode::Static || flags.pie || flags.shared || !flags.linker_args.is_empty();
+    if link_options && (flags.target == Arch::Wasm32 || flags.emit != Emit::Default) {
+        println!("Linker options and object files can't be used when nothing is linked (with --target=wasm32 or --emit).");
+        return false
+    }
+    if flags.pic && flags.target == Arch::Wasm32 {
+        println!("--pic can't be used with --target=wasm32, which has no addresses to relocate.");
+        return false
+    }
+    if flags.shared && (flags.run || flags.pie || flags.link_mode == LinkMode::Static) {
+        println!("--shared builds a library, so it can't be used with -r, --pie or --static.");
+        return false
+    }
+    if flags.shared && (flags.freestanding || flags.os == Os::None) {
+        println!("--shared libraries are loaded by libc's dynamic linker, so they can't be used with --freestanding or --target=x86_64-none.");
+        return false
+    }
+    if flags.pie && (flags.freestanding || flags.os == Os::None) {
+        println!("--pie needs libc's startup code to relocate the program, so it can't be used with --freestanding or --target=x86_64-none.");
+        return false
+    }
     if flags.just_asm && flags.just_obj {
         println!("Cannot have both -S and -c flags, must select one.");
         return false
@@ -37,29 +208,136 @@ fn check_flags_allowed(flags: &Flags) -> bool {
     true
 }
 
-fn help(arg0: &str) {
+// Printed by --help. The README only has a line for each of these.
+const OPTIONS: &str = "Options:
+  -r                  Run the program after building it. Can't be used with -S, -c or --emit.
+  -c                  Output only an object file, to be linked with other objects manually.
+  -S                  Output only the generated assembly.
+  -o <file>           Name the output file. With -S, -c or --emit and more than one input file,
+                      each output is named after its input file instead, and -o can't be used.
+  -O0, -O1, -O2       Optimisation level. -O0 (the default) folds constant expressions, -O1 also
+                      propagates constants, simplifies blocks with constant conditions, removes
+                      dead code and runs the peephole pass, and -O2 adds copy propagation and
+                      inlining of small functions.
+  --stats             Print how many times each peephole rule was applied.
+  --asm-comments      Add comments to the generated assembly.
+  --asm-dialect=<d>   nasm (the default) or gas, for GNU as compatible Intel syntax (to out.s).
+  --assembler=<a>     builtin (the default), nasm, or as to use binutils.
+  --target=<target>   x86_64-linux (the default), x86_64-none for bare metal, aarch64-linux or
+                      riscv64-linux (assembled and linked with the target's cross binutils and
+                      gcc), or wasm32 for a WebAssembly text module importing externs from env.
+  --emit=<kind>       Output something else instead of an executable, to out.<ext> or -o:
+                      ir (the IR), c (C11, build it with -funsigned-char), llvm (LLVM IR with
+                      opaque pointers), header (a C header with the exported functions), or
+                      staticlib (an ar archive of every input file's object, with a symbol index).
+  --freestanding      Don't use libc. The compiler emits its own _start, the standard library
+                      makes system calls itself, and the program is linked with ld on its own,
+                      so extern functions can't be called. Not allowed with wasm32 or --emit.
+  --no-std            Leave out the standard library.
+  --multiboot2        Add a Multiboot2 header in a .multiboot section (x86_64-none only), which
+                      has to be placed in the first 32KiB of the file by the linker script.
+  --linker-script <f> Pass a linker script to the linker with -T.
+  --linker=<l>        gcc (the default), clang, ld or mold. Programs using libc are always linked
+                      through the C compiler, using ld or mold with -fuse-ld.
+  -l <lib>            Link against a library. .o, .a and .so files given as inputs are linked too.
+  -L <dir>            Add a directory to look for libraries in.
+  -Wl,<args>          Pass comma separated arguments straight through to the linker.
+  -I <dir>            Add a directory to look for modules and C headers in.
+  --static            Link statically, rather than against the shared libc (--dynamic, the default).
+  --pie               Build a position independent executable (with --static, a static one).
+                      Not allowed with --freestanding or x86_64-none.
+  --pic               Generate position independent code. Turned on by --pie and --shared.
+  --shared            Build a shared library (out.so) exporting only pub functions. It doesn't need
+                      a main, and can't be used with -r, --static, --pie, --freestanding or
+                      x86_64-none.
+  -h, --help          Print this.";
+
+fn usage(arg0: &str) {
     println!("CTFAW Compiler, licensed under the Mozilla Public License 2.0 by Jake Steinburger (UnmappedStack).\n");
     println!("Usage:");
-    println!("{} <input file path> -o <output file path> <options>\n", arg0);
+    println!("{} <input file path> -o <output file path> <options>", arg0);
+    println!("{} <build|run|check|clean> <options>, for the project in ctfaw.toml\n", arg0);
+}
+
+fn help(arg0: &str) {
+    usage(arg0);
+    println!("Run with --help to see the options.");
     println!("Error: No input files to compile.");
 }
 
-fn main() {
-    let args = env::args().collect::<Vec<_>>();
-    if args.len() == 1 {
-        help(&args[0]);
-        return
+// The architecture and OS of a target like `x86_64-linux`, as given with --target=.
+pub fn parse_target(name: &str) -> Option<(Arch, Os)> {
+    match name {
+        "x86_64-linux" => Some((Arch::X86_64, Os::Linux)),
+        "x86_64-none" => Some((Arch::X86_64, Os::None)),
+        "aarch64-linux" => Some((Arch::Aarch64, Os::Linux)),
+        "riscv64-linux" => Some((Arch::Riscv64, Os::Linux)),
+        "wasm32" => Some((Arch::Wasm32, Os::Linux)),
+        _ => None,
     }
+}
+
+// Sets the flags given on the command line, and returns the input files.
+pub fn parse_args(args: &[String], flags: &mut Flags) -> Vec<String> {
     let mut input_files = Vec::new();
-    let mut iter = args.iter().skip(1);
-    let mut flags = Flags::default();
+    let mut iter = args.iter();
     while let Some(arg) = iter.next() {
-        if arg.bytes().nth(0).unwrap() == '-' as u8 {
+        if arg.starts_with('-') {
             match arg.as_str() {
                 "-r" => flags.run = true,
                 "-S" => flags.just_asm = true,
                 "-c" => flags.just_obj = true,
                 "--asm-comments" => flags.include_comments = true,
+                "--emit=ir" => flags.emit = Emit::Ir,
+                "--emit=c" => flags.emit = Emit::C,
+                "--emit=llvm" => flags.emit = Emit::Llvm,
+                "--emit=header" => flags.emit = Emit::Header,
+                "--emit=staticlib" => flags.emit = Emit::StaticLib,
+                "-O0" | "-O1" | "-O2" => {
+                    flags.opt_level = arg.as_bytes()[2] - b'0';
+                    flags.opt_level_set = true;
+                },
+                "--stats" => flags.stats = true,
+                "--assembler=builtin" => flags.assembler = Assembler::Builtin,
+                "--assembler=nasm" => flags.assembler = Assembler::Nasm,
+                "--assembler=as" => flags.assembler = Assembler::Gas,
+                "--asm-dialect=nasm" => flags.asm_dialect = AsmDialect::Nasm,
+                "--asm-dialect=gas" => flags.asm_dialect = AsmDialect::Gas,
+                target if target.starts_with("--target=") => match parse_target(&target[9..]) {
+                    Some(t) => {
+                        (flags.target, flags.os) = t;
+                        flags.target_set = true;
+                    },
+                    None => println!("Unknown target: {}\nCould not compile.", &target[9..]),
+                },
+                "--freestanding" => flags.freestanding = true,
+                "--multiboot2" => flags.multiboot2 = true,
+                "--no-std" => flags.no_std = true,
+                "--linker-script" => {
+                    flags.linker_script = Some(iter.next().expect("Expected path after --linker-script, got end of command.").to_string());
+                },
+                "--static" => flags.link_mode = LinkMode::Static,
+                "--dynamic" => flags.link_mode = LinkMode::Dynamic,
+                "--pie" => flags.pie = true,
+                "--pic" => flags.pic = true,
+                "--shared" => flags.shared = true,
+                "--linker=ld" => flags.linker = Linker::Ld,
+                "--linker=gcc" => flags.linker = Linker::Gcc,
+                "--linker=clang" => flags.linker = Linker::Clang,
+                "--linker=mold" => flags.linker = Linker::Mold,
+                "-l" => {
+                    flags.libs.push(iter.next().expect("Expected library after -l, got end of command.").to_string());
+                },
+                lib if lib.starts_with("-l") => flags.libs.push(lib[2..].to_string()),
+                "-L" => {
+                    flags.lib_dirs.push(iter.next().expect("Expected directory after -L, got end of command.").to_string());
+                },
+                dir if dir.starts_with("-L") => flags.lib_dirs.push(dir[2..].to_string()),
+                args if args.starts_with("-Wl,") => flags.linker_args.push(args.to_string()),
+                "-I" => {
+                    flags.include_dirs.push(iter.next().expect("Expected directory after -I, got end of command.").to_string());
+                },
+                dir if dir.starts_with("-I") => flags.include_dirs.push(dir[2..].to_string()),
                 "-o" => {
                     flags.outfile_set = true;
                     flags.out_file = iter.next().expect("Expected filename after -o, got end of command.").to_string();
@@ -68,55 +346,259 @@ fn main() {
                     println!("Unknown flag: {}\nCould not compile.", arg);
                 }
             };
+        } else if arg.ends_with(".o") || arg.ends_with(".a") || arg.ends_with(".so") {
+            // Already compiled, so they're just passed on to the linker.
+            flags.objects.push(arg.clone());
         } else {
-            input_files.push(arg);
+            input_files.push(arg.clone());
         }
     }
-    if input_files.len() > 1 {
-        println!("Currently CTFAW only supports passing a single input file. Compilation terminated.");
+    input_files
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() == 1 {
+        help(&args[0]);
         return
     }
-    env::set_var("CTFAW_SRC_FILENAME", input_files[0]);
-    if !check_flags_allowed(&flags) { return }
-    let input: &str = &fs::read_to_string(input_files[0]).expect("Couldn't read input file.");
-    println!("[ SELF ] Compiling...");
-    let tokens = lexer::lex(input);
-    let mut global_vars = Vec::new();
-    let mut externs = Vec::new();
-    let mut ir = parser::parse(tokens, &mut global_vars, &mut externs);
-    typecheck::typecheck(&mut ir, &global_vars, &HashMap::new());
-    backend::compile(&mut ir, global_vars, externs, flags.clone());
-    
-    if flags.just_asm {
-        if flags.outfile_set { let _ = fs::rename("out.asm", flags.out_file); }
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        usage(&args[0]);
+        println!("{}", OPTIONS);
         return
     }
-    println!("[ NASM ] Assembling...");
-    Command::new("nasm")
-        .args(["-f", "elf64", "out.asm", "-g"])
-        .status()
-        .expect("Failed to run assembler");
-    //let _ = fs::remove_file("out.asm");
-    if flags.just_obj {
-        if flags.outfile_set { let _ = fs::rename("out.o", flags.out_file); }
+    if project::is_subcommand(&args[1]) {
+        project::main(&args[1], &args[2..]);
         return
     }
-    println!("[  LD  ] Linking...");
-    Command::new("gcc")
-        .args(["-o", "out", "out.o", "-lc", "-no-pie"])
-        .status()
-        .expect("Failed to run linker");
-    let _ = fs::remove_file("out.o");
-    if flags.outfile_set {
-        let _ = fs::rename("out", flags.out_file.clone());
-    } else {
-        flags.out_file = String::from("out");
+    let mut flags = Flags::default();
+    let input_files = parse_args(&args[1..], &mut flags);
+    if input_files.is_empty() {
+        help(&args[0]);
+        return
     }
+    let input_files: Vec<&String> = input_files.iter().collect();
+    // Each build gets a directory of its own, so that builds in the same directory don't overwrite each other's files.
+    let build_dir = env::temp_dir().join(format!("ctfaw-{}", std::process::id()));
+    let Some(exe) = build(&input_files, &mut flags, &build_dir, false) else { return };
     if !flags.run { return }
     println!("[ SELF ] Built successfully, trying to run compiled program...");
     let output = Command::new("sh")
-        .args(["-c", format!("./{}; echo Exited with status $?", flags.out_file).as_str()])
+        .args(["-c", format!("./{}; echo Exited with status $?", exe).as_str()])
         .output()
         .expect("Failed to run final program");
     println!("{}", String::from_utf8_lossy(&output.stdout));
 }
+
+/* Compiles the input files and links them, returning the executable if one was made. Everything in
+ * between is written to `build_dir`, which is removed afterwards unless `cache` is set. Then each
+ * object is kept along with a hash of what it was built from, and used again while that's the same. */
+pub fn build(input_files: &[&String], flags: &mut Flags, build_dir: &Path, cache: bool) -> Option<String> {
+    // Only x86_64 has a NASM syntax, or can be assembled with the built in assembler.
+    if flags.target != Arch::X86_64 {
+        flags.asm_dialect = AsmDialect::Gas;
+        if flags.assembler == Assembler::Builtin { flags.assembler = Assembler::Gas }
+    }
+    // Anything loaded at an address only known when it runs has to be position independent.
+    flags.pic |= flags.pie || flags.shared;
+    env::set_var("CTFAW_SRC_FILENAME", input_files[0]);
+    // Cached objects are kept when the build fails, but a temporary build directory isn't.
+    if !cache { env::set_var("CTFAW_BUILD_DIR", build_dir) }
+    if !check_flags_allowed(flags) { return None }
+    if input_files.len() > 1 && flags.outfile_set && (flags.just_asm || flags.just_obj || (flags.emit != Emit::Default && flags.emit != Emit::StaticLib) || flags.target == Arch::Wasm32) {
+        println!("Cannot use -o with more than one input file unless they're linked, each one gets its own output file.");
+        return None
+    }
+    println!("[ SELF ] Compiling...");
+    let units = modules::load_all(input_files, &flags.include_dirs);
+    // Calls to these don't need anything else linked in, since another input file defines them.
+    let linked: HashSet<String> = units.iter().flatten().flat_map(|m| m.exported()).cloned().collect();
+    // Shared libraries only export the functions marked pub.
+    let mut public: Vec<String> = units.iter().flatten().flat_map(|m| m.public()).cloned().collect();
+    public.sort();
+    let single = input_files.len() == 1;
+    let mut objects = Vec::new();
+    for (i, (unit, input)) in units.into_iter().zip(input_files).enumerate() {
+        let stem = Path::new(input).file_stem().unwrap().to_string_lossy().to_string();
+        let base = build_dir.join(format!("{}-{}", i, stem));
+        let hash = if cache && !flags.check { Some(project::hash_unit(&unit, flags)) } else { None };
+        let obj = PathBuf::from(format!("{}.o", base.display()));
+        if hash.as_ref().is_some_and(|h| project::is_fresh(&base, &obj, h)) {
+            println!("[ SELF ] {} hasn't changed since the last build.", input);
+            objects.push(obj);
+            continue;
+        }
+        if let Some(obj) = compile_file(unit, input, i, &base, single, flags, &linked) {
+            if let Some(hash) = hash { project::save_hash(&base, &hash) }
+            objects.push(obj);
+        }
+    }
+    if objects.is_empty() {
+        if !cache { let _ = fs::remove_dir_all(build_dir); }
+        return None
+    }
+    if flags.emit == Emit::StaticLib {
+        println!("[  AR  ] Archiving...");
+        let out_file = if flags.outfile_set { flags.out_file.clone() } else { String::from("out.a") };
+        if let Some(dir) = Path::new(&out_file).parent() { let _ = fs::create_dir_all(dir); }
+        // Members are named after their input file, without the number keeping them apart in the build directory.
+        let members: Vec<(String, Vec<u8>)> = objects.iter().map(|obj| {
+            let name = obj.file_name().unwrap().to_string_lossy().to_string();
+            (String::from(name.split_once('-').unwrap().1), fs::read(obj).expect("Couldn't read object file."))
+        }).collect();
+        fs::write(&out_file, archive::write(&members)).expect("Couldn't write archive.");
+        if !cache { let _ = fs::remove_dir_all(build_dir); }
+        return Some(out_file)
+    }
+    println!("[  LD  ] Linking...");
+    let out_file = if flags.outfile_set { flags.out_file.clone() } else if flags.shared { String::from("out.so") } else { String::from("out") };
+    if let Some(dir) = Path::new(&out_file).parent() { let _ = fs::create_dir_all(dir); }
+    if flags.shared {
+        let version_script = build_dir.join("exports.map");
+        let globals: String = public.iter().map(|f| format!(" {};", f)).collect();
+        fs::write(&version_script, format!("{{\n    global:{}\n    local: *;\n}};\n", globals)).expect("Couldn't write version script.");
+        flags.linker_args.push(format!("-Wl,--version-script={}", version_script.display()));
+    }
+    let linked = link(&objects, &out_file, flags);
+    if !cache { let _ = fs::remove_dir_all(build_dir); }
+    if !linked { error::fail(error::Component::LINKER) }
+    Some(out_file)
+}
+
+/* Links the objects into an executable. Programs using libc are linked through a C compiler, which
+ * knows where its startup files and libraries are, so --linker=ld and --linker=mold just tell it which
+ * linker to run. Ones without libc are given straight to the linker. Returns whether it worked. */
+fn link(objects: &[PathBuf], out_file: &str, flags: &Flags) -> bool {
+    let target = target::get_target(&flags.target);
+    let libc = flags.os == Os::Linux && !flags.freestanding;
+    let (linker, mut args): (&str, Vec<String>) = match (&flags.linker, libc) {
+        (Linker::Default | Linker::Gcc, true) => (target.linker_cmd(), vec![]),
+        (Linker::Ld, true) => (target.linker_cmd(), vec![String::from("-fuse-ld=bfd")]),
+        (Linker::Mold, true) => (target.linker_cmd(), vec![String::from("-fuse-ld=mold")]),
+        (Linker::Clang, _) => ("clang", vec![format!("--target={}", target.triple())]),
+        (Linker::Gcc, false) => (target.linker_cmd(), vec![]),
+        (Linker::Default | Linker::Ld, false) => (target.ld_cmd(), vec![]),
+        (Linker::Mold, false) => ("mold", vec![]),
+    };
+    // C compilers take linker options with -Wl, but linkers take them as they are.
+    let driver = libc || flags.linker == Linker::Gcc || flags.linker == Linker::Clang;
+    if !libc {
+        if driver || flags.os == Os::None { args.push(String::from("-nostdlib")) }
+        args.push(String::from("-static"));
+    } else if flags.shared {
+        args.push(String::from("-shared"));
+    } else if flags.link_mode == LinkMode::Static {
+        args.push(String::from(if flags.pie { "-static-pie" } else { "-static" }));
+    } else {
+        args.push(String::from(if flags.pie { "-pie" } else { "-no-pie" }));
+    }
+    args.extend([String::from("-o"), String::from(out_file)]);
+    args.extend(objects.iter().map(|obj| obj.to_string_lossy().to_string()));
+    args.extend(flags.objects.iter().cloned());
+    args.extend(flags.lib_dirs.iter().map(|dir| format!("-L{}", dir)));
+    args.extend(flags.libs.iter().map(|lib| format!("-l{}", lib)));
+    if libc { args.push(String::from("-lc")) }
+    for arg in &flags.linker_args {
+        if driver { args.push(arg.clone()) } else { args.extend(arg[4..].split(',').map(String::from)) }
+    }
+    if let Some(script) = &flags.linker_script {
+        args.extend([String::from("-T"), script.clone()]);
+    }
+    error::run_tool(Command::new(linker).args(args))
+}
+
+// Moves a finished file out of the build directory, which might be on a different filesystem.
+fn move_file(from: &Path, to: &str) {
+    fs::copy(from, to).expect("Couldn't write output file.");
+    let _ = fs::remove_file(from);
+}
+
+/* Compiles one of the input files (along with the modules it imports) and returns the object file it
+ * was assembled into, or nothing if the output for it was something else which has already been written.
+ * Outputs other than the executable are written to -o, to out.<ext> when there's one input file, or
+ * are named after each input file when there's more than one. */
+fn compile_file(modules: Vec<modules::Module>, input: &str, index: usize, base: &Path, single: bool, flags: &Flags, linked: &HashSet<String>) -> Option<PathBuf> {
+    env::set_var("CTFAW_SRC_FILENAME", input);
+    let stem = Path::new(input).file_stem().unwrap().to_string_lossy().to_string();
+    let output = |ext: &str| {
+        if flags.outfile_set { flags.out_file.clone() } else if single { format!("out.{}", ext) } else { format!("{}.{}", stem, ext) }
+    };
+    let files: Vec<(String, Vec<String>)> = modules.iter().map(|m| (m.path.clone(), m.functions())).collect();
+    let (mut ir, global_vars, mut externs) = modules::merge(modules);
+    if !flags.no_std { stdlib::add_std(&mut ir, &mut externs, flags) }
+    intrinsics::add_intrinsics(&mut ir);
+    typecheck::typecheck_files(&mut ir, &global_vars, &files);
+    intrinsics::check_intrinsics(&ir, &flags.target);
+    if flags.freestanding || flags.os == Os::None { typecheck::check_freestanding(&ir, linked) }
+    // Other targets' inline assembly is passed through to their assembler untouched.
+    if flags.target == Arch::X86_64 {
+        // C compilers and LLVM are given GAS syntax, so that's what inline assembly gets translated to.
+        let dialect = if flags.emit == Emit::C || flags.emit == Emit::Llvm { AsmDialect::Gas } else { flags.asm_dialect.clone() };
+        dialect::check_inline_asm(&ir, &dialect);
+    } else if flags.target == Arch::Wasm32 && flags.emit == Emit::Default {
+        dialect::forbid_inline_asm(&ir, "wasm32");
+        parser::forbid_attribute(&ir, &global_vars, "section", "wasm32");
+        parser::forbid_attribute(&ir, &global_vars, "naked", "wasm32");
+    }
+    // Interrupt handlers return with iretq, which only the x86_64 backend knows how to do.
+    if flags.target != Arch::X86_64 || flags.emit == Emit::C || flags.emit == Emit::Llvm {
+        parser::forbid_attribute(&ir, &global_vars, "interrupt", "anything other than x86_64 assembly");
+    }
+    if flags.check { return None }
+    if flags.emit == Emit::Header {
+        // Named after the header, like FOO_H for foo.h.
+        let path = output("h");
+        let stem = Path::new(&path).file_stem().unwrap().to_string_lossy().to_uppercase();
+        let guard: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        fs::write(&path, cgen::header(&ir, format!("{}_H", guard).as_str())).expect("Couldn't write header file.");
+        return None
+    }
+    inliner::inline_functions(&mut ir, flags.opt_level);
+    optimisation::optimise(&mut ir, &global_vars, flags.opt_level);
+    if flags.emit == Emit::C {
+        fs::write(output("c"), cgen::generate(&ir, &global_vars, &externs, flags.target.clone())).expect("Couldn't write C file.");
+        return None
+    }
+    let module = ir::build(&mut ir, &global_vars, &externs);
+    if flags.emit == Emit::Ir {
+        fs::write(output("ir"), module.to_string()).expect("Couldn't write IR file.");
+        return None
+    }
+    if flags.emit == Emit::Llvm {
+        fs::write(output("ll"), llvm::generate(&module, flags.target.clone())).expect("Couldn't write LLVM IR file.");
+        return None
+    }
+    fs::create_dir_all(base.parent().unwrap()).expect("Couldn't create build directory.");
+    let mut flags = flags.clone();
+    let asm_path = PathBuf::from(format!("{}.{}", base.display(), flags.asm_ext()));
+    let obj_path = PathBuf::from(format!("{}.o", base.display()));
+    flags.asm_file = asm_path.to_string_lossy().to_string();
+    // The kernel only needs one multiboot2 header.
+    flags.multiboot2 &= index == 0;
+    backend::compile(&module, flags.clone());
+    let target = target::get_target(&flags.target);
+
+    // A wasm32 module is left as text, to be converted with wat2wasm or loaded directly by a runtime.
+    if flags.just_asm || flags.target == Arch::Wasm32 {
+        move_file(&asm_path, output(flags.asm_ext()).as_str());
+        return None
+    }
+    let obj = obj_path.to_str().unwrap();
+    if flags.assembler == Assembler::Nasm {
+        println!("[ NASM ] Assembling...");
+        if !error::run_tool(Command::new("nasm").args(["-f", "elf64", flags.asm_file.as_str(), "-g", "-o", obj])) { error::fail_assembling(&flags.asm_file) }
+    } else if flags.assembler == Assembler::Gas {
+        println!("[  AS  ] Assembling...");
+        if !error::run_tool(Command::new(target.assembler_cmd()).args(["-g", "-o", obj, flags.asm_file.as_str()])) { error::fail_assembling(&flags.asm_file) }
+    } else {
+        println!("[ SELF ] Assembling...");
+        let asm = fs::read_to_string(&flags.asm_file).expect("Couldn't read assembly file.");
+        let object = assembler::assemble(&asm, flags.asm_file.as_str());
+        fs::write(&obj_path, elf::write_relocatable(&object)).expect("Couldn't write object file.");
+    }
+    if flags.just_obj {
+        move_file(&obj_path, output("o").as_str());
+        return None
+    }
+    Some(obj_path)
+}
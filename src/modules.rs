@@ -0,0 +1,419 @@
+/* Loads the files a program is made of, starting from the ones given on the command line and
+ * following their imports, and merges them into a single function table.
+ *
+ * Every module has its own namespace. The functions and constants of an imported module are
+ * renamed to `prefix__name` (with the prefix coming from its path) so they can't clash with anything
+ * in other modules, and other modules use them as `socket::name` (or `net::socket::name`) after
+ * importing them, which only works for ones marked `pub`. Nothing is renamed in the files given on
+ * the command line, so `main` is still `main`.
+ *
+ * Imports are looked up next to the file importing them, then next to the file given on the
+ * command line, then in each directory given with `-I`.
+ * A module is only loaded once however many files import it, so modules can import each other.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::parser::*;
+use crate::lexer::{self, *};
+use crate::error::*;
+use crate::ast::*;
+use crate::statements::*;
+use crate::cimport;
+
+pub struct Module {
+    pub path: String,
+    canonical: PathBuf,
+    prefix: String, // empty for the root namespace
+    tokens: Vec<Token>,
+    pub program: HashMap<String, FuncTableVal>,
+    pub globals: Vec<GlobalVar>,
+    pub externs: Vec<String>,
+    imports: HashMap<String, usize>, // by the last part of their name, and by their whole name
+    c_header: cimport::Header, // everything declared by the C headers it imports
+}
+
+// What a module defines, and whether each is `pub`, before anything is renamed.
+#[derive(Default)]
+struct Names {
+    functions: HashMap<String, bool>,
+    consts: HashMap<String, bool>,
+}
+
+fn mangle(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() { String::from(name) } else { format!("{}__{}", prefix, name) }
+}
+
+fn set_src_file(path: &str) {
+    env::set_var("CTFAW_SRC_FILENAME", path);
+}
+
+fn read_module(path: &Path, prefix: String) -> (Module, Vec<Import>) {
+    let path_str = path.to_string_lossy().to_string();
+    set_src_file(&path_str);
+    let src = match fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(_) => {
+            println!("Couldn't read input file {}.", path_str);
+            std::process::exit(1);
+        }
+    };
+    let mut tokens = lexer::lex(&src);
+    let imports = parse_imports(&mut tokens);
+    let mut globals = Vec::new();
+    let mut externs = Vec::new();
+    let program = parse(tokens.clone(), &mut globals, &mut externs);
+    (Module { path: path_str, canonical: fs::canonicalize(path).unwrap(), prefix, tokens, program, globals, externs, imports: HashMap::new(), c_header: cimport::Header::default() }, imports)
+}
+
+fn import_file(import: &Import) -> PathBuf {
+    match &import.path {
+        ImportPath::File(path) => PathBuf::from(path),
+        ImportPath::Module(name) => PathBuf::from(format!("{}.ctf", name.replace("::", "/"))),
+        ImportPath::C(_) => unreachable!(),
+    }
+}
+
+fn dir_of(path: &str) -> PathBuf {
+    Path::new(path).parent().unwrap_or(Path::new("")).to_path_buf()
+}
+
+fn find_import(import: &Import, from: &str, root: &str, include_dirs: &[String]) -> PathBuf {
+    let file = import_file(import);
+    let dirs = [dir_of(from), dir_of(root)].into_iter().chain(include_dirs.iter().map(PathBuf::from));
+    for dir in dirs {
+        let candidate = dir.join(&file);
+        if candidate.is_file() { return candidate }
+    }
+    report_err(Component::PARSER, import.tok.clone(), format!("Couldn't find {}, it isn't next to this file, the input file importing it or in any directory given with -I.", file.display()).as_str());
+    unreachable!();
+}
+
+// The name a module is used by in the file importing it, and the name it can also be used by in full.
+fn import_names(import: &Import) -> (String, String) {
+    match &import.path {
+        ImportPath::File(path) => {
+            let stem = Path::new(path).file_stem().unwrap().to_string_lossy().to_string();
+            (stem.clone(), stem)
+        },
+        ImportPath::Module(name) => (String::from(name.rsplit("::").next().unwrap()), name.clone()),
+        ImportPath::C(_) => unreachable!(),
+    }
+}
+
+// Two different files can be imported with the same name from different places, so they're numbered.
+fn unique_prefix(import: &Import, used: &mut HashSet<String>) -> String {
+    let base = import_names(import).1.replace("::", "__");
+    let mut prefix = base.clone();
+    let mut n = 2;
+    while used.contains(&prefix) {
+        prefix = format!("{}{}", base, n);
+        n += 1;
+    }
+    used.insert(prefix.clone());
+    prefix
+}
+
+fn load(root: &str, include_dirs: &[String]) -> Vec<Module> {
+    let (module, imports) = read_module(Path::new(root), String::new());
+    let mut modules = vec![module];
+    let mut pending = vec![(0, imports)];
+    let mut by_path = HashMap::from([(modules[0].canonical.clone(), 0)]);
+    let mut prefixes = HashSet::new();
+    // Modules found while going through the list are added to the end of it.
+    let mut i = 0;
+    while i < pending.len() {
+        let (importer, imports) = pending[i].clone();
+        i += 1;
+        for import in imports {
+            set_src_file(&modules[importer].path);
+            if let ImportPath::C(header) = &import.path {
+                let header = cimport::import(header, &modules[importer].path, include_dirs, import.tok.clone());
+                modules[importer].add_c_header(header);
+                continue;
+            }
+            let path = find_import(&import, &modules[importer].path, root, include_dirs);
+            let target = match by_path.get(&fs::canonicalize(&path).unwrap()) {
+                Some(target) => *target,
+                None => {
+                    let (module, imports) = read_module(&path, unique_prefix(&import, &mut prefixes));
+                    by_path.insert(module.canonical.clone(), modules.len());
+                    pending.push((modules.len(), imports));
+                    modules.push(module);
+                    modules.len() - 1
+                }
+            };
+            set_src_file(&modules[importer].path);
+            let (name, full_name) = import_names(&import);
+            let taken = modules[importer].imports.get(&name).is_some_and(|m| *m != target);
+            assert_report(!taken, Component::PARSER, import.tok.clone(), format!("A different module named {} has already been imported here.", name).as_str());
+            modules[importer].imports.insert(name, target);
+            modules[importer].imports.insert(full_name, target);
+        }
+    }
+    resolve(&mut modules);
+    set_src_file(root);
+    modules
+}
+
+/* Loads each file given on the command line along with the modules it imports. Every one of those
+ * files is compiled to its own object, so a module imported by more than one of them is only
+ * compiled into the object of the first, and the others call its functions there as externs. */
+pub fn load_all(input_files: &[&String], include_dirs: &[String]) -> Vec<Vec<Module>> {
+    let mut compiled = HashSet::new();
+    input_files.iter().map(|file| {
+        let mut modules = load(file, include_dirs);
+        for module in &mut modules[1..] {
+            if !compiled.insert(module.canonical.clone()) { module.declare_only() }
+        }
+        modules
+    }).collect()
+}
+
+struct Resolver<'a> {
+    names: &'a [Names],
+    prefixes: &'a [String],
+    imports: &'a HashMap<String, usize>,
+    current: usize,
+    root_names: &'a HashSet<String>,
+    locals: HashSet<String>, // variables and arguments of the function, which hide constants
+}
+
+impl Resolver<'_> {
+    // What a function or constant used in the current module is called once everything is renamed.
+    fn resolve(&self, name: &str, is_fn: bool, tok: Token) -> Option<String> {
+        let kind = if is_fn { "function" } else { "constant" };
+        let (module, item) = match name.rsplit_once("::") {
+            Some(v) => v,
+            None => {
+                if !is_fn && self.locals.contains(name) { return None }
+                let names = &self.names[self.current];
+                let defined_here = if is_fn { names.functions.contains_key(name) } else { names.consts.contains_key(name) };
+                if defined_here { return Some(mangle(&self.prefixes[self.current], name)) }
+                let from_root = !self.prefixes[self.current].is_empty() && self.root_names.contains(name);
+                assert_report(!from_root, Component::ANALYSIS, tok, format!("{} isn't defined in this module. Import the file it's in and use it by its qualified name instead.", name).as_str());
+                return None
+            },
+        };
+        let target = match self.imports.get(module) {
+            Some(t) => *t,
+            None => {
+                report_err(Component::ANALYSIS, tok, format!("No module named {} has been imported here.", module).as_str());
+                unreachable!();
+            }
+        };
+        let names = if is_fn { &self.names[target].functions } else { &self.names[target].consts };
+        match names.get(item) {
+            Some(true) => Some(mangle(&self.prefixes[target], item)),
+            Some(false) => {
+                report_err(Component::ANALYSIS, tok, format!("{} isn't marked `pub` in {}, so it can only be used inside of it.", item, module).as_str());
+                unreachable!();
+            },
+            None => {
+                report_err(Component::ANALYSIS, tok, format!("{} has no {} named {}.", module, kind, item).as_str());
+                unreachable!();
+            },
+        }
+    }
+
+    fn call(&self, call: &mut FuncCallStatement) {
+        if let Some(name) = self.resolve(&call.fn_ident, true, call.ident_tok.clone()) { call.fn_ident = name }
+        for arg in &mut call.args {
+            self.expr(arg);
+        }
+    }
+
+    fn expr(&self, expr: &mut BranchChild) {
+        let tok = Token { val: TokenVal::Endln, row: expr.row, col: expr.col };
+        match &mut expr.val {
+            BranchChildVal::Ident(s) | BranchChildVal::Ref(s) => {
+                if let Some(name) = self.resolve(s, false, tok) { *s = name }
+            },
+            BranchChildVal::Branch(b) => {
+                self.expr(&mut b.left_val);
+                self.expr(&mut b.right_val);
+            },
+            BranchChildVal::Unary(u) => self.expr(&mut u.val),
+            BranchChildVal::Deref(v) => self.expr(v),
+            BranchChildVal::Cast(c) => self.expr(&mut c.val),
+            BranchChildVal::Fn(call) => self.call(call),
+            _ => {},
+        }
+    }
+
+    fn statements(&self, statements: &mut [Statement]) {
+        for statement in statements {
+            match statement {
+                Statement::Define(s) => self.expr(&mut s.expr),
+                Statement::Assign(s) => self.expr(&mut s.expr),
+                Statement::Return(e) => self.expr(e),
+                Statement::FuncCall(c) => self.call(c),
+                Statement::InlineAsm(s) => s.inputs.iter_mut().for_each(|i| self.expr(&mut i.expr)),
+                Statement::If(s) => {
+                    self.expr(&mut s.condition);
+                    self.statements(&mut s.body);
+                },
+                Statement::While(s) => {
+                    self.expr(&mut s.condition);
+                    self.statements(&mut s.body);
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+fn defined_vars(statements: &[Statement], out: &mut HashSet<String>) {
+    for statement in statements {
+        match statement {
+            Statement::Define(s) => { out.insert(s.identifier.clone()); },
+            Statement::If(s) => defined_vars(&s.body, out),
+            Statement::While(s) => defined_vars(&s.body, out),
+            _ => {},
+        }
+    }
+}
+
+// Points every name used in each module at what it refers to, then renames what the modules define.
+fn resolve(modules: &mut [Module]) {
+    let names: Vec<Names> = modules.iter().map(|m| Names {
+        functions: m.program.iter().filter(|(_, f)| !f.is_extern).map(|(n, f)| (n.clone(), f.is_pub)).collect(),
+        consts: m.globals.iter().map(|g| (g.identifier.clone(), g.is_pub)).collect(),
+    }).collect();
+    let prefixes: Vec<String> = modules.iter().map(|m| m.prefix.clone()).collect();
+    let root_names: HashSet<String> = modules.iter().zip(&names)
+        .filter(|(m, _)| m.prefix.is_empty())
+        .flat_map(|(_, n)| n.functions.keys().chain(n.consts.keys()).cloned())
+        .collect();
+    for (i, module) in modules.iter_mut().enumerate() {
+        set_src_file(&module.path);
+        let imports = std::mem::take(&mut module.imports);
+        for func in module.program.values_mut() {
+            let statements = match &mut func.statements {
+                Some(s) => s,
+                None => continue,
+            };
+            let mut locals: HashSet<String> = func.signature.args.iter().map(|a| a.val.clone()).collect();
+            defined_vars(statements, &mut locals);
+            let resolver = Resolver { names: &names, prefixes: &prefixes, imports: &imports, current: i, root_names: &root_names, locals };
+            resolver.statements(statements);
+        }
+        module.imports = imports;
+        if module.prefix.is_empty() { continue }
+        module.program = std::mem::take(&mut module.program).into_iter()
+            .map(|(name, func)| if func.is_extern { (name, func) } else { (mangle(&module.prefix, &name), func) })
+            .collect();
+        for global in &mut module.globals {
+            global.identifier = mangle(&module.prefix, &global.identifier);
+        }
+    }
+}
+
+impl Module {
+    // Where something the module defines is defined, for errors about it.
+    fn definition_tok(&self, name: &str) -> Token {
+        let prefix = format!("{}__", self.prefix);
+        let name = if self.prefix.is_empty() { name } else { name.strip_prefix(prefix.as_str()).unwrap_or(name) };
+        self.tokens.windows(2)
+            .find(|w| matches!(w[0].val, TokenVal::Func | TokenVal::Const) && w[1].val == TokenVal::Literal(Literal { val: LitVal::Ident(String::from(name)), typ: Type {val: TypeVal::Any, ptr_depth: 0} }))
+            .map_or(self.tokens[0].clone(), |w| w[1].clone())
+    }
+
+    // The names of the functions defined in the module, once renamed.
+    pub fn functions(&self) -> Vec<String> {
+        self.program.iter().filter(|(_, f)| !f.is_extern).map(|(n, _)| n.clone()).collect()
+    }
+
+    // The functions which other object files can call.
+    pub fn exported(&self) -> impl Iterator<Item = &String> {
+        self.program.iter().filter(|(_, f)| !f.is_extern && !f.is_static).map(|(n, _)| n)
+    }
+
+    // The functions marked pub, which a shared library exports.
+    pub fn public(&self) -> impl Iterator<Item = &String> {
+        self.program.iter().filter(|(_, f)| f.is_pub && !f.is_extern && !f.is_static).map(|(n, _)| n)
+    }
+
+    // The C headers the module's `import_c`s read.
+    pub fn c_headers(&self) -> &[PathBuf] {
+        &self.c_header.files
+    }
+
+    // Headers imported earlier take priority, the same as declarations earlier in a header.
+    fn add_c_header(&mut self, header: cimport::Header) {
+        for (name, func) in header.functions {
+            self.c_header.functions.entry(name).or_insert(func);
+        }
+        for (name, global) in header.consts {
+            self.c_header.consts.entry(name).or_insert(global);
+        }
+        self.c_header.files.extend(header.files);
+    }
+
+    // Turns everything the module defines into an extern, for when it's compiled into another object.
+    fn declare_only(&mut self) {
+        self.program.retain(|_, f| !f.is_static);
+        for (name, func) in &mut self.program {
+            if func.is_extern { continue }
+            func.statements = None;
+            func.is_extern = true;
+            func.attributes.clear();
+            self.externs.push(name.clone());
+        }
+        for global in &mut self.globals {
+            global.attributes.retain(|a| a.name != "section");
+        }
+    }
+}
+
+/* Merges the file given on the command line and every module it imports into one function table,
+ * list of constants and list of externs. A file can declare something defined in another as an
+ * extern, in which case the definition is kept. Functions and constants from C headers are only
+ * added if they're used and nothing else defines them, since headers declare far more than any
+ * program needs. */
+pub fn merge(modules: Vec<Module>) -> (HashMap<String, FuncTableVal>, Vec<GlobalVar>, Vec<String>) {
+    let first = modules[0].path.clone();
+    let mut program: HashMap<String, FuncTableVal> = HashMap::new();
+    let mut globals: Vec<GlobalVar> = Vec::new();
+    let mut externs = Vec::new();
+    let used: HashSet<&String> = modules.iter().flat_map(|m| &m.tokens).filter_map(|t| match &t.val {
+        TokenVal::Literal(Literal { val: LitVal::Ident(name), .. }) => Some(name),
+        _ => None,
+    }).collect();
+    let mut from_c = Vec::new();
+    for module in &modules {
+        set_src_file(&module.path);
+        for (name, func) in &module.program {
+            if let Some(existing) = program.get(name) {
+                assert_report(existing.is_extern || func.is_extern, Component::PARSER, module.definition_tok(name), format!("{} is already defined in another file.", name).as_str());
+                if func.is_extern { continue }
+            }
+            program.insert(name.clone(), func.clone());
+        }
+        for global in &module.globals {
+            let defined = globals.iter().any(|g| g.identifier == global.identifier);
+            assert_report(!defined, Component::PARSER, module.definition_tok(&global.identifier), format!("{} is already defined in another file.", global.identifier).as_str());
+            globals.push(global.clone());
+        }
+        for name in &module.externs {
+            if !externs.contains(name) { externs.push(name.clone()) }
+        }
+        for (name, func) in &module.c_header.functions {
+            if used.contains(name) { from_c.push((name.clone(), func.clone())) }
+        }
+        for (name, global) in &module.c_header.consts {
+            if used.contains(name) && !globals.iter().any(|g| g.identifier == *name) { globals.push(global.clone()) }
+        }
+    }
+    for (name, func) in from_c {
+        if program.contains_key(&name) { continue }
+        program.insert(name.clone(), func);
+        externs.push(name);
+    }
+    // Externs which one of the files defines aren't external after all.
+    externs.retain(|name| program[name].is_extern);
+    set_src_file(&first);
+    (program, globals, externs)
+}
@@ -1,53 +1,585 @@
 #![allow(dead_code)]
 
+use std::collections::HashMap;
 use crate::ast::*;
 use crate::lexer::*;
+use crate::parser::*;
+use crate::statements::*;
+use crate::ir::{canonical, is_comparison};
+use crate::backend::check_type_signed;
+use crate::error::*;
 
 /* "Folds" an expression, meaning it evaluates an expression at compile time (if possible), so that
  * it doesn't have to evaluate every time the program is run. This is the absolute most basic form
  * of optimisation.
+ *
+ * Values are folded with the same semantics the backends give them at runtime: operations happen
+ * on 64 bit values which are then truncated and zero or sign extended to the type of the
+ * expression. Operands with no type of their own (plain integer literals) take the type of the
+ * other operand, or `want` (the type the expression is used as) when neither has one, the same way
+ * IR lowering picks their type. When that isn't known yet (`want` is None), operations on them
+ * whose result depends on the type are left for later.
+ * Returns the value and type of the branch if it could be folded entirely, and folds as much of it
+ * as it can either way.
  */
-fn fold_branch(branch: &mut BranchChild) -> bool {
-    match branch.val.clone() {
-        BranchChildVal::Branch(mut val) => {
-            if !(fold_branch(val.left_val.as_mut()) && fold_branch(val.right_val.as_mut())) { return false }
-            let left = match val.left_val.val {
-                BranchChildVal::Int(v) => v,
-                _ => return false
+fn fold_branch(branch: &mut BranchChild, want: Option<&Type>, from_source: bool, changed: &mut bool) -> Option<(u64, Type)> {
+    let any = Type {val: TypeVal::Any, ptr_depth: 0};
+    let folded = match &mut branch.val {
+        BranchChildVal::Int(v) => return Some((*v, any)),
+        BranchChildVal::Char(v) => return Some((*v as u64, Type {val: TypeVal::Char, ptr_depth: 0})),
+        BranchChildVal::Cast(cast) => {
+            let already_literal = matches!(cast.val.val, BranchChildVal::Int(v) if v == canonical(v, &cast.typ));
+            let (val, from) = fold_branch(&mut cast.val, Some(&cast.typ), from_source, changed)?;
+            if already_literal { return Some((val, cast.typ.clone())) }
+            (canonical(wrap(val, &from), &cast.typ), cast.typ.clone())
+        },
+        BranchChildVal::Unary(unary) => {
+            let (val, typ) = fold_branch(&mut unary.val, want, from_source, changed)?;
+            let typ = match want {
+                Some(want) if typ.val == TypeVal::Any => resolve_any(want),
+                _ => typ,
+            };
+            let result = match unary.op {
+                Operation::BitNot => !val,
+                // Whether it's zero depends on how much of it the type keeps.
+                Operation::Not if typ.val == TypeVal::Any => return None,
+                Operation::Not => (canonical(val, &typ) == 0) as u64,
+                Operation::Sub => val.wrapping_neg(),
+                _ => return None,
+            };
+            (wrap(result, &typ), typ)
+        },
+        BranchChildVal::Branch(b) => {
+            let is_logical = is_comparison(b.op) || b.op == Operation::And || b.op == Operation::Or;
+            let operand_type = match (literal_type(&b.left_val), literal_type(&b.right_val)) {
+                (Some(l), _) if l.val != TypeVal::Any => Some(l),
+                (_, Some(r)) if r.val != TypeVal::Any => Some(r),
+                (Some(_), Some(_)) if is_logical => Some(Type {val: TypeVal::U64, ptr_depth: 0}),
+                (Some(_), Some(_)) => want.map(resolve_any),
+                // The other operand's type only becomes known when type checking.
+                _ => None,
             };
-            let right = match val.right_val.val {
-                BranchChildVal::Int(v) => v,
-                _ => return false
+            let left = fold_branch(&mut b.left_val, operand_type.as_ref(), from_source, changed);
+            let right = fold_branch(&mut b.right_val, operand_type.as_ref(), from_source, changed);
+            if matches!(b.op, Operation::Div | Operation::Mod) && matches!(right, Some((0, _))) {
+                // Only a zero written in the source is an error. One that the optimiser propagated
+                // into the divisor is left for runtime, so whether a program builds can't depend on -O.
+                if !from_source { return None }
+                report_err(Component::ANALYSIS, Token {val: TokenVal::Endln, row: branch.row, col: branch.col}, "Division or modulo by zero.");
+                unreachable!();
+            }
+            let ((left, left_type), (right, right_type)) = (left?, right?);
+            let typ = if left_type.val == TypeVal::Any {
+                right_type
+            } else if right_type.val == TypeVal::Any || left_type == right_type {
+                left_type
+            } else {
+                // Mismatched types are for the type checker to complain about.
+                return None
             };
-            let result = match val.op {
-                Operation::Add => {
-                    left + right
-                },
-                Operation::Sub => {
-                    left - right
-                },
-                Operation::Star => {
-                    left * right
-                },
-                Operation::Div => {
-                    left / right
-                },
-                _ => { return false }
+            let typ = match (&typ.val, operand_type) {
+                (TypeVal::Any, Some(operand_type)) => operand_type,
+                (TypeVal::Any, None) if !is_width_independent(b.op) => return None,
+                _ => typ,
             };
-            *branch = BranchChild {val: BranchChildVal::Int(result), row: 0, col: 0};
-            true
+            let result = fold_operation(b.op, wrap(left, &typ), wrap(right, &typ), &typ)?;
+            let result_type = if is_comparison(b.op) { Type {val: TypeVal::Boolean, ptr_depth: 0} } else { typ };
+            (wrap(result, &result_type), result_type)
         },
-        BranchChildVal::Int(val) => {
-            true
+        _ => return None,
+    };
+    *changed = true;
+    *branch = typed_literal(folded.0, &folded.1, branch);
+    Some(folded)
+}
+
+// The type untyped operands take when they're used as `want`, which is u64 if that has no type either.
+fn resolve_any(want: &Type) -> Type {
+    if want.val == TypeVal::Any { Type {val: TypeVal::U64, ptr_depth: 0} } else { want.clone() }
+}
+
+/* The type of an expression made up of only literals, casts and operations on them, or None if it
+ * has anything else in it (whose type isn't known before type checking). */
+fn literal_type(expr: &BranchChild) -> Option<Type> {
+    match &expr.val {
+        BranchChildVal::Int(_) => Some(Type {val: TypeVal::Any, ptr_depth: 0}),
+        BranchChildVal::Char(_) => Some(Type {val: TypeVal::Char, ptr_depth: 0}),
+        BranchChildVal::Cast(cast) => Some(cast.typ.clone()),
+        BranchChildVal::Unary(unary) => literal_type(&unary.val),
+        BranchChildVal::Branch(b) => {
+            let (left, right) = (literal_type(&b.left_val)?, literal_type(&b.right_val)?);
+            if is_comparison(b.op) { return Some(Type {val: TypeVal::Boolean, ptr_depth: 0}) }
+            Some(if left.val == TypeVal::Any { right } else { left })
         },
-        _ => {
-            false
-        }
+        _ => None,
+    }
+}
+
+/* Operations whose bottom bits only depend on the bottom bits of their operands, so they give the
+ * same result (once truncated) whatever the type of their operands turns out to be. */
+fn is_width_independent(op: Operation) -> bool {
+    matches!(op, Operation::Add | Operation::Sub | Operation::Star | Operation::Pow | Operation::Ampersand | Operation::BitOr | Operation::BitXor | Operation::LeftShift)
+}
+
+// Like `canonical`, but leaves untyped values as they are.
+fn wrap(val: u64, typ: &Type) -> u64 {
+    if typ.val == TypeVal::Any { val } else { canonical(val, typ) }
+}
+
+fn fold_operation(op: Operation, left: u64, right: u64, typ: &Type) -> Option<u64> {
+    let is_signed = check_type_signed(typ.clone());
+    let result = match op {
+        Operation::Add => left.wrapping_add(right),
+        Operation::Sub => left.wrapping_sub(right),
+        Operation::Star => left.wrapping_mul(right),
+        Operation::Div if is_signed => (left as i64).wrapping_div(right as i64) as u64,
+        Operation::Div => left / right,
+        Operation::Mod if is_signed => (left as i64).wrapping_rem(right as i64) as u64,
+        Operation::Mod => left % right,
+        Operation::Pow => left.wrapping_pow(u32::try_from(right).ok()?),
+        Operation::Ampersand => left & right,
+        Operation::BitOr => left | right,
+        Operation::BitXor => left ^ right,
+        // Shifts happen on 64 bit registers, which only look at the bottom 6 bits of the count.
+        Operation::LeftShift => left << (right & 63),
+        Operation::RightShift if is_signed => ((left as i64) >> (right & 63)) as u64,
+        Operation::RightShift => left >> (right & 63),
+        Operation::And => (left != 0 && right != 0) as u64,
+        Operation::Or => (left != 0 || right != 0) as u64,
+        Operation::Equ => (left == right) as u64,
+        Operation::NotEqu => (left != right) as u64,
+        Operation::Less if is_signed => ((left as i64) < (right as i64)) as u64,
+        Operation::Less => (left < right) as u64,
+        Operation::LessEqu if is_signed => ((left as i64) <= (right as i64)) as u64,
+        Operation::LessEqu => (left <= right) as u64,
+        Operation::Greater if is_signed => ((left as i64) > (right as i64)) as u64,
+        Operation::Greater => (left > right) as u64,
+        Operation::GreaterEqu if is_signed => ((left as i64) >= (right as i64)) as u64,
+        Operation::GreaterEqu => (left >= right) as u64,
+        _ => return None,
+    };
+    Some(result)
+}
+
+// A literal with an explicit type, so that it still type checks the same way as what it replaces.
+fn typed_literal(val: u64, typ: &Type, template: &BranchChild) -> BranchChild {
+    let int = BranchChild {val: BranchChildVal::Int(wrap(val, typ)), row: template.row, col: template.col};
+    if typ.val == TypeVal::Any { return int }
+    BranchChild {
+        val: BranchChildVal::Cast(Box::new(Cast {
+            val: int,
+            typ: typ.clone(),
+            original_type: Type {val: TypeVal::Any, ptr_depth: 0},
+        })),
+        row: template.row,
+        col: template.col,
+    }
+}
+
+/* Gets the value of an expression if it's a (possibly typed) literal. */
+pub fn constant_value(expr: &BranchChild) -> Option<u64> {
+    match &expr.val {
+        BranchChildVal::Int(v) => Some(*v),
+        BranchChildVal::Char(v) => Some(*v as u64),
+        BranchChildVal::Cast(c) => constant_value(&c.val).map(|v| canonical(v, &c.typ)),
+        _ => None,
     }
 }
 
 pub fn fold_expr(ast: BranchChild) -> (bool, BranchChild) {
-    let mut ast_clone = ast.clone();
-    let can_fold = fold_branch(&mut ast_clone);
-    if can_fold { (can_fold, ast_clone) } else { (can_fold, ast) }
+    let mut ast = ast;
+    let can_fold = fold_branch(&mut ast, None, true, &mut false).is_some();
+    (can_fold, ast)
+}
+
+/* Folds an expression which is going to be used as a value of type `typ`. */
+pub fn fold_expr_typed(ast: BranchChild, typ: &Type) -> (bool, BranchChild) {
+    let mut ast = ast;
+    match fold_branch(&mut ast, Some(typ), true, &mut false) {
+        Some((val, _)) => (true, typed_literal(val, typ, &ast)),
+        None => (false, ast),
+    }
+}
+
+/* The value of an inline assembly operand with the "i" constraint, as a `typ`. `global` gives the
+ * value of the constants it can refer to, which are put in first since they aren't propagated at -O0. */
+pub fn immediate_value(expr: &BranchChild, typ: &Type, global: &dyn Fn(&str) -> Option<u64>) -> Option<u64> {
+    let mut expr = expr.clone();
+    rewrite_expr(&mut expr, &mut |node| match &node.val {
+        BranchChildVal::Ident(ident) => global(ident).map(|v| BranchChild {val: BranchChildVal::Int(v), ..node.clone()}),
+        _ => None,
+    });
+    constant_value(&fold_expr_typed(expr, typ).1).map(|v| canonical(v, typ))
+}
+
+/* Everything below is the optimisation pipeline which runs on the function table after type
+ * checking, controlled by the -O flag:
+ *   -O0: nothing besides the constant folding done by the parser
+ *   -O1: constant propagation of locals & globals, constant branch simplification and dead code
+ *        elimination
+ *   -O2: everything in -O1, plus copy propagation
+ * The passes keep running until none of them can change anything.
+ */
+
+#[derive(Debug, Default, Clone)]
+struct VarInfo {
+    defines: usize,
+    assigns: usize,
+    reads: usize,
+    address_taken: bool,
+    asm_output: bool,
+    typ: Option<Type>,
+    init: Option<BranchChild>,
+}
+
+impl VarInfo {
+    // A variable is effectively constant if it's given a value once and can't be changed after.
+    fn is_constant(&self) -> bool {
+        self.defines == 1 && self.assigns == 0 && !self.address_taken && !self.asm_output
+    }
+}
+
+/* Calls `f` on every node of an expression, parents before children. */
+pub fn visit_expr(expr: &BranchChild, f: &mut dyn FnMut(&BranchChild)) {
+    f(expr);
+    match &expr.val {
+        BranchChildVal::Branch(b) => {
+            visit_expr(&b.left_val, f);
+            visit_expr(&b.right_val, f);
+        },
+        BranchChildVal::Unary(u) => visit_expr(&u.val, f),
+        BranchChildVal::Deref(v) => visit_expr(v, f),
+        BranchChildVal::Cast(c) => visit_expr(&c.val, f),
+        BranchChildVal::Fn(call) => {
+            for arg in &call.args {
+                visit_expr(arg, f);
+            }
+        },
+        _ => {},
+    }
+}
+
+/* Replaces nodes of an expression with whatever `f` returns for them, without looking inside of
+ * the replacements. Returns whether anything was replaced. */
+pub fn rewrite_expr(expr: &mut BranchChild, f: &mut dyn FnMut(&BranchChild) -> Option<BranchChild>) -> bool {
+    if let Some(new) = f(expr) {
+        *expr = new;
+        return true
+    }
+    match &mut expr.val {
+        BranchChildVal::Branch(b) => {
+            let left = rewrite_expr(&mut b.left_val, f);
+            let right = rewrite_expr(&mut b.right_val, f);
+            left || right
+        },
+        BranchChildVal::Unary(u) => rewrite_expr(&mut u.val, f),
+        BranchChildVal::Deref(v) => rewrite_expr(v, f),
+        BranchChildVal::Cast(c) => rewrite_expr(&mut c.val, f),
+        BranchChildVal::Fn(call) => {
+            let mut changed = false;
+            for arg in &mut call.args {
+                changed |= rewrite_expr(arg, f);
+            }
+            changed
+        },
+        _ => false,
+    }
+}
+
+/* Calls `f` on every top level expression within a list of statements, recursing into blocks. */
+pub fn rewrite_statement_exprs(statements: &mut [Statement], f: &mut dyn FnMut(&mut BranchChild) -> bool) -> bool {
+    let mut changed = false;
+    for statement in statements {
+        match statement {
+            Statement::Define(s) => changed |= f(&mut s.expr),
+            Statement::Assign(s) => changed |= f(&mut s.expr),
+            Statement::Return(e) => changed |= f(e),
+            Statement::FuncCall(c) => {
+                for arg in &mut c.args {
+                    changed |= f(arg);
+                }
+            },
+            // Outputs are variables being written to, so only the inputs are expressions.
+            Statement::InlineAsm(s) => {
+                for input in &mut s.inputs {
+                    changed |= f(&mut input.expr);
+                }
+            },
+            Statement::If(s) => {
+                changed |= f(&mut s.condition);
+                changed |= rewrite_statement_exprs(&mut s.body, f);
+            },
+            Statement::While(s) => {
+                changed |= f(&mut s.condition);
+                changed |= rewrite_statement_exprs(&mut s.body, f);
+            },
+            _ => {},
+        }
+    }
+    changed
+}
+
+fn count_reads(expr: &BranchChild, vars: &mut HashMap<String, VarInfo>) {
+    visit_expr(expr, &mut |node| {
+        match &node.val {
+            BranchChildVal::Ident(s) => vars.entry(s.clone()).or_default().reads += 1,
+            BranchChildVal::Ref(s) => {
+                let info = vars.entry(s.clone()).or_default();
+                info.reads += 1;
+                info.address_taken = true;
+            },
+            _ => {},
+        }
+    });
+}
+
+fn collect_var_info(statements: &[Statement], vars: &mut HashMap<String, VarInfo>) {
+    for statement in statements {
+        match statement {
+            Statement::Define(s) => {
+                count_reads(&s.expr, vars);
+                let info = vars.entry(s.identifier.clone()).or_default();
+                info.defines += 1;
+                info.typ = Some(s.def_type.clone());
+                info.init = Some(s.expr.clone());
+            },
+            Statement::Assign(s) => {
+                count_reads(&s.expr, vars);
+                let info = vars.entry(s.identifier.clone()).or_default();
+                // Assigning through a pointer reads the pointer rather than changing it.
+                if s.deref { info.reads += 1 } else { info.assigns += 1 }
+            },
+            Statement::Return(e) => count_reads(e, vars),
+            Statement::FuncCall(c) => {
+                for arg in &c.args {
+                    count_reads(arg, vars);
+                }
+            },
+            Statement::InlineAsm(s) => {
+                for input in &s.inputs {
+                    count_reads(&input.expr, vars);
+                }
+                for output in &s.outputs {
+                    let info = vars.entry(String::from(output.identifier())).or_default();
+                    info.reads += 1;
+                    info.asm_output = true;
+                }
+            },
+            Statement::If(s) => {
+                count_reads(&s.condition, vars);
+                collect_var_info(&s.body, vars);
+            },
+            Statement::While(s) => {
+                count_reads(&s.condition, vars);
+                collect_var_info(&s.body, vars);
+            },
+            _ => {},
+        }
+    }
+}
+
+pub fn has_side_effects(expr: &BranchChild) -> bool {
+    let mut found = false;
+    visit_expr(expr, &mut |node| {
+        if let BranchChildVal::Fn(_) = node.val { found = true }
+    });
+    found
+}
+
+fn propagate(statements: &mut [Statement], params: &[FuncArg], globals: &[GlobalVar], copy_propagation: bool) -> bool {
+    let mut vars = HashMap::new();
+    collect_var_info(statements, &mut vars);
+    let mut replacements: HashMap<String, BranchChild> = HashMap::new();
+    for global in globals {
+        // Locals and parameters shadow globals of the same name.
+        if vars.get(&global.identifier).is_some_and(|v| v.defines > 0) || params.iter().any(|p| p.val == global.identifier) { continue }
+        let template = BranchChild {val: BranchChildVal::Int(0), row: 0, col: 0};
+        replacements.insert(global.identifier.clone(), typed_literal(global.val, &global.typ, &template));
+    }
+    for (ident, info) in &vars {
+        if !info.is_constant() || params.iter().any(|p| p.val == *ident) { continue }
+        let init = info.init.as_ref().unwrap();
+        let typ = info.typ.as_ref().unwrap();
+        if let Some(val) = constant_value(init) {
+            replacements.insert(ident.clone(), typed_literal(val, typ, init));
+            continue
+        }
+        if !copy_propagation { continue }
+        if let BranchChildVal::Ident(src) = &init.val {
+            if src == ident { continue }
+            let src_typ = match (vars.get(src), params.iter().find(|p| p.val == *src)) {
+                (Some(src_info), Some(param)) if src_info.assigns == 0 && !src_info.address_taken && !src_info.asm_output && src_info.defines == 0 => param.arg_type.clone(),
+                (Some(src_info), None) if src_info.is_constant() => src_info.typ.clone().unwrap(),
+                _ => continue,
+            };
+            if src_typ == *typ {
+                replacements.insert(ident.clone(), init.clone());
+            }
+        }
+    }
+    // Copies of copies need to be followed all the way back to where they came from.
+    for _ in 0..replacements.len() {
+        let snapshot = replacements.clone();
+        for val in replacements.values_mut() {
+            if let BranchChildVal::Ident(src) = &val.val {
+                if let Some(new) = snapshot.get(src) { *val = new.clone() }
+            }
+        }
+    }
+    if replacements.is_empty() { return false }
+    rewrite_statement_exprs(statements, &mut |expr| {
+        rewrite_expr(expr, &mut |node| {
+            match &node.val {
+                BranchChildVal::Ident(s) => replacements.get(s).map(|r| {
+                    let mut r = r.clone();
+                    r.row = node.row;
+                    r.col = node.col;
+                    r
+                }),
+                _ => None,
+            }
+        })
+    })
+}
+
+fn fold_statements(statements: &mut [Statement]) -> bool {
+    rewrite_statement_exprs(statements, &mut |expr| {
+        let mut changed = false;
+        fold_branch(expr, None, false, &mut changed);
+        changed
+    })
+}
+
+// Whether a define or assign of a variable nobody ever reads can be dropped.
+fn is_dead_store(ident: &str, expr: &BranchChild, vars: &HashMap<String, VarInfo>) -> bool {
+    vars.get(ident).is_some_and(|v| v.reads == 0) &&
+        (!has_side_effects(expr) || matches!(expr.val, BranchChildVal::Fn(_)))
+}
+
+fn eliminate_dead_code(statements: &mut Vec<Statement>, vars: &HashMap<String, VarInfo>) -> bool {
+    let mut changed = false;
+    let mut new_statements = Vec::new();
+    let mut iter = std::mem::take(statements).into_iter();
+    while let Some(statement) = iter.next() {
+        match statement {
+            Statement::If(mut s) => {
+                match constant_value(&s.condition) {
+                    Some(0) => changed = true,
+                    Some(_) => {
+                        changed = true;
+                        eliminate_dead_code(&mut s.body, vars);
+                        new_statements.append(&mut s.body);
+                    },
+                    None => {
+                        changed |= eliminate_dead_code(&mut s.body, vars);
+                        if s.body.is_empty() && !has_side_effects(&s.condition) {
+                            changed = true;
+                        } else {
+                            new_statements.push(Statement::If(s));
+                        }
+                    },
+                }
+            },
+            Statement::While(mut s) => {
+                if constant_value(&s.condition) == Some(0) {
+                    changed = true;
+                    continue
+                }
+                changed |= eliminate_dead_code(&mut s.body, vars);
+                new_statements.push(Statement::While(s));
+            },
+            Statement::Define(s) if is_dead_store(&s.identifier, &s.expr, vars) => {
+                changed = true;
+                // The value isn't needed, but the call still has to happen.
+                if let BranchChildVal::Fn(call) = s.expr.val {
+                    new_statements.push(Statement::FuncCall(call));
+                }
+            },
+            Statement::Assign(s) if !s.deref && is_dead_store(&s.identifier, &s.expr, vars) => {
+                changed = true;
+                if let BranchChildVal::Fn(call) = s.expr.val {
+                    new_statements.push(Statement::FuncCall(call));
+                }
+            },
+            Statement::Return(e) => {
+                // Nothing after a return in the same block can ever run.
+                new_statements.push(Statement::Return(e));
+                changed |= iter.next().is_some();
+                break
+            },
+            other => new_statements.push(other),
+        }
+    }
+    *statements = new_statements;
+    changed
+}
+
+fn optimise_function(func: &mut FuncTableVal, globals: &[GlobalVar], level: u8) {
+    let params = func.signature.args.clone();
+    let statements = match func.statements.as_mut() {
+        Some(v) => v,
+        None => return,
+    };
+    loop {
+        let mut changed = propagate(statements, &params, globals, level >= 2);
+        changed |= fold_statements(statements);
+        let mut vars = HashMap::new();
+        collect_var_info(statements, &mut vars);
+        changed |= eliminate_dead_code(statements, &vars);
+        if !changed { break }
+    }
+}
+
+pub fn optimise(program: &mut HashMap<String, FuncTableVal>, globals: &[GlobalVar], level: u8) {
+    if level == 0 { return }
+    for func in program.values_mut() {
+        optimise_function(func, globals, level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typ(val: TypeVal) -> Type {
+        Type {val, ptr_depth: 0}
+    }
+
+    fn fold(src: &str, want: TypeVal) -> Option<u64> {
+        let (folded, expr) = fold_expr_typed(parse_expression(lex(src)), &typ(want));
+        if folded { constant_value(&expr) } else { None }
+    }
+
+    #[test]
+    fn folds_operations() {
+        assert_eq!(fold_operation(Operation::Div, u64::MAX, 2, &typ(TypeVal::U64)), Some(u64::MAX / 2));
+        assert_eq!(fold_operation(Operation::Div, -7i64 as u64, 2, &typ(TypeVal::I64)), Some(-3i64 as u64));
+        assert_eq!(fold_operation(Operation::RightShift, u64::MAX, 60, &typ(TypeVal::U64)), Some(15));
+        assert_eq!(fold_operation(Operation::RightShift, u64::MAX, 60, &typ(TypeVal::I64)), Some(u64::MAX));
+        assert_eq!(fold_operation(Operation::Less, u64::MAX, 0, &typ(TypeVal::I64)), Some(1));
+        assert_eq!(fold_operation(Operation::Less, u64::MAX, 0, &typ(TypeVal::U64)), Some(0));
+        // Shift counts only use the bottom 6 bits, like the instructions do.
+        assert_eq!(fold_operation(Operation::LeftShift, 1, 65, &typ(TypeVal::U64)), Some(2));
+        assert_eq!(fold_operation(Operation::Pow, 3, 4, &typ(TypeVal::U64)), Some(81));
+    }
+
+    #[test]
+    fn folds_literals_as_the_expected_type() {
+        assert_eq!(fold("18446744073709551615 / 2", TypeVal::U64), Some(u64::MAX / 2));
+        assert_eq!(fold("18446744073709551615 >> 60", TypeVal::U64), Some(15));
+        assert_eq!(fold("(0 - 64) >> 2", TypeVal::I64), Some(-16i64 as u64));
+        assert_eq!(fold("200 + 100", TypeVal::U8), Some(44));
+        assert_eq!(fold("(0 - 128) / 2", TypeVal::I8), Some(-64i64 as u64));
+    }
+
+    #[test]
+    fn leaves_propagated_division_by_zero_alone() {
+        // As if `z` had been propagated as 0.
+        let mut expr = parse_expression(lex("5 / z"));
+        rewrite_expr(&mut expr, &mut |e| match e.val {
+            BranchChildVal::Int(_) | BranchChildVal::Branch(_) => None,
+            _ => Some(BranchChild {val: BranchChildVal::Int(0), row: e.row, col: e.col}),
+        });
+        assert_eq!(fold_branch(&mut expr, None, false, &mut false), None);
+    }
 }
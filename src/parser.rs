@@ -8,6 +8,7 @@ use crate::error::*;
 use crate::utils::*;
 use std::collections::HashMap;
 use crate::optimisation;
+use crate::intrinsics;
 use crate::ast::*;
 use crate::statements::*;
 use crate::lexer::*;
@@ -25,11 +26,47 @@ pub struct FuncSig {
     pub varargs_idx: Option<u64>,
 }
 
+#[derive(Debug, Clone)]
+pub struct Attribute {
+    pub name: String,
+    pub args: Vec<String>,
+    pub tok: Token,
+}
+
 #[derive(Debug, Clone)]
 pub struct FuncTableVal {
     pub signature: FuncSig,
     pub statements: Option<Vec<Statement>>,
     pub is_extern: bool,
+    pub is_pub: bool, // can be used from other modules
+    pub is_static: bool, // isn't exported from the object file
+    pub attributes: Vec<Attribute>,
+}
+
+impl FuncTableVal {
+    pub fn has_attribute(&self, name: &str) -> bool {
+        self.attributes.iter().any(|a| a.name == name)
+    }
+
+    // The section set with #[section("name")], if it should go somewhere other than .text.
+    pub fn section(&self) -> Option<String> {
+        section_of(&self.attributes)
+    }
+}
+
+const FUNC_ATTRIBUTES: [&str; 5] = ["inline", "noinline", "section", "naked", "interrupt"];
+const CONST_ATTRIBUTES: [&str; 1] = ["section"];
+
+fn section_of(attributes: &[Attribute]) -> Option<String> {
+    attributes.iter().find(|a| a.name == "section").map(|a| a.args[0].clone())
+}
+
+// For attributes which can't be supported by what's being compiled for, like sections on wasm32.
+pub fn forbid_attribute(program: &HashMap<String, FuncTableVal>, global_vars: &[GlobalVar], name: &str, target: &str) {
+    let attributes = program.values().flat_map(|f| &f.attributes).chain(global_vars.iter().flat_map(|g| &g.attributes));
+    for attr in attributes {
+        assert_report(attr.name != name, Component::PARSER, attr.tok.clone(), format!("The {} attribute can't be used when compiling for {}.", name, target).as_str());
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -37,6 +74,16 @@ pub struct GlobalVar {
     pub identifier: String,
     pub typ: Type,
     pub val: u64,
+    pub is_pub: bool,
+    pub attributes: Vec<Attribute>,
+}
+
+impl GlobalVar {
+    /* Constants are normally folded into wherever they're used, but ones placed in a section with
+     * #[section("name")] are also emitted there as data, for things like boot headers. */
+    pub fn section(&self) -> Option<String> {
+        section_of(&self.attributes)
+    }
 }
 
 fn parse_scope(statement_tokens: &[Token]) -> Vec<Statement> {
@@ -53,21 +100,20 @@ fn parse_scope(statement_tokens: &[Token]) -> Vec<Statement> {
             y += 2;
             let mut condition_tokens = Vec::new();
             let mut num_open_lparens = 1;
-            while let Some(this_tok) = tok_iter.next() {
+            for this_tok in tok_iter.by_ref() {
                 y += 1;
                 if this_tok.val == TokenVal::Lparen { num_open_lparens += 1; }
                 if this_tok.val == TokenVal::Rparen {
                     num_open_lparens -= 1;
                     if num_open_lparens == 0 { break }
-                    continue;
                 }
                 condition_tokens.push(this_tok.clone());
             }
             let mut num_open_lbraces = 1;
             let mut n = 0;
             tok_iter.next();
-            while let Some(this_tok) = tok_iter.next() {
-                if this_tok.val == TokenVal::Lbrace { num_open_lbraces += 1; continue }
+            for this_tok in tok_iter.by_ref() {
+                if this_tok.val == TokenVal::Lbrace { num_open_lbraces += 1; }
                 if this_tok.val == TokenVal::Rbrace {
                     num_open_lbraces -= 1;
                     if num_open_lbraces == 0 { break }
@@ -75,6 +121,7 @@ fn parse_scope(statement_tokens: &[Token]) -> Vec<Statement> {
                 n += 1;
             }
             let inner_statement_tokens = &statement_tokens[y..y + n];
+            y += n + 1;
             let statement_list = parse_scope(inner_statement_tokens);
             let condition_tree = parse_expression(condition_tokens);
             
@@ -110,12 +157,80 @@ fn parse_scope(statement_tokens: &[Token]) -> Vec<Statement> {
     statements
 }
 
+/* Attribute syntax, placed before the thing it applies to:
+ * #[name]
+ *  -- OR --
+ * #[name(arg, arg)]
+ *
+ * Returns the attribute and the number of tokens it took up.
+ */
+fn parse_attribute(tokens: &[Token]) -> (Attribute, usize) {
+    let expect = |n: usize, val: TokenVal, msg: &str| {
+        let tok = tokens.get(n).unwrap_or(&tokens[tokens.len() - 1]);
+        assert_report(tok.val == val, Component::PARSER, tok.clone(), msg);
+    };
+    expect(1, TokenVal::Lbracket, "Expected `[` after `#` in attribute, got something else.");
+    assert_report(tokens.len() > 3, Component::PARSER, tokens[0].clone(), "Attribute was never closed with `]`.");
+    let name = get_ident(&tokens[2]);
+    let mut args = Vec::new();
+    let mut n = 3;
+    if tokens[n].val == TokenVal::Lparen {
+        n += 1;
+        while n < tokens.len() && tokens[n].val != TokenVal::Rparen {
+            match &tokens[n].val {
+                TokenVal::Comma => {},
+                TokenVal::Literal(Literal {val: LitVal::Ident(v), ..}) | TokenVal::Literal(Literal {val: LitVal::Str(v), ..}) => args.push(v.clone()),
+                TokenVal::Literal(Literal {val: LitVal::Int(v), ..}) => args.push(v.to_string()),
+                _ => report_err(Component::PARSER, tokens[n].clone(), "Attribute arguments must be identifiers, strings or numbers."),
+            }
+            n += 1;
+        }
+        n += 1;
+    }
+    expect(n, TokenVal::Rbracket, "Expected `]` at the end of attribute, got something else.");
+    (Attribute { name, args, tok: tokens[2].clone() }, n + 1)
+}
+
+/* Naked functions have no prologue or epilogue, so there's nowhere for the compiler to keep anything
+ * and their body can only be inline assembly which handles everything itself, including returning.
+ * Interrupt handlers are entered by the CPU, which only ever passes an error code. */
+fn check_function_kind(attributes: &[Attribute], signature: &FuncSig, statements: &[Statement]) {
+    for attr in attributes {
+        if attr.name == "naked" {
+            let only_asm = statements.iter().all(|s| matches!(s, Statement::InlineAsm(a) if a.inputs.is_empty() && a.outputs.is_empty() && a.clobbers.is_empty()));
+            assert_report(only_asm, Component::PARSER, attr.tok.clone(), "Naked functions can only contain inline assembly, without any inputs, outputs or clobbers.");
+        }
+        if attr.name == "interrupt" {
+            let takes_error_code = signature.args.len() == 1 && signature.args[0].arg_type == Type {val: TypeVal::U64, ptr_depth: 0};
+            assert_report(signature.args.is_empty() || takes_error_code, Component::PARSER, attr.tok.clone(), "Interrupt handlers can only take the error code, as a single u64 argument.");
+        }
+    }
+}
+
+fn check_attributes(attributes: &[Attribute], allowed: &[&str], kind: &str) {
+    for attr in attributes {
+        assert_report(allowed.contains(&attr.name.as_str()), Component::PARSER, attr.tok.clone(), format!("Unknown {} attribute: {}", kind, attr.name).as_str());
+        if attr.name == "section" {
+            assert_report(attr.args.len() == 1, Component::PARSER, attr.tok.clone(), "The section attribute takes the name of the section, like #[section(\".multiboot\")].");
+            assert_report(attributes.iter().filter(|a| a.name == "section").count() == 1, Component::PARSER, attr.tok.clone(), "Only one section can be given.");
+            continue;
+        }
+        assert_report(attr.args.is_empty(), Component::PARSER, attr.tok.clone(), format!("The {} attribute doesn't take any arguments.", attr.name).as_str());
+        if attr.name == "inline" {
+            assert_report(!attributes.iter().any(|a| a.name == "noinline"), Component::PARSER, attr.tok.clone(), "A function can't be both inline and noinline.");
+        }
+        if attr.name == "naked" {
+            assert_report(!attributes.iter().any(|a| a.name == "interrupt" || a.name == "inline"), Component::PARSER, attr.tok.clone(), "A naked function can't also be inline or an interrupt handler.");
+        }
+    }
+}
+
 // Returns is_specified, 
 pub fn parse_func_sig(tokens_whole: Vec<Token>, i: usize, tokens: Vec<TokenVal>) -> (bool, FuncSig, TokenVal, usize, String) {
     let identifier = get_ident(&tokens_whole[i + 1]);
     // Get the args
     let mut args = Vec::new();
-    let mut decl_iter = (&tokens[i + 2..]).iter().peekable();
+    let mut decl_iter = tokens[i + 2..].iter().peekable();
     let next = decl_iter.next().unwrap().clone();
     assert_report(next == TokenVal::Lparen, Component::PARSER, tokens_whole[i + 2].clone(), "Expected token after function identifier to be `(`, got something else instead.");
     let mut num_open_lparens = 1;
@@ -169,25 +284,82 @@ pub fn parse_func_sig(tokens_whole: Vec<Token>, i: usize, tokens: Vec<TokenVal>)
     let rettype = if *next_tok == TokenVal::Arrow {
         is_specified = true;
         offset += 2;
-        let result = if let TokenVal::Type(mut t) = decl_iter.next().unwrap().clone() {
+        if let TokenVal::Type(mut t) = decl_iter.next().unwrap().clone() {
             to_check = decl_iter.next().unwrap().clone();
-            for tok in &tokens_whole[i + 6..] {
-                if tok.val != TokenVal::Ops(Operation::Star) {break}
+            while to_check == TokenVal::Ops(Operation::Star) {
                 to_check = decl_iter.next().unwrap().clone();
                 t.ptr_depth += 1;
+                offset += 1;
             }
             t
         } else {
             report_err(Component::PARSER, tokens_whole[i + 7].clone(), "Expected type after -> in function declaration specifying return type, got something else.");
             unreachable!();
-        };
-        result
+        }
     } else {
         Type {val: TypeVal::U32, ptr_depth: 0}
     };
     (is_specified, FuncSig { ret_type: rettype, args, varargs_idx }, to_check, offset, identifier)
 }
 
+#[derive(Debug, Clone)]
+pub enum ImportPath {
+    File(String), // import "path/to/module.ctf";
+    Module(String), // import net::socket;
+    C(String), // import_c "stdio.h";
+}
+
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub path: ImportPath,
+    pub tok: Token,
+}
+
+/* Import syntax, only allowed outside of functions:
+ * import "path/to/module.ctf";
+ *  -- OR --
+ * import net::socket;
+ *  -- OR --
+ * import_c "stdio.h";
+ *
+ * Takes the imports out of the tokens, so the rest can be parsed as usual, and returns them.
+ */
+pub fn parse_imports(tokens: &mut Vec<Token>) -> Vec<Import> {
+    let mut imports = Vec::new();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i].val {
+            TokenVal::Lbrace => depth += 1,
+            TokenVal::Rbrace => depth -= 1,
+            TokenVal::Import | TokenVal::ImportC => {
+                assert_report(depth == 0, Component::PARSER, tokens[i].clone(), "Imports can only be at the top level of a file, not inside of functions.");
+                let path = match (&tokens[i].val, tokens.get(i + 1).map(|t| &t.val)) {
+                    (TokenVal::ImportC, Some(TokenVal::Literal(Literal {val: LitVal::Str(s), ..}))) => ImportPath::C(s.clone()),
+                    (TokenVal::ImportC, _) => {
+                        report_err(Component::PARSER, tokens[i].clone(), "Expected the path of a C header in quotes after `import_c`.");
+                        unreachable!();
+                    },
+                    (_, Some(TokenVal::Literal(Literal {val: LitVal::Str(s), ..}))) => ImportPath::File(s.clone()),
+                    (_, Some(TokenVal::Literal(Literal {val: LitVal::Ident(s), ..}))) => ImportPath::Module(s.clone()),
+                    _ => {
+                        report_err(Component::PARSER, tokens[i].clone(), "Expected a path in quotes or a module name like `net::socket` after `import`.");
+                        unreachable!();
+                    }
+                };
+                let end = tokens.get(i + 2).unwrap_or(&tokens[i + 1]);
+                assert_report(end.val == TokenVal::Endln, Component::PARSER, end.clone(), "Expected `;` after import, got something else.");
+                imports.push(Import { path, tok: tokens[i + 1].clone() });
+                tokens.drain(i..i + 3);
+                continue;
+            },
+            _ => {},
+        }
+        i += 1;
+    }
+    imports
+}
+
 /* Function declaration syntax:
  * func fnName(arg: type, arg: type) -> retType {}
  *  -- OR --
@@ -202,46 +374,77 @@ pub fn parse(tokens_whole: Vec<Token>, global_vars: &mut Vec<GlobalVar>, externs
         .collect();
     let mut function_table = HashMap::new();
     let mut skip = 0;
+    let mut attributes: Vec<Attribute> = Vec::new();
+    let mut is_pub = false;
+    let mut is_static = false;
     for (i, token) in tokens.iter().enumerate() {
         if skip > 0 {
             skip -= 1;
             continue;
         }
+        if *token == TokenVal::Pub {
+            let next = tokens.get(i + 1);
+            assert_report(next == Some(&TokenVal::Func) || next == Some(&TokenVal::Const), Component::PARSER, tokens_whole[i].clone(), "`pub` can only be applied to function definitions and constants.");
+            is_pub = true;
+            continue;
+        }
+        // Other modules could be compiled into a different object file, where they couldn't call a static function.
+        if *token == TokenVal::Static {
+            assert_report(tokens.get(i + 1) == Some(&TokenVal::Func), Component::PARSER, tokens_whole[i].clone(), "`static` can only be applied to function definitions.");
+            is_static = true;
+            continue;
+        }
+        if *token == TokenVal::Hash {
+            let (attribute, n) = parse_attribute(&tokens_whole[i..]);
+            attributes.push(attribute);
+            skip += n - 1;
+            continue;
+        }
+        if *token == TokenVal::Extern {
+            if let Some(attr) = attributes.first() {
+                report_err(Component::PARSER, attr.tok.clone(), "Attributes can only be applied to function definitions and constants.");
+            }
+        }
         assert_report(*token != TokenVal::Let, Component::PARSER, tokens_whole[i].clone(), "Global variables must be constant, but one was defined with the `let` keyword.");
         if *token == TokenVal::Const {
             let mut n = 0;
-            let mut global_iter = tokens.iter().skip(i);
-            while let Some(this_token) = global_iter.next() {
+            for this_token in tokens.iter().skip(i) {
                 if *this_token == TokenVal::Endln { break }
                 n += 1;
             }
             let global_def_statement = if let Statement::Define(v) = parse_define_statement(Vec::from(&tokens_whole[i..i + n + 1])) {Some(v)} else { unreachable!() };
-            let (can_fold, new_ast) = optimisation::fold_expr(global_def_statement.as_ref().unwrap().expr.clone());
+            let def_type = global_def_statement.as_ref().unwrap().def_type.clone();
+            let (can_fold, new_ast) = optimisation::fold_expr_typed(global_def_statement.as_ref().unwrap().expr.clone(), &def_type);
             assert_report(can_fold, Component::PARSER, tokens_whole[i + 1].clone(), "Global constants cannot contain identifiers, function calls, or anything besides numbers & operations.");
-            let val = if let BranchChildVal::Int(v) = global_def_statement.clone().unwrap().expr.val { v } else { unreachable!() };
-            global_vars.push(GlobalVar { identifier: global_def_statement.clone().unwrap().identifier, typ: global_def_statement.unwrap().def_type, val });
+            let val = optimisation::constant_value(&new_ast).unwrap();
+            check_attributes(&attributes, &CONST_ATTRIBUTES, "constant");
+            global_vars.push(GlobalVar { identifier: global_def_statement.clone().unwrap().identifier, typ: global_def_statement.unwrap().def_type, val, is_pub: std::mem::take(&mut is_pub), attributes: std::mem::take(&mut attributes) });
             skip += n;
         }
         if *token == TokenVal::Extern {
             let (_, signature, _, _, identifier) = parse_func_sig(tokens_whole.clone(), i, tokens.clone());
+            intrinsics::check_not_intrinsic(&identifier, &tokens_whole[i + 1]);
             function_table.insert(
                 identifier.clone(),
                 FuncTableVal {
                     signature,
                     statements: None,
-                    is_extern: true
+                    is_extern: true,
+                    is_pub: false,
+                    is_static: false,
+                    attributes: Vec::new(),
                 }
             );
             externs.push(identifier);
         }
         if *token != TokenVal::Func { continue }
         let (is_specified, signature, to_check, offset, identifier) = parse_func_sig(tokens_whole.clone(), i, tokens.clone());
+        intrinsics::check_not_intrinsic(&identifier, &tokens_whole[i + 1]);
         let o = if is_specified { 6 } else { 4 } as usize;
         assert_report(to_check == TokenVal::Lbrace, Component::PARSER, tokens_whole[i + o].clone(), "Expected left brace (`{`) after function declaration, got something else.");
         let mut num_open_lbraces = 1;
         let mut n = 0;
-        let mut decl_iter = tokens.iter().skip(offset);
-        while let Some(this_token) = decl_iter.next() {
+        for this_token in tokens.iter().skip(offset) {
             if *this_token == TokenVal::Lbrace { n += 1; num_open_lbraces += 1; continue }
             if *this_token == TokenVal::Rbrace {
                 num_open_lbraces -= 1;
@@ -253,14 +456,23 @@ pub fn parse(tokens_whole: Vec<Token>, global_vars: &mut Vec<GlobalVar>, externs
         }
         let statement_tokens = &tokens_whole[offset..offset + n];
         skip += n + offset - i; 
+        check_attributes(&attributes, &FUNC_ATTRIBUTES, "function");
+        let statements = parse_scope(statement_tokens);
+        check_function_kind(&attributes, &signature, &statements);
         function_table.insert(
             identifier.clone(),
             FuncTableVal {
                 signature,
-                statements: Some(parse_scope(statement_tokens)),
+                statements: Some(statements),
                 is_extern: false,
+                is_pub: std::mem::take(&mut is_pub),
+                is_static: std::mem::take(&mut is_static),
+                attributes: std::mem::take(&mut attributes),
             }
         );
     }
+    if let Some(attr) = attributes.first() {
+        report_err(Component::PARSER, attr.tok.clone(), "Attribute isn't followed by a function definition or constant to apply to.");
+    }
     function_table
 }
@@ -0,0 +1,321 @@
+/* Peephole optimiser. Runs over the list of generated instructions just before they're written out,
+ * replacing short sequences of adjacent instructions with cheaper equivalents. Each rule looks at a
+ * fixed size window of instructions (comments are skipped over), and the rules keep being applied
+ * until none of them match anywhere. Labels, directives and inline assembly are never changed, and
+ * no rule matches across them.
+ */
+
+// Inline assembly is written between these, so that it's left exactly as the programmer wrote it.
+pub const ASM_BEGIN: &str = ";#asm";
+pub const ASM_END: &str = ";#endasm";
+
+const DIRECTIVES: [&str; 17] = [
+    "section", "segment", "global", "extern", "default", "bits", "align",
+    "db", "dw", "dd", "dq", "resb", "resw", "resd", "resq", "times", "incbin",
+];
+
+const REGS_64: [&str; 16] = ["rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+
+struct Rule {
+    name: &'static str,
+    window: usize,
+    rewrite: fn(&[Line]) -> Option<Vec<String>>,
+}
+
+const RULES: &[Rule] = &[
+    Rule { name: "jump-to-next-label", window: 2, rewrite: jump_to_next_label },
+    Rule { name: "branch-over-jump", window: 3, rewrite: branch_over_jump },
+    Rule { name: "unreachable-after-jump", window: 2, rewrite: unreachable_after_jump },
+    Rule { name: "push-pop", window: 2, rewrite: push_pop },
+    Rule { name: "store-reload", window: 2, rewrite: store_reload },
+    Rule { name: "store-forward", window: 2, rewrite: store_forward },
+    Rule { name: "imm-through-rax", window: 3, rewrite: imm_through_rax },
+    Rule { name: "self-move", window: 1, rewrite: self_move },
+    Rule { name: "zero-stack-adjust", window: 1, rewrite: zero_stack_adjust },
+];
+
+#[derive(Debug, Default)]
+pub struct PeepholeStats {
+    pub hits: Vec<(&'static str, u64)>,
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+    text: String,
+    mnemonic: String,
+    operands: Vec<String>,
+    is_barrier: bool,
+}
+
+fn parse_line(line: &str) -> Option<Line> {
+    let text = line.trim();
+    if text.is_empty() || text.starts_with(';') { return None }
+    let (mnemonic, rest) = match text.split_once(' ') {
+        Some((m, r)) => (m, r.trim()),
+        None => (text, ""),
+    };
+    let operands = if rest.is_empty() {
+        Vec::new()
+    } else {
+        rest.split(',').map(|o| String::from(o.trim())).collect()
+    };
+    Some(Line {
+        text: String::from(text),
+        mnemonic: String::from(mnemonic),
+        operands,
+        /* Anything starting with a label (including function entries like `main: push rbp`) could be
+         * jumped to, so it's treated as a label even if there's an instruction after it. Directives
+         * (in either syntax) aren't instructions at all, so they can't be moved or removed either. */
+        is_barrier: mnemonic.ends_with(':') || mnemonic.starts_with('.') || mnemonic.starts_with('[')
+            || DIRECTIVES.contains(&mnemonic.to_ascii_lowercase().as_str()),
+    })
+}
+
+fn is_reg64(op: &str) -> bool {
+    REGS_64.contains(&op)
+}
+
+fn is_mem(op: &str) -> bool {
+    op.ends_with(']')
+}
+
+fn is_imm(op: &str) -> bool {
+    !op.is_empty() && op.trim_start_matches('-').chars().all(|c| c.is_ascii_digit())
+}
+
+fn fits_imm32(op: &str) -> bool {
+    match op.parse::<i64>() {
+        Ok(v) => v >= i32::MIN as i64 && v <= i32::MAX as i64,
+        Err(_) => op.parse::<u64>().is_ok_and(|v| (v as i64) >= i32::MIN as i64 && (v as i64) < 0),
+    }
+}
+
+fn is_inst(line: &Line, mnemonic: &str, num_operands: usize) -> bool {
+    !line.is_barrier && line.mnemonic == mnemonic && line.operands.len() == num_operands
+}
+
+fn mentions_rax(op: &str) -> bool {
+    ["rax", "eax", "ax", "al", "ah"].iter().any(|r| {
+        op.split(|c: char| !c.is_ascii_alphanumeric()).any(|word| word == *r)
+    })
+}
+
+// Whether an instruction writes the whole of rax without caring about its old value.
+fn overwrites_rax(line: &Line) -> bool {
+    if line.is_barrier || line.operands.len() != 2 { return false }
+    let (dst, src) = (&line.operands[0], &line.operands[1]);
+    if line.mnemonic == "xor" && dst == src && (dst == "rax" || dst == "eax") { return true }
+    ["mov", "movzx", "movsx", "movsxd", "lea"].contains(&line.mnemonic.as_str()) &&
+        (dst == "rax" || dst == "eax") && !mentions_rax(src)
+}
+
+fn inverse_jump(mnemonic: &str) -> Option<&'static str> {
+    let pairs = [
+        ("je", "jne"), ("jz", "jnz"), ("jl", "jge"), ("jle", "jg"), ("jb", "jae"), ("jbe", "ja"),
+    ];
+    for (a, b) in pairs {
+        if mnemonic == a { return Some(b) }
+        if mnemonic == b { return Some(a) }
+    }
+    None
+}
+
+/* jmp L / L: -> L: */
+fn jump_to_next_label(lines: &[Line]) -> Option<Vec<String>> {
+    if is_inst(&lines[0], "jmp", 1) && lines[1].text == format!("{}:", lines[0].operands[0]) {
+        return Some(vec![lines[1].text.clone()])
+    }
+    None
+}
+
+/* je L1 / jmp L2 / L1: -> jne L2 / L1: */
+fn branch_over_jump(lines: &[Line]) -> Option<Vec<String>> {
+    if lines[0].is_barrier || lines[0].operands.len() != 1 || !is_inst(&lines[1], "jmp", 1) { return None }
+    let inverse = inverse_jump(&lines[0].mnemonic)?;
+    if lines[2].text != format!("{}:", lines[0].operands[0]) { return None }
+    Some(vec![format!("{} {}", inverse, lines[1].operands[0]), lines[2].text.clone()])
+}
+
+/* Anything after an unconditional jump or return which isn't a label or directive can't ever run. */
+fn unreachable_after_jump(lines: &[Line]) -> Option<Vec<String>> {
+    let first = &lines[0];
+    if first.is_barrier || !(first.mnemonic == "jmp" || first.mnemonic == "ret") || lines[1].is_barrier { return None }
+    Some(vec![first.text.clone()])
+}
+
+/* push X / pop Y -> mov Y, X */
+fn push_pop(lines: &[Line]) -> Option<Vec<String>> {
+    if !is_inst(&lines[0], "push", 1) || !is_inst(&lines[1], "pop", 1) { return None }
+    let (src, dst) = (&lines[0].operands[0], &lines[1].operands[0]);
+    if src == dst { return Some(Vec::new()) }
+    if !(is_reg64(dst) || (is_reg64(src) && is_mem(dst))) || (is_imm(src) && !fits_imm32(src)) { return None }
+    Some(vec![format!("mov {}, {}", dst, src)])
+}
+
+/* mov QWORD [m], rax / mov rax, QWORD [m] -> mov QWORD [m], rax */
+fn store_reload(lines: &[Line]) -> Option<Vec<String>> {
+    if !is_inst(&lines[0], "mov", 2) || !is_inst(&lines[1], "mov", 2) { return None }
+    let (mem, reg) = (&lines[0].operands[0], &lines[0].operands[1]);
+    if !(mem.starts_with("QWORD") && is_reg64(reg)) { return None }
+    if lines[1].operands[0] == *reg && lines[1].operands[1] == *mem {
+        return Some(vec![lines[0].text.clone()])
+    }
+    None
+}
+
+/* mov QWORD [m], rax / mov rcx, QWORD [m] -> mov QWORD [m], rax / mov rcx, rax */
+fn store_forward(lines: &[Line]) -> Option<Vec<String>> {
+    if !is_inst(&lines[0], "mov", 2) || !is_inst(&lines[1], "mov", 2) { return None }
+    let (mem, reg) = (&lines[0].operands[0], &lines[0].operands[1]);
+    let (dst, src) = (&lines[1].operands[0], &lines[1].operands[1]);
+    if !(mem.starts_with("QWORD") && is_reg64(reg) && is_reg64(dst)) || src != mem || dst == reg { return None }
+    // The address itself mustn't depend on the register being written.
+    if mem.contains(dst.as_str()) { return None }
+    Some(vec![lines[0].text.clone(), format!("mov {}, {}", dst, reg)])
+}
+
+/* mov rax, N / mov X, rax / (something which overwrites rax) -> mov X, N / (...) */
+fn imm_through_rax(lines: &[Line]) -> Option<Vec<String>> {
+    if !is_inst(&lines[0], "mov", 2) || !is_inst(&lines[1], "mov", 2) || !overwrites_rax(&lines[2]) { return None }
+    if lines[0].operands[0] != "rax" || lines[1].operands[1] != "rax" { return None }
+    let (imm, dst) = (&lines[0].operands[1], &lines[1].operands[0]);
+    if !is_imm(imm) { return None }
+    let allowed = (is_reg64(dst) && dst != "rax") || (dst.starts_with("QWORD") && fits_imm32(imm) && !mentions_rax(dst));
+    if !allowed { return None }
+    Some(vec![format!("mov {}, {}", dst, imm), lines[2].text.clone()])
+}
+
+/* mov rax, rax -> (nothing). This doesn't apply to 32 bit registers, as writing those clears the
+ * upper half of the 64 bit register. */
+fn self_move(lines: &[Line]) -> Option<Vec<String>> {
+    if is_inst(&lines[0], "mov", 2) && is_reg64(&lines[0].operands[0]) && lines[0].operands[0] == lines[0].operands[1] {
+        return Some(Vec::new())
+    }
+    None
+}
+
+/* add rsp, 0 / sub rsp, 0 -> (nothing) */
+fn zero_stack_adjust(lines: &[Line]) -> Option<Vec<String>> {
+    if (is_inst(&lines[0], "add", 2) || is_inst(&lines[0], "sub", 2)) && lines[0].operands[0] == "rsp" && lines[0].operands[1] == "0" {
+        return Some(Vec::new())
+    }
+    None
+}
+
+fn indentation(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/* Applies every rule over the list of lines until none of them match, returning how many times
+ * each rule was used. */
+pub fn optimise(lines: &mut Vec<String>) -> PeepholeStats {
+    let mut stats = PeepholeStats { hits: RULES.iter().map(|r| (r.name, 0)).collect() };
+    let mut changed = true;
+    while changed {
+        changed = false;
+        // Indices (into `lines`) of everything that isn't a comment or blank, with each block of inline assembly as a single barrier.
+        let mut positions = Vec::new();
+        let mut parsed = Vec::new();
+        let mut in_asm = false;
+        for (i, line) in lines.iter().enumerate() {
+            match line.trim() {
+                ASM_BEGIN => {
+                    in_asm = true;
+                    positions.push(i);
+                    parsed.push(Line { text: String::from(ASM_BEGIN), mnemonic: String::new(), operands: Vec::new(), is_barrier: true });
+                },
+                ASM_END => in_asm = false,
+                _ if in_asm => {},
+                _ => if let Some(line) = parse_line(line) {
+                    positions.push(i);
+                    parsed.push(line);
+                },
+            }
+        }
+        let mut replaced: Vec<Option<Vec<String>>> = vec![None; lines.len()];
+        let mut i = 0;
+        while i < parsed.len() {
+            let mut matched = None;
+            for (rule_idx, rule) in RULES.iter().enumerate() {
+                if i + rule.window > parsed.len() { continue }
+                if let Some(new) = (rule.rewrite)(&parsed[i..i + rule.window]) {
+                    matched = Some((rule_idx, rule.window, new));
+                    break
+                }
+            }
+            match matched {
+                Some((rule_idx, window, new)) => {
+                    stats.hits[rule_idx].1 += 1;
+                    changed = true;
+                    let indent = String::from(indentation(&lines[positions[i]]));
+                    let new_lines = new.into_iter()
+                        .map(|l| if l.ends_with(':') { l } else { format!("{}{}", indent, l) })
+                        .collect();
+                    replaced[positions[i]] = Some(new_lines);
+                    for pos in &positions[i + 1..i + window] {
+                        replaced[*pos] = Some(Vec::new());
+                    }
+                    i += window;
+                },
+                None => i += 1,
+            }
+        }
+        if changed {
+            let mut new_lines = Vec::new();
+            for (line, replacement) in lines.drain(..).zip(replaced) {
+                match replacement {
+                    Some(mut r) => new_lines.append(&mut r),
+                    None => new_lines.push(line),
+                }
+            }
+            *lines = new_lines;
+        }
+    }
+    stats
+}
+
+// Removes the markers around inline assembly, once the pass is done with them.
+pub fn remove_asm_markers(lines: &mut Vec<String>) {
+    lines.retain(|l| l.trim() != ASM_BEGIN && l.trim() != ASM_END);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(src: &str) -> Vec<String> {
+        let mut lines: Vec<String> = src.lines().map(String::from).collect();
+        optimise(&mut lines);
+        remove_asm_markers(&mut lines);
+        lines
+    }
+
+    #[test]
+    fn rewrites_instructions() {
+        assert_eq!(run("push rax\npop rbx"), ["mov rbx, rax"]);
+        assert_eq!(run("mov QWORD [rbp - 8], rax\nmov rax, QWORD [rbp - 8]"), ["mov QWORD [rbp - 8], rax"]);
+        assert_eq!(run("mov QWORD [rbp - 8], rax\nmov rcx, QWORD [rbp - 8]"), ["mov QWORD [rbp - 8], rax", "mov rcx, rax"]);
+        assert_eq!(run("je .a\njmp .b\n.a:"), ["jne .b", ".a:"]);
+        assert_eq!(run("jmp .a\n.a:"), [".a:"]);
+        assert_eq!(run("mov rax, rax\nadd rsp, 0"), Vec::<String>::new());
+        // Writing eax clears the top half of rax, so it isn't a no-op.
+        assert_eq!(run("mov eax, eax"), ["mov eax, eax"]);
+    }
+
+    #[test]
+    fn removes_unreachable_code() {
+        assert_eq!(run("ret\nmov rax, 1\nnop\nf:\nnop"), ["ret", "f:", "nop"]);
+    }
+
+    #[test]
+    fn keeps_directives() {
+        let src = "ret\nsection .text\nglobal f\nf: push rbp\njmp .x\n.section .text.boot,\"ax\",@progbits\n.text\nalign 8\ndb 1, 2";
+        assert_eq!(run(src), src.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn leaves_inline_asm_alone() {
+        let src = format!("push rcx\n{}\npush rax\npop rbx\njmp done\nnop\ndone:\nret\nint3\n{}\npop rcx", ASM_BEGIN, ASM_END);
+        assert_eq!(run(&src), ["push rcx", "push rax", "pop rbx", "jmp done", "nop", "done:", "ret", "int3", "pop rcx"]);
+    }
+}
@@ -0,0 +1,262 @@
+/* Projects, which are described by a `ctfaw.toml` manifest and built with subcommands:
+ *
+ * ctfaw build     compiles and links the project into target/<name>
+ * ctfaw run       builds it, then runs it with any arguments given after `--`
+ * ctfaw check     only parses and type checks it, without generating any code
+ * ctfaw clean     removes target/
+ *
+ * The manifest is looked for in the current directory and every directory above it, and everything
+ * but the paths given on the command line is relative to the directory it's in. It's a small subset
+ * of TOML:
+ *
+ * [package]
+ * name = "hello"
+ * entry = "src/main.ctf"        # the file with main in it (the default)
+ * src-dirs = ["src", "lib"]     # where imports are looked for, like -I (defaults to src)
+ *
+ * [build]
+ * target = "x86_64-linux"       # like --target=
+ * opt-level = 2                 # like -O2
+ * libs = ["m"]                  # linked with -l
+ * objects = ["vendor/util.o"]   # linked in as well
+ *
+ * Objects are kept in target/obj along with a hash of the sources and options they were built from,
+ * and are only compiled again once that changes. Given input files instead, the subcommands build
+ * those like `ctfaw <files>` does, so `ctfaw run main.ctf` is the same as `ctfaw main.ctf -r`.
+ */
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use crate::modules::Module;
+use crate::stdlib;
+use crate::{Flags, Arch, Emit};
+
+pub const MANIFEST: &str = "ctfaw.toml";
+pub const TARGET_DIR: &str = "target";
+const SUBCOMMANDS: [&str; 4] = ["build", "run", "check", "clean"];
+
+pub struct Manifest {
+    pub name: String,
+    pub entry: String,
+    pub src_dirs: Vec<String>,
+    pub target: Option<String>,
+    pub opt_level: Option<u8>,
+    pub libs: Vec<String>,
+    pub objects: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Int(u64),
+    List(Vec<String>),
+}
+
+fn manifest_err(line: usize, msg: &str) -> ! {
+    println!("{}:{}: {}", MANIFEST, line, msg);
+    process::exit(1);
+}
+
+fn parse_string(s: &str, line: usize) -> String {
+    match s.trim().strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(s) => String::from(s),
+        None => manifest_err(line, "Expected a string in quotes."),
+    }
+}
+
+fn parse_value(s: &str, line: usize) -> Value {
+    let s = s.trim();
+    if let Some(list) = s.strip_prefix('[') {
+        let Some(list) = list.strip_suffix(']') else { manifest_err(line, "Lists have to be closed with `]` on the same line.") };
+        return Value::List(list.split(',').filter(|s| !s.trim().is_empty()).map(|s| parse_string(s, line)).collect());
+    }
+    if s.starts_with('"') { return Value::Str(parse_string(s, line)) }
+    match s.parse() {
+        Ok(n) => Value::Int(n),
+        Err(_) => manifest_err(line, "Expected a string, a number or a list of strings."),
+    }
+}
+
+impl Manifest {
+    fn parse(src: &str) -> Manifest {
+        let mut manifest = Manifest { name: String::new(), entry: String::from("src/main.ctf"), src_dirs: vec![String::from("src")], target: None, opt_level: None, libs: Vec::new(), objects: Vec::new() };
+        let mut section = String::new();
+        for (i, line) in src.lines().enumerate() {
+            let n = i + 1;
+            // Comments can't be inside of strings here, since none of the values need a #.
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() { continue }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                if name != "package" && name != "build" { manifest_err(n, format!("Unknown section [{}].", name).as_str()) }
+                section = String::from(name);
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else { manifest_err(n, "Expected `key = value`.") };
+            let value = parse_value(value, n);
+            let wrong_type = || manifest_err(n, format!("Wrong type of value for {}.", key.trim()).as_str());
+            match (section.as_str(), key.trim(), value) {
+                ("package", "name", Value::Str(s)) => manifest.name = s,
+                ("package", "entry", Value::Str(s)) => manifest.entry = s,
+                ("package", "src-dirs", Value::List(l)) => manifest.src_dirs = l,
+                ("build", "target", Value::Str(s)) => manifest.target = Some(s),
+                ("build", "opt-level", Value::Int(level)) if level <= 2 => manifest.opt_level = Some(level as u8),
+                ("build", "opt-level", _) => manifest_err(n, "opt-level has to be 0, 1 or 2."),
+                ("build", "libs", Value::List(l)) => manifest.libs = l,
+                ("build", "objects", Value::List(l)) => manifest.objects = l,
+                ("package", "name" | "entry" | "src-dirs", _) | ("build", "target" | "libs" | "objects", _) => wrong_type(),
+                ("", key, _) => manifest_err(n, format!("{} has to be in the [package] or [build] section.", key).as_str()),
+                (section, key, _) => manifest_err(n, format!("Unknown key {} in [{}].", key, section).as_str()),
+            }
+        }
+        if manifest.name.is_empty() {
+            println!("{} has to give the name of the package, with `name = \"...\"` under [package].", MANIFEST);
+            process::exit(1);
+        }
+        manifest
+    }
+
+    /* Finds the manifest in the current directory or one above it and moves into its directory, so
+     * the paths in it (and in errors) are relative to it. */
+    fn find() -> Manifest {
+        let mut dir = env::current_dir().unwrap();
+        loop {
+            if dir.join(MANIFEST).is_file() { break }
+            if !dir.pop() {
+                println!("No input files, and no {} in this directory or any above it.", MANIFEST);
+                process::exit(1);
+            }
+        }
+        env::set_current_dir(&dir).unwrap();
+        Manifest::parse(&fs::read_to_string(MANIFEST).expect("Couldn't read manifest."))
+    }
+
+    // Options given on the command line take priority over these, and libraries and objects come after them.
+    fn apply(&self, flags: &mut Flags) {
+        if let Some(target) = self.target.as_ref().filter(|_| !flags.target_set) {
+            match crate::parse_target(target) {
+                Some(t) => (flags.target, flags.os) = t,
+                None => {
+                    println!("Unknown target in {}: {}", MANIFEST, target);
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(level) = self.opt_level.filter(|_| !flags.opt_level_set) { flags.opt_level = level }
+        flags.include_dirs.splice(0..0, self.src_dirs.iter().cloned());
+        flags.libs.splice(0..0, self.libs.iter().cloned());
+        flags.objects.splice(0..0, self.objects.iter().cloned());
+    }
+}
+
+pub fn is_subcommand(arg: &str) -> bool {
+    SUBCOMMANDS.contains(&arg)
+}
+
+// FNV-1a, which is plenty to notice a file changing.
+fn hash(data: &[u8], state: &mut u64) {
+    for b in data {
+        *state ^= *b as u64;
+        *state = state.wrapping_mul(0x100000001b3);
+    }
+}
+
+// A hash of everything an input file's object is built from: its sources, the C headers they import, the options and the compiler.
+pub fn hash_unit(modules: &[Module], flags: &Flags) -> String {
+    let mut state = 0xcbf29ce484222325;
+    // Running what's built doesn't change how it's built.
+    let flags = Flags { run: false, ..flags.clone() };
+    // The version doesn't change with every build of the compiler, but the executable's size and
+    // modification time do. The standard library is built into it, but is hashed too in case those can't be read.
+    if let Ok(meta) = env::current_exe().and_then(fs::metadata) {
+        hash(format!("{} {:?}", meta.len(), meta.modified().ok()).as_bytes(), &mut state);
+    }
+    for source in stdlib::ALL_SOURCES {
+        hash(source.as_bytes(), &mut state);
+    }
+    hash(format!("{:?}", flags).as_bytes(), &mut state);
+    for module in modules {
+        hash(module.path.as_bytes(), &mut state);
+        hash(&fs::read(&module.path).unwrap_or_default(), &mut state);
+        for header in module.c_headers() {
+            hash(header.to_string_lossy().as_bytes(), &mut state);
+            hash(&fs::read(header).unwrap_or_default(), &mut state);
+        }
+    }
+    format!("{:016x}", state)
+}
+
+fn hash_file(base: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.hash", base.display()))
+}
+
+pub fn save_hash(base: &Path, hash: &str) {
+    fs::write(hash_file(base), hash).expect("Couldn't write object hash.");
+}
+
+// Whether the object built last time is still from the same sources.
+pub fn is_fresh(base: &Path, obj: &Path, hash: &str) -> bool {
+    obj.is_file() && fs::read_to_string(hash_file(base)).is_ok_and(|h| h == hash)
+}
+
+/* Finding the manifest moves into its directory, so paths given on the command line are made
+ * absolute first, since they're relative to where ctfaw was run. */
+fn resolve_paths(flags: &mut Flags, cwd: &Path) {
+    let resolve = |path: &mut String| *path = cwd.join(&*path).to_string_lossy().to_string();
+    flags.include_dirs.iter_mut().for_each(resolve);
+    flags.lib_dirs.iter_mut().for_each(resolve);
+    flags.objects.iter_mut().for_each(resolve);
+    flags.linker_script.iter_mut().for_each(resolve);
+    if flags.outfile_set { resolve(&mut flags.out_file) }
+}
+
+pub fn main(command: &str, args: &[String]) {
+    let (args, program_args) = match args.iter().position(|a| a == "--") {
+        Some(i) => (&args[..i], &args[i + 1..]),
+        None => (args, &args[args.len()..]),
+    };
+    let mut flags = Flags::default();
+    let files = crate::parse_args(args, &mut flags);
+    if command == "clean" {
+        Manifest::find();
+        let _ = fs::remove_dir_all(TARGET_DIR);
+        return
+    }
+    let (files, build_dir, cache) = if files.is_empty() {
+        resolve_paths(&mut flags, &env::current_dir().unwrap());
+        let manifest = Manifest::find();
+        manifest.apply(&mut flags);
+        if !flags.outfile_set {
+            flags.outfile_set = true;
+            flags.out_file = if flags.target == Arch::Wasm32 {
+                format!("{}/{}.wat", TARGET_DIR, manifest.name)
+            } else if flags.shared {
+                format!("{}/lib{}.so", TARGET_DIR, manifest.name)
+            } else if flags.emit == Emit::StaticLib {
+                format!("{}/lib{}.a", TARGET_DIR, manifest.name)
+            } else {
+                format!("{}/{}", TARGET_DIR, manifest.name)
+            };
+        }
+        (vec![manifest.entry], Path::new(TARGET_DIR).join("obj"), true)
+    } else {
+        (files, env::temp_dir().join(format!("ctfaw-{}", process::id())), false)
+    };
+    flags.check = command == "check";
+    flags.run = command == "run";
+    let files: Vec<&String> = files.iter().collect();
+    let built = crate::build(&files, &mut flags, &build_dir, cache);
+    if flags.check {
+        println!("[ SELF ] No errors found.");
+        return
+    }
+    let Some(exe) = built else { return };
+    if !flags.run { return }
+    let exe = if Path::new(&exe).is_absolute() { PathBuf::from(exe) } else { Path::new(".").join(exe) };
+    let status = Command::new(exe)
+        .args(program_args)
+        .status()
+        .expect("Failed to run final program");
+    process::exit(status.code().unwrap_or(1));
+}
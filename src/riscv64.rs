@@ -0,0 +1,451 @@
+/* RISC-V 64 backend, generating GAS syntax assembly for RV64GC Linux from the IR, with the LP64
+ * ABI: integer arguments are passed in a0-a7 and then on the stack, results come back in a0, and
+ * sp is kept 16 byte aligned. The frame is laid out the same as on AArch64 (see `Frame`), with
+ * s0 as the frame pointer and t0/t1 used as scratch registers.
+ */
+
+
+use crate::lexer::*;
+use crate::ir::*;
+use crate::Flags;
+use crate::backend::{self, *};
+use crate::target::Target;
+use crate::error::*;
+use crate::intrinsics::{self, Intrinsic};
+
+// Registers in order of arguments for passing into a function with the LP64 ABI
+const REGS: [&str; 8] = ["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+// Registers "r" operands of inline assembly can be given. t3 is left out, since it's needed to
+// reach slots in big frames.
+const ASM_SCRATCH_REGS: [&str; 14] = ["t0", "t1", "t2", "t4", "t5", "t6", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+
+pub struct Riscv64;
+
+impl Target for Riscv64 {
+    fn arg_regs(&self) -> &'static [&'static str] {
+        &REGS
+    }
+
+    // There are no smaller views of registers, loads and stores pick the size instead.
+    fn register_of_size(&self, original: &str, typ: Type) -> String {
+        String::from(original)
+    }
+
+    // Suffix of the load/store instructions for an access of the type's size.
+    fn ptr_ident_of_size(&self, typ: Type) -> String {
+        let s = match type_to_size(typ) {
+            1 => "b",
+            2 => "h",
+            4 => "w",
+            8 => "d",
+            _ => unreachable!()
+        };
+        String::from(s)
+    }
+
+    fn compile(&self, module: &Module, flags: &Flags) -> String {
+        compile(module, flags.clone())
+    }
+
+    fn assembler_cmd(&self) -> &'static str {
+        "riscv64-linux-gnu-as"
+    }
+
+    fn linker_cmd(&self) -> &'static str {
+        "riscv64-linux-gnu-gcc"
+    }
+
+    fn ld_cmd(&self) -> &'static str {
+        "riscv64-linux-gnu-ld"
+    }
+
+    fn triple(&self) -> &'static str {
+        "riscv64-linux-gnu"
+    }
+
+    fn start_stub(&self) -> &'static str {
+        "_start: .option push
+        .option norelax
+        lla gp, __global_pointer$
+        .option pop
+        li s0, 0
+        ld a0, 0(sp)
+        addi a1, sp, 8
+        call main
+        li a7, 93
+        ecall"
+    }
+}
+
+/* Accesses memory at an offset from sp. Offsets only have 12 bits (signed), so anything further
+ * away is worked out in t3 first. */
+fn frame_access(op: &str, reg: &str, off: usize) -> String {
+    if off <= 2047 {
+        return format!("{} {}, {}(sp)", op, reg, off)
+    }
+    format!("li t3, {}\nadd t3, sp, t3\n{} {}, 0(t3)", off, op, reg)
+}
+
+// The load instruction which gives a value of the type, zero or sign extended to 64 bits.
+fn load_op(typ: Type) -> String {
+    let ident = Riscv64.ptr_ident_of_size(typ.clone());
+    if ident == "d" || check_type_signed(typ) { format!("l{}", ident) } else { format!("l{}u", ident) }
+}
+
+fn store_op(typ: Type) -> String {
+    format!("s{}", Riscv64.ptr_ident_of_size(typ))
+}
+
+fn load_vreg(frame: &Frame, reg: &str, v: VReg) -> String {
+    frame_access("ld", reg, frame.vreg_off(v))
+}
+
+fn store_vreg(frame: &Frame, reg: &str, v: VReg) -> String {
+    frame_access("sd", reg, frame.vreg_off(v))
+}
+
+// The result is fixed up in t0.
+fn normalise(out: &mut CompiledAsm, typ: Type) {
+    backend::normalise(out, typ, |size, signed| match (size, signed) {
+        (4, false) => "slli t0, t0, 32\nsrli t0, t0, 32",
+        (4, true) => "sext.w t0, t0",
+        (2, false) => "slli t0, t0, 48\nsrli t0, t0, 48",
+        (2, true) => "slli t0, t0, 48\nsrai t0, t0, 48",
+        (_, false) => "andi t0, t0, 255",
+        (_, true) => "slli t0, t0, 56\nsrai t0, t0, 56",
+    });
+}
+
+/* Operands are in t0 and t1, and returns in t0. `typ` is the type of the operands. */
+fn compile_operation(out: &mut CompiledAsm, op: Operation, typ: Type) {
+    let is_signed = check_type_signed(typ.clone());
+    let u = if is_signed { "" } else { "u" };
+    match op {
+        Operation::Div => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("div{} t0, t0, t1", u).as_str());
+        },
+        Operation::Mod => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("rem{} t0, t0, t1", u).as_str());
+        },
+        Operation::GreaterEqu | Operation::LessEqu | Operation::Less | Operation::Greater | Operation::NotEqu | Operation::Equ => {
+            // Everything is built from `<`, swapping the operands and/or inverting the result.
+            let cmp = match op {
+                Operation::Less => format!("slt{} t0, t0, t1", u),
+                Operation::Greater => format!("slt{} t0, t1, t0", u),
+                Operation::GreaterEqu => format!("slt{} t0, t0, t1\nxori t0, t0, 1", u),
+                Operation::LessEqu => format!("slt{} t0, t1, t0\nxori t0, t0, 1", u),
+                Operation::NotEqu => String::from("xor t0, t0, t1\nsnez t0, t0"),
+                _ => String::from("xor t0, t0, t1\nseqz t0, t0"),
+            };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), cmp.as_str());
+            return
+        },
+        Operation::And | Operation::Or => {
+            let combine = if op == Operation::And { "and" } else { "or" };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("snez t0, t0\nsnez t1, t1\n{} t0, t0, t1", combine).as_str());
+            return
+        },
+        Operation::BitXor => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "xor t0, t0, t1");
+        },
+        Operation::Ampersand => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "and t0, t0, t1");
+        },
+        Operation::BitOr => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "or t0, t0, t1");
+        },
+        Operation::LeftShift => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "sll t0, t0, t1");
+        },
+        Operation::RightShift => {
+            let shift = if is_signed { "sra" } else { "srl" };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{} t0, t0, t1", shift).as_str());
+        },
+        Operation::Star => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mul t0, t0, t1");
+        },
+        Operation::Add => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "add t0, t0, t1");
+        },
+        Operation::Sub => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "sub t0, t0, t1");
+        },
+        Operation::Pow => {
+            let loop_label = format!("sect{}", out.num_subroutines);
+            let done_label = format!("sect{}", out.num_subroutines + 1);
+            out.num_subroutines += 2;
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mv t2, t0\nli t0, 1");
+            write_text(&mut out.text, String::new(), out.flags.clone(), format!("{}:", loop_label).as_str());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("beqz t1, {}\nmul t0, t0, t2\naddi t1, t1, -1\nj {}", done_label, loop_label).as_str());
+            write_text(&mut out.text, String::new(), out.flags.clone(), format!("{}:", done_label).as_str());
+        },
+        _ => {
+            panic!("Unsupported operation.")
+        }
+    }
+    normalise(out, typ);
+}
+
+fn compile_unary_operation(out: &mut CompiledAsm, op: Operation, typ: Type) {
+    match op {
+        Operation::BitNot => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "not t0, t0");
+            normalise(out, typ);
+        },
+        Operation::Not => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "seqz t0, t0");
+        },
+        Operation::Sub => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "neg t0, t0");
+            normalise(out, typ);
+        },
+        _ => {
+            panic!("Unary operator not implemented yet.");
+        }
+    }
+}
+
+// Clobbered registers are saved below sp, which moves the memory operands (see `Frame`).
+fn compile_inline_asm(out: &mut CompiledAsm, frame: &Frame, asm: &str, inputs: &[AsmInput], outputs: &[AsmOutput], clobbers: &[String], tok: &Token) {
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "# Inline assembly");
+    let regs = pick_asm_regs(inputs, outputs, clobbers, &ASM_SCRATCH_REGS, tok);
+    for clobber in clobbers {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("addi sp, sp, -16\nsd {}, 0(sp)", clobber).as_str());
+    }
+    let saved = clobbers.len() * 16;
+    let mem_operand = |off: usize| {
+        assert_report(off <= 2047, Component::CODEGEN, tok.clone(), "Memory operand of inline assembly is too far from sp to be addressed directly.");
+        format!("{}(sp)", off)
+    };
+    let mut texts = Vec::new();
+    for (output, reg) in outputs.iter().zip(&regs) {
+        match reg {
+            Some(reg) => {
+                if output.read_write {
+                    let op = load_op(frame.func.slots[output.slot].typ.clone());
+                    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), frame_access(&op, reg, frame.slot_off(output.slot) + saved).as_str());
+                }
+                texts.push(reg.clone());
+            },
+            None => texts.push(mem_operand(frame.slot_off(output.slot) + saved)),
+        }
+    }
+    for (input, reg) in inputs.iter().zip(&regs[outputs.len()..]) {
+        match (&input.val, reg) {
+            (AsmValue::Imm(v), _) => texts.push(v.to_string()),
+            (AsmValue::VReg(v), Some(reg)) => {
+                write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), frame_access("ld", reg, frame.vreg_off(*v) + saved).as_str());
+                texts.push(reg.clone());
+            },
+            (AsmValue::VReg(v), None) => texts.push(mem_operand(frame.vreg_off(*v) + saved)),
+        }
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), substitute_asm_operands(asm, &asm_operand_names(inputs, outputs), &texts).as_str());
+    for (output, reg) in outputs.iter().zip(&regs) {
+        if let Some(reg) = reg {
+            let op = store_op(frame.func.slots[output.slot].typ.clone());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), frame_access(&op, reg, frame.slot_off(output.slot) + saved).as_str());
+        }
+    }
+    for clobber in clobbers.iter().rev() {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("ld {}, 0(sp)\naddi sp, sp, 16", clobber).as_str());
+    }
+}
+
+/* The system call number goes in a7 and its arguments in a0-a5. `pause` is written out as the
+ * fence it's encoded as, since it needs the Zihintpause extension which older assemblers lack. */
+fn compile_intrinsic(out: &mut CompiledAsm, frame: &Frame, dst: Option<VReg>, intrinsic: Intrinsic, args: &[VReg]) {
+    let asm = match intrinsic {
+        Intrinsic::Syscall => {
+            let mut asm = load_vreg(frame, "a7", args[0]);
+            for (reg, arg) in Riscv64.arg_regs().iter().zip(&args[1..]) {
+                asm.push_str(format!("\n{}", load_vreg(frame, reg, *arg)).as_str());
+            }
+            asm + "\necall\nmv t0, a0"
+        },
+        Intrinsic::Rdtsc => String::from("rdtime t0"),
+        Intrinsic::Pause => String::from(".4byte 0x0100000f"),
+        Intrinsic::Hlt => String::from("wfi"),
+        Intrinsic::Unreachable | Intrinsic::Trap => String::from("unimp"),
+        Intrinsic::Outb | Intrinsic::Inb | Intrinsic::Cpuid => unreachable!(),
+    };
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), asm.as_str());
+    if let Some(dst) = dst {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), store_vreg(frame, "t0", dst).as_str());
+    }
+}
+
+fn compile_func_call(out: &mut CompiledAsm, frame: &Frame, dst: Option<VReg>, callee: &str, args: &[VReg]) {
+    if let Some(intrinsic) = intrinsics::lookup(callee) {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("# Intrinsic {}", callee).as_str());
+        return compile_intrinsic(out, frame, dst, intrinsic, args)
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("# Call {}", callee).as_str());
+    // Arguments past the eighth go at the bottom of the frame, which is where the callee expects them.
+    for (i, arg) in args.iter().enumerate().skip(Riscv64.arg_regs().len()) {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", load_vreg(frame, "t0", *arg), frame_access("sd", "t0", (i - Riscv64.arg_regs().len()) * 8)).as_str());
+    }
+    for (reg, arg) in Riscv64.arg_regs().iter().zip(args.iter()) {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_vreg(frame, reg, *arg).as_str());
+    }
+    let plt = if out.flags.pic { "@plt" } else { "" };
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("call {}{}", callee, plt).as_str());
+    if let Some(dst) = dst {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mv t0, a0");
+        normalise(out, frame.func.vreg_type(dst));
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), store_vreg(frame, "t0", dst).as_str());
+    }
+}
+
+fn compile_inst(out: &mut CompiledAsm, frame: &Frame, inst: &Inst) {
+    let func = frame.func;
+    match inst {
+        Inst::Imm { dst, val } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("li t0, {}\n{}", *val as i64, store_vreg(frame, "t0", *dst)).as_str());
+        },
+        Inst::Load { dst, slot } => {
+            let op = load_op(func.slots[*slot].typ.clone());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", frame_access(&op, "t0", frame.slot_off(*slot)), store_vreg(frame, "t0", *dst)).as_str());
+        },
+        Inst::Store { slot, src } => {
+            let op = store_op(func.slots[*slot].typ.clone());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", load_vreg(frame, "t0", *src), frame_access(&op, "t0", frame.slot_off(*slot))).as_str());
+        },
+        Inst::AddrOf { dst, slot } => {
+            let off = frame.slot_off(*slot);
+            let addr = if off <= 2047 { format!("addi t0, sp, {}", off) } else { format!("li t0, {}\nadd t0, sp, t0", off) };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", addr, store_vreg(frame, "t0", *dst)).as_str());
+        },
+        Inst::StrAddr { dst, idx } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("lla t0, strlit{}\n{}", idx, store_vreg(frame, "t0", *dst)).as_str());
+        },
+        Inst::LoadPtr { dst, addr } => {
+            let op = load_op(func.vreg_type(*dst));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{} t0, 0(t1)\n{}", load_vreg(frame, "t1", *addr), op, store_vreg(frame, "t0", *dst)).as_str());
+        },
+        Inst::StorePtr { addr, src } => {
+            let op = store_op(func.vreg_type(*src));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}\n{} t0, 0(t1)", load_vreg(frame, "t1", *addr), load_vreg(frame, "t0", *src), op).as_str());
+        },
+        Inst::Binary { dst, op, lhs, rhs } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\n{}", load_vreg(frame, "t0", *lhs), load_vreg(frame, "t1", *rhs)).as_str());
+            compile_operation(out, *op, func.vreg_type(*lhs));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), store_vreg(frame, "t0", *dst).as_str());
+        },
+        Inst::Unary { dst, op, src } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_vreg(frame, "t0", *src).as_str());
+            compile_unary_operation(out, *op, func.vreg_type(*dst));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), store_vreg(frame, "t0", *dst).as_str());
+        },
+        Inst::Cast { dst, src } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_vreg(frame, "t0", *src).as_str());
+            normalise(out, func.vreg_type(*dst));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), store_vreg(frame, "t0", *dst).as_str());
+        },
+        Inst::Call { dst, func: callee, args, .. } => {
+            compile_func_call(out, frame, *dst, callee, args);
+        },
+        Inst::InlineAsm { asm, inputs, outputs, clobbers, tok } => {
+            compile_inline_asm(out, frame, asm, inputs, outputs, clobbers, tok);
+        },
+    }
+}
+
+fn compile_epilogue(out: &mut CompiledAsm) {
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "addi sp, s0, -16");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "ld ra, 8(sp)\nld s0, 0(sp)");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "addi sp, sp, 16");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "ret");
+}
+
+fn compile_terminator(out: &mut CompiledAsm, frame: &Frame, term: &Terminator, base: u64) {
+    match term {
+        Terminator::Jump(target) => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("j {}", block_label(base, *target)).as_str());
+        },
+        Terminator::Branch { cond, then_blk, else_blk } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_vreg(frame, "t0", *cond).as_str());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("beqz t0, {}\nj {}", block_label(base, *else_blk), block_label(base, *then_blk)).as_str());
+        },
+        Terminator::Return(v) => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "# Return from function");
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_vreg(frame, "a0", *v).as_str());
+            compile_epilogue(out);
+        },
+    }
+}
+
+fn compile_function(out: &mut CompiledAsm, func: &Function) {
+    let frame = Frame::new(func, REGS.len());
+    if !func.is_static { out.globals.push(func.name.clone()) }
+    out.spaces.clear();
+    gas_func_section(out, func, '@');
+    if func.naked {
+        compile_naked_function(out, func);
+        if func.section.is_some() {
+            write_text(&mut out.text, String::new(), out.flags.clone(), ".text");
+        }
+        return
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("\n{}: addi sp, sp, -16", func.name).as_str());
+    for _ in func.name.chars() {
+        out.spaces.push(' ');
+    }
+    out.spaces.push_str("  ");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "sd ra, 8(sp)\nsd s0, 0(sp)");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "addi s0, sp, 16");
+    let size = frame.size();
+    let alloc = if size <= 2047 { format!("addi sp, sp, -{}", size) } else { format!("li t3, {}\nsub sp, sp, t3", size) };
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), alloc.as_str());
+    for (i, slot) in func.params.iter().enumerate() {
+        let op = store_op(func.slots[*slot].typ.clone());
+        if i < Riscv64.arg_regs().len() {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), frame_access(&op, Riscv64.arg_regs()[i], frame.slot_off(*slot)).as_str());
+        } else {
+            // The caller left these at the bottom of its frame, which is where s0 points.
+            let stack_off = (i - Riscv64.arg_regs().len()) * 8;
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("ld t0, {}(s0)\n{}", stack_off, frame_access(&op, "t0", frame.slot_off(*slot))).as_str());
+        }
+    }
+    let base = out.num_subroutines;
+    out.num_subroutines += func.blocks.len() as u64;
+    for block in &func.blocks {
+        write_text(&mut out.text, String::new(), out.flags.clone(), format!("{}:", block_label(base, block.id)).as_str());
+        for inst in &block.insts {
+            compile_inst(out, &frame, inst);
+        }
+        compile_terminator(out, &frame, &block.term, base);
+    }
+    if func.section.is_some() {
+        write_text(&mut out.text, String::new(), out.flags.clone(), ".text");
+    }
+}
+
+fn compile(module: &Module, flags: Flags) -> String {
+    let mut out = CompiledAsm::new(flags);
+    write_start_stub(&mut out, module, &Riscv64);
+    for func in &module.functions {
+        compile_function(&mut out, func);
+    }
+    let sections = gas_const_sections(&mut out, module, '@');
+
+    out.spaces.clear();
+
+    for (i, strlit) in module.strings.iter().enumerate() {
+        let mut stringchars: Vec<String> = strlit.bytes().map(|c| c.to_string()).collect();
+        stringchars.push(String::from("0")); // make sure it has a null terminator
+        write_text(&mut out.rodata, out.spaces.clone(), out.flags.clone(), format!("strlit{}: .byte {}", i, stringchars.join(", ")).as_str());
+    }
+
+    let mut asm = String::from(".text\n");
+    for global in &out.globals {
+        asm.push_str(format!(".globl {}\n.type {}, @function\n", global, global).as_str());
+    }
+    asm.push_str(format!("{}\n", out.text).as_str());
+    asm.push_str(format!(".data\n{}", out.data).as_str());
+    asm.push_str(format!(".section .rodata\n{}", out.rodata).as_str());
+    asm.push_str(sections.as_str());
+    // Without this, linkers assume the stack needs to be executable.
+    asm.push_str(".section .note.GNU-stack,\"\",@progbits\n");
+    asm
+}
@@ -7,6 +7,7 @@ use crate::utils::*;
 use crate::error::*;
 use crate::lexer::*;
 use crate::ast::*;
+use crate::optimisation;
 
 // Some structures first need to be defined
 // TODO: Add a generic assign statement used for both assigning existing vars and defining new ones
@@ -25,7 +26,7 @@ pub struct WhileStatement {
 
 #[derive(Debug, Clone)]
 pub struct DefineStatement {
-    is_const: bool,
+    pub is_const: bool,
     pub identifier: String,
     pub def_type: Type,
     pub type_tok: Token,
@@ -50,17 +51,39 @@ pub struct FuncCallStatement {
     pub col: u64,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmConstraint {
+    Reg, // "r", any register picked by the compiler
+    Mem, // "m", the operand is used from memory
+    Imm, // "i", a constant substituted directly
+    Fixed(String), // a specific register, like "rax"
+}
+
 #[derive(Debug, Clone)]
-pub struct AsmIOEntry {
-    pub register: String,
-    pub identifier: String,
+pub struct AsmOperand {
+    pub name: Option<String>,
+    pub constraint: AsmConstraint,
+    pub read_write: bool, // "+" outputs, which also take the variable's value in
+    pub expr: BranchChild,
+    pub tok: Token,
+}
+
+impl AsmOperand {
+    // Outputs are always a plain variable, which the parser checks.
+    pub fn identifier(&self) -> &str {
+        match &self.expr.val {
+            BranchChildVal::Ident(s) => s,
+            _ => unreachable!(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct InlineAsmStatement {
     pub asm: String,
-    pub inputs: Vec<AsmIOEntry>,
-    pub outputs: Vec<AsmIOEntry>,
+    pub asm_tok: Token,
+    pub inputs: Vec<AsmOperand>,
+    pub outputs: Vec<AsmOperand>,
     pub clobbers: Vec<String>,
 }
 
@@ -70,7 +93,9 @@ pub struct ExternStatement {
     pub val: FuncTableVal,
 }
 
+// NullStatement is named like this so it stands out, since it's only for debugging.
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Statement {
     Define(DefineStatement),
     Assign(AssignStatement),
@@ -105,10 +130,11 @@ pub fn parse_define_statement(tokens: Vec<Token>) -> Statement {
             unreachable!();
         }
     };
-    let expr = parse_expression(tokens[(5 + typ.ptr_depth) as usize..tokens.len() - 1].to_vec());
+    // Untyped literals in it are folded as the variable's type, which is what they'll be at runtime.
+    let expr = optimisation::fold_expr_typed(parse_expression(tokens[(5 + typ.ptr_depth) as usize..tokens.len() - 1].to_vec()), &typ).1;
     Statement::Define(
         DefineStatement {
-            is_const: is_const,
+            is_const,
             identifier,
             def_type: typ,
             type_tok: tokens[3].clone(),
@@ -119,7 +145,7 @@ pub fn parse_define_statement(tokens: Vec<Token>) -> Statement {
 
 fn parse_assign_statement(mut tokens: Vec<Token>, deref: bool) -> Statement {
     if tokens[0].val == TokenVal::Ops(Operation::Star) {
-        tokens = (&tokens[1..]).to_vec();
+        tokens = tokens[1..].to_vec();
     }
     assert_report(tokens[1].val == TokenVal::Assign, Component::PARSER, tokens[1].clone(), "Couldn't parse statement, expected = but it wasn't there.");
     let expr = parse_expression(tokens[2..tokens.len() - 1].to_vec());
@@ -138,14 +164,20 @@ fn parse_assign_statement(mut tokens: Vec<Token>, deref: bool) -> Statement {
 fn parse_expr_list(tokens: Vec<Token>) -> Vec<BranchChild> {
     let mut arg_tokens: Vec<Vec<Token>> = Vec::new();
     let mut arg_idx: i64 = -1;
-    for tok in 0..tokens.len() {
-        if tokens[tok].val == TokenVal::Comma || tok == 0 {
+    // Commas inside brackets belong to the argument list of a nested call.
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.val {
+            TokenVal::Lparen => depth += 1,
+            TokenVal::Rparen => depth -= 1,
+            _ => {},
+        }
+        if (token.val == TokenVal::Comma && depth == 0) || i == 0 {
             arg_tokens.push(Vec::new());
             arg_idx += 1;
-            if tokens[tok].val == TokenVal::Comma { continue; }
+            if token.val == TokenVal::Comma { continue; }
         }
-        let token = tokens[tok].clone();
-        arg_tokens[arg_idx as usize].push(token);
+        arg_tokens[arg_idx as usize].push(token.clone());
     }
     let mut args: Vec<BranchChild> = Vec::new();
     for arg in arg_tokens {
@@ -158,15 +190,106 @@ fn parse_expr_list(tokens: Vec<Token>) -> Vec<BranchChild> {
 fn get_index(v: Vec<Token>, occurrence: usize, value: TokenVal) -> Option<usize> {
     v.iter()
         .enumerate()
-        .filter(|(_, &ref v) | v.val == value)
+        .filter(|(_, v) | v.val == value)
         .map(|(i, _)| i)
         .nth(occurrence - 1)
 }
 
-/* asm(asm : reg | identifier, reg | identifier, reg : identifier : reg, reg, reg);
- *      ^              ^                                   ^               ^
- * asm source       inputs list                        outputs list     clobbered register list
- */ 
+/* Splits a list on commas, skipping ones inside parentheses (like in function call arguments). */
+fn split_top_level(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, tok) in tokens.iter().enumerate() {
+        match tok.val {
+            TokenVal::Lparen => depth += 1,
+            TokenVal::Rparen => depth -= 1,
+            TokenVal::Comma if depth == 0 => {
+                parts.push(&tokens[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    parts.push(&tokens[start..]);
+    parts.into_iter().filter(|v| !v.is_empty()).collect()
+}
+
+/* An operand is either `"reg" | identifier`, binding a variable to a specific register, or
+ * `[name] "constraint" (expression)` like in GCC. The name is optional, and outputs have to start
+ * their constraint with = (written only) or + (read and written). */
+fn parse_asm_operand(tokens: &[Token], is_output: bool) -> AsmOperand {
+    if tokens.len() == 3 && tokens[1].val == TokenVal::Ops(Operation::BitOr) {
+        return AsmOperand {
+            name: None,
+            constraint: AsmConstraint::Fixed(get_str(&tokens[0])),
+            read_write: false,
+            expr: parse_expression(tokens[2..].to_vec()),
+            tok: tokens[0].clone(),
+        }
+    }
+    let mut idx = 0;
+    let mut name = None;
+    if tokens[0].val == TokenVal::Lbracket {
+        assert_report(tokens.len() > 3 && tokens[2].val == TokenVal::Rbracket, Component::PARSER, tokens[0].clone(), "Expected a name between [ and ] in inline assembly operand.");
+        name = Some(get_ident(&tokens[1]));
+        idx = 3;
+    }
+    assert_report(tokens.len() > idx + 3 && tokens[idx + 1].val == TokenVal::Lparen && tokens.last().unwrap().val == TokenVal::Rparen, Component::PARSER, tokens[idx].clone(),
+        "Expected inline assembly operand to be either \"reg\" | identifier or [name] \"constraint\" (expression).");
+    let mut constraint_str = get_str(&tokens[idx]);
+    let mut read_write = false;
+    if is_output {
+        assert_report(constraint_str.starts_with(['=', '+']), Component::PARSER, tokens[idx].clone(), "Inline assembly output constraints must start with = or +.");
+        read_write = constraint_str.starts_with('+');
+        constraint_str.remove(0);
+    }
+    let constraint = match constraint_str.as_str() {
+        "r" => AsmConstraint::Reg,
+        "m" => AsmConstraint::Mem,
+        "i" => {
+            assert_report(!is_output, Component::PARSER, tokens[idx].clone(), "Inline assembly outputs can't be immediates.");
+            AsmConstraint::Imm
+        },
+        "" => {
+            report_err(Component::PARSER, tokens[idx].clone(), "Expected a constraint (r, m, i or a register name) in inline assembly operand.");
+            unreachable!();
+        },
+        _ => {
+            assert_report(!constraint_str.starts_with(['=', '+']), Component::PARSER, tokens[idx].clone(), "Only inline assembly outputs can have constraints starting with = or +.");
+            AsmConstraint::Fixed(constraint_str)
+        }
+    };
+    let expr = parse_expression(tokens[idx + 2..tokens.len() - 1].to_vec());
+    AsmOperand { name, constraint, read_write, expr, tok: tokens[idx].clone() }
+}
+
+/* asm(asm : operand, operand : operand : reg, reg, reg);
+ *      ^        ^                 ^            ^
+ * asm source  inputs list     outputs list   clobbered register list
+ *
+ * Operands can be referred to in the source with {name}, or by their position with {0}, {1}, ...
+ * (counting outputs first, then inputs).
+ */
+/* Operands are referred to as {name}, so an identifier in braces which isn't one is almost certainly a
+ * typo, which would otherwise only show up as an assembler error in the generated file. AVX-512 masks
+ * like {k1} and {z} are left alone, as is anything else in braces (like AArch64 register lists). */
+fn check_asm_operand_names(asm: &str, operands: &[&AsmOperand], tok: &Token) {
+    let mut rest = asm;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('}') else { break };
+        let inner = &rest[..end];
+        let is_ident = inner.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && inner.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        let is_mask = inner == "z" || inner == "sae" || (inner.len() == 2 && inner.starts_with('k') && inner.as_bytes()[1].is_ascii_digit());
+        if is_ident && !is_mask {
+            assert_report(operands.iter().any(|o| o.name.as_deref() == Some(inner)), Component::PARSER, tok.clone(),
+                format!("Inline assembly refers to {{{}}}, but no operand is named {}.", inner, inner).as_str());
+        }
+        rest = &rest[end + 1..];
+    }
+}
+
 pub fn parse_inline_asm_statement(tokens: Vec<Token>) -> Statement {
     let asm = get_str(&tokens[2]);
     // get inputs & outputs
@@ -174,31 +297,24 @@ pub fn parse_inline_asm_statement(tokens: Vec<Token>) -> Statement {
     let second_colon_idx = get_index(tokens.clone(), 2, TokenVal::Colon).unwrap();
     let third_colon_idx  = get_index(tokens.clone(), 3, TokenVal::Colon).unwrap();
     let closing_paren_idx = tokens.len() - 2;
-    let input_tokens  = &tokens[first_colon_idx + 1..second_colon_idx];
-    let output_tokens = &tokens[second_colon_idx + 1..third_colon_idx];
-    let input_split: Vec<_> = input_tokens
-        .split(|e| e.val == TokenVal::Comma)
-        .filter(|v| !v.is_empty())
+    let inputs: Vec<AsmOperand> = split_top_level(&tokens[first_colon_idx + 1..second_colon_idx])
+        .into_iter()
+        .map(|t| parse_asm_operand(t, false))
         .collect();
-    let output_split: Vec<_> = output_tokens
-        .split(|e| e.val == TokenVal::Comma)
-        .filter(|v| !v.is_empty())
+    let outputs: Vec<AsmOperand> = split_top_level(&tokens[second_colon_idx + 1..third_colon_idx])
+        .into_iter()
+        .map(|t| parse_asm_operand(t, true))
         .collect();
-    
-    let io_split = Vec::from([input_split.clone(), output_split.clone()]);
-
-    let mut io: Vec<Vec<AsmIOEntry>> = Vec::from([Vec::from([]), Vec::from([])]);
-    for t in 0..2 {
-        for i in 0..io_split[t].len() {
-            assert_report(io_split[t][i][1].val == TokenVal::Ops(Operation::BitOr), Component::PARSER, io_split[t][i][1].clone(), "Expected | in inline assembly input/output between register name and identifier, got other value.");
-            let register = get_str(&io_split[t][i][0]);
-            let identifier = get_ident(&io_split[t][i][2]);
-            io[t].push(AsmIOEntry {
-                register,
-                identifier,
-            });
+    for output in &outputs {
+        assert_report(matches!(output.expr.val, BranchChildVal::Ident(_)), Component::PARSER, output.tok.clone(), "Inline assembly outputs must be a variable.");
+    }
+    let all: Vec<&AsmOperand> = outputs.iter().chain(inputs.iter()).collect();
+    for (i, operand) in all.iter().enumerate() {
+        if let Some(name) = &operand.name {
+            assert_report(!all[..i].iter().any(|o| o.name.as_ref() == Some(name)), Component::PARSER, operand.tok.clone(), format!("Inline assembly operand {} is named more than once.", name).as_str());
         }
     }
+    check_asm_operand_names(&asm, &all, &tokens[2]);
     
     let clobber_tokens = &tokens[third_colon_idx + 1..closing_paren_idx];
     let clobber_split: Vec<_> = clobber_tokens
@@ -206,16 +322,21 @@ pub fn parse_inline_asm_statement(tokens: Vec<Token>) -> Statement {
         .filter(|v| !v.is_empty())
         .collect();
     let mut clobbers = Vec::new();
-    for i in 0..clobber_split.len() {
-        let reg = get_str(&clobber_split[i][0]);
-        clobbers.push(reg);
+    for clobber in clobber_split {
+        let name = get_str(&clobber[0]);
+        // Variables are always reloaded after a block and the flags are never live across one, so these need nothing saved.
+        if name == "memory" || name == "cc" { continue }
+        assert_report(crate::backend::is_register(&name), Component::PARSER, tokens[2].clone(),
+            format!("Inline assembly can only clobber registers, memory or cc, but {} isn't one.", name).as_str());
+        clobbers.push(name);
     }
 
     Statement::InlineAsm(
         InlineAsmStatement {
             asm,
-            inputs: io[0].clone(),
-            outputs: io[1].clone(),
+            asm_tok: tokens[2].clone(),
+            inputs,
+            outputs,
             clobbers
         }
     )
@@ -229,8 +350,8 @@ pub fn parse_func_call_statement(tokens: Vec<Token>) -> Statement {
             ident_tok: tokens[0].clone(),
             fn_ident: identifier,
             args,
-            row: tokens[0].row.clone(),
-            col: tokens[0].col.clone(),
+            row: tokens[0].row,
+            col: tokens[0].col,
         }
     )
 }
@@ -253,12 +374,16 @@ pub fn parse_statement(tokens: Vec<Token>) -> Statement {
                 .map(|parent| parent.val)
                 .collect();
             let (_, signature, _, _, identifier) = parse_func_sig(tokens.clone(), 0, token_vals.clone());
+            crate::intrinsics::check_not_intrinsic(&identifier, &tokens[1]);
             Statement::Extern(ExternStatement {
                 identifier,
                 val: FuncTableVal {
                     signature,
                     statements: None,
-                    is_extern: true
+                    is_extern: true,
+                    is_pub: false,
+                    is_static: false,
+                    attributes: Vec::new(),
                 }
             })
         },
@@ -0,0 +1,74 @@
+/* The standard library, which is written in CTFAW and built into the compiler. Its source files are
+ * parsed along with the program, and only the functions the program ends up calling (directly or
+ * through other functions of the standard library) are kept. Anything the program defines or
+ * declares itself takes priority, so programs which declare the libc functions they use keep working.
+ *
+ * On top of libc, the memory and string functions and the system calls are libc's own. Freestanding
+ * programs get versions written in CTFAW instead, which make system calls with `__syscall`.
+ */
+
+use std::collections::{HashMap, HashSet};
+use crate::parser::*;
+use crate::lexer;
+use crate::typecheck::visit_calls;
+use crate::{Flags, Arch, Os};
+
+const STRING: &str = include_str!("../std/string.ctf");
+const IO: &str = include_str!("../std/io.ctf");
+const PROCESS: &str = include_str!("../std/process.ctf");
+const LIBC: &str = include_str!("../std/libc.ctf");
+const FREESTANDING: &str = include_str!("../std/freestanding.ctf");
+const ALLOC: &str = include_str!("../std/alloc.ctf");
+const LINUX_X86_64: &str = include_str!("../std/linux_x86_64.ctf");
+// AArch64 and RISC-V share the same system call numbers.
+const LINUX_GENERIC: &str = include_str!("../std/linux_generic.ctf");
+
+// Every source of it, for telling when it's changed.
+pub const ALL_SOURCES: [&str; 8] = [STRING, IO, PROCESS, LIBC, FREESTANDING, ALLOC, LINUX_X86_64, LINUX_GENERIC];
+
+// Bare metal programs have no kernel to make system calls to, so they only get the functions which don't need one.
+fn sources(flags: &Flags) -> Vec<&'static str> {
+    if flags.os == Os::None { return vec![STRING, FREESTANDING] }
+    let mut sources = vec![STRING, IO, PROCESS];
+    if flags.freestanding {
+        sources.extend([FREESTANDING, ALLOC, if flags.target == Arch::X86_64 { LINUX_X86_64 } else { LINUX_GENERIC }]);
+    } else {
+        sources.push(LIBC);
+    }
+    sources
+}
+
+fn push_calls(func: &FuncTableVal, queue: &mut Vec<String>) {
+    if let Some(statements) = &func.statements { visit_calls(statements, &mut |call| queue.push(call.fn_ident.clone())) }
+}
+
+/* Adds the functions of the standard library which the program uses to it. They're static, since
+ * every object file which uses one gets its own copy. */
+pub fn add_std(program: &mut HashMap<String, FuncTableVal>, externs: &mut Vec<String>, flags: &Flags) {
+    let mut std_table = HashMap::new();
+    let mut std_externs = Vec::new();
+    for src in sources(flags) {
+        std_table.extend(parse(lexer::lex(src), &mut Vec::new(), &mut std_externs));
+    }
+    let mut queue = Vec::new();
+    for func in program.values() {
+        push_calls(func, &mut queue);
+    }
+    let mut used = HashSet::new();
+    while let Some(name) = queue.pop() {
+        if program.contains_key(&name) || used.contains(&name) { continue }
+        if let Some(func) = std_table.get(&name) {
+            push_calls(func, &mut queue);
+            used.insert(name);
+        }
+    }
+    // Sorted so the output doesn't change from one build to the next.
+    let mut used: Vec<String> = used.into_iter().collect();
+    used.sort();
+    for name in used {
+        if std_externs.contains(&name) { externs.push(name.clone()) }
+        let mut func = std_table.remove(&name).unwrap();
+        func.is_static = !func.is_extern;
+        program.insert(name, func);
+    }
+}
@@ -0,0 +1,41 @@
+/* Everything that depends on the architecture being compiled for sits behind the `Target` trait,
+ * which each backend implements. The IR and everything before it are the same for every target.
+ */
+
+use crate::lexer::*;
+use crate::ir::*;
+use crate::{Arch, Flags};
+use crate::x86_64::X86_64;
+use crate::aarch64::Aarch64;
+use crate::riscv64::Riscv64;
+use crate::wasm32::Wasm32;
+
+pub trait Target {
+    // Registers integer arguments are passed in, in order.
+    fn arg_regs(&self) -> &'static [&'static str];
+    // Takes a (64 bit) register and a type, outputs the name to use for a value of that type in it.
+    fn register_of_size(&self, original: &str, typ: Type) -> String;
+    // How the size of a memory access of the given type is written.
+    fn ptr_ident_of_size(&self, typ: Type) -> String;
+    // Generates the assembly for the whole module.
+    fn compile(&self, module: &Module, flags: &Flags) -> String;
+    // External programs used to assemble (when not using the built in assembler) and link.
+    fn assembler_cmd(&self) -> &'static str;
+    fn linker_cmd(&self) -> &'static str;
+    // Linker used directly for --freestanding, since there's no C runtime or libc to bring in.
+    fn ld_cmd(&self) -> &'static str;
+    // What clang calls the target, for --linker=clang.
+    fn triple(&self) -> &'static str;
+    /* The `_start` used with --freestanding. It's entered with argc at the top of the stack and the
+     * argv array above it, passes them to main, and exits with its result using a syscall. */
+    fn start_stub(&self) -> &'static str;
+}
+
+pub fn get_target(arch: &Arch) -> Box<dyn Target> {
+    match arch {
+        Arch::X86_64 => Box::new(X86_64),
+        Arch::Aarch64 => Box::new(Aarch64),
+        Arch::Riscv64 => Box::new(Riscv64),
+        Arch::Wasm32 => Box::new(Wasm32),
+    }
+}
@@ -3,12 +3,14 @@
 #![allow(unused_variables, unused_imports)]
 
 use crate::backend::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use crate::parser::*;
 use crate::lexer::*;
 use crate::error::*;
 use crate::ast::*;
 use crate::statements::*;
+use crate::intrinsics;
 
 pub fn typecheck_expr(mut expr: BranchChild, vars: &HashMap<String, Type>, program: &HashMap<String, FuncTableVal>) -> Type {
     match expr.val {
@@ -65,6 +67,9 @@ pub fn typecheck_expr(mut expr: BranchChild, vars: &HashMap<String, Type>, progr
         BranchChildVal::Fn(f) => {
             match program.get(&f.fn_ident) {
                 Some(func) => {
+                    assert_report(!func.has_attribute("interrupt"), Component::ANALYSIS, f.ident_tok.clone(), "Interrupt handlers can't be called, they're only run by the CPU.");
+                    let no_value = intrinsics::lookup(&f.fn_ident).is_some_and(|i| !i.returns_value());
+                    assert_report(!no_value, Component::ANALYSIS, f.ident_tok, format!("{} doesn't return a value.", f.fn_ident).as_str());
                     func.signature.ret_type.clone()
                 },
                 None => {
@@ -89,7 +94,7 @@ fn typecheck_simple(ret_type: Type, expr: BranchChild, vars: &HashMap<String, Ty
     }
 }
 
-fn typecheck_function(i: usize, func: (&String, &FuncTableVal), program: &mut HashMap<String, FuncTableVal>, globals: &Vec<GlobalVar>, startwith: &HashMap<String, Type>) {
+fn typecheck_function(func: (&String, &FuncTableVal), program: &mut HashMap<String, FuncTableVal>, globals: &Vec<GlobalVar>, startwith: &HashMap<String, Type>) {
     let mut local_vars = HashMap::new();
     local_vars.extend(startwith.clone());
     for global in globals {
@@ -98,12 +103,7 @@ fn typecheck_function(i: usize, func: (&String, &FuncTableVal), program: &mut Ha
     for arg in func.1.signature.args.clone() {
         local_vars.insert(arg.val, arg.arg_type);
     }
-    let statements_wrapped = func.1.statements.clone();
-    match statements_wrapped {
-        None => return,
-        Some(_) => {},
-    };
-    let statements = statements_wrapped.unwrap();
+    let Some(statements) = func.1.statements.clone() else { return };
     for statement in statements {
         match statement {
             Statement::Extern(s) => {
@@ -112,12 +112,12 @@ fn typecheck_function(i: usize, func: (&String, &FuncTableVal), program: &mut Ha
             Statement::If(s) => {
                 let mut second = func.1.clone();
                 second.statements = Some(s.body.clone());
-                typecheck_function(i, (func.0, &second), program, globals, &local_vars);
+                typecheck_function((func.0, &second), program, globals, &local_vars);
             },
             Statement::While(s) => {
                 let mut second = func.1.clone();
                 second.statements = Some(s.body.clone());
-                typecheck_function(i, (func.0, &second), program, globals, &local_vars);
+                typecheck_function((func.0, &second), program, globals, &local_vars);
             },
             Statement::Define(s) => {
                 typecheck_simple(s.def_type.clone(), s.expr.clone(), &local_vars, false, program);
@@ -139,6 +139,14 @@ fn typecheck_function(i: usize, func: (&String, &FuncTableVal), program: &mut Ha
             Statement::Return(s) => {
                 typecheck_simple(func.1.signature.ret_type.clone(), s.clone(), &local_vars, true, program);
             },
+            Statement::InlineAsm(s) => {
+                for input in &s.inputs {
+                    typecheck_expr(input.expr.clone(), &local_vars, program);
+                }
+                for output in &s.outputs {
+                    assert_report(local_vars.contains_key(output.identifier()), Component::ANALYSIS, output.tok.clone(), format!("Undefined variable: {}", output.identifier()).as_str());
+                }
+            },
             Statement::FuncCall(c) => {
                 let func = match program.get(&c.fn_ident) {
                     Some(f) => f,
@@ -147,6 +155,7 @@ fn typecheck_function(i: usize, func: (&String, &FuncTableVal), program: &mut Ha
                         unreachable!();
                     }
                 };
+                assert_report(!func.has_attribute("interrupt"), Component::ANALYSIS, c.ident_tok.clone(), "Interrupt handlers can't be called, they're only run by the CPU.");
                 match func.signature.varargs_idx {
                     Some(v) => {
                         assert_report(c.args.len() >= v as usize, Component::ANALYSIS, Token {val: TokenVal::Endln, row: c.row, col: c.col}, "Incorrect number of arguments given to function call (has var args)");
@@ -156,10 +165,9 @@ fn typecheck_function(i: usize, func: (&String, &FuncTableVal), program: &mut Ha
                     }
                 }
                 for (i, arg) in c.args.clone().into_iter().enumerate() {
-                    match func.signature.varargs_idx {
-                        Some(v) => if i >= v as usize { break },
-                        None => {}
-                    };
+                    if let Some(v) = func.signature.varargs_idx {
+                        if i >= v as usize { break }
+                    }
                     let val_type = typecheck_expr(arg, &local_vars, program);
                     assert_report(!(val_type != func.signature.args[i].arg_type && val_type.val != TypeVal::Any), Component::ANALYSIS, Token {val: TokenVal::Endln, row: c.row, col: c.col}, format!("Argument {} of function call recieved is type {:?}, expected type {:?}", i, val_type, func.signature.args[i].arg_type).as_str());
                 }
@@ -169,8 +177,65 @@ fn typecheck_function(i: usize, func: (&String, &FuncTableVal), program: &mut Ha
     }
 }
 
-pub fn typecheck(program: &mut HashMap<String, FuncTableVal>, globals: &Vec<GlobalVar>, startwith: &HashMap<String, Type>) {
-    for (i, entry) in program.clone().iter().enumerate() {
-        typecheck_function(i, entry, program, globals, startwith);
+/* Type checks the functions from each file with errors pointing at that file, then everything which
+ * didn't come from one (like the standard library). `files` has the path of each file and the names
+ * of the functions it defines, all of which are already merged into `program`. */
+pub fn typecheck_files(program: &mut HashMap<String, FuncTableVal>, globals: &Vec<GlobalVar>, files: &[(String, Vec<String>)]) {
+    let mut checked = HashSet::new();
+    for (path, names) in files {
+        env::set_var("CTFAW_SRC_FILENAME", path);
+        for name in names {
+            let func = program[name].clone();
+            typecheck_function((name, &func), program, globals, &HashMap::new());
+            checked.insert(name.clone());
+        }
+    }
+    env::set_var("CTFAW_SRC_FILENAME", &files[0].0);
+    for (name, func) in program.clone() {
+        if !checked.contains(&name) { typecheck_function((&name, &func), program, globals, &HashMap::new()) }
+    }
+}
+
+fn check_freestanding_call(call: &FuncCallStatement, program: &HashMap<String, FuncTableVal>, linked: &HashSet<String>) {
+    let is_extern = program.get(&call.fn_ident).is_some_and(|f| f.is_extern) && !linked.contains(&call.fn_ident);
+    assert_report(!is_extern, Component::ANALYSIS, call.ident_tok.clone(),
+        format!("{} is an extern function which no input file defines, and nothing else is linked with --freestanding or --target=x86_64-none (including libc).", call.fn_ident).as_str());
+}
+
+/* Calls `f` with every call in a list of statements, including ones nested inside of expressions
+ * and the bodies of `if`s and `while`s. */
+pub fn visit_calls(statements: &[Statement], f: &mut dyn FnMut(&FuncCallStatement)) {
+    let visit = |expr: &BranchChild, f: &mut dyn FnMut(&FuncCallStatement)| {
+        crate::optimisation::visit_expr(expr, &mut |node| {
+            if let BranchChildVal::Fn(call) = &node.val { f(call) }
+        });
+    };
+    for statement in statements {
+        match statement {
+            Statement::Define(s) => visit(&s.expr, f),
+            Statement::Assign(s) => visit(&s.expr, f),
+            Statement::Return(e) => visit(e, f),
+            Statement::InlineAsm(s) => s.inputs.iter().for_each(|i| visit(&i.expr, f)),
+            Statement::FuncCall(c) => {
+                f(c);
+                c.args.iter().for_each(|a| visit(a, f));
+            },
+            Statement::If(s) => {
+                visit(&s.condition, f);
+                visit_calls(&s.body, f);
+            },
+            Statement::While(s) => {
+                visit(&s.condition, f);
+                visit_calls(&s.body, f);
+            },
+            _ => {},
+        }
+    }
+}
+
+// Freestanding and bare metal programs only have themselves, so they can't call anything declared as extern.
+pub fn check_freestanding(program: &HashMap<String, FuncTableVal>, linked: &HashSet<String>) {
+    for func in program.values() {
+        if let Some(statements) = &func.statements { visit_calls(statements, &mut |call| check_freestanding_call(call, program, linked)) }
     }
 }
@@ -32,8 +32,5 @@ pub fn get_str(tok: &Token) -> String {
 }
 
 pub fn token_is_type(token: TokenVal) -> bool {
-    match token {
-        TokenVal::Type(_) => true,
-        _ => false,
-    }
+    matches!(token, TokenVal::Type(_))
 }
@@ -0,0 +1,403 @@
+/* WebAssembly backend, generating a module in the text format (`.wat`). Virtual registers become
+ * i64 locals (values are kept canonical the same as in registers on other targets), and the slots
+ * live in a stack frame in linear memory so their address can be taken, with the stack pointer in
+ * the `$sp` global. Since wasm only has structured control flow, each function's blocks are run
+ * from a loop which dispatches on the number of the next block with `br_table`.
+ *
+ * Externs become imports from "env". They're given C types (pointers are 32 bit) and variadic
+ * arguments are passed the same way clang does for wasm32: written to a buffer on the stack, with
+ * a pointer to it as the last argument.
+ */
+
+
+use crate::lexer::*;
+use crate::ir::*;
+use crate::parser::FuncSig;
+use crate::Flags;
+use crate::backend::{self, *};
+use crate::target::Target;
+use crate::intrinsics;
+
+// String literals are put from here onwards, leaving address 0 for null.
+const DATA_START: usize = 1024;
+// 16 pages (1MiB) of memory, with the stack growing down from the end.
+const MEMORY_PAGES: usize = 16;
+const STACK_TOP: usize = MEMORY_PAGES * 65536;
+
+const PRELUDE: &str = "(func $ctfaw_pow (param $base i64) (param $exp i64) (result i64)
+  (local $result i64)
+  i64.const 1
+  local.set $result
+  block $done
+    loop $loop
+      local.get $exp
+      i64.eqz
+      br_if $done
+      local.get $result
+      local.get $base
+      i64.mul
+      local.set $result
+      local.get $exp
+      i64.const 1
+      i64.sub
+      local.set $exp
+      br $loop
+    end
+  end
+  local.get $result)";
+
+pub struct Wasm32;
+
+impl Target for Wasm32 {
+    // Arguments are wasm parameters rather than registers.
+    fn arg_regs(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn register_of_size(&self, original: &str, typ: Type) -> String {
+        String::from(original)
+    }
+
+    // Suffix of the memory instructions for an access of the type's size.
+    fn ptr_ident_of_size(&self, typ: Type) -> String {
+        let s = match type_to_size(typ) {
+            1 => "8",
+            2 => "16",
+            4 => "32",
+            8 => "",
+            _ => unreachable!()
+        };
+        String::from(s)
+    }
+
+    fn compile(&self, module: &Module, flags: &Flags) -> String {
+        compile(module, flags.clone())
+    }
+
+    fn assembler_cmd(&self) -> &'static str {
+        "wat2wasm"
+    }
+
+    fn linker_cmd(&self) -> &'static str {
+        "wasm-ld"
+    }
+
+    fn ld_cmd(&self) -> &'static str {
+        "wasm-ld"
+    }
+
+    fn triple(&self) -> &'static str {
+        "wasm32-unknown-unknown"
+    }
+
+    // Modules don't have an entry point like this, so --freestanding isn't allowed.
+    fn start_stub(&self) -> &'static str {
+        ""
+    }
+}
+
+// The wasm value type an argument of this type has in C.
+fn c_abi_type(typ: &Type) -> &'static str {
+    if typ.ptr_depth == 0 && type_to_size(typ.clone()) == 8 { "i64" } else { "i32" }
+}
+
+fn load_op(typ: Type) -> String {
+    let ident = Wasm32.ptr_ident_of_size(typ.clone());
+    if ident.is_empty() { return String::from("i64.load") }
+    format!("i64.load{}_{}", ident, if check_type_signed(typ) { "s" } else { "u" })
+}
+
+fn store_op(typ: Type) -> String {
+    format!("i64.store{}", Wasm32.ptr_ident_of_size(typ))
+}
+
+fn frame_size(func: &Function) -> usize {
+    (func.slots.len() * 8 + 15) & !15
+}
+
+// The result is fixed up on top of the stack.
+fn normalise(out: &mut CompiledAsm, typ: Type) {
+    backend::normalise(out, typ, |size, signed| match (size, signed) {
+        (4, false) => "i64.const 4294967295\ni64.and",
+        (4, true) => "i64.extend32_s",
+        (2, false) => "i64.const 65535\ni64.and",
+        (2, true) => "i64.extend16_s",
+        (_, false) => "i64.const 255\ni64.and",
+        (_, true) => "i64.extend8_s",
+    });
+}
+
+/* Operands are on the stack, and the result is left there. `typ` is the type of the operands. */
+fn compile_operation(out: &mut CompiledAsm, op: Operation, typ: Type) {
+    let is_signed = check_type_signed(typ.clone());
+    let sign = if is_signed { "s" } else { "u" };
+    let inst = match op {
+        Operation::Add => String::from("i64.add"),
+        Operation::Sub => String::from("i64.sub"),
+        Operation::Star => String::from("i64.mul"),
+        Operation::Div => format!("i64.div_{}", sign),
+        Operation::Mod => format!("i64.rem_{}", sign),
+        Operation::LeftShift => String::from("i64.shl"),
+        Operation::RightShift => format!("i64.shr_{}", sign),
+        Operation::Ampersand => String::from("i64.and"),
+        Operation::BitOr => String::from("i64.or"),
+        Operation::BitXor => String::from("i64.xor"),
+        Operation::Pow => String::from("call $ctfaw_pow"),
+        Operation::Less | Operation::LessEqu | Operation::Greater | Operation::GreaterEqu | Operation::Equ | Operation::NotEqu => {
+            let cmp = match op {
+                Operation::Less => format!("lt_{}", sign),
+                Operation::LessEqu => format!("le_{}", sign),
+                Operation::Greater => format!("gt_{}", sign),
+                Operation::GreaterEqu => format!("ge_{}", sign),
+                Operation::Equ => String::from("eq"),
+                _ => String::from("ne"),
+            };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("i64.{}\ni64.extend_i32_u", cmp).as_str());
+            return
+        },
+        _ => panic!("Unsupported operation."),
+    };
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), inst.as_str());
+    normalise(out, typ);
+}
+
+fn compile_unary_operation(out: &mut CompiledAsm, op: Operation, typ: Type, src: VReg) {
+    match op {
+        Operation::BitNot => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $v{}\ni64.const -1\ni64.xor", src.0).as_str());
+            normalise(out, typ);
+        },
+        Operation::Not => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $v{}\ni64.eqz\ni64.extend_i32_u", src.0).as_str());
+        },
+        Operation::Sub => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("i64.const 0\nlocal.get $v{}\ni64.sub", src.0).as_str());
+            normalise(out, typ);
+        },
+        _ => {
+            panic!("Unary operator not implemented yet.");
+        }
+    }
+}
+
+/* Calls to other CTFAW functions pass every argument as an i64. Imports take C types instead, with
+ * variadic arguments written to a buffer on the stack: anything smaller than 64 bits is promoted
+ * to a 32 bit int, and each is aligned to its size. */
+fn compile_func_call(out: &mut CompiledAsm, module: &Module, func: &Function, dst: Option<VReg>, callee: &str, args: &[VReg]) {
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!(";; Call {}", callee).as_str());
+    let ext = if module.functions.iter().any(|f| f.name == callee) {
+        None
+    } else {
+        module.externs.iter().find(|e| e.identifier == callee).map(|e| e.signature.clone())
+    };
+    let ext = match ext {
+        None => {
+            for arg in args {
+                write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $v{}", arg.0).as_str());
+            }
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("call ${}", callee).as_str());
+            match dst {
+                Some(d) => write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.set $v{}", d.0).as_str()),
+                None => write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "drop"),
+            }
+            return
+        },
+        Some(sig) => sig,
+    };
+    let num_fixed = ext.varargs_idx.map(|v| v as usize).unwrap_or(args.len()).min(args.len());
+    let mut buf_size = 0;
+    if ext.varargs_idx.is_some() {
+        let mut offsets = Vec::new();
+        for arg in &args[num_fixed..] {
+            let size = if c_abi_type(&func.vreg_type(*arg)) == "i64" { 8 } else { 4 };
+            buf_size = (buf_size + size - 1) / size * size;
+            offsets.push(buf_size);
+            buf_size += size;
+        }
+        buf_size = (buf_size + 15) & !15;
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("global.get $sp\ni32.const {}\ni32.sub\nlocal.tee $va\nglobal.set $sp", buf_size).as_str());
+        for (arg, off) in args[num_fixed..].iter().zip(offsets) {
+            let store = if c_abi_type(&func.vreg_type(*arg)) == "i64" { "\ni64.store" } else { "\ni32.wrap_i64\ni32.store" };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $va\nlocal.get $v{}{} offset={}", arg.0, store, off).as_str());
+        }
+    }
+    for arg in &args[..num_fixed] {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $v{}", arg.0).as_str());
+        if c_abi_type(&func.vreg_type(*arg)) == "i32" {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "i32.wrap_i64");
+        }
+    }
+    if ext.varargs_idx.is_some() {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "local.get $va");
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("call ${}", callee).as_str());
+    if ext.varargs_idx.is_some() {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $va\ni32.const {}\ni32.add\nglobal.set $sp", buf_size).as_str());
+    }
+    match dst {
+        Some(d) => {
+            if c_abi_type(&ext.ret_type) == "i32" {
+                let extend = if check_type_signed(ext.ret_type.clone()) { "i64.extend_i32_s" } else { "i64.extend_i32_u" };
+                write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), extend);
+            }
+            normalise(out, func.vreg_type(d));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.set $v{}", d.0).as_str());
+        },
+        None => write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "drop"),
+    }
+}
+
+fn compile_inst(out: &mut CompiledAsm, module: &Module, func: &Function, inst: &Inst) {
+    match inst {
+        Inst::Imm { dst, val } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("i64.const {}\nlocal.set $v{}", *val as i64, dst.0).as_str());
+        },
+        Inst::Load { dst, slot } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $fp\n{} offset={}\nlocal.set $v{}", load_op(func.slots[*slot].typ.clone()), slot * 8, dst.0).as_str());
+        },
+        Inst::Store { slot, src } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $fp\nlocal.get $v{}\n{} offset={}", src.0, store_op(func.slots[*slot].typ.clone()), slot * 8).as_str());
+        },
+        Inst::AddrOf { dst, slot } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $fp\ni32.const {}\ni32.add\ni64.extend_i32_u\nlocal.set $v{}", slot * 8, dst.0).as_str());
+        },
+        Inst::StrAddr { dst, idx } => {
+            let addr = DATA_START + module.strings[..*idx].iter().map(|s| s.len() + 1).sum::<usize>();
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("i64.const {}\nlocal.set $v{}", addr, dst.0).as_str());
+        },
+        Inst::LoadPtr { dst, addr } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $v{}\ni32.wrap_i64\n{}\nlocal.set $v{}", addr.0, load_op(func.vreg_type(*dst)), dst.0).as_str());
+        },
+        Inst::StorePtr { addr, src } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $v{}\ni32.wrap_i64\nlocal.get $v{}\n{}", addr.0, src.0, store_op(func.vreg_type(*src))).as_str());
+        },
+        Inst::Binary { dst, op, lhs, rhs } => {
+            let typ = func.vreg_type(*lhs);
+            if *op == Operation::And || *op == Operation::Or {
+                let combine = if *op == Operation::And { "i32.and" } else { "i32.or" };
+                write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $v{}\ni64.const 0\ni64.ne\nlocal.get $v{}\ni64.const 0\ni64.ne\n{}\ni64.extend_i32_u", lhs.0, rhs.0, combine).as_str());
+            } else {
+                write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $v{}\nlocal.get $v{}", lhs.0, rhs.0).as_str());
+                compile_operation(out, *op, typ);
+            }
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.set $v{}", dst.0).as_str());
+        },
+        Inst::Unary { dst, op, src } => {
+            compile_unary_operation(out, *op, func.vreg_type(*dst), *src);
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.set $v{}", dst.0).as_str());
+        },
+        Inst::Cast { dst, src } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $v{}", src.0).as_str());
+            normalise(out, func.vreg_type(*dst));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.set $v{}", dst.0).as_str());
+        },
+        // Trapping is the only intrinsic wasm has an instruction for, the rest are rejected before getting here.
+        Inst::Call { func: callee, .. } if intrinsics::lookup(callee).is_some() => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "unreachable");
+        },
+        Inst::Call { dst, func: callee, args, .. } => {
+            compile_func_call(out, module, func, *dst, callee, args);
+        },
+        Inst::InlineAsm { .. } => {
+            // Rejected before getting here, since there's nothing to pass it through to.
+            unreachable!();
+        },
+    }
+}
+
+fn compile_terminator(out: &mut CompiledAsm, func: &Function, term: &Terminator) {
+    match term {
+        Terminator::Jump(target) => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("i32.const {}\nlocal.set $bb\nbr $dispatch", target.0).as_str());
+        },
+        Terminator::Branch { cond, then_blk, else_blk } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("i32.const {}\ni32.const {}\nlocal.get $v{}\ni64.const 0\ni64.ne\nselect\nlocal.set $bb\nbr $dispatch", then_blk.0, else_blk.0, cond.0).as_str());
+        },
+        Terminator::Return(v) => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), ";; Return from function");
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $v{}\nlocal.get $fp\ni32.const {}\ni32.add\nglobal.set $sp\nreturn", v.0, frame_size(func)).as_str());
+        },
+    }
+}
+
+fn compile_function(out: &mut CompiledAsm, module: &Module, func: &Function) {
+    let params: Vec<String> = func.params.iter().map(|p| format!(" (param $p{} i64)", p)).collect();
+    let export = if func.is_static { String::new() } else { format!(" (export \"{}\")", func.name) };
+    write_text(&mut out.text, String::new(), out.flags.clone(), format!("\n(func ${}{}{} (result i64)", func.name, export, params.concat()).as_str());
+    out.spaces = String::from("  ");
+    let locals: Vec<String> = (0..func.vregs.len()).map(|v| format!(" (local $v{} i64)", v)).collect();
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("(local $fp i32) (local $bb i32) (local $va i32){}", locals.concat()).as_str());
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("global.get $sp\ni32.const {}\ni32.sub\nlocal.tee $fp\nglobal.set $sp", frame_size(func)).as_str());
+    for p in &func.params {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $fp\nlocal.get $p{}\n{} offset={}", p, store_op(func.slots[*p].typ.clone()), p * 8).as_str());
+    }
+    // Each block's code comes after the end of the wasm block with its number, so branching out of that block runs it.
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "loop $dispatch");
+    for block in func.blocks.iter().rev() {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("block $bb{}", block.id.0).as_str());
+    }
+    let targets: Vec<String> = func.blocks.iter().map(|b| format!("$bb{}", b.id.0)).collect();
+    out.spaces.push_str("  ");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("local.get $bb\nbr_table {}", targets.join(" ")).as_str());
+    for block in &func.blocks {
+        write_text(&mut out.text, String::from("  "), out.flags.clone(), format!("end ;; {}", block.id).as_str());
+        for inst in &block.insts {
+            compile_inst(out, module, func, inst);
+        }
+        compile_terminator(out, func, &block.term);
+    }
+    out.spaces = String::from("  ");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "end\nunreachable)");
+}
+
+fn import(name: &str, sig: &FuncSig) -> String {
+    let mut params: Vec<&str> = sig.args.iter().map(|a| c_abi_type(&a.arg_type)).collect();
+    if sig.varargs_idx.is_some() {
+        params.push("i32");
+    }
+    let params: Vec<String> = params.iter().map(|p| format!(" (param {})", p)).collect();
+    format!("(import \"env\" \"{}\" (func ${}{} (result {})))", name, name, params.concat(), c_abi_type(&sig.ret_type))
+}
+
+fn wat_string(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for b in bytes {
+        match b {
+            b'"' | b'\\' => out.push_str(format!("\\{:02x}", b).as_str()),
+            0x20..=0x7E => out.push(*b as char),
+            _ => out.push_str(format!("\\{:02x}", b).as_str()),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn compile(module: &Module, flags: Flags) -> String {
+    let mut out = CompiledAsm::new(flags);
+    for func in &module.functions {
+        compile_function(&mut out, module, func);
+    }
+
+    let mut addr = DATA_START;
+    for strlit in &module.strings {
+        let mut bytes = strlit.as_bytes().to_vec();
+        bytes.push(0); // make sure it has a null terminator
+        write_text(&mut out.data, String::from("  "), out.flags.clone(), format!("(data (i32.const {}) {})", addr, wat_string(&bytes)).as_str());
+        addr += bytes.len();
+    }
+
+    let mut wat = String::from("(module\n");
+    for ext in &module.externs {
+        if module.functions.iter().any(|f| f.name == ext.identifier) { continue }
+        wat.push_str(format!("  {}\n", import(&ext.identifier, &ext.signature)).as_str());
+    }
+    wat.push_str(format!("  (memory (export \"memory\") {})\n", MEMORY_PAGES).as_str());
+    wat.push_str(format!("  (global $sp (mut i32) (i32.const {}))\n", STACK_TOP).as_str());
+    wat.push_str(out.data.as_str());
+    wat.push_str(format!("  {}\n", PRELUDE.replace('\n', "\n  ")).as_str());
+    for line in out.text.lines() {
+        if !line.is_empty() { wat.push_str(format!("  {}\n", line).as_str()) }
+    }
+    wat.push_str(")\n");
+    wat
+}
@@ -0,0 +1,578 @@
+
+use std::fs;
+use std::path::Path;
+use crate::lexer::*;
+use crate::ir::*;
+use crate::Flags;
+use crate::backend::{self, *};
+use crate::target::Target;
+use crate::AsmDialect;
+use crate::peephole;
+use crate::dialect;
+use crate::error::*;
+use crate::intrinsics::{self, Intrinsic};
+
+// Registers in order of arguments for passing into a function with the SYS-V ABI
+const REGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+// Registers "r" operands of inline assembly can be given, which are all free to use between instructions.
+const ASM_SCRATCH_REGS: [&str; 9] = ["rax", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11"];
+// Everything an interrupt handler saves, since the code it interrupted could be using any of them.
+const INTERRUPT_SAVED_REGS: [&str; 15] = ["rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+// Registers the arguments of a system call go in, after the number in rax.
+const SYSCALL_REGS: [&str; 6] = ["rdi", "rsi", "rdx", "r10", "r8", "r9"];
+const MULTIBOOT2_MAGIC: u32 = 0xE85250D6;
+const MULTIBOOT2_HEADER_SIZE: u32 = 24;
+
+pub struct X86_64;
+
+impl Target for X86_64 {
+    fn arg_regs(&self) -> &'static [&'static str] {
+        &REGS
+    }
+
+    fn register_of_size(&self, original: &str, typ: Type) -> String {
+        if original.chars().nth(0).unwrap() == 'r' && original.chars().nth(1).unwrap().is_ascii_digit() {
+            let mut copy = String::from(original);
+            match type_to_size(typ) {
+                1 => copy.push('b'),
+                2 => copy.push('w'),
+                4 => copy.push('d'),
+                _ => {},
+            };
+            return copy
+        }
+        match type_to_size(typ) {
+            1 => {
+                let mut copy = String::from(&original[1..]);
+                copy.replace_range(1..2, "l"); // copy[1] = 'l';
+                if original == "rsi" || original == "rdi" {
+                    copy.insert(1, 'i');
+                }
+                copy
+            },
+            2 => {
+                String::from(&original[1..])
+            },
+            4 => {
+                let mut copy = String::from(original);
+                copy.replace_range(0..1, "e"); // copy[0] = 'e';
+                copy
+            },
+            8 => {
+                String::from(original)
+            },
+            _ => unreachable!()
+        }
+    }
+
+    fn ptr_ident_of_size(&self, typ: Type) -> String {
+        let s = match type_to_size(typ) {
+            1 => "BYTE",
+            2 => "WORD",
+            4 => "DWORD",
+            8 => "QWORD",
+            _ => unreachable!()
+        };
+        String::from(s)
+    }
+
+    fn compile(&self, module: &Module, flags: &Flags) -> String {
+        compile(module, flags.clone())
+    }
+
+    fn assembler_cmd(&self) -> &'static str {
+        "as"
+    }
+
+    fn linker_cmd(&self) -> &'static str {
+        "gcc"
+    }
+
+    fn ld_cmd(&self) -> &'static str {
+        "ld"
+    }
+
+    fn triple(&self) -> &'static str {
+        "x86_64-linux-gnu"
+    }
+
+    fn start_stub(&self) -> &'static str {
+        "_start: xor rbp, rbp
+        mov rdi, [rsp]
+        lea rsi, [rsp + 8]
+        and rsp, -16
+        call main
+        mov rdi, rax
+        mov rax, 60
+        syscall"
+    }
+}
+
+/* Every stack slot and every virtual register gets its own 8 byte home in the stack frame. Slots
+ * come first, directly below the saved rbp, followed by the virtual registers. */
+fn slot_loc(func: &Function, slot: usize) -> String {
+    format!("{} [rbp - {}]", X86_64.ptr_ident_of_size(func.slots[slot].typ.clone()), 8 * (slot + 1))
+}
+
+fn vreg_loc(func: &Function, v: VReg) -> String {
+    format!("QWORD [rbp - {}]", 8 * (func.slots.len() + v.0 + 1))
+}
+
+fn frame_size(func: &Function) -> usize {
+    ((func.slots.len() + func.vregs.len()) * 8 + 15) & !15
+}
+
+// Loads a value of the given type from memory into a full 64 bit register, extending it as needed.
+fn load_extended(reg: &str, loc: &str, typ: Type) -> String {
+    match (type_to_size(typ.clone()), check_type_signed(typ.clone())) {
+        (8, _) => format!("mov {}, {}", reg, loc),
+        (4, false) => format!("mov {}, {}", X86_64.register_of_size(reg, typ), loc),
+        (4, true) => format!("movsxd {}, {}", reg, loc),
+        (_, false) => format!("movzx {}, {}", reg, loc),
+        (_, true) => format!("movsx {}, {}", reg, loc),
+    }
+}
+
+// The result is fixed up in rax.
+fn normalise(out: &mut CompiledAsm, typ: Type) {
+    let rax_sized = X86_64.register_of_size("rax", typ.clone());
+    backend::normalise(out, typ, |size, signed| match (size, signed) {
+        (4, false) => format!("mov {}, {}", rax_sized, rax_sized),
+        (4, true) => format!("movsxd rax, {}", rax_sized),
+        (_, false) => format!("movzx rax, {}", rax_sized),
+        (_, true) => format!("movsx rax, {}", rax_sized),
+    });
+}
+
+/* Operands are in rax and rcx, and returns in rax. `typ` is the type of the operands. */
+fn compile_operation(out: &mut CompiledAsm, op: Operation, typ: Type) {
+    let is_signed = check_type_signed(typ.clone());
+    match op {
+        Operation::Mod | Operation::Div => {
+            if is_signed {
+                write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "cqo\nidiv rcx");
+            } else {
+                write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "xor edx, edx\ndiv rcx");
+            }
+            if op == Operation::Mod {
+                write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mov rax, rdx");
+            }
+        },
+        Operation::GreaterEqu | Operation::LessEqu | Operation::Less | Operation::Greater | Operation::NotEqu | Operation::Equ => {
+            let set = match (op, is_signed) {
+                (Operation::GreaterEqu, true) => "setge",
+                (Operation::GreaterEqu, false) => "setae",
+                (Operation::LessEqu, true) => "setle",
+                (Operation::LessEqu, false) => "setbe",
+                (Operation::Less, true) => "setl",
+                (Operation::Less, false) => "setb",
+                (Operation::Greater, true) => "setg",
+                (Operation::Greater, false) => "seta",
+                (Operation::NotEqu, _) => "setne",
+                _ => "sete",
+            };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("cmp rax, rcx\n{} al\nmovzx eax, al", set).as_str());
+            return
+        },
+        Operation::And | Operation::Or => {
+            let combine = if op == Operation::And { "and" } else { "or" };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("test rax, rax\nsetnz al\ntest rcx, rcx\nsetnz cl\n{} al, cl\nmovzx eax, al", combine).as_str());
+            return
+        },
+        Operation::BitXor => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "xor rax, rcx");
+        },
+        Operation::Ampersand => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "and rax, rcx");
+        },
+        Operation::BitOr => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "or rax, rcx");
+        },
+        Operation::LeftShift => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "shl rax, cl");
+        },
+        Operation::RightShift => {
+            let shift = if is_signed { "sar" } else { "shr" };
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{} rax, cl", shift).as_str());
+        },
+        Operation::Star => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "imul rax, rcx");
+        },
+        Operation::Add => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "add rax, rcx");
+        },
+        Operation::Sub => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "sub rax, rcx");
+        },
+        Operation::Pow => {
+            let loop_label = format!("sect{}", out.num_subroutines);
+            let done_label = format!("sect{}", out.num_subroutines + 1);
+            out.num_subroutines += 2;
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mov rdx, rax\nmov eax, 1");
+            write_text(&mut out.text, String::new(), out.flags.clone(), format!("{}:", loop_label).as_str());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("test rcx, rcx\njz {}\nimul rax, rdx\ndec rcx\njmp {}", done_label, loop_label).as_str());
+            write_text(&mut out.text, String::new(), out.flags.clone(), format!("{}:", done_label).as_str());
+        },
+        _ => {
+            panic!("Unsupported operation.")
+        }
+    }
+    normalise(out, typ);
+}
+
+fn compile_unary_operation(out: &mut CompiledAsm, op: Operation, typ: Type) {
+    match op {
+        Operation::BitNot => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "not rax");
+            normalise(out, typ);
+        },
+        Operation::Not => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "test rax, rax\nsetz al\nmovzx eax, al");
+        },
+        Operation::Sub => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "neg rax");
+            normalise(out, typ);
+        },
+        _ => {
+            panic!("Unary operator not implemented yet.");
+        }
+    }
+}
+
+/* Operands in registers are loaded before the block and stored after it, extended to 64 bits but
+ * substituted as the view of the register matching their type. Memory operands are used from the
+ * variable's slot (or the input's virtual register) directly. */
+pub fn compile_inline_asm(out: &mut CompiledAsm, func: &Function, asm: &str, inputs: &[AsmInput], outputs: &[AsmOutput], clobbers: &[String], tok: &Token) {
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), ";; Inline assembly");
+    let regs = pick_asm_regs(inputs, outputs, clobbers, &ASM_SCRATCH_REGS, tok);
+    for clobber in clobbers {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("push {}", clobber).as_str());
+    }
+    let mut texts = Vec::new();
+    for (output, reg) in outputs.iter().zip(&regs) {
+        let typ = func.slots[output.slot].typ.clone();
+        match reg {
+            Some(reg) => {
+                if output.read_write {
+                    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), load_extended(reg, slot_loc(func, output.slot).as_str(), typ.clone()).as_str());
+                }
+                texts.push(X86_64.register_of_size(reg, typ));
+            },
+            None => texts.push(slot_loc(func, output.slot)),
+        }
+    }
+    for (input, reg) in inputs.iter().zip(&regs[outputs.len()..]) {
+        match (&input.val, reg) {
+            (AsmValue::Imm(v), _) => texts.push(v.to_string()),
+            (AsmValue::VReg(v), Some(reg)) => {
+                write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov {}, {}", reg, vreg_loc(func, *v)).as_str());
+                texts.push(X86_64.register_of_size(reg, func.vreg_type(*v)));
+            },
+            (AsmValue::VReg(v), None) => texts.push(format!("{} [rbp - {}]", X86_64.ptr_ident_of_size(func.vreg_type(*v)), 8 * (func.slots.len() + v.0 + 1))),
+        }
+    }
+    write_inline_asm(out, substitute_asm_operands(asm, &asm_operand_names(inputs, outputs), &texts).as_str());
+    for (output, reg) in outputs.iter().zip(&regs) {
+        if let Some(reg) = reg {
+            let typ = func.slots[output.slot].typ.clone();
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov {}, {}", slot_loc(func, output.slot), X86_64.register_of_size(reg, typ)).as_str());
+        }
+    }
+    for clobber in clobbers.iter().rev() {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("pop {}", clobber).as_str());
+    }
+}
+
+/* Nothing is kept in registers between instructions, so intrinsics can use (and clobber) any of the
+ * caller-saved ones. rbx is callee-saved though, and cpuid writes to it. */
+fn compile_intrinsic(out: &mut CompiledAsm, func: &Function, dst: Option<VReg>, intrinsic: Intrinsic, args: &[VReg]) {
+    let asm = match intrinsic {
+        Intrinsic::Syscall => {
+            let mut asm = format!("mov rax, {}", vreg_loc(func, args[0]));
+            for (reg, arg) in SYSCALL_REGS.iter().zip(&args[1..]) {
+                asm.push_str(format!("\nmov {}, {}", reg, vreg_loc(func, *arg)).as_str());
+            }
+            asm + "\nsyscall"
+        },
+        Intrinsic::Outb => format!("mov rdx, {}\nmov rax, {}\nout dx, al", vreg_loc(func, args[0]), vreg_loc(func, args[1])),
+        Intrinsic::Inb => format!("mov rdx, {}\nxor eax, eax\nin al, dx", vreg_loc(func, args[0])),
+        Intrinsic::Rdtsc => String::from("rdtsc\nshl rdx, 32\nor rax, rdx"),
+        Intrinsic::Cpuid => format!("mov rax, {}\nmov rcx, {}\nmov rsi, {}\nmov rdi, {}\nmov r8, {}\nmov r9, {}\npush rbx\ncpuid\nmov DWORD [rsi], eax\nmov DWORD [rdi], ebx\nmov DWORD [r8], ecx\nmov DWORD [r9], edx\npop rbx",
+            vreg_loc(func, args[0]), vreg_loc(func, args[1]), vreg_loc(func, args[2]), vreg_loc(func, args[3]), vreg_loc(func, args[4]), vreg_loc(func, args[5])),
+        Intrinsic::Pause => String::from("pause"),
+        Intrinsic::Hlt => String::from("hlt"),
+        Intrinsic::Unreachable | Intrinsic::Trap => String::from("ud2"),
+    };
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), asm.as_str());
+    if let Some(dst) = dst {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov {}, rax", vreg_loc(func, dst)).as_str());
+    }
+}
+
+pub fn compile_func_call(out: &mut CompiledAsm, func: &Function, dst: Option<VReg>, callee: &str, args: &[VReg]) {
+    if let Some(intrinsic) = intrinsics::lookup(callee) {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!(";; Intrinsic {}", callee).as_str());
+        return compile_intrinsic(out, func, dst, intrinsic, args)
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!(";; Call {}", callee).as_str());
+    // Arguments past the sixth go on the stack, which must stay 16 byte aligned at the call.
+    let num_stack_args = args.len().saturating_sub(X86_64.arg_regs().len());
+    let padding = if num_stack_args % 2 == 1 { 8 } else { 0 };
+    if padding != 0 {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("sub rsp, {}", padding).as_str());
+    }
+    for arg in args.iter().skip(X86_64.arg_regs().len()).rev() {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("push {}", vreg_loc(func, *arg)).as_str());
+    }
+    for (reg, arg) in X86_64.arg_regs().iter().zip(args.iter()) {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov {}, {}", reg, vreg_loc(func, *arg)).as_str());
+    }
+    // al holds the number of vector registers used by a variadic call, which is always none.
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "xor eax, eax");
+    // Position independent code calls functions through the PLT, since they might be in another shared object.
+    let plt = if out.flags.pic { " wrt ..plt" } else { "" };
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("call {}{}", callee, plt).as_str());
+    if num_stack_args > 0 {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("add rsp, {}", num_stack_args * 8 + padding).as_str());
+    }
+    if let Some(dst) = dst {
+        normalise(out, func.vreg_type(dst));
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov {}, rax", vreg_loc(func, dst)).as_str());
+    }
+}
+
+fn compile_inst(out: &mut CompiledAsm, func: &Function, inst: &Inst) {
+    match inst {
+        Inst::Imm { dst, val } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rax, {}\nmov {}, rax", val, vreg_loc(func, *dst)).as_str());
+        },
+        Inst::Load { dst, slot } => {
+            let load = load_extended("rax", slot_loc(func, *slot).as_str(), func.slots[*slot].typ.clone());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("{}\nmov {}, rax", load, vreg_loc(func, *dst)).as_str());
+        },
+        Inst::Store { slot, src } => {
+            let rax_sized = X86_64.register_of_size("rax", func.slots[*slot].typ.clone());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rax, {}\nmov {}, {}", vreg_loc(func, *src), slot_loc(func, *slot), rax_sized).as_str());
+        },
+        Inst::AddrOf { dst, slot } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("lea rax, [rbp - {}]\nmov {}, rax", 8 * (slot + 1), vreg_loc(func, *dst)).as_str());
+        },
+        Inst::StrAddr { dst, idx } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("lea rax, [strlit{}]\nmov {}, rax", idx, vreg_loc(func, *dst)).as_str());
+        },
+        Inst::LoadPtr { dst, addr } => {
+            let typ = func.vreg_type(*dst);
+            let loc = format!("{} [rax]", X86_64.ptr_ident_of_size(typ.clone()));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rax, {}\n{}\nmov {}, rax", vreg_loc(func, *addr), load_extended("rax", loc.as_str(), typ), vreg_loc(func, *dst)).as_str());
+        },
+        Inst::StorePtr { addr, src } => {
+            let typ = func.vreg_type(*src);
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rcx, {}\nmov rax, {}\nmov {} [rcx], {}", vreg_loc(func, *addr), vreg_loc(func, *src), X86_64.ptr_ident_of_size(typ.clone()), X86_64.register_of_size("rax", typ)).as_str());
+        },
+        Inst::Binary { dst, op, lhs, rhs } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rax, {}\nmov rcx, {}", vreg_loc(func, *lhs), vreg_loc(func, *rhs)).as_str());
+            compile_operation(out, *op, func.vreg_type(*lhs));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov {}, rax", vreg_loc(func, *dst)).as_str());
+        },
+        Inst::Unary { dst, op, src } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rax, {}", vreg_loc(func, *src)).as_str());
+            compile_unary_operation(out, *op, func.vreg_type(*dst));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov {}, rax", vreg_loc(func, *dst)).as_str());
+        },
+        Inst::Cast { dst, src } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rax, {}", vreg_loc(func, *src)).as_str());
+            normalise(out, func.vreg_type(*dst));
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov {}, rax", vreg_loc(func, *dst)).as_str());
+        },
+        Inst::Call { dst, func: callee, args, .. } => {
+            compile_func_call(out, func, *dst, callee, args);
+        },
+        Inst::InlineAsm { asm, inputs, outputs, clobbers, tok } => {
+            compile_inline_asm(out, func, asm, inputs, outputs, clobbers, tok);
+        },
+    }
+}
+
+fn compile_epilogue(out: &mut CompiledAsm, func: &Function) {
+    if func.interrupt {
+        // The stack was realigned, so rbp is the only way back to where the registers were saved.
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mov rsp, rbp\npop rbp");
+        for reg in INTERRUPT_SAVED_REGS.iter().rev() {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("pop {}", reg).as_str());
+        }
+        if !func.params.is_empty() {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), ";; Drop the error code pushed by the CPU");
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "add rsp, 8");
+        }
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "iretq");
+        return
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("add rsp, {}", frame_size(func)).as_str());
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "pop rbp");
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "ret");
+}
+
+fn compile_terminator(out: &mut CompiledAsm, func: &Function, term: &Terminator, base: u64) {
+    match term {
+        Terminator::Jump(target) => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("jmp {}", block_label(base, *target)).as_str());
+        },
+        Terminator::Branch { cond, then_blk, else_blk } => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rax, {}\ntest rax, rax", vreg_loc(func, *cond)).as_str());
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("je {}\njmp {}", block_label(base, *else_blk), block_label(base, *then_blk)).as_str());
+        },
+        Terminator::Return(v) => {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), ";; Return from function");
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rax, {}", vreg_loc(func, *v)).as_str());
+            compile_epilogue(out, func);
+        },
+    }
+}
+
+fn compile_function(out: &mut CompiledAsm, func: &Function) {
+    if !func.is_static { out.globals.push(func.name.clone()) }
+    out.spaces.clear();
+    if let Some(section) = &func.section {
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("\nsection {} exec", section).as_str());
+    }
+    if func.naked {
+        compile_naked_function(out, func);
+        if func.section.is_some() {
+            write_text(&mut out.text, String::new(), out.flags.clone(), "section .text");
+        }
+        return
+    }
+    let first = if func.interrupt { INTERRUPT_SAVED_REGS[0] } else { "rbp" };
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("\n{}: push {}", func.name, first).as_str());
+    for _ in func.name.chars() {
+        out.spaces.push(' ');
+    }
+    out.spaces.push_str("  ");
+    if func.interrupt {
+        for reg in &INTERRUPT_SAVED_REGS[1..] {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("push {}", reg).as_str());
+        }
+        // The interrupted code could have left the direction flag set, but the ABI expects it clear.
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "cld");
+        if !func.params.is_empty() {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), ";; The error code is just above the saved registers");
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rdi, QWORD [rsp + {}]", INTERRUPT_SAVED_REGS.len() * 8).as_str());
+        }
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "push rbp");
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "mov rbp, rsp");
+    if func.interrupt {
+        // Interrupts can happen with any stack alignment.
+        write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), "and rsp, -16");
+    }
+    write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("sub rsp, {}", frame_size(func)).as_str());
+    for (i, slot) in func.params.iter().enumerate() {
+        let typ = func.slots[*slot].typ.clone();
+        if i < X86_64.arg_regs().len() {
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov {}, {}", slot_loc(func, *slot), X86_64.register_of_size(X86_64.arg_regs()[i], typ)).as_str());
+        } else {
+            // The caller pushed these, so they sit above the return address and saved rbp.
+            let stack_off = 16 + (i - X86_64.arg_regs().len()) * 8;
+            write_text(&mut out.text, out.spaces.clone(), out.flags.clone(), format!("mov rax, QWORD [rbp + {}]\nmov {}, {}", stack_off, slot_loc(func, *slot), X86_64.register_of_size("rax", typ)).as_str());
+        }
+    }
+    let base = out.num_subroutines;
+    out.num_subroutines += func.blocks.len() as u64;
+    for block in &func.blocks {
+        write_text(&mut out.text, String::new(), out.flags.clone(), format!("{}:", block_label(base, block.id)).as_str());
+        for inst in &block.insts {
+            compile_inst(out, func, inst);
+        }
+        compile_terminator(out, func, &block.term, base);
+    }
+    if func.section.is_some() {
+        write_text(&mut out.text, String::new(), out.flags.clone(), "section .text");
+    }
+}
+
+/* Constants with a section and the Multiboot2 header are the only data that doesn't come from the
+ * functions themselves. The header has no tags other than the one ending the list, so the
+ * bootloader loads the ELF by its program headers. */
+fn compile_sections(out: &mut CompiledAsm, module: &Module) -> String {
+    let mut sections = String::new();
+    if out.flags.multiboot2 {
+        let checksum = 0u32.wrapping_sub(MULTIBOOT2_MAGIC.wrapping_add(MULTIBOOT2_HEADER_SIZE));
+        write_text(&mut sections, String::new(), out.flags.clone(), ";; Multiboot2 header: magic, architecture (i386), length, checksum, then the end tag");
+        write_text(&mut sections, String::new(), out.flags.clone(), format!("section .multiboot\nalign 8\nmultiboot2_header: dd {}, 0, {}, {}\ndw 0, 0\ndd 8", MULTIBOOT2_MAGIC, MULTIBOOT2_HEADER_SIZE, checksum).as_str());
+    }
+    for global in &module.globals {
+        let section = match global.section() {
+            Some(section) => section,
+            None => continue,
+        };
+        let size = type_to_size(global.typ.clone());
+        let directive = match size {
+            1 => "db",
+            2 => "dw",
+            4 => "dd",
+            _ => "dq",
+        };
+        let val = if size >= 8 { global.val } else { global.val & ((1u64 << (size * 8)) - 1) };
+        out.globals.push(global.identifier.clone());
+        write_text(&mut sections, String::new(), out.flags.clone(), format!("section {}\nalign {}\n{}: {} {}", section, size, global.identifier, directive, val).as_str());
+    }
+    sections
+}
+
+fn compile(module: &Module, flags: Flags) -> String {
+    let mut out = CompiledAsm::new(flags);
+    write_start_stub(&mut out, module, &X86_64);
+    for func in &module.functions {
+        compile_function(&mut out, func);
+    }
+    let sections = compile_sections(&mut out, module);
+
+    out.spaces.clear();
+
+    let mut text_lines: Vec<String> = out.text.lines().map(String::from).collect();
+    if out.flags.opt_level >= 1 {
+        let stats = peephole::optimise(&mut text_lines);
+        if out.flags.stats {
+            for (rule, hits) in stats.hits {
+                println!("[ STAT ] peephole {}: {}", rule, hits);
+            }
+        }
+    }
+    peephole::remove_asm_markers(&mut text_lines);
+
+    for (i, strlit) in module.strings.iter().enumerate() {
+        let mut stringchars: Vec<String> = strlit.bytes().map(|c| c.to_string()).collect();
+        stringchars.push(String::from("0")); // make sure it has a null terminator
+        write_text(&mut out.rodata, out.spaces.clone(), out.flags.clone(), format!("strlit{}: db {}", i, stringchars.join(", ")).as_str());
+    }
+
+    let mut asm = String::from("[BITS 64]\n");
+    // Makes labels in memory operands (like strlit0) relative to rip, rather than absolute addresses.
+    if out.flags.pic { asm.push_str("default rel\n") }
+    asm.push('\n');
+    for global in out.globals {
+        asm.push_str(format!("global {}\n", global).as_str());
+    }
+    for ext in &module.externs {
+        asm.push_str(format!("extern {}\n", ext.identifier).as_str());
+    }
+    asm.push_str(format!("\nsection .text\n{}\n", text_lines.join("\n")).as_str());
+    asm.push_str(format!("section .data\n\n{}", out.data).as_str());
+    asm.push_str(format!("section .rodata\n\n{}", out.rodata).as_str());
+    asm.push_str(sections.as_str());
+
+    let file_name = out.flags.asm_file.as_str();
+    if out.flags.asm_dialect == AsmDialect::Gas {
+        asm = match dialect::to_gas(&asm) {
+            Ok(gas) => gas,
+            Err((line, msg)) => {
+                // The error is in the NASM syntax assembly, so that's what gets kept.
+                let nasm_file = Path::new(file_name).with_extension("asm").to_string_lossy().to_string();
+                let _ = fs::write(&nasm_file, &asm);
+                report_asm_err(nasm_file.as_str(), line, asm.lines().nth(line - 1).unwrap_or(""), msg.as_str());
+                unreachable!()
+            },
+        };
+    }
+    asm
+}
@@ -0,0 +1,28 @@
+/* Builds the program in tests/cimport, which imports a C header of its own and one of libc's, and
+ * generates a header for the function it defines. */
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn import_c_program() {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cimport");
+    let expected = fs::read_to_string(src.join("main.out")).unwrap();
+    let dir = common::build_dir("cimport");
+    let exe = common::build(&dir, &[src.join("main.ctf").to_str().unwrap()]);
+    let output = common::run(&exe, &[], &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn emit_header() {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cimport");
+    let expected = fs::read_to_string(src.join("main.h")).unwrap();
+    let dir = common::build_dir("header");
+    common::compile(&dir, &[src.join("main.ctf").to_str().unwrap(), "--emit=header", "-o", "main.h"]);
+    assert_eq!(fs::read_to_string(dir.join("main.h")).unwrap(), expected);
+    let _ = fs::remove_dir_all(&dir);
+}
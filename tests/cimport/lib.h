@@ -0,0 +1,39 @@
+/* A header in the style of a C library's, using libc's own functions. */
+#ifndef LIB_H
+#define LIB_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define LIB_VERSION 3
+#define LIB_FLAG (0x10u)
+#define LIB_ERROR (-2)
+#define LIB_LAST LIB_VERSION
+#define LIB_MAX(a, b) ((a) > (b) ? (a) : (b))
+
+typedef unsigned long lib_size;
+typedef struct lib_buffer lib_buffer;
+typedef enum { LIB_RED, LIB_GREEN } lib_colour;
+
+struct lib_point {
+    int x, y;
+};
+
+lib_size strlen(const char *s);
+int abs(int n) __attribute__((const));
+char *strchr(const char *s, int c);
+int puts(const char *s);
+void qsort(void *base, size_t n, size_t size, int (*compare)(const void *, const void *));
+
+static inline int lib_twice(int n) {
+    return n * 2;
+}
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,10 @@
+/* Generated by CTFAW. CTFAW's char is unsigned, so build with -funsigned-char for the same results. */
+#ifndef MAIN_H
+#define MAIN_H
+
+#include <stdint.h>
+#include <stdbool.h>
+
+int32_t lib_add(int32_t a, int32_t b);
+
+#endif /* MAIN_H */
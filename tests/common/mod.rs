@@ -0,0 +1,54 @@
+/* Helpers shared by the tests which build CTFAW programs and run them. */
+
+// Not every test uses every helper.
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// The compiler writes its output to the current directory, so every build gets its own.
+pub fn build_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ctfaw-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Runs the compiler in `dir` with the given arguments, panicking with its output if it fails.
+pub fn compile(dir: &Path, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_ctfaw"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?} didn't compile:\n{}", args, String::from_utf8_lossy(&output.stdout));
+    output
+}
+
+// Builds a program in `dir`, returning the path to the executable.
+pub fn build(dir: &Path, args: &[&str]) -> PathBuf {
+    let build = compile(dir, args);
+    assert!(dir.join("out").exists(), "{:?} didn't build:\n{}", args, String::from_utf8_lossy(&build.stdout));
+    dir.join("out")
+}
+
+pub fn run(exe: &Path, args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(exe)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Tests needing a tool which isn't installed (like an assembler or emulator) are skipped.
+pub fn has_tool(name: &str) -> bool {
+    let found = Command::new(name).arg("--version").stdout(Stdio::null()).stderr(Stdio::null()).status().is_ok();
+    if !found { println!("Skipping, since {} isn't installed.", name) }
+    found
+}
@@ -0,0 +1,60 @@
+/* Builds the kernel in tests/kernel for bare metal, and checks that everything ends up in the section
+ * it was put in at every optimisation level and with both assembly dialects, and that the linked
+ * kernel starts at kmain with a valid Multiboot2 header. */
+
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn readelf(file: &Path, args: &[&str]) -> String {
+    let output = Command::new("readelf").arg("-W").args(args).arg(file).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// The section each symbol is defined in, and its value.
+fn symbols(file: &Path) -> HashMap<String, (String, u64)> {
+    let sections: HashMap<String, String> = readelf(file, &["-S"]).lines()
+        .filter_map(|l| l.trim().strip_prefix('['))
+        .filter_map(|l| l.split_once(']'))
+        .filter_map(|(num, rest)| Some((num.trim().to_string(), rest.split_whitespace().next()?.to_string())))
+        .collect();
+    readelf(file, &["-s"]).lines().filter_map(|l| {
+        let fields: Vec<&str> = l.split_whitespace().collect();
+        let [_, value, _, _, _, _, ndx, name] = fields[..] else { return None };
+        Some((name.to_string(), (sections.get(ndx)?.clone(), u64::from_str_radix(value, 16).ok()?)))
+    }).collect()
+}
+
+#[test]
+fn kernel_sections() {
+    if !common::has_tool("readelf") { return }
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/kernel");
+    let kernel = src.join("kernel.ctf");
+    for level in ["-O0", "-O1", "-O2"] {
+        for dialect in ["--asm-dialect=nasm", "--asm-dialect=gas"] {
+            let dir = common::build_dir(&format!("kernel{}-{}", level, &dialect[14..]));
+            common::compile(&dir, &[kernel.to_str().unwrap(), "--target=x86_64-none", level, dialect, "-c"]);
+            let obj = dir.join("out.o");
+            let sections = symbols(&obj);
+            for (name, section) in [("kmain", ".text.boot"), ("putc", ".text"), ("other", ".text.other"), ("KERNEL_VERSION", ".kdata"), ("SIGNED", ".kdata")] {
+                assert_eq!(sections[name].0, section, "{} is in the wrong section with {} {}", name, level, dialect);
+            }
+            // 0xCAFEBABE, then -2 as an i16.
+            assert!(readelf(&obj, &["-x", ".kdata"]).contains("bebafeca feff"));
+
+            common::compile(&dir, &[kernel.to_str().unwrap(), "--target=x86_64-none", level, dialect, "--multiboot2", "--linker-script", src.join("link.ld").to_str().unwrap()]);
+            let exe = dir.join("out");
+            let entry = readelf(&exe, &["-h"]).lines().find_map(|l| l.trim().strip_prefix("Entry point address:")).unwrap().trim().to_string();
+            assert_eq!(u64::from_str_radix(entry.trim_start_matches("0x"), 16).unwrap(), symbols(&exe)["kmain"].1);
+            let bytes = fs::read(&exe).unwrap();
+            let words = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+            let header = (0..32768.min(bytes.len() - 16)).step_by(8).find(|&at| words(at) == 0xE85250D6).expect("no Multiboot2 header");
+            assert_eq!((0..4).fold(0u32, |sum, i| sum.wrapping_add(words(header + i * 4))), 0);
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+/* Links the program in tests/link against C code given as an object file and as a library, checks
+ * that a failed link is reported as one, builds a shared library for a C program to load, and builds
+ * a static library for a CTFAW program to link against. */
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn link_objects_and_libraries() {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/link");
+    let dir = common::build_dir("link");
+    for c in ["foo.c", "bar.c"] {
+        assert!(Command::new("gcc").args(["-c", src.join(c).to_str().unwrap()]).current_dir(&dir).status().unwrap().success());
+    }
+    fs::create_dir(dir.join("lib")).unwrap();
+    assert!(Command::new("ar").args(["rcs", "lib/libfoo.a", "foo.o"]).current_dir(&dir).status().unwrap().success());
+    let main = src.join("main.ctf");
+    for extra in [&["-L", "lib", "-lfoo"][..], &["lib/libfoo.a", "--static"], &["-Llib", "-lfoo", "--pie", "--linker=ld"]] {
+        let _ = fs::remove_file(dir.join("out"));
+        let mut args = vec![main.to_str().unwrap(), "bar.o"];
+        args.extend(extra);
+        let exe = common::build(&dir, &args);
+        assert_eq!(String::from_utf8_lossy(&common::run(&exe, &[], &[]).stdout), "42\n");
+    }
+    // Without the library, foo is undefined.
+    let _ = fs::remove_file(dir.join("out"));
+    let build = Command::new(env!("CARGO_BIN_EXE_ctfaw")).args([main.to_str().unwrap(), "bar.o", "-r"]).current_dir(&dir).output().unwrap();
+    assert!(!build.status.success());
+    assert!(String::from_utf8_lossy(&build.stdout).contains("linker error"));
+    assert!(!String::from_utf8_lossy(&build.stdout).contains("Built successfully"));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn shared_library() {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/link");
+    let dir = common::build_dir("shared");
+    let lib = src.join("shared.ctf");
+    for extra in [&[][..], &["--assembler=as", "--asm-dialect=gas"]] {
+        let mut args = vec![lib.to_str().unwrap(), "--shared", "-o", "libshared.so"];
+        args.extend(extra);
+        common::compile(&dir, &args);
+        assert!(Command::new("gcc").args([src.join("dlopen.c").to_str().unwrap(), "-o", "dlopen", "-ldl"]).current_dir(&dir).status().unwrap().success());
+        // Only greet is pub, so helper isn't exported.
+        let output = common::run(&dir.join("dlopen"), &[dir.join("libshared.so").to_str().unwrap()], &[]);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "hello 40\n41 (nil)\n");
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn static_library() {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/link/staticlib");
+    let dir = common::build_dir("staticlib");
+    let (helpers, io) = (src.join("arithmetic_helpers.ctf"), src.join("io.ctf"));
+    // arithmetic_helpers.o is too long for a member header, so it goes in the long name table.
+    common::compile(&dir, &[helpers.to_str().unwrap(), io.to_str().unwrap(), "--emit=staticlib", "-o", "libutil.a"]);
+    let index = Command::new("nm").args(["--print-armap", "libutil.a"]).current_dir(&dir).output().unwrap();
+    let index = String::from_utf8_lossy(&index.stdout);
+    assert!(index.contains("triple in arithmetic_helpers.o") && index.contains("shout in io.o"), "{}", index);
+    let main = src.join("main.ctf");
+    for extra in [&["libutil.a"][..], &["-L.", "-lutil"]] {
+        let _ = fs::remove_file(dir.join("out"));
+        let mut args = vec![main.to_str().unwrap()];
+        args.extend(extra);
+        let exe = common::build(&dir, &args);
+        assert_eq!(String::from_utf8_lossy(&common::run(&exe, &[], &[]).stdout), "hey!\n42\n");
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,9 @@
+#include <dlfcn.h>
+#include <stdio.h>
+int main(int argc, char **argv) {
+    void *lib = dlopen(argv[1], RTLD_NOW);
+    if (!lib) { printf("%s\n", dlerror()); return 1; }
+    int (*greet)(int) = (int (*)(int))dlsym(lib, "greet");
+    printf("%d %p\n", greet(20), dlsym(lib, "helper"));
+    return 0;
+}
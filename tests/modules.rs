@@ -0,0 +1,36 @@
+/* Builds the program in tests/modules, which is split across two separately compiled files and
+ * modules imported by name, by path, in a cycle and from a directory given with -I. Also checks
+ * that a failed build doesn't leave its temporary directory behind. */
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn modules_program() {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/modules");
+    let expected = fs::read_to_string(src.join("main.out")).unwrap();
+    let dir = common::build_dir("modules");
+    let main = src.join("main.ctf");
+    let second = src.join("second.ctf");
+    let include = src.join("include");
+    let exe = common::build(&dir, &[main.to_str().unwrap(), second.to_str().unwrap(), "-I", include.to_str().unwrap()]);
+    let output = common::run(&exe, &[], &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn failed_build_cleans_up() {
+    let dir = common::build_dir("modules-failed");
+    let tmp = dir.join("tmp");
+    fs::create_dir(&tmp).unwrap();
+    fs::write(dir.join("good.ctf"), "fn main() -> i32 {\n    return 0;\n}\n").unwrap();
+    fs::write(dir.join("bad.ctf"), "fn broken() -> i32 {\n    return missing;\n}\n").unwrap();
+    let build = Command::new(env!("CARGO_BIN_EXE_ctfaw")).args(["good.ctf", "bad.ctf"]).env("TMPDIR", &tmp).current_dir(&dir).output().unwrap();
+    assert!(!build.status.success());
+    assert_eq!(fs::read_dir(&tmp).unwrap().count(), 0, "{}", String::from_utf8_lossy(&build.stdout));
+    let _ = fs::remove_dir_all(&dir);
+}
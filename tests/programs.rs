@@ -0,0 +1,312 @@
+/* Builds every program in tests/programs and checks what it prints against its `.out` file, and
+ * that it exits with the status in its `.status` file (or 0 without one).
+ */
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Output};
+
+const LEVELS: [&str; 3] = ["-O0", "-O1", "-O2"];
+
+struct Program {
+    name: String,
+    src: PathBuf,
+    expected: String,
+    status: i32,
+    // Inline assembly is written for x86_64, so programs using it are only built for that.
+    x86_64_only: bool,
+    // Interrupt handlers can only be compiled to assembly, not C or LLVM IR.
+    assembly_only: bool,
+}
+
+fn source(src: &Path) -> String {
+    fs::read_to_string(src).unwrap()
+}
+
+fn programs() -> Vec<Program> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut sources: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ctf"))
+        .collect();
+    sources.sort();
+    assert!(!sources.is_empty());
+    sources.into_iter().map(|src| Program {
+        name: src.file_stem().unwrap().to_str().unwrap().to_string(),
+        expected: fs::read_to_string(src.with_extension("out")).unwrap(),
+        status: fs::read_to_string(src.with_extension("status")).map_or(0, |s| s.trim().parse().unwrap()),
+        x86_64_only: ["asm(", "__cpuid", "__outb", "__inb"].iter().any(|s| source(&src).contains(s)),
+        assembly_only: source(&src).contains("#[interrupt]"),
+        src,
+    }).collect()
+}
+
+fn check(program: &Program, exe: &Path, mode: &[&str]) {
+    check_output(program, common::run(exe, &[], &[]), mode);
+}
+
+fn check_output(program: &Program, output: Output, mode: &[&str]) {
+    assert_eq!(String::from_utf8_lossy(&output.stdout), program.expected, "{} printed the wrong thing with {:?}", program.name, mode);
+    assert_eq!(output.status.code(), Some(program.status), "{} exited with the wrong status with {:?}", program.name, mode);
+}
+
+#[test]
+fn native() {
+    for program in programs() {
+        for level in LEVELS {
+            let dir = common::build_dir(&format!("programs-{}{}", program.name, level));
+            let exe = common::build(&dir, &[program.src.to_str().unwrap(), level]);
+            check(&program, &exe, &[level]);
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
+
+// The IR every backend is driven from can be written out on its own.
+#[test]
+fn emit_ir() {
+    for program in programs() {
+        let dir = common::build_dir(&format!("programs-ir-{}", program.name));
+        common::compile(&dir, &[program.src.to_str().unwrap(), "--emit=ir"]);
+        let ir = fs::read_to_string(dir.join("out.ir")).unwrap();
+        assert!(ir.contains("fn main("), "{}", ir);
+        assert!(!dir.join("out").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+// Objects from the built in assembler have to be ones binutils is happy with too.
+#[test]
+fn valid_objects() {
+    if !common::has_tool("readelf") { return }
+    for program in programs() {
+        let dir = common::build_dir(&format!("programs-obj-{}", program.name));
+        common::compile(&dir, &[program.src.to_str().unwrap(), "-c"]);
+        let readelf = Command::new("readelf").args(["-W", "-a", "out.o"]).current_dir(&dir).output().unwrap();
+        assert!(readelf.status.success() && readelf.stderr.is_empty(), "{}", String::from_utf8_lossy(&readelf.stderr));
+        assert!(String::from_utf8_lossy(&readelf.stdout).contains("REL (Relocatable file)"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+// Assembly which doesn't assemble is kept where the error says it is, even though the build directory isn't.
+#[test]
+fn assembler_error_keeps_asm() {
+    let dir = common::build_dir("programs-asm-error");
+    fs::write(dir.join("bad.ctf"), "fn main() -> i32 {\n    asm(\"movv rax, 1\" :::);\n    return 0;\n}\n").unwrap();
+    for (dialect, kept) in [("--asm-dialect=nasm", "bad.asm"), ("--asm-dialect=gas", "bad.s")] {
+        let build = Command::new(env!("CARGO_BIN_EXE_ctfaw")).args(["bad.ctf", dialect]).current_dir(&dir).output().unwrap();
+        let stdout = String::from_utf8_lossy(&build.stdout);
+        assert!(!build.status.success());
+        let line = stdout.lines().find_map(|l| l.split(&format!("{}:", kept)).nth(1)).expect(&stdout);
+        let asm = fs::read_to_string(dir.join(kept)).unwrap();
+        assert_eq!(asm.lines().nth(line.parse::<usize>().unwrap() - 1).unwrap().trim(), "movv rax, 1");
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// GAS syntax, assembled by the built in assembler and by binutils, and NASM syntax assembled by NASM.
+#[test]
+fn assemblers() {
+    let mut modes = vec![&["--asm-dialect=gas"][..]];
+    if common::has_tool("as") { modes.push(&["--asm-dialect=gas", "--assembler=as"]) }
+    if common::has_tool("nasm") { modes.push(&["--assembler=nasm"]) }
+    for program in programs() {
+        for (i, mode) in modes.iter().enumerate() {
+            let dir = common::build_dir(&format!("programs-gas-{}-{}", program.name, i));
+            let mut args = vec![program.src.to_str().unwrap(), "-O2"];
+            args.extend(*mode);
+            let exe = common::build(&dir, &args);
+            check(&program, &exe, mode);
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
+
+/* Builds the programs for another architecture, checking that they compile to assembly, then runs them
+ * under qemu-user if it and the cross toolchain are installed, with the toolchain's libraries. */
+fn cross(target: &str, toolchain: &str, qemu: &str) {
+    let target = format!("--target={}", target);
+    let can_run = common::has_tool(&format!("{}-gcc", toolchain)) && common::has_tool(qemu);
+    for program in programs().into_iter().filter(|p| !p.x86_64_only) {
+        for level in LEVELS {
+            let dir = common::build_dir(&format!("programs-{}-{}{}", qemu, program.name, level));
+            common::compile(&dir, &[program.src.to_str().unwrap(), &target, level, "-S"]);
+            assert!(dir.join("out.s").exists());
+            if can_run {
+                let exe = common::build(&dir, &[program.src.to_str().unwrap(), &target, level]);
+                let output = Command::new(qemu).args(["-L", &format!("/usr/{}", toolchain)]).arg(exe).output().unwrap();
+                check_output(&program, output, &[&target, level]);
+            }
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
+
+#[test]
+fn aarch64() {
+    cross("aarch64-linux", "aarch64-linux-gnu", "qemu-aarch64");
+}
+
+#[test]
+fn riscv64() {
+    cross("riscv64-linux", "riscv64-linux-gnu", "qemu-riscv64");
+}
+
+/* The C backend is checked against the native one: the C it outputs is built with gcc, with and
+ * without optimisations, and has to do exactly what the natively built program does. */
+#[test]
+fn c_backend() {
+    if !common::has_tool("gcc") { return }
+    for program in programs().into_iter().filter(|p| !p.assembly_only) {
+        for level in LEVELS {
+            let dir = common::build_dir(&format!("programs-c-{}{}", program.name, level));
+            let native = common::run(&common::build(&dir, &[program.src.to_str().unwrap(), level]), &[], &[]);
+            common::compile(&dir, &[program.src.to_str().unwrap(), level, "--emit=c"]);
+            for gcc_level in ["-O0", "-O2"] {
+                let gcc = Command::new("gcc").args(["out.c", "-funsigned-char", "-w", gcc_level, "-o", "c"]).current_dir(&dir).output().unwrap();
+                assert!(gcc.status.success(), "{}", String::from_utf8_lossy(&gcc.stderr));
+                let output = common::run(&dir.join("c"), &[], &[]);
+                assert_eq!(output.stdout, native.stdout, "{} prints something else through C with {} {}", program.name, level, gcc_level);
+                assert_eq!(output.status.code(), native.status.code(), "{} exits differently through C with {} {}", program.name, level, gcc_level);
+                check_output(&program, output, &[level, "--emit=c", gcc_level]);
+            }
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
+
+// LLVM IR, compiled with llc. LLVM 14 and older only take opaque pointers when asked to.
+#[test]
+fn llvm_backend() {
+    if !common::has_tool("llc") { return }
+    let version = String::from_utf8_lossy(&Command::new("llc").arg("--version").output().unwrap().stdout).into_owned();
+    let major: u32 = version.split("version ").nth(1).and_then(|v| v.split('.').next()).and_then(|v| v.trim().parse().ok()).unwrap_or(0);
+    for program in programs().into_iter().filter(|p| !p.assembly_only) {
+        for level in LEVELS {
+            let dir = common::build_dir(&format!("programs-llvm-{}{}", program.name, level));
+            common::compile(&dir, &[program.src.to_str().unwrap(), level, "--emit=llvm"]);
+            let mut llc = Command::new("llc");
+            llc.args(["-O2", "-relocation-model=pic", "-filetype=obj", "out.ll", "-o", "out.o"]).current_dir(&dir);
+            if major <= 14 { llc.arg("-opaque-pointers"); }
+            let llc = llc.output().unwrap();
+            assert!(llc.status.success(), "{}", String::from_utf8_lossy(&llc.stderr));
+            assert!(Command::new("gcc").args(["out.o", "-o", "ll"]).current_dir(&dir).status().unwrap().success());
+            check(&program, &dir.join("ll"), &[level, "--emit=llvm"]);
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
+
+/* WebAssembly, converted with wat2wasm and run under node by tests/wasm/run.js, which gives the
+ * module the libc functions it imports. Without them, it's only checked that the module is written. */
+#[test]
+fn wasm32() {
+    let can_run = common::has_tool("wat2wasm") && common::has_tool("node");
+    let runner = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/wasm/run.js");
+    for program in programs().into_iter().filter(|p| !p.x86_64_only) {
+        for level in LEVELS {
+            let dir = common::build_dir(&format!("programs-wasm-{}{}", program.name, level));
+            common::compile(&dir, &[program.src.to_str().unwrap(), "--target=wasm32", level]);
+            assert!(fs::read_to_string(dir.join("out.wat")).unwrap().starts_with("(module"));
+            if can_run {
+                assert!(Command::new("wat2wasm").args(["out.wat", "-o", "out.wasm"]).current_dir(&dir).status().unwrap().success());
+                let output = Command::new("node").arg(&runner).arg("out.wasm").current_dir(&dir).output().unwrap();
+                check_output(&program, output, &["--target=wasm32", level]);
+            }
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
+
+/* Freestanding programs are static executables starting at the compiler's own _start, with no libc,
+ * so the one in tests/freestanding makes its own system calls. */
+#[test]
+fn freestanding() {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/freestanding/main.ctf");
+    for level in LEVELS {
+        let dir = common::build_dir(&format!("freestanding{}", level));
+        let exe = common::build(&dir, &[src.to_str().unwrap(), "--freestanding", level]);
+        let output = common::run(&exe, &[], &[]);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "freestanding\n");
+        assert_eq!(output.status.code(), Some(55), "fib(10) isn't 55 with {}", level);
+        if common::has_tool("readelf") {
+            let headers = Command::new("readelf").args(["-W", "-h", "-l", "-s"]).arg(&exe).output().unwrap();
+            let headers = String::from_utf8_lossy(&headers.stdout);
+            assert!(!headers.contains("INTERP") && !headers.contains("DYNAMIC"), "{}", headers);
+            let entry = headers.lines().find_map(|l| l.trim().strip_prefix("Entry point address:")).unwrap().trim().trim_start_matches("0x");
+            assert!(headers.lines().any(|l| l.contains(" _start") && l.split_whitespace().nth(1).is_some_and(|v| v.trim_start_matches('0') == entry)), "{}", headers);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+// Nothing gets linked in, so calling an extern nothing defines is an error.
+#[test]
+fn freestanding_extern() {
+    let dir = common::build_dir("freestanding-extern");
+    fs::write(dir.join("main.ctf"), "extern puts(s: char*) -> i32;\nfn main() -> i32 {\n    puts(\"hi\");\n    return 0;\n}\n").unwrap();
+    let build = Command::new(env!("CARGO_BIN_EXE_ctfaw")).args(["main.ctf", "--freestanding"]).current_dir(&dir).output().unwrap();
+    assert!(!build.status.success());
+    assert!(String::from_utf8_lossy(&build.stdout).contains("puts is an extern function"));
+    assert!(!dir.join("out").exists());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// Clobbers which aren't registers (or memory or cc) are reported where the asm block is.
+#[test]
+fn asm_bad_clobber() {
+    let dir = common::build_dir("programs-bad-clobber");
+    fs::write(dir.join("main.ctf"), "fn main() -> i32 {\n    asm(\"nop\" ::: \"rax\", \"flags\");\n    return 0;\n}\n").unwrap();
+    let build = Command::new(env!("CARGO_BIN_EXE_ctfaw")).arg("main.ctf").current_dir(&dir).output().unwrap();
+    let stdout = String::from_utf8_lossy(&build.stdout);
+    assert!(!build.status.success());
+    assert!(stdout.contains("flags isn't one") && stdout.contains("main.ctf:2:"), "{}", stdout);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// So are operands which are referred to but don't exist, rather than being left for the assembler.
+#[test]
+fn asm_unknown_operand() {
+    let dir = common::build_dir("programs-unknown-operand");
+    fs::write(dir.join("main.ctf"), "fn main() -> i32 {\n    let x: u64 = 0;\n    asm(\"mov {zz}, 1\" :: [x] \"=r\" (x) :);\n    return 0;\n}\n").unwrap();
+    let build = Command::new(env!("CARGO_BIN_EXE_ctfaw")).arg("main.ctf").current_dir(&dir).output().unwrap();
+    let stdout = String::from_utf8_lossy(&build.stdout);
+    assert!(!build.status.success());
+    assert!(stdout.contains("no operand is named zz") && stdout.contains("main.ctf:3:"), "{}", stdout);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// __trap crashes with an illegal instruction however the program is built.
+#[test]
+fn trap() {
+    let dir = common::build_dir("programs-trap");
+    fs::write(dir.join("trap.ctf"), "fn main() -> i32 {\n    __syscall(1, 1, \"before\\n\", 7);\n    __trap();\n    __syscall(1, 1, \"after\\n\", 6);\n    return 0;\n}\n").unwrap();
+    for mode in [&["-O0"][..], &["-O2"], &["--freestanding"], &["--asm-dialect=gas"]] {
+        let _ = fs::remove_file(dir.join("out"));
+        let mut args = vec!["trap.ctf"];
+        args.extend(mode);
+        let output = common::run(&common::build(&dir, &args), &[], &[]);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "before\n");
+        assert_eq!(output.status.signal(), Some(4), "{:?} didn't die with SIGILL", mode);
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// The peephole optimiser mustn't touch the bodies of naked functions (or any other inline assembly).
+#[test]
+fn naked_functions_verbatim() {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs/interrupts.ctf");
+    for dialect in ["--asm-dialect=nasm", "--asm-dialect=gas"] {
+        let dir = common::build_dir(&format!("programs-verbatim-{}", &dialect[14..]));
+        common::compile(&dir, &[src.to_str().unwrap(), "-O2", "-S", dialect]);
+        let asm = fs::read_to_string(dir.join(if dialect.ends_with("gas") { "out.s" } else { "out.asm" })).unwrap();
+        let lines: Vec<&str> = asm.lines().map(|l| l.trim()).collect();
+        let body = ["mov rax, 1", "push rax", "pop rcx", "jmp verbatim_done", "nop", "verbatim_done:", "add rax, rcx", "ret"];
+        assert!(lines.windows(body.len()).any(|w| w == body), "{}", asm);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
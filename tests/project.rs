@@ -0,0 +1,53 @@
+/* Builds the project in tests/project with the subcommands, from a copy of it, since they write
+ * their output to target/ inside of the project. */
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()));
+        } else {
+            fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
+#[test]
+fn project_subcommands() {
+    let dir = common::build_dir("project");
+    copy_dir(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/project"), &dir);
+    // The manifest is found from any directory inside of the project. run exits with the program's status.
+    let output = Command::new(env!("CARGO_BIN_EXE_ctfaw")).args(["run", "--", "hi", "there"]).current_dir(dir.join("src")).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).ends_with("hi\n6\n"), "{}", String::from_utf8_lossy(&output.stdout));
+    assert_eq!(output.status.code(), Some(3));
+    assert!(dir.join("target/hello").exists());
+    let output = common::compile(&dir, &["build"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("hasn't changed since the last build"));
+    fs::write(dir.join("lib/util.ctf"), "pub fn twice(n: u64) -> u64 {\n    return n * 3;\n}\n").unwrap();
+    common::compile(&dir, &["build"]);
+    let output = common::run(&dir.join("target/hello"), &["hi"], &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\n6\n");
+    // So is changing a C header one of them imports.
+    fs::write(dir.join("src/status.h"), "#define EXIT_STATUS 4\n").unwrap();
+    common::compile(&dir, &["build"]);
+    assert_eq!(common::run(&dir.join("target/hello"), &["hi"], &[]).status.code(), Some(4));
+    // Paths on the command line are relative to where ctfaw was run, not to the manifest.
+    fs::write(dir.join("src/extra.ctf"), "fn extra() -> i32 {\n    return 1;\n}\n").unwrap();
+    common::compile(&dir.join("src"), &["extra.ctf", "-c", "-o", "extra.o"]);
+    common::compile(&dir.join("src"), &["build", "extra.o", "-o", "../bin/hello"]);
+    assert!(dir.join("bin/hello").exists());
+    // Input files are still built on their own, even inside of a project.
+    let exe = common::build(&dir, &["src/main.ctf", "-I", "lib"]);
+    let output = common::run(&exe, &["hi", "there"], &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\n9\n");
+    common::compile(&dir, &["clean"]);
+    assert!(!dir.join("target").exists());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1 @@
+#define EXIT_STATUS 3
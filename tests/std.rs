@@ -0,0 +1,42 @@
+/* Builds every program in std/tests with the standard library, on top of libc and freestanding,
+ * and checks what it prints. A program is given the arguments `one two`, its `.in` file (if there
+ * is one) on stdin, and has to exit with the status in its `.status` file, or 0 without one.
+ */
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MODES: [&[&str]; 4] = [&["-O0"], &["-O2"], &["--freestanding", "-O0"], &["--freestanding", "-O2"]];
+
+fn run_program(src: &Path) {
+    let name = src.file_stem().unwrap().to_str().unwrap();
+    let expected = fs::read_to_string(src.with_extension("out")).unwrap();
+    let stdin = fs::read(src.with_extension("in")).unwrap_or_default();
+    let status = fs::read_to_string(src.with_extension("status")).map_or(0, |s| s.trim().parse().unwrap());
+    for (i, mode) in MODES.iter().enumerate() {
+        let dir = common::build_dir(&format!("std-{}-{}", name, i));
+        let mut args = vec![src.to_str().unwrap()];
+        args.extend(*mode);
+        let exe = common::build(&dir, &args);
+        let output = common::run(&exe, &["one", "two"], &stdin);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected, "{} printed the wrong thing with {:?}", name, mode);
+        assert_eq!(output.status.code(), Some(status), "{} exited with the wrong status with {:?}", name, mode);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+#[test]
+fn std_programs() {
+    let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("std/tests");
+    let mut sources: Vec<PathBuf> = fs::read_dir(tests).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ctf"))
+        .collect();
+    sources.sort();
+    assert!(!sources.is_empty());
+    for src in sources {
+        run_program(&src);
+    }
+}
@@ -0,0 +1,43 @@
+// Runs a program built with --target=wasm32 (after converting it with wat2wasm) under node, providing
+// the libc functions the test programs import, and exits with what main returns.
+// Usage: node run.js out.wasm
+const fs = require('fs');
+
+let memory;
+const bytes = () => new Uint8Array(memory.buffer);
+const string = (ptr) => {
+    const mem = bytes();
+    let end = ptr;
+    while (mem[end] !== 0) end++;
+    return Buffer.from(mem.slice(ptr, end)).toString();
+};
+const env = {
+    // Variadic arguments are in a buffer, each aligned to its size: 64 bits with `l`, otherwise 32.
+    printf(fmt, args) {
+        const view = new DataView(memory.buffer);
+        const next = (long) => {
+            const size = long ? 8 : 4;
+            args = Math.ceil(args / size) * size;
+            const val = long ? view.getBigUint64(args, true) : BigInt(view.getUint32(args, true));
+            args += size;
+            return val;
+        };
+        const out = string(fmt).replace(/%(l?)([dusxc%])/g, (_, l, conv) => {
+            switch (conv) {
+                case '%': return '%';
+                case 'd': return String(BigInt.asIntN(l ? 64 : 32, next(l)));
+                case 'u': return String(next(l));
+                case 'x': return next(l).toString(16);
+                case 'c': return String.fromCharCode(Number(next(l)));
+                case 's': return string(Number(next(l)));
+            }
+        });
+        fs.writeSync(1, out);
+        return out.length;
+    },
+};
+
+const wasm = new WebAssembly.Module(fs.readFileSync(process.argv[2]));
+const instance = new WebAssembly.Instance(wasm, { env });
+memory = instance.exports.memory;
+process.exitCode = Number(BigInt.asUintN(8, BigInt(instance.exports.main())));